@@ -0,0 +1,332 @@
+use serde::Deserialize;
+use tracing::{error, info};
+use trust_dns_proto::op::{Message, MessageType, Query};
+use trust_dns_proto::rr::{Name, RecordType};
+
+use crate::helper::{call_next_plugin, load_config};
+use crate::plugin::{Error, Plugin};
+
+wit_bindgen::generate!("rubydns");
+
+/// shrink the ANY responses of the zone apexes, which hold large record sets such as MX, TXT
+/// and DNSKEY, so they are less useful for amplification
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    /// the zone apex names
+    zones: Vec<String>,
+    #[serde(default)]
+    apex_any: ApexAny,
+}
+
+/// what an ANY query of a zone apex is answered with
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ApexAny {
+    /// only the SOA record
+    #[default]
+    Soa,
+
+    /// the SOA and NS records
+    Minimal,
+
+    /// the full response of the next plugin
+    Full,
+}
+
+impl ApexAny {
+    fn record_types(&self) -> &'static [RecordType] {
+        match self {
+            ApexAny::Soa => &[RecordType::SOA],
+            ApexAny::Minimal => &[RecordType::SOA, RecordType::NS],
+            ApexAny::Full => &[],
+        }
+    }
+}
+
+impl Config {
+    fn zones(&self) -> Result<Vec<Name>, Error> {
+        self.zones
+            .iter()
+            .map(|zone| {
+                Name::from_ascii(zone).map_err(|err| {
+                    error!(%err, zone, "invalid zone");
+
+                    Error {
+                        code: 1,
+                        msg: format!("invalid zone {zone}: {err}"),
+                    }
+                })
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug)]
+struct ApexAnyRunner;
+
+impl Plugin for ApexAnyRunner {
+    fn run(dns_packet: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let config = load_config();
+        let config: Config = serde_yaml::from_str(&config).map_err(|err| {
+            error!(%err, "load apex any config failed");
+
+            Error {
+                code: 1,
+                msg: err.to_string(),
+            }
+        })?;
+
+        answer_query(&config, &dns_packet, call_next)
+    }
+
+    fn valid_config() -> Result<(), Error> {
+        let config: Config = serde_yaml::from_str(&load_config()).map_err(|err| {
+            error!(%err, "load apex any config failed");
+
+            Error {
+                code: 1,
+                msg: err.to_string(),
+            }
+        })?;
+
+        config.zones()?;
+
+        Ok(())
+    }
+
+    fn version() -> String {
+        env!("CARGO_PKG_VERSION").to_string()
+    }
+}
+
+/// answer the ANY query of a zone apex with the configured record types, they are queried
+/// one by one by `exchange`, the other queries are sent as they are
+fn answer_query(
+    config: &Config,
+    dns_packet: &[u8],
+    mut exchange: impl FnMut(&[u8]) -> Result<Vec<u8>, Error>,
+) -> Result<Vec<u8>, Error> {
+    if config.apex_any == ApexAny::Full {
+        return exchange(dns_packet);
+    }
+
+    let request_message = Message::from_vec(dns_packet).map_err(|err| {
+        error!(%err, "decode dns request packet failed");
+
+        Error {
+            code: 1,
+            msg: err.to_string(),
+        }
+    })?;
+
+    let query = match request_message.query() {
+        Some(query) if query.query_type() == RecordType::ANY => query,
+        _ => return exchange(dns_packet),
+    };
+
+    // the name comparison is case insensitive
+    if !config.zones()?.contains(query.name()) {
+        return exchange(dns_packet);
+    }
+
+    info!(name = %query.name(), apex_any = ?config.apex_any, "answer apex ANY query");
+
+    let mut response_message = Message::new();
+    response_message
+        .set_id(request_message.id())
+        .set_message_type(MessageType::Response)
+        .set_op_code(request_message.op_code())
+        .set_recursion_desired(request_message.recursion_desired())
+        .add_queries(request_message.queries().iter().cloned());
+
+    for (index, record_type) in config.apex_any.record_types().iter().enumerate() {
+        let mut type_request = request_message.clone();
+        type_request.take_queries();
+        type_request.add_query(Query::query(query.name().clone(), *record_type));
+
+        let type_response = exchange(&encode(&type_request)?)?;
+        let mut type_response = Message::from_vec(&type_response).map_err(|err| {
+            error!(%err, "decode dns response packet failed");
+
+            Error {
+                code: 1,
+                msg: err.to_string(),
+            }
+        })?;
+
+        // the SOA response decides the header and the authority section
+        if index == 0 {
+            response_message
+                .set_response_code(type_response.response_code())
+                .set_authoritative(type_response.authoritative())
+                .set_recursion_available(type_response.recursion_available())
+                .add_name_servers(type_response.take_name_servers());
+        }
+
+        response_message.add_answers(type_response.take_answers());
+    }
+
+    encode(&response_message)
+}
+
+fn encode(message: &Message) -> Result<Vec<u8>, Error> {
+    message.to_vec().map_err(|err| {
+        error!(%err, "encode dns packet failed");
+
+        Error {
+            code: 1,
+            msg: err.to_string(),
+        }
+    })
+}
+
+fn call_next(dns_packet: &[u8]) -> Result<Vec<u8>, Error> {
+    match call_next_plugin(dns_packet) {
+        None => Err(Error {
+            code: 1,
+            msg: "no next plugin".to_string(),
+        }),
+
+        Some(result) => result,
+    }
+}
+
+export_rubydns!(ApexAnyRunner);
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use trust_dns_proto::rr::rdata::{MX, SOA};
+    use trust_dns_proto::rr::{RData, Record};
+
+    use super::*;
+
+    fn name(name: &str) -> Name {
+        Name::from_str(name).unwrap()
+    }
+
+    fn config(apex_any: &str) -> Config {
+        serde_yaml::from_str(&format!("{{zones: [example.com.], apex_any: {apex_any}}}")).unwrap()
+    }
+
+    fn request(query_name: &str, query_type: RecordType) -> Vec<u8> {
+        let mut message = Message::new();
+        message
+            .set_id(1234)
+            .add_query(Query::query(name(query_name), query_type));
+
+        message.to_vec().unwrap()
+    }
+
+    /// the records of the example.com zone apex
+    fn zone_records(query_type: RecordType) -> Vec<Record> {
+        let apex = name("example.com.");
+        let soa = SOA::new(
+            name("ns1.example.com."),
+            name("admin.example.com."),
+            1,
+            3600,
+            600,
+            86400,
+            300,
+        );
+        let records = [
+            RData::SOA(soa),
+            RData::NS(name("ns1.example.com.")),
+            RData::MX(MX::new(10, name("mail.example.com."))),
+        ];
+
+        records
+            .into_iter()
+            .map(|rdata| Record::from_rdata(apex.clone(), 300, rdata))
+            .filter(|record| query_type == RecordType::ANY || record.record_type() == query_type)
+            .collect()
+    }
+
+    /// answer from the zone apex, the sent query types are collected
+    fn zone<'a>(
+        query_types: &'a mut Vec<RecordType>,
+    ) -> impl FnMut(&[u8]) -> Result<Vec<u8>, Error> + 'a {
+        |dns_packet| {
+            let mut message = Message::from_vec(dns_packet).unwrap();
+            let query_type = message.query().unwrap().query_type();
+            query_types.push(query_type);
+
+            message
+                .set_message_type(MessageType::Response)
+                .set_authoritative(true)
+                .add_answers(zone_records(query_type));
+
+            Ok(message.to_vec().unwrap())
+        }
+    }
+
+    fn answer_types(response: &[u8]) -> Vec<RecordType> {
+        Message::from_vec(response)
+            .unwrap()
+            .answers()
+            .iter()
+            .map(|record| record.record_type())
+            .collect()
+    }
+
+    #[test]
+    fn answer_apex_any_with_soa_only() {
+        let mut query_types = vec![];
+
+        let response = answer_query(
+            &config("soa"),
+            &request("example.com.", RecordType::ANY),
+            zone(&mut query_types),
+        )
+        .unwrap();
+
+        assert_eq!(query_types, [RecordType::SOA]);
+        assert_eq!(answer_types(&response), [RecordType::SOA]);
+
+        let response = Message::from_vec(&response).unwrap();
+        assert_eq!(response.id(), 1234);
+        assert!(response.authoritative());
+        assert_eq!(response.query().unwrap().query_type(), RecordType::ANY);
+    }
+
+    #[test]
+    fn answer_apex_any_with_soa_and_ns() {
+        let mut query_types = vec![];
+
+        let response = answer_query(
+            &config("minimal"),
+            &request("EXAMPLE.com.", RecordType::ANY),
+            zone(&mut query_types),
+        )
+        .unwrap();
+
+        assert_eq!(query_types, [RecordType::SOA, RecordType::NS]);
+        assert_eq!(answer_types(&response), [RecordType::SOA, RecordType::NS]);
+    }
+
+    #[test]
+    fn pass_through_full_and_other_queries() {
+        for (config, query_name, query_type) in [
+            (config("full"), "example.com.", RecordType::ANY),
+            (config("soa"), "www.example.com.", RecordType::ANY),
+            (config("soa"), "example.com.", RecordType::MX),
+        ] {
+            let mut query_types = vec![];
+
+            let response = answer_query(
+                &config,
+                &request(query_name, query_type),
+                zone(&mut query_types),
+            )
+            .unwrap();
+
+            assert_eq!(query_types, [query_type]);
+            if query_name == "example.com." {
+                assert!(!answer_types(&response).is_empty());
+            }
+        }
+    }
+}
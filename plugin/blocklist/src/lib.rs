@@ -0,0 +1,171 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use serde::Deserialize;
+use tracing::{error, info};
+use trust_dns_proto::op::{Message, MessageType, ResponseCode};
+use trust_dns_proto::rr::{Name, RData, Record, RecordType};
+
+use crate::helper::{call_next_plugin, load_config};
+use crate::plugin::{Error, Plugin};
+use crate::trie::SuffixTrie;
+
+mod trie;
+
+wit_bindgen::generate!("rubydns");
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    /// the blocked domains, `*.example.com` blocks the subdomains of `example.com`
+    domains: Vec<String>,
+    #[serde(default)]
+    mode: Mode,
+    /// the ttl of the sinkhole answers
+    #[serde(default = "default_ttl")]
+    ttl: u32,
+}
+
+fn default_ttl() -> u32 {
+    300
+}
+
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Mode {
+    /// answer NXDOMAIN
+    #[default]
+    Nxdomain,
+
+    /// answer `0.0.0.0` to A and `::` to AAAA, the other types get no data
+    Sinkhole,
+}
+
+#[derive(Debug)]
+struct BlocklistRunner;
+
+impl Plugin for BlocklistRunner {
+    fn run(dns_packet: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let config = load_config();
+        let config: Config = serde_yaml::from_str(&config).map_err(|err| {
+            error!(%err, "load blocklist config failed");
+
+            Error {
+                code: 1,
+                msg: err.to_string(),
+            }
+        })?;
+
+        let request_message = Message::from_vec(&dns_packet).map_err(|err| {
+            error!(%err, "decode dns request packet failed");
+
+            Error {
+                code: 1,
+                msg: err.to_string(),
+            }
+        })?;
+
+        let mut blocklist = SuffixTrie::default();
+        for domain in &config.domains {
+            blocklist.insert(domain);
+        }
+
+        let query = match request_message.query() {
+            Some(query) if blocklist.contains(query.name()) => query,
+            _ => return call_next(&dns_packet),
+        };
+
+        info!(name = %query.name(), mode = ?config.mode, "block query");
+
+        let mut response_message = Message::new();
+        response_message
+            .set_id(request_message.id())
+            .set_message_type(MessageType::Response)
+            .set_op_code(request_message.op_code())
+            .set_recursion_desired(request_message.recursion_desired())
+            .set_recursion_available(true)
+            .add_queries(request_message.queries().iter().cloned());
+
+        match config.mode {
+            Mode::Nxdomain => {
+                response_message.set_response_code(ResponseCode::NXDomain);
+            }
+
+            Mode::Sinkhole => {
+                let rdata = match query.query_type() {
+                    RecordType::A => Some(RData::A(Ipv4Addr::UNSPECIFIED)),
+                    RecordType::AAAA => Some(RData::AAAA(Ipv6Addr::UNSPECIFIED)),
+                    _ => None,
+                };
+
+                response_message.set_response_code(ResponseCode::NoError);
+                if let Some(rdata) = rdata {
+                    response_message.add_answer(Record::from_rdata(
+                        query.name().clone(),
+                        config.ttl,
+                        rdata,
+                    ));
+                }
+            }
+        }
+
+        response_message.to_vec().map_err(|err| {
+            error!(%err, "encode dns response packet failed");
+
+            Error {
+                code: 1,
+                msg: err.to_string(),
+            }
+        })
+    }
+
+    fn valid_config() -> Result<(), Error> {
+        let config: Config = serde_yaml::from_str(&load_config()).map_err(|err| {
+            error!(%err, "load blocklist config failed");
+
+            Error {
+                code: 1,
+                msg: err.to_string(),
+            }
+        })?;
+
+        for domain in &config.domains {
+            let name = domain.strip_prefix("*.").unwrap_or(domain);
+            Name::from_ascii(name).map_err(|err| {
+                error!(%err, domain, "invalid blocklist domain");
+
+                Error {
+                    code: 1,
+                    msg: format!("invalid domain {domain}: {err}"),
+                }
+            })?;
+
+            if name.contains('*') {
+                error!(domain, "wildcard is only allowed as the first label");
+
+                return Err(Error {
+                    code: 1,
+                    msg: format!("invalid domain {domain}"),
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    fn version() -> String {
+        env!("CARGO_PKG_VERSION").to_string()
+    }
+}
+
+fn call_next(dns_packet: &[u8]) -> Result<Vec<u8>, Error> {
+    match call_next_plugin(dns_packet) {
+        None => Err(Error {
+            code: 1,
+            msg: "no next plugin".to_string(),
+        }),
+
+        Some(result) => result,
+    }
+}
+
+export_rubydns!(BlocklistRunner);
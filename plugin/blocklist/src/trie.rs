@@ -0,0 +1,112 @@
+use std::collections::HashMap;
+
+use trust_dns_proto::rr::Name;
+
+/// the blocked domains keyed by their labels from the root, so a lookup walks the query name
+/// once whatever the list size is
+#[derive(Debug, Default)]
+pub struct SuffixTrie {
+    children: HashMap<String, SuffixTrie>,
+    /// the domain ending at this node is blocked
+    exact: bool,
+    /// the subdomains of the domain ending at this node are blocked
+    wildcard: bool,
+}
+
+impl SuffixTrie {
+    /// `*.example.com` blocks the subdomains of `example.com`, but not itself
+    pub fn insert(&mut self, domain: &str) {
+        let (wildcard, domain) = match domain.strip_prefix("*.") {
+            None => (false, domain),
+            Some(domain) => (true, domain),
+        };
+
+        let node = domain
+            .trim_end_matches('.')
+            .rsplit('.')
+            .fold(self, |node, label| {
+                node.children.entry(label.to_lowercase()).or_default()
+            });
+
+        if wildcard {
+            node.wildcard = true;
+        } else {
+            node.exact = true;
+        }
+    }
+
+    pub fn contains(&self, name: &Name) -> bool {
+        let labels = name
+            .iter()
+            .rev()
+            .map(|label| String::from_utf8_lossy(label).to_lowercase())
+            .collect::<Vec<_>>();
+
+        let mut node = self;
+        for (index, label) in labels.iter().enumerate() {
+            node = match node.children.get(label) {
+                None => return false,
+                Some(node) => node,
+            };
+
+            let last = index + 1 == labels.len();
+            if (last && node.exact) || (!last && node.wildcard) {
+                return true;
+            }
+        }
+
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    fn trie(domains: &[&str]) -> SuffixTrie {
+        let mut trie = SuffixTrie::default();
+        for domain in domains {
+            trie.insert(domain);
+        }
+
+        trie
+    }
+
+    fn contains(trie: &SuffixTrie, name: &str) -> bool {
+        trie.contains(&Name::from_str(name).unwrap())
+    }
+
+    #[test]
+    fn exact_match() {
+        let trie = trie(&["tracker.example.com"]);
+
+        assert!(contains(&trie, "tracker.example.com."));
+        assert!(contains(&trie, "Tracker.Example.COM."));
+        assert!(!contains(&trie, "a.tracker.example.com."));
+        assert!(!contains(&trie, "example.com."));
+        assert!(!contains(&trie, "tracker.example.org."));
+    }
+
+    #[test]
+    fn wildcard_match() {
+        let trie = trie(&["*.ads.example."]);
+
+        assert!(contains(&trie, "x.ads.example."));
+        assert!(contains(&trie, "a.b.ads.example."));
+        assert!(!contains(&trie, "ads.example."));
+        assert!(!contains(&trie, "badads.example."));
+    }
+
+    #[test]
+    fn wildcard_and_exact_match() {
+        let trie = trie(&["ads.example", "*.ads.example", "*.cdn.example"]);
+
+        assert!(contains(&trie, "ads.example."));
+        assert!(contains(&trie, "x.ads.example."));
+        assert!(contains(&trie, "x.cdn.example."));
+        assert!(!contains(&trie, "cdn.example."));
+        assert!(!contains(&trie, "example."));
+    }
+}
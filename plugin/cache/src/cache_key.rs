@@ -1,7 +1,9 @@
 use std::fmt;
 use std::fmt::{Debug, Formatter};
+use std::net::IpAddr;
 use std::ops::{Deref, DerefMut};
 
+use plugin_utils::subnet;
 use serde::de::{Error, Visitor};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use trust_dns_proto::op::Query;
@@ -9,9 +11,29 @@ use trust_dns_proto::serialize::binary::{BinDecodable, BinEncodable};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CacheKey {
+    /// the client subnet which owns the cache entry, shared by all clients when unset
+    pub partition: Option<IpAddr>,
+    /// iterative and recursive queries expect different answers, so they never share entries
+    pub recursion_desired: bool,
     pub query: Vec<QueryDef>,
 }
 
+/// mask the client ip with the partition prefix length, return [`None`] when the cache is
+/// shared by the client address family
+pub fn partition(
+    client_ip: IpAddr,
+    prefix_v4: Option<u8>,
+    prefix_v6: Option<u8>,
+) -> Option<IpAddr> {
+    let prefix = if client_ip.to_canonical().is_ipv4() {
+        prefix_v4?
+    } else {
+        prefix_v6?
+    };
+
+    Some(subnet::mask(client_ip, prefix, prefix))
+}
+
 pub struct QueryDef(Query);
 
 impl Debug for QueryDef {
@@ -87,3 +109,36 @@ impl<'a> Visitor<'a> for BytesVisitor {
         Ok(v)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn mask_client_ip_by_family() {
+        assert_eq!(
+            partition(ip("192.0.2.130"), Some(25), Some(56)),
+            Some(ip("192.0.2.128"))
+        );
+        assert_eq!(
+            partition(ip("2001:db8:0:1ff::1"), Some(25), Some(56)),
+            Some(ip("2001:db8:0:100::"))
+        );
+    }
+
+    #[test]
+    fn no_partition_without_prefix() {
+        assert_eq!(partition(ip("192.0.2.1"), None, Some(56)), None);
+        assert_eq!(partition(ip("2001:db8::1"), Some(24), None), None);
+    }
+
+    #[test]
+    fn mapped_ipv4_uses_ipv4_prefix() {
+        assert_eq!(partition(ip("::ffff:192.0.2.1"), None, Some(56)), None);
+        assert!(partition(ip("::ffff:192.0.2.1"), Some(24), None).is_some());
+    }
+}
@@ -0,0 +1,194 @@
+use serde::{Deserialize, Serialize};
+use trust_dns_proto::op::{Message, ResponseCode};
+use trust_dns_proto::rr::rdata::TXT;
+use trust_dns_proto::rr::{Name, RData, Record, RecordType};
+
+/// the EDNS option code a client sets to ask for the cache metadata, it is in the local
+/// use range
+pub const DEBUG_EDNS_OPTION: u16 = 65001;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CacheEntry {
+    /// unix seconds when the entry is stored, so the remaining ttl can be served, identical
+    /// responses stored in the same second still share one copy in a dedup store
+    pub inserted_at: Option<u64>,
+    /// the original min ttl of the answers, or the negative ttl of a response without answer,
+    /// scaled by the aa ttl multiplier for the authoritative answers and clamped by the min
+    /// and max ttl
+    pub ttl: u32,
+    /// the response only has the CNAME records, the client needs to follow it
+    pub cname_only: bool,
+    pub response: Vec<u8>,
+}
+
+impl CacheEntry {
+    /// [`None`] means the insert time is unknown
+    pub fn remaining_ttl(&self, now: u64) -> Option<u32> {
+        let inserted_at = self.inserted_at?;
+
+        Some((inserted_at + self.ttl as u64).saturating_sub(now) as u32)
+    }
+
+    /// describe the entry with a TXT record, which is used to debug the cache behavior
+    pub fn debug_record(&self, name: Name, now: u64) -> Record {
+        let metadata = match self.inserted_at {
+            None => format!("original_ttl={}", self.ttl),
+            Some(inserted_at) => {
+                let expires_at = inserted_at + self.ttl as u64;
+
+                format!(
+                    "inserted_at={} original_ttl={} remaining_ttl={} expires_at={}",
+                    inserted_at,
+                    self.ttl,
+                    expires_at.saturating_sub(now),
+                    expires_at
+                )
+            }
+        };
+
+        let metadata = if self.cname_only {
+            metadata + " cname_only=true"
+        } else {
+            metadata
+        };
+
+        Record::from_rdata(name, 0, RData::TXT(TXT::new(vec![metadata])))
+    }
+}
+
+/// the ttl to cache the response, which is the min ttl of the answers, [`None`] means the
+/// response isn't cached
+///
+/// a CNAME only response is cached too, with the CNAME ttl, the target addresses may come
+/// from other entries with their own ttl
+pub fn cache_ttl(response_message: &Message, max_negative_ttl: u32) -> Option<u32> {
+    response_message
+        .answers()
+        .iter()
+        .map(|answer| answer.ttl())
+        .min()
+        .or_else(|| negative_ttl(response_message, max_negative_ttl))
+}
+
+/// a NXDOMAIN or NODATA response is cached with the smaller one of the SOA ttl and the SOA
+/// minimum in the authority section, as RFC 2308 says, the response without SOA isn't cached
+fn negative_ttl(response_message: &Message, max_negative_ttl: u32) -> Option<u32> {
+    if !matches!(
+        response_message.response_code(),
+        ResponseCode::NXDomain | ResponseCode::NoError
+    ) {
+        return None;
+    }
+
+    let ttl = response_message
+        .name_servers()
+        .iter()
+        .find_map(|record| match record.data() {
+            Some(RData::SOA(soa)) => Some(record.ttl().min(soa.minimum())),
+            _ => None,
+        })?
+        .min(max_negative_ttl);
+
+    (ttl > 0).then_some(ttl)
+}
+
+pub fn is_cname_only(response_message: &Message) -> bool {
+    !response_message.answers().is_empty()
+        && response_message
+            .answers()
+            .iter()
+            .all(|answer| answer.record_type() == RecordType::CNAME)
+}
+
+pub fn is_debug_query(request_message: &Message) -> bool {
+    request_message
+        .extensions()
+        .as_ref()
+        .and_then(|edns| edns.option(DEBUG_EDNS_OPTION.into()))
+        .is_some()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use std::net::Ipv4Addr;
+
+    use trust_dns_proto::op::{Edns, Query};
+    use trust_dns_proto::rr::rdata::opt::EdnsOption;
+
+    use super::*;
+
+    fn request(options: &[u16]) -> Message {
+        let mut message = Message::new();
+        message.add_query(Query::query(
+            Name::from_str("www.example.com.").unwrap(),
+            RecordType::A,
+        ));
+
+        let mut edns = Edns::new();
+        for code in options {
+            edns.options_mut()
+                .insert(EdnsOption::Unknown(*code, vec![]));
+        }
+        message.set_edns(edns);
+
+        message
+    }
+
+    #[test]
+    fn detect_debug_query() {
+        assert!(is_debug_query(&request(&[DEBUG_EDNS_OPTION])));
+        assert!(!is_debug_query(&request(&[])));
+        assert!(!is_debug_query(&Message::new()));
+    }
+
+    fn name(name: &str) -> Name {
+        Name::from_str(name).unwrap()
+    }
+
+    fn cname_record(ttl: u32) -> Record {
+        Record::from_rdata(
+            name("www.example.com."),
+            ttl,
+            RData::CNAME(name("cdn.example.net.")),
+        )
+    }
+
+    #[test]
+    fn cache_cname_only_response_with_cname_ttl() {
+        let mut response_message = request(&[]);
+        response_message.add_answer(cname_record(120));
+
+        assert!(is_cname_only(&response_message));
+        assert_eq!(cache_ttl(&response_message, 300), Some(120));
+
+        let entry = CacheEntry {
+            inserted_at: Some(1000),
+            ttl: 120,
+            cname_only: true,
+            response: vec![],
+        };
+        let record = entry.debug_record(name("www.example.com."), 1000);
+        match record.data() {
+            Some(RData::TXT(txt)) => assert!(txt.to_string().ends_with("cname_only=true")),
+            data => panic!("unexpected debug record data {data:?}"),
+        }
+    }
+
+    #[test]
+    fn cache_cname_chain_with_min_ttl() {
+        let mut response_message = request(&[]);
+        response_message
+            .add_answer(cname_record(120))
+            .add_answer(Record::from_rdata(
+                name("cdn.example.net."),
+                30,
+                RData::A(Ipv4Addr::new(192, 0, 2, 1)),
+            ));
+
+        assert!(!is_cname_only(&response_message));
+        assert_eq!(cache_ttl(&response_message, 300), Some(30));
+        assert!(!is_cname_only(&request(&[])));
+    }
+}
@@ -1,20 +1,80 @@
+use std::net::SocketAddr;
+
 use bincode::{DefaultOptions, Options};
-use tracing::error;
+use plugin_utils::intercept::{intercept_response, NextError};
+use plugin_utils::net::client_addr;
+use plugin_utils::{time, DROP_ERROR_CODE};
+use serde::Deserialize;
+use tracing::{error, warn};
 use trust_dns_proto::op::{Message, MessageType};
+use trust_dns_proto::rr::Record;
 
 use crate::cache_key::{CacheKey, QueryDef};
-use crate::helper::{call_next_plugin, map_get, map_set};
+use crate::entry::CacheEntry;
+use crate::helper::{load_config, map_get, map_set, metric_incr};
 use crate::plugin::{Error, Plugin};
 
 mod cache_key;
+mod entry;
 
 wit_bindgen::generate!("rubydns");
 
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    /// isolate the cache entries of ipv4 clients by the subnet with this prefix length
+    partition_prefix_v4: Option<u8>,
+    /// isolate the cache entries of ipv6 clients by the subnet with this prefix length
+    partition_prefix_v6: Option<u8>,
+    /// add a TXT record describing the cache entry to the additional section when the query
+    /// carries the debug EDNS option
+    #[serde(default)]
+    debug_queries: bool,
+    /// only cache the authoritative answers, which have the AA bit set
+    #[serde(default)]
+    cache_only_authoritative: bool,
+    /// cache the authoritative answers longer or shorter, by scaling their ttl
+    aa_ttl_multiplier: Option<f64>,
+    /// cap the ttl of the cached NXDOMAIN and NODATA responses, 0 disables negative caching
+    #[serde(default = "default_max_negative_ttl")]
+    max_negative_ttl: u32,
+    /// cache the responses at least this long, even when their ttl is shorter
+    min_ttl: Option<u32>,
+    /// cache the responses at most this long, even when their ttl is longer
+    max_ttl: Option<u32>,
+    /// keep the responses this long after their ttl expires, they are answered when the
+    /// lookup fails, disabled when unset
+    serve_stale_ttl: Option<u32>,
+}
+
+/// the ttl of a stale answer, as RFC 8767 suggests
+const STALE_ANSWER_TTL: u32 = 30;
+
+/// RFC 2308 suggests 1 to 3 hours
+fn default_max_negative_ttl() -> u32 {
+    3 * 60 * 60
+}
+
+/// the unknown fields are rejected, the error tells which field is wrong, so a typo isn't
+/// ignored silently
+fn parse_config(config: &str) -> Result<Config, Error> {
+    serde_yaml::from_str(config).map_err(|err| {
+        error!(%err, "load cache config failed");
+
+        Error {
+            code: 1,
+            msg: err.to_string(),
+        }
+    })
+}
+
 #[derive(Debug)]
 struct CacheRunner;
 
 impl Plugin for CacheRunner {
     fn run(dns_packet: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let config = parse_config(&load_config())?;
+
         let request_message = Message::from_vec(&dns_packet).map_err(|err| {
             error!(%err, "decode dns request packet failed");
 
@@ -24,68 +84,167 @@ impl Plugin for CacheRunner {
             }
         })?;
 
-        let cache_key = CacheKey {
-            query: request_message
-                .queries()
-                .iter()
-                .map(|query| QueryDef::from(query.clone()))
-                .collect(),
+        let cache_key = cache_key(&request_message, client_addr(), &config)?;
+
+        let debug = config.debug_queries && entry::is_debug_query(&request_message);
+
+        let cache_entry = map_get(&cache_key).and_then(|data| {
+            DefaultOptions::new()
+                .deserialize::<CacheEntry>(&data)
+                .map_err(|err| error!(%err, "decode cache entry failed, ignore it"))
+                .ok()
+        });
+
+        let cache_entry = match cache_entry {
+            None => {
+                metric_incr("miss");
+
+                return call_next_and_set_cache(&dns_packet, cache_key, &config, debug);
+            }
+
+            Some(cache_entry) => cache_entry,
         };
 
-        let cache_key = DefaultOptions::new().serialize(&cache_key).map_err(|err| {
-            error!(%err, ?cache_key, "encode cache key failed");
+        // the store timeout is in seconds, the entry may live until the end of its last
+        // second, or it is kept longer to serve stale, look it up again rather than
+        // answering a zero ttl
+        let now = time::now_unix_secs();
+        if cache_entry.remaining_ttl(now) != Some(0) {
+            metric_incr("hit");
 
-            Error {
-                code: 1,
-                msg: err.to_string(),
+            return create_response_from_cache(request_message, cache_entry, now, false, debug);
+        }
+
+        metric_incr("miss");
+
+        match call_next_and_set_cache(&dns_packet, cache_key, &config, debug) {
+            Err(err) if should_serve_stale(&config, &err) => {
+                warn!(
+                    code = err.code,
+                    msg = err.msg,
+                    "lookup failed, serve stale answer"
+                );
+
+                metric_incr("stale");
+
+                create_response_from_cache(request_message, cache_entry, now, true, debug)
             }
-        })?;
 
-        match map_get(&cache_key) {
-            None => call_next_and_set_cache(&dns_packet, cache_key),
-            Some(response_packet) => create_response_from_cache(&dns_packet, response_packet),
+            result => result,
         }
     }
 
     fn valid_config() -> Result<(), Error> {
+        let config = parse_config(&load_config())?;
+
+        if config.partition_prefix_v4.unwrap_or(0) > 32
+            || config.partition_prefix_v6.unwrap_or(0) > 128
+        {
+            error!(?config, "invalid cache partition prefix length");
+
+            return Err(Error {
+                code: 1,
+                msg: "invalid cache partition prefix length".to_string(),
+            });
+        }
+
+        if config.min_ttl.unwrap_or(0) > config.max_ttl.unwrap_or(u32::MAX) {
+            error!(
+                min_ttl = config.min_ttl,
+                max_ttl = config.max_ttl,
+                "invalid cache ttl range"
+            );
+
+            return Err(Error {
+                code: 1,
+                msg: "min_ttl must not be greater than max_ttl".to_string(),
+            });
+        }
+
+        if let Some(multiplier) = config.aa_ttl_multiplier {
+            if !multiplier.is_finite() || multiplier <= 0.0 {
+                error!(multiplier, "invalid cache aa ttl multiplier");
+
+                return Err(Error {
+                    code: 1,
+                    msg: "aa_ttl_multiplier must be positive".to_string(),
+                });
+            }
+        }
+
         Ok(())
     }
+
+    fn version() -> String {
+        env!("CARGO_PKG_VERSION").to_string()
+    }
 }
 
-fn call_next_and_set_cache(dns_packet: &[u8], cache_key: Vec<u8>) -> Result<Vec<u8>, Error> {
-    let response_packet = match call_next_plugin(dns_packet) {
-        None => {
-            return Err(Error {
-                code: 1,
-                msg: "no next plugin".to_string(),
-            })
+impl From<NextError> for Error {
+    fn from(err: NextError) -> Self {
+        Error {
+            code: err.code(),
+            msg: err.to_string(),
         }
+    }
+}
 
-        Some(result) => result?,
+/// the key of the query in the store, the clients in different partitions never share the
+/// entries
+fn cache_key(
+    request_message: &Message,
+    client_addr: Option<SocketAddr>,
+    config: &Config,
+) -> Result<Vec<u8>, Error> {
+    let partition = client_addr.and_then(|client_addr| {
+        cache_key::partition(
+            client_addr.ip(),
+            config.partition_prefix_v4,
+            config.partition_prefix_v6,
+        )
+    });
+
+    let cache_key = CacheKey {
+        partition,
+        recursion_desired: request_message.recursion_desired(),
+        query: request_message
+            .queries()
+            .iter()
+            .map(|query| QueryDef::from(query.clone()))
+            .collect(),
     };
 
-    let message = Message::from_vec(&response_packet).map_err(|err| {
-        error!(%err, "decode dns packet failed");
+    DefaultOptions::new().serialize(&cache_key).map_err(|err| {
+        error!(%err, ?cache_key, "encode cache key failed");
 
         Error {
             code: 1,
             msg: err.to_string(),
         }
-    })?;
-
-    if let Some(ttl) = message.answers().iter().map(|answer| answer.ttl()).min() {
-        map_set(&cache_key, &response_packet, Some(ttl as _));
-    }
-
-    Ok(response_packet)
+    })
 }
 
-fn create_response_from_cache(
+/// the cache is a response interceptor, it sees the response processed by all the
+/// downstream plugins
+fn call_next_and_set_cache(
     dns_packet: &[u8],
+    cache_key: Vec<u8>,
+    config: &Config,
+    debug: bool,
+) -> Result<Vec<u8>, Error> {
+    intercept_response(dns_packet, |response_packet| {
+        set_cache(response_packet, cache_key, config, debug)
+    })
+}
+
+fn set_cache(
     response_packet: Vec<u8>,
+    cache_key: Vec<u8>,
+    config: &Config,
+    debug: bool,
 ) -> Result<Vec<u8>, Error> {
-    let request_message = Message::from_vec(dns_packet).map_err(|err| {
-        error!(%err, "decode dns request packet failed");
+    let mut message = Message::from_vec(&response_packet).map_err(|err| {
+        error!(%err, "decode dns packet failed");
 
         Error {
             code: 1,
@@ -93,7 +252,92 @@ fn create_response_from_cache(
         }
     })?;
 
-    let response_message = Message::from_vec(&response_packet).map_err(|err| {
+    let now = time::now_unix_secs();
+    let cache_entry = match build_cache_entry(&message, &response_packet, config, now) {
+        None => return Ok(response_packet),
+        Some(cache_entry) => cache_entry,
+    };
+
+    let data = DefaultOptions::new()
+        .serialize(&cache_entry)
+        .map_err(|err| {
+            error!(%err, "encode cache entry failed");
+
+            Error {
+                code: 1,
+                msg: err.to_string(),
+            }
+        })?;
+
+    let timeout = cache_entry.ttl as u64 + config.serve_stale_ttl.unwrap_or(0) as u64;
+    map_set(&cache_key, &data, Some(timeout));
+
+    if !debug {
+        return Ok(response_packet);
+    }
+
+    if let Some(query) = message.query() {
+        let record = cache_entry.debug_record(query.name().clone(), now);
+        message.add_additional(record);
+    }
+
+    message.to_vec().map_err(|err| {
+        error!(%err, "encode dns response packet failed");
+
+        Error {
+            code: 1,
+            msg: err.to_string(),
+        }
+    })
+}
+
+/// the cache entry of the response, [`None`] means the response isn't cached
+fn build_cache_entry(
+    message: &Message,
+    response_packet: &[u8],
+    config: &Config,
+    now: u64,
+) -> Option<CacheEntry> {
+    let authoritative = message.authoritative();
+    if config.cache_only_authoritative && !authoritative {
+        return None;
+    }
+
+    let ttl = entry::cache_ttl(message, config.max_negative_ttl)?;
+    let ttl = match config.aa_ttl_multiplier {
+        Some(multiplier) if authoritative => (ttl as f64 * multiplier) as u32,
+        _ => ttl,
+    };
+    let ttl = ttl
+        .max(config.min_ttl.unwrap_or(0))
+        .min(config.max_ttl.unwrap_or(u32::MAX));
+
+    // the id is rebuilt from the request when the entry is used, clear it so identical
+    // responses are stored as identical bytes
+    let mut cached_response = response_packet.to_vec();
+    cached_response[..2].fill(0);
+
+    Some(CacheEntry {
+        inserted_at: Some(now),
+        ttl,
+        cname_only: entry::is_cname_only(message),
+        response: cached_response,
+    })
+}
+
+/// a failed lookup is answered with the stale entry, except an intentional drop
+fn should_serve_stale(config: &Config, err: &Error) -> bool {
+    config.serve_stale_ttl.is_some() && err.code != DROP_ERROR_CODE
+}
+
+fn create_response_from_cache(
+    request_message: Message,
+    cache_entry: CacheEntry,
+    now: u64,
+    stale: bool,
+    debug: bool,
+) -> Result<Vec<u8>, Error> {
+    let response_message = Message::from_vec(&cache_entry.response).map_err(|err| {
         error!(%err, "decode dns response packet failed");
 
         Error {
@@ -102,6 +346,30 @@ fn create_response_from_cache(
         }
     })?;
 
+    // the client caches the records for their remaining ttl, not the original one, the
+    // records shorter than the entry, which is extended by the min ttl or the aa ttl
+    // multiplier, live as long as the entry
+    let with_remaining_ttl = |records: &[Record]| {
+        records
+            .iter()
+            .cloned()
+            .map(|mut record| {
+                if stale {
+                    record.set_ttl(STALE_ANSWER_TTL);
+                } else if let (Some(inserted_at), Some(remaining_ttl)) =
+                    (cache_entry.inserted_at, cache_entry.remaining_ttl(now))
+                {
+                    let elapsed = now.saturating_sub(inserted_at);
+                    let ttl = (record.ttl() as u64).saturating_sub(elapsed) as u32;
+
+                    record.set_ttl(ttl.max(remaining_ttl));
+                }
+
+                record
+            })
+            .collect::<Vec<_>>()
+    };
+
     let mut request_message = request_message.into_parts();
 
     request_message
@@ -109,15 +377,27 @@ fn create_response_from_cache(
         .set_message_type(MessageType::Response)
         .set_response_code(response_message.response_code())
         .set_answer_count(response_message.answer_count())
+        .set_name_server_count(response_message.name_server_count())
         .set_additional_count(response_message.additional_count())
         .set_authoritative(response_message.authoritative());
     request_message
         .answers
-        .extend_from_slice(response_message.answers());
+        .extend(with_remaining_ttl(response_message.answers()));
+    // the SOA of the negative response tells the client how long to cache it
+    request_message
+        .name_servers
+        .extend(with_remaining_ttl(response_message.name_servers()));
     request_message
         .additionals
         .extend_from_slice(response_message.additionals());
 
+    if debug {
+        if let Some(query) = request_message.queries.first() {
+            let record = cache_entry.debug_record(query.name().clone(), now);
+            request_message.additionals.push(record);
+        }
+    }
+
     let request_message = Message::from(request_message);
     let data = request_message.to_vec().map_err(|err| {
         error!(%err, "encode dns response packet failed");
@@ -132,3 +412,217 @@ fn create_response_from_cache(
 }
 
 export_rubydns!(CacheRunner);
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::str::FromStr;
+
+    use trust_dns_proto::op::Query;
+    use trust_dns_proto::rr::{Name, RData, RecordType};
+
+    use super::*;
+
+    fn config(config: &str) -> Config {
+        parse_config(config).unwrap()
+    }
+
+    fn request() -> Message {
+        let mut message = Message::new();
+        message.set_recursion_desired(true).add_query(Query::query(
+            Name::from_str("www.example.com.").unwrap(),
+            RecordType::A,
+        ));
+
+        message
+    }
+
+    fn key(config: &Config, client_addr: &str) -> Vec<u8> {
+        cache_key(&request(), Some(client_addr.parse().unwrap()), config).unwrap()
+    }
+
+    #[test]
+    fn partition_by_client_subnet() {
+        let config = config("partition_prefix_v4: 24\npartition_prefix_v6: 56");
+
+        assert_eq!(
+            key(&config, "192.0.2.1:53"),
+            key(&config, "192.0.2.200:5353")
+        );
+        assert_ne!(
+            key(&config, "192.0.2.1:53"),
+            key(&config, "198.51.100.1:53")
+        );
+        assert_eq!(
+            key(&config, "[2001:db8:0:1::1]:53"),
+            key(&config, "[2001:db8:0:ff::1]:53")
+        );
+        assert_ne!(
+            key(&config, "[2001:db8:0:1::1]:53"),
+            key(&config, "[2001:db8:1::1]:53")
+        );
+    }
+
+    #[test]
+    fn share_entries_without_partition() {
+        let config = config("{}");
+
+        assert_eq!(
+            key(&config, "192.0.2.1:53"),
+            key(&config, "198.51.100.1:53")
+        );
+        assert_eq!(
+            key(&config, "192.0.2.1:53"),
+            cache_key(&request(), None, &config).unwrap()
+        );
+    }
+
+    #[test]
+    fn share_entries_of_family_without_prefix() {
+        let config = config("partition_prefix_v4: 24");
+
+        assert_ne!(
+            key(&config, "192.0.2.1:53"),
+            key(&config, "198.51.100.1:53")
+        );
+        assert_eq!(
+            key(&config, "[2001:db8::1]:53"),
+            key(&config, "[2001:db8:1::1]:53")
+        );
+    }
+
+    #[test]
+    fn reject_unknown_config_field() {
+        let err = parse_config("serve_stale: 60").unwrap_err();
+
+        assert!(
+            err.msg.contains("unknown field `serve_stale`"),
+            "{}",
+            err.msg
+        );
+    }
+
+    #[test]
+    fn separate_entries_by_recursion_desired() {
+        let config = config("{}");
+        let mut iterative_request = request();
+        iterative_request.set_recursion_desired(false);
+
+        assert_ne!(
+            cache_key(&request(), None, &config).unwrap(),
+            cache_key(&iterative_request, None, &config).unwrap()
+        );
+    }
+
+    fn response(ttl: u32, authoritative: bool) -> Message {
+        let mut message = request();
+        message
+            .set_id(1234)
+            .set_message_type(MessageType::Response)
+            .set_authoritative(authoritative)
+            .add_answer(Record::from_rdata(
+                Name::from_str("www.example.com.").unwrap(),
+                ttl,
+                RData::A(Ipv4Addr::new(192, 0, 2, 1)),
+            ));
+
+        message
+    }
+
+    fn cache_entry(inserted_at: u64, ttl: u32) -> CacheEntry {
+        CacheEntry {
+            inserted_at: Some(inserted_at),
+            ttl,
+            cname_only: false,
+            response: response(ttl, false).to_vec().unwrap(),
+        }
+    }
+
+    fn build(config: &Config, response: &Message) -> Option<CacheEntry> {
+        build_cache_entry(response, &response.to_vec().unwrap(), config, 1000)
+    }
+
+    fn response_from_cache(cache_entry: CacheEntry, now: u64, stale: bool, debug: bool) -> Message {
+        let response_packet =
+            create_response_from_cache(request(), cache_entry, now, stale, debug).unwrap();
+
+        Message::from_vec(&response_packet).unwrap()
+    }
+
+    #[test]
+    fn answer_with_remaining_ttl() {
+        let response = response_from_cache(cache_entry(1000, 300), 1100, false, false);
+
+        assert_eq!(response.message_type(), MessageType::Response);
+        assert_eq!(response.answers()[0].ttl(), 200);
+        assert!(response.additionals().is_empty());
+    }
+
+    #[test]
+    fn answer_stale_entry_with_stale_ttl() {
+        // the entry expired 100 seconds ago
+        let response = response_from_cache(cache_entry(1000, 300), 1400, true, false);
+
+        assert_eq!(response.message_type(), MessageType::Response);
+        assert_eq!(response.answers()[0].ttl(), 30);
+    }
+
+    #[test]
+    fn serve_stale_unless_dropped() {
+        let err = |code| Error {
+            code,
+            msg: "lookup failed".to_string(),
+        };
+
+        let stale_config = config("serve_stale_ttl: 3600");
+        assert!(should_serve_stale(&stale_config, &err(1)));
+        assert!(!should_serve_stale(&stale_config, &err(DROP_ERROR_CODE)));
+
+        assert!(!should_serve_stale(&config("{}"), &err(1)));
+    }
+
+    #[test]
+    fn add_metadata_txt_to_debug_query() {
+        let response = response_from_cache(cache_entry(1000, 300), 1100, false, true);
+
+        let metadata = match response.additionals()[0].data() {
+            Some(RData::TXT(txt)) => txt.to_string(),
+            data => panic!("unexpected debug record {data:?}"),
+        };
+        assert_eq!(
+            metadata,
+            "inserted_at=1000 original_ttl=300 remaining_ttl=200 expires_at=1300"
+        );
+    }
+
+    #[test]
+    fn cache_both_aa_responses_by_default() {
+        let config = config("{}");
+
+        let cache_entry = build(&config, &response(300, false)).unwrap();
+        assert_eq!(cache_entry.inserted_at, Some(1000));
+        assert_eq!(cache_entry.ttl, 300);
+        // the id is cleared
+        assert_eq!(cache_entry.response[..2], [0, 0]);
+
+        assert_eq!(build(&config, &response(300, true)).unwrap().ttl, 300);
+    }
+
+    #[test]
+    fn skip_non_authoritative_response() {
+        let config = config("cache_only_authoritative: true");
+
+        assert!(build(&config, &response(300, false)).is_none());
+        assert_eq!(build(&config, &response(300, true)).unwrap().ttl, 300);
+    }
+
+    #[test]
+    fn multiply_authoritative_ttl() {
+        let config = config("aa_ttl_multiplier: 2.0\nmax_ttl: 500");
+
+        assert_eq!(build(&config, &response(200, true)).unwrap().ttl, 400);
+        assert_eq!(build(&config, &response(200, false)).unwrap().ttl, 200);
+        // the multiplied ttl is still clamped
+        assert_eq!(build(&config, &response(300, true)).unwrap().ttl, 500);
+    }
+}
@@ -0,0 +1,455 @@
+use std::net::IpAddr;
+
+use plugin_utils::http;
+use plugin_utils::http::Url;
+use serde::Deserialize;
+use tracing::{error, info, warn};
+use trust_dns_proto::op::{Message, MessageType, ResponseCode};
+use trust_dns_proto::rr::rdata::SRV;
+use trust_dns_proto::rr::{Name, RData, Record, RecordType};
+
+use crate::helper::{call_next_plugin, load_config};
+use crate::plugin::{Error, Plugin};
+
+wit_bindgen::generate!("rubydns");
+
+/// answer the `<service>.service[.<datacenter>].<domain>` names with the service instances
+/// registered in a consul style catalog
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    /// the catalog http api, such as `http://127.0.0.1:8500`
+    url: Url,
+    #[serde(default = "default_domain")]
+    domain: String,
+    /// the ttl of the answers, so the cache plugin can cache them
+    #[serde(default = "default_ttl")]
+    ttl: u32,
+}
+
+fn default_domain() -> String {
+    "consul".to_string()
+}
+
+fn default_ttl() -> u32 {
+    30
+}
+
+/// a service instance returned by `/v1/catalog/service/<service>`
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct CatalogService {
+    node: String,
+    address: String,
+    datacenter: String,
+    /// the node address is used when it is empty
+    #[serde(default)]
+    service_address: String,
+    service_port: u16,
+}
+
+impl CatalogService {
+    fn addr(&self) -> Option<IpAddr> {
+        let addr = if self.service_address.is_empty() {
+            &self.address
+        } else {
+            &self.service_address
+        };
+
+        match addr.parse() {
+            Err(err) => {
+                warn!(%err, addr, node = %self.node, "invalid service address, skip it");
+
+                None
+            }
+
+            Ok(addr) => Some(addr),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct CatalogRunner;
+
+impl Plugin for CatalogRunner {
+    fn run(dns_packet: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let config = load_config();
+        let config: Config = serde_yaml::from_str(&config).map_err(|err| {
+            error!(%err, "load catalog config failed");
+
+            Error {
+                code: 1,
+                msg: err.to_string(),
+            }
+        })?;
+
+        let response = resolve(&config, &dns_packet, |service, datacenter| {
+            lookup(&config.url, service, datacenter)
+        })?;
+
+        match response {
+            None => call_next(&dns_packet),
+            Some(response_packet) => Ok(response_packet),
+        }
+    }
+
+    fn valid_config() -> Result<(), Error> {
+        let config: Config = serde_yaml::from_str(&load_config()).map_err(|err| {
+            error!(%err, "load catalog config failed");
+
+            Error {
+                code: 1,
+                msg: err.to_string(),
+            }
+        })?;
+
+        Name::from_ascii(&config.domain).map_err(|err| {
+            error!(%err, domain = config.domain, "invalid catalog domain");
+
+            Error {
+                code: 1,
+                msg: format!("invalid domain {}: {err}", config.domain),
+            }
+        })?;
+
+        Ok(())
+    }
+
+    fn version() -> String {
+        env!("CARGO_PKG_VERSION").to_string()
+    }
+}
+
+/// answer the service name with the instances returned by `lookup`, [`None`] means the name
+/// isn't a service name and the query is passed through
+fn resolve(
+    config: &Config,
+    dns_packet: &[u8],
+    lookup: impl FnOnce(&str, Option<&str>) -> Result<Vec<CatalogService>, Error>,
+) -> Result<Option<Vec<u8>>, Error> {
+    let request_message = Message::from_vec(dns_packet).map_err(|err| {
+        error!(%err, "decode dns request packet failed");
+
+        Error {
+            code: 1,
+            msg: err.to_string(),
+        }
+    })?;
+
+    let query = match request_message.query() {
+        None => return Ok(None),
+        Some(query) => query,
+    };
+
+    let name = query.name().to_lowercase().to_ascii();
+    let (service, datacenter) = match parse_service_name(&name, &config.domain) {
+        None => return Ok(None),
+        Some(service_name) => service_name,
+    };
+
+    let services = lookup(service, datacenter)?;
+
+    info!(
+        service,
+        ?datacenter,
+        instances = services.len(),
+        "catalog lookup done"
+    );
+
+    let mut response_message = Message::new();
+    response_message
+        .set_id(request_message.id())
+        .set_message_type(MessageType::Response)
+        .set_op_code(request_message.op_code())
+        .set_recursion_desired(request_message.recursion_desired())
+        .set_recursion_available(true)
+        .set_authoritative(true)
+        .add_queries(request_message.queries().iter().cloned());
+
+    if services.is_empty() {
+        response_message.set_response_code(ResponseCode::NXDomain);
+    } else {
+        add_records(
+            &mut response_message,
+            query.name(),
+            query.query_type(),
+            &services,
+            config,
+        )?;
+    }
+
+    response_message.to_vec().map(Some).map_err(|err| {
+        error!(%err, "encode dns response packet failed");
+
+        Error {
+            code: 1,
+            msg: err.to_string(),
+        }
+    })
+}
+
+/// get the instances of the service from `/v1/catalog/service/<service>`
+fn lookup(
+    url: &Url,
+    service: &str,
+    datacenter: Option<&str>,
+) -> Result<Vec<CatalogService>, Error> {
+    let mut params = vec![];
+    if let Some(datacenter) = datacenter {
+        params.push(("dc", datacenter));
+    }
+
+    let url = url.join("v1").join("catalog").join("service").join(service);
+    let response = http::get(&url, &params).map_err(|err| {
+        error!(%err, ?url, "request catalog failed");
+
+        Error {
+            code: err.raw_os_error().unwrap_or(1) as _,
+            msg: err.to_string(),
+        }
+    })?;
+
+    if response.status != 200 {
+        error!(status = response.status, service, "catalog response failed");
+
+        return Err(Error {
+            code: 1,
+            msg: format!("catalog response status {}", response.status),
+        });
+    }
+
+    serde_json::from_slice(&response.body).map_err(|err| {
+        error!(%err, "decode catalog response failed");
+
+        Error {
+            code: 1,
+            msg: err.to_string(),
+        }
+    })
+}
+
+/// split the name into the service and the optional datacenter, [`None`] means the name
+/// isn't a service name
+fn parse_service_name<'a>(name: &'a str, domain: &str) -> Option<(&'a str, Option<&'a str>)> {
+    let labels = name
+        .trim_end_matches('.')
+        .strip_suffix(domain.trim_end_matches('.'))?
+        .strip_suffix('.')?;
+
+    match labels.split('.').collect::<Vec<_>>()[..] {
+        [service, "service"] => Some((service, None)),
+        [service, "service", datacenter] => Some((service, Some(datacenter))),
+        _ => None,
+    }
+}
+
+/// answer the addresses for A and AAAA, or the ports for SRV with the target addresses in
+/// the additional section, the other types get an empty answer
+fn add_records(
+    response_message: &mut Message,
+    name: &Name,
+    query_type: RecordType,
+    services: &[CatalogService],
+    config: &Config,
+) -> Result<(), Error> {
+    for service in services {
+        let addr = match service.addr() {
+            None => continue,
+            Some(addr) => addr,
+        };
+
+        match (query_type, addr) {
+            (RecordType::A, IpAddr::V4(addr)) => {
+                response_message.add_answer(Record::from_rdata(
+                    name.clone(),
+                    config.ttl,
+                    RData::A(addr),
+                ));
+            }
+
+            (RecordType::AAAA, IpAddr::V6(addr)) => {
+                response_message.add_answer(Record::from_rdata(
+                    name.clone(),
+                    config.ttl,
+                    RData::AAAA(addr),
+                ));
+            }
+
+            (RecordType::SRV, addr) => {
+                let target = format!(
+                    "{}.node.{}.{}",
+                    service.node, service.datacenter, config.domain
+                );
+                let target = Name::from_ascii(&target).map_err(|err| {
+                    error!(%err, target, "invalid srv target");
+
+                    Error {
+                        code: 1,
+                        msg: err.to_string(),
+                    }
+                })?;
+
+                let srv = SRV::new(1, 1, service.service_port, target.clone());
+                response_message.add_answer(Record::from_rdata(
+                    name.clone(),
+                    config.ttl,
+                    RData::SRV(srv),
+                ));
+
+                let rdata = match addr {
+                    IpAddr::V4(addr) => RData::A(addr),
+                    IpAddr::V6(addr) => RData::AAAA(addr),
+                };
+                response_message.add_additional(Record::from_rdata(target, config.ttl, rdata));
+            }
+
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+fn call_next(dns_packet: &[u8]) -> Result<Vec<u8>, Error> {
+    match call_next_plugin(dns_packet) {
+        None => Err(Error {
+            code: 1,
+            msg: "no next plugin".to_string(),
+        }),
+
+        Some(result) => result,
+    }
+}
+
+export_rubydns!(CatalogRunner);
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::str::FromStr;
+
+    use trust_dns_proto::op::Query;
+
+    use super::*;
+
+    fn config() -> Config {
+        serde_yaml::from_str("url: http://127.0.0.1:8500").unwrap()
+    }
+
+    fn request(name: &str, query_type: RecordType) -> Vec<u8> {
+        let mut message = Message::new();
+        message
+            .set_id(1234)
+            .add_query(Query::query(Name::from_str(name).unwrap(), query_type));
+
+        message.to_vec().unwrap()
+    }
+
+    /// a catalog with two instances of the web service in dc1
+    fn mock_catalog(service: &str, datacenter: Option<&str>) -> Result<Vec<CatalogService>, Error> {
+        assert_eq!(service, "web");
+        assert_eq!(datacenter, Some("dc1"));
+
+        Ok(serde_json::from_str(
+            r#"[
+                {
+                    "Node": "node1", "Address": "192.0.2.1", "Datacenter": "dc1",
+                    "ServiceAddress": "", "ServicePort": 8080
+                },
+                {
+                    "Node": "node2", "Address": "192.0.2.2", "Datacenter": "dc1",
+                    "ServiceAddress": "192.0.2.12", "ServicePort": 8081
+                }
+            ]"#,
+        )
+        .unwrap())
+    }
+
+    #[test]
+    fn answer_two_instances() {
+        let response = resolve(
+            &config(),
+            &request("web.service.dc1.consul.", RecordType::A),
+            mock_catalog,
+        )
+        .unwrap()
+        .unwrap();
+        let response = Message::from_vec(&response).unwrap();
+
+        assert_eq!(response.id(), 1234);
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        assert_eq!(
+            response
+                .answers()
+                .iter()
+                .map(|record| record.data().cloned())
+                .collect::<Vec<_>>(),
+            [
+                Some(RData::A(Ipv4Addr::new(192, 0, 2, 1))),
+                Some(RData::A(Ipv4Addr::new(192, 0, 2, 12)))
+            ]
+        );
+        assert!(response.answers().iter().all(|record| record.ttl() == 30));
+    }
+
+    #[test]
+    fn answer_srv_with_target_addresses() {
+        let response = resolve(
+            &config(),
+            &request("web.service.dc1.consul.", RecordType::SRV),
+            mock_catalog,
+        )
+        .unwrap()
+        .unwrap();
+        let response = Message::from_vec(&response).unwrap();
+
+        let ports = response
+            .answers()
+            .iter()
+            .map(|record| match record.data() {
+                Some(RData::SRV(srv)) => (srv.port(), srv.target().to_ascii()),
+                data => panic!("unexpected answer {data:?}"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            ports,
+            [
+                (8080, "node1.node.dc1.consul.".to_string()),
+                (8081, "node2.node.dc1.consul.".to_string())
+            ]
+        );
+        assert_eq!(response.additionals().len(), 2);
+    }
+
+    #[test]
+    fn answer_nxdomain_for_unknown_service() {
+        let response = resolve(
+            &config(),
+            &request("db.service.consul.", RecordType::A),
+            |service, datacenter| {
+                assert_eq!((service, datacenter), ("db", None));
+
+                Ok(vec![])
+            },
+        )
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(
+            Message::from_vec(&response).unwrap().response_code(),
+            ResponseCode::NXDomain
+        );
+    }
+
+    #[test]
+    fn pass_through_other_names() {
+        let response = resolve(
+            &config(),
+            &request("www.example.com.", RecordType::A),
+            |_, _| panic!("catalog is queried"),
+        )
+        .unwrap();
+
+        assert!(response.is_none());
+    }
+}
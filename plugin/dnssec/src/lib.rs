@@ -0,0 +1,696 @@
+use plugin_utils::time;
+use serde::Deserialize;
+use tracing::{error, info, warn};
+use trust_dns_proto::op::{Edns, Message, MessageType, ResponseCode};
+use trust_dns_proto::rr::dnssec::rdata::DS;
+use trust_dns_proto::rr::dnssec::{Algorithm, DigestType};
+use trust_dns_proto::rr::{Name, RecordType};
+
+use crate::helper::{call_next_plugin, load_config};
+use crate::plugin::{Error, Plugin};
+use crate::validator::{Host, PluginHost, Validator};
+
+mod validator;
+
+wit_bindgen::generate!("rubydns");
+
+/// the EDNS payload size advertised to the next plugins when the client query has no EDNS
+const EDNS_PAYLOAD_SIZE: u16 = 1232;
+
+/// validate the answer section of the responses with DNSSEC, a bogus answer gets SERVFAIL
+/// and a validated one gets the AD bit
+///
+/// the signatures are verified by the host, so they don't consume the plugin fuel, but the
+/// chain walk sends a DS and a DNSKEY query through the next plugins for every signer and
+/// decodes them in the instance, a long chain may use up a low `fuel_limit`, put the cache
+/// plugin before this one so the validated answers are cached
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    /// the zones whose answers are validated, the most specific zone of the query name wins,
+    /// the queries out of the zones are passed through
+    zones: Vec<Zone>,
+    /// bound the delegations walked from a signer up to its zone
+    #[serde(default = "default_max_depth")]
+    max_depth: u8,
+}
+
+fn default_max_depth() -> u8 {
+    8
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Zone {
+    name: String,
+    /// the DS records of the zone apex keys
+    trust_anchors: Vec<TrustAnchor>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TrustAnchor {
+    key_tag: u16,
+    algorithm: u8,
+    digest_type: u8,
+    /// the hex digest
+    digest: String,
+}
+
+impl Zone {
+    fn name(&self) -> Result<Name, Error> {
+        Name::from_ascii(&self.name)
+            .map(|name| name.to_lowercase())
+            .map_err(|err| {
+                error!(%err, zone = self.name, "invalid dnssec zone");
+
+                Error {
+                    code: 1,
+                    msg: format!("invalid zone {}: {err}", self.name),
+                }
+            })
+    }
+
+    fn trust_anchors(&self) -> Result<Vec<DS>, Error> {
+        self.trust_anchors
+            .iter()
+            .map(|anchor| {
+                let digest_type = DigestType::from_u8(anchor.digest_type).map_err(|err| {
+                    error!(%err, zone = self.name, "invalid trust anchor digest type");
+
+                    Error {
+                        code: 1,
+                        msg: err.to_string(),
+                    }
+                })?;
+
+                let digest = decode_hex(&anchor.digest).ok_or_else(|| {
+                    error!(zone = self.name, "invalid trust anchor digest");
+
+                    Error {
+                        code: 1,
+                        msg: format!("invalid trust anchor digest {}", anchor.digest),
+                    }
+                })?;
+
+                Ok(DS::new(
+                    anchor.key_tag,
+                    Algorithm::from_u8(anchor.algorithm),
+                    digest_type,
+                    digest,
+                ))
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug)]
+struct DnssecRunner;
+
+impl Plugin for DnssecRunner {
+    fn run(dns_packet: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let config = load_config();
+        let config: Config = serde_yaml::from_str(&config).map_err(|err| {
+            error!(%err, "load dnssec config failed");
+
+            Error {
+                code: 1,
+                msg: err.to_string(),
+            }
+        })?;
+
+        validate_query(&config, &dns_packet, unix_now(), &mut PluginHost)
+    }
+
+    fn valid_config() -> Result<(), Error> {
+        let config: Config = serde_yaml::from_str(&load_config()).map_err(|err| {
+            error!(%err, "load dnssec config failed");
+
+            Error {
+                code: 1,
+                msg: err.to_string(),
+            }
+        })?;
+
+        for zone in &config.zones {
+            zone.name()?;
+
+            if zone.trust_anchors()?.is_empty() {
+                error!(zone = zone.name, "dnssec zone has no trust anchor");
+
+                return Err(Error {
+                    code: 1,
+                    msg: format!("zone {} has no trust anchor", zone.name),
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    fn version() -> String {
+        env!("CARGO_PKG_VERSION").to_string()
+    }
+}
+
+/// send the query of a configured zone with the DO bit by the host, and validate the answers
+/// of the response, the other queries are passed through
+fn validate_query(
+    config: &Config,
+    dns_packet: &[u8],
+    now: u32,
+    host: &mut impl Host,
+) -> Result<Vec<u8>, Error> {
+    let mut request_message = Message::from_vec(dns_packet).map_err(|err| {
+        error!(%err, "decode dns request packet failed");
+
+        Error {
+            code: 1,
+            msg: err.to_string(),
+        }
+    })?;
+
+    // the client asks for the data without validation
+    if request_message.checking_disabled() {
+        return host.exchange(dns_packet);
+    }
+
+    let query_name = match request_message.query() {
+        None => return host.exchange(dns_packet),
+        Some(query) => query.name().clone(),
+    };
+
+    let zones = config
+        .zones
+        .iter()
+        .map(|zone| Ok((zone.name()?, zone)))
+        .collect::<Result<Vec<_>, Error>>()?;
+
+    // max_by_key returns the last max one, reverse so the first zone wins the tie
+    let zone = zones
+        .into_iter()
+        .rev()
+        .filter(|(name, _)| name.zone_of(&query_name))
+        .max_by_key(|(name, _)| name.num_labels());
+    let (zone_name, zone) = match zone {
+        None => return host.exchange(dns_packet),
+        Some(zone) => zone,
+    };
+
+    let client_edns = request_message.extensions().is_some();
+    let client_dnssec_ok = matches!(request_message.extensions(), Some(edns) if edns.dnssec_ok());
+
+    let edns = request_message.extensions_mut().get_or_insert_with(|| {
+        let mut edns = Edns::new();
+        edns.set_max_payload(EDNS_PAYLOAD_SIZE);
+
+        edns
+    });
+    edns.set_dnssec_ok(true);
+
+    let request_packet = request_message.to_vec().map_err(|err| {
+        error!(%err, "encode dns request packet failed");
+
+        Error {
+            code: 1,
+            msg: err.to_string(),
+        }
+    })?;
+
+    let response_packet = host.exchange(&request_packet)?;
+    let mut response_message = Message::from_vec(&response_packet).map_err(|err| {
+        error!(%err, "decode dns response packet failed");
+
+        Error {
+            code: 1,
+            msg: err.to_string(),
+        }
+    })?;
+
+    // only the answers are validated, the negative responses need the NSEC proofs
+    let authentic = if response_message.answers().is_empty() {
+        false
+    } else {
+        let trust_anchors = zone.trust_anchors()?;
+        let mut validator = Validator::new(host, &zone_name, &trust_anchors, config.max_depth, now);
+
+        if let Err(reason) = validator.validate_answers(response_message.answers()) {
+            warn!(name = %query_name, zone = %zone_name, reason, "dnssec validation failed");
+
+            return servfail_response(&request_message);
+        }
+
+        info!(name = %query_name, zone = %zone_name, "dnssec validation done");
+
+        true
+    };
+
+    response_message.set_authentic_data(authentic);
+
+    // the client which doesn't set the DO bit doesn't expect the DNSSEC records
+    if !client_dnssec_ok {
+        let answers = response_message.take_answers();
+        let name_servers = response_message.take_name_servers();
+        response_message
+            .add_answers(
+                answers
+                    .into_iter()
+                    .filter(|record| !is_dnssec_record(record.record_type())),
+            )
+            .add_name_servers(
+                name_servers
+                    .into_iter()
+                    .filter(|record| !is_dnssec_record(record.record_type())),
+            );
+    }
+
+    if client_edns {
+        if let Some(edns) = response_message.extensions_mut() {
+            edns.set_dnssec_ok(client_dnssec_ok);
+        }
+    } else {
+        *response_message.extensions_mut() = None;
+    }
+
+    response_message.to_vec().map_err(|err| {
+        error!(%err, "encode dns response packet failed");
+
+        Error {
+            code: 1,
+            msg: err.to_string(),
+        }
+    })
+}
+
+fn is_dnssec_record(record_type: RecordType) -> bool {
+    matches!(
+        record_type,
+        RecordType::RRSIG | RecordType::NSEC | RecordType::NSEC3
+    )
+}
+
+fn servfail_response(request_message: &Message) -> Result<Vec<u8>, Error> {
+    let mut response_message = Message::new();
+    response_message
+        .set_id(request_message.id())
+        .set_message_type(MessageType::Response)
+        .set_op_code(request_message.op_code())
+        .set_recursion_desired(request_message.recursion_desired())
+        .set_recursion_available(true)
+        .set_response_code(ResponseCode::ServFail)
+        .add_queries(request_message.queries().iter().cloned());
+
+    response_message.to_vec().map_err(|err| {
+        error!(%err, "encode dns response packet failed");
+
+        Error {
+            code: 1,
+            msg: err.to_string(),
+        }
+    })
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| {
+            let pair = std::str::from_utf8(pair)
+                .ok()
+                .filter(|pair| pair.len() == 2)?;
+
+            u8::from_str_radix(pair, 16).ok()
+        })
+        .collect()
+}
+
+/// the RRSIG validity period is in 32 bits seconds
+fn unix_now() -> u32 {
+    time::now_unix_secs() as _
+}
+
+fn call_next(dns_packet: &[u8]) -> Result<Vec<u8>, Error> {
+    match call_next_plugin(dns_packet) {
+        None => Err(Error {
+            code: 1,
+            msg: "no next plugin".to_string(),
+        }),
+
+        Some(result) => result,
+    }
+}
+
+export_rubydns!(DnssecRunner);
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::str::FromStr;
+
+    use trust_dns_proto::op::Query;
+    use trust_dns_proto::rr::dnssec::rdata::{DNSSECRData, DNSKEY, SIG};
+    use trust_dns_proto::rr::dnssec::tbs;
+    use trust_dns_proto::rr::{DNSClass, RData, Record};
+
+    use super::*;
+    use crate::validator::ds_digest_data;
+
+    /// the signatures are valid an hour around it
+    const NOW: u32 = 1_700_000_000;
+
+    /// stands in for the host digests and signatures, a signature is the hash of the public
+    /// key and the signed data
+    fn fake_hash(parts: &[&[u8]]) -> Vec<u8> {
+        let mut hash = 0xcbf2_9ce4_8422_2325_u64;
+        for byte in parts.iter().flat_map(|part| part.iter()) {
+            hash ^= *byte as u64;
+            hash = hash.wrapping_mul(0x100_0000_01b3);
+        }
+
+        hash.to_be_bytes().to_vec()
+    }
+
+    /// the next plugins answering from the records, and the host checking the fake hashes
+    #[derive(Default)]
+    struct FakeHost {
+        records: Vec<Record>,
+        requests: Vec<Message>,
+    }
+
+    impl Host for FakeHost {
+        fn exchange(&mut self, dns_packet: &[u8]) -> Result<Vec<u8>, Error> {
+            let mut message = Message::from_vec(dns_packet).unwrap();
+            self.requests.push(message.clone());
+
+            let query = message.query().unwrap().clone();
+            let answers = self
+                .records
+                .iter()
+                .filter(|record| record.name() == query.name())
+                .filter(|record| match record.data() {
+                    Some(RData::DNSSEC(DNSSECRData::SIG(sig))) => {
+                        sig.type_covered() == query.query_type()
+                    }
+                    _ => record.record_type() == query.query_type(),
+                })
+                .cloned()
+                .collect::<Vec<_>>();
+            message
+                .set_message_type(MessageType::Response)
+                .add_answers(answers);
+
+            Ok(message.to_vec().unwrap())
+        }
+
+        fn verify(&self, _algorithm: u8, public_key: &[u8], data: &[u8], signature: &[u8]) -> bool {
+            fake_hash(&[public_key, data]) == signature
+        }
+
+        fn digest(&self, digest_type: u8, data: &[u8]) -> Option<Vec<u8>> {
+            Some(fake_hash(&[&[digest_type][..], data]))
+        }
+
+        fn random_u64(&self) -> u64 {
+            1
+        }
+    }
+
+    fn name(name: &str) -> Name {
+        Name::from_str(name).unwrap()
+    }
+
+    fn key(public_key: &str, secure_entry_point: bool) -> DNSKEY {
+        DNSKEY::new(
+            true,
+            secure_entry_point,
+            false,
+            Algorithm::ECDSAP256SHA256,
+            public_key.as_bytes().to_vec(),
+        )
+    }
+
+    fn ds(owner: &str, key: &DNSKEY) -> DS {
+        let data = ds_digest_data(&name(owner), key).unwrap();
+
+        DS::new(
+            key.calculate_key_tag().unwrap(),
+            key.algorithm(),
+            DigestType::SHA256,
+            fake_hash(&[&[u8::from(DigestType::SHA256)][..], &data]),
+        )
+    }
+
+    fn record(owner: &str, rdata: RData) -> Record {
+        Record::from_rdata(name(owner), 300, rdata)
+    }
+
+    /// the RRSIG of the RRset by the key of the signer
+    fn sign(rrset: &[Record], signer: &str, key: &DNSKEY) -> Record {
+        let new_sig = |signature| {
+            SIG::new(
+                rrset[0].record_type(),
+                key.algorithm(),
+                rrset[0].name().num_labels(),
+                rrset[0].ttl(),
+                NOW + 3600,
+                NOW - 3600,
+                key.calculate_key_tag().unwrap(),
+                name(signer),
+                signature,
+            )
+        };
+        let data = tbs::rrset_tbs_with_sig(rrset[0].name(), DNSClass::IN, &new_sig(vec![]), rrset)
+            .unwrap();
+        let sig = new_sig(fake_hash(&[key.public_key(), data.as_ref()]));
+
+        let mut rrsig = Record::from_rdata(
+            rrset[0].name().clone(),
+            rrset[0].ttl(),
+            RData::DNSSEC(DNSSECRData::SIG(sig)),
+        );
+        rrsig.set_record_type(RecordType::RRSIG);
+
+        rrsig
+    }
+
+    /// add the RRset and its RRSIG
+    fn add_signed(records: &mut Vec<Record>, rrset: Vec<Record>, signer: &str, key: &DNSKEY) {
+        records.push(sign(&rrset, signer, key));
+        records.extend(rrset);
+    }
+
+    /// `example.com.` and its delegation `sub.example.com.`, both have a KSK signing the key
+    /// set and a ZSK signing the others
+    fn signed_zones() -> FakeHost {
+        let (ksk, zsk) = (key("example ksk", true), key("example zsk", false));
+        let (sub_ksk, sub_zsk) = (key("sub ksk", true), key("sub zsk", false));
+        let dnskey =
+            |owner, key: &DNSKEY| record(owner, RData::DNSSEC(DNSSECRData::DNSKEY(key.clone())));
+
+        let mut records = vec![];
+        add_signed(
+            &mut records,
+            vec![dnskey("example.com.", &ksk), dnskey("example.com.", &zsk)],
+            "example.com.",
+            &ksk,
+        );
+        add_signed(
+            &mut records,
+            vec![record(
+                "www.example.com.",
+                RData::A(Ipv4Addr::new(192, 0, 2, 1)),
+            )],
+            "example.com.",
+            &zsk,
+        );
+        add_signed(
+            &mut records,
+            vec![record(
+                "sub.example.com.",
+                RData::DNSSEC(DNSSECRData::DS(ds("sub.example.com.", &sub_ksk))),
+            )],
+            "example.com.",
+            &zsk,
+        );
+        add_signed(
+            &mut records,
+            vec![
+                dnskey("sub.example.com.", &sub_ksk),
+                dnskey("sub.example.com.", &sub_zsk),
+            ],
+            "sub.example.com.",
+            &sub_ksk,
+        );
+        add_signed(
+            &mut records,
+            vec![record(
+                "www.sub.example.com.",
+                RData::A(Ipv4Addr::new(192, 0, 2, 2)),
+            )],
+            "sub.example.com.",
+            &sub_zsk,
+        );
+
+        FakeHost {
+            records,
+            requests: vec![],
+        }
+    }
+
+    /// the zone `example.com.` whose trust anchor is the DS of its KSK
+    fn config(max_depth: u8) -> Config {
+        let anchor = ds("example.com.", &key("example ksk", true));
+        let digest = anchor
+            .digest()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect::<String>();
+
+        serde_yaml::from_str(&format!(
+            "
+zones:
+  - name: example.com
+    trust_anchors:
+      - {{ key_tag: {}, algorithm: 13, digest_type: 2, digest: '{digest}' }}
+max_depth: {max_depth}
+",
+            anchor.key_tag()
+        ))
+        .unwrap()
+    }
+
+    fn request(query_name: &str, dnssec_ok: Option<bool>) -> Vec<u8> {
+        let mut message = Message::new();
+        message
+            .set_id(1234)
+            .set_recursion_desired(true)
+            .add_query(Query::query(name(query_name), RecordType::A));
+        if let Some(dnssec_ok) = dnssec_ok {
+            let mut edns = Edns::new();
+            edns.set_dnssec_ok(dnssec_ok);
+            message.set_edns(edns);
+        }
+
+        message.to_vec().unwrap()
+    }
+
+    fn validate(host: &mut FakeHost, config: &Config, dns_packet: &[u8], now: u32) -> Message {
+        let response_packet = validate_query(config, dns_packet, now, host).unwrap();
+
+        Message::from_vec(&response_packet).unwrap()
+    }
+
+    #[test]
+    fn validate_answer_and_set_ad() {
+        let mut host = signed_zones();
+
+        let response = validate(
+            &mut host,
+            &config(8),
+            &request("www.example.com.", None),
+            NOW,
+        );
+
+        // the query is sent with the DO bit
+        assert!(host.requests[0].extensions().as_ref().unwrap().dnssec_ok());
+
+        assert_eq!(response.id(), 1234);
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        assert!(response.authentic_data());
+        // the client without EDNS gets no DNSSEC records and no EDNS
+        assert_eq!(response.answers().len(), 1);
+        assert_eq!(response.answers()[0].record_type(), RecordType::A);
+        assert!(response.extensions().is_none());
+    }
+
+    #[test]
+    fn keep_rrsig_for_dnssec_ok_client() {
+        let mut host = signed_zones();
+
+        let response = validate(
+            &mut host,
+            &config(8),
+            &request("www.example.com.", Some(true)),
+            NOW,
+        );
+
+        assert!(response.authentic_data());
+        assert_eq!(response.answers().len(), 2);
+        assert!(response.extensions().as_ref().unwrap().dnssec_ok());
+    }
+
+    #[test]
+    fn servfail_bogus_answer() {
+        let mut host = signed_zones();
+        for record in &mut host.records {
+            if record.record_type() == RecordType::A && record.name() == &name("www.example.com.") {
+                record.set_data(Some(RData::A(Ipv4Addr::new(192, 0, 2, 99))));
+            }
+        }
+
+        let response = validate(
+            &mut host,
+            &config(8),
+            &request("www.example.com.", None),
+            NOW,
+        );
+
+        assert_eq!(response.id(), 1234);
+        assert_eq!(response.response_code(), ResponseCode::ServFail);
+        assert!(!response.authentic_data());
+        assert!(response.answers().is_empty());
+    }
+
+    #[test]
+    fn servfail_expired_signature() {
+        let mut host = signed_zones();
+
+        let response = validate(
+            &mut host,
+            &config(8),
+            &request("www.example.com.", None),
+            NOW + 7200,
+        );
+
+        assert_eq!(response.response_code(), ResponseCode::ServFail);
+    }
+
+    #[test]
+    fn validate_delegation_within_max_depth() {
+        let response = validate(
+            &mut signed_zones(),
+            &config(2),
+            &request("www.sub.example.com.", None),
+            NOW,
+        );
+        assert!(response.authentic_data());
+
+        // the DS of the signer needs the keys of the parent one level deeper
+        let response = validate(
+            &mut signed_zones(),
+            &config(1),
+            &request("www.sub.example.com.", None),
+            NOW,
+        );
+        assert_eq!(response.response_code(), ResponseCode::ServFail);
+    }
+
+    #[test]
+    fn pass_through_out_of_zone_and_checking_disabled() {
+        let mut host = signed_zones();
+        let dns_packet = request("www.example.org.", None);
+
+        validate(&mut host, &config(8), &dns_packet, NOW);
+
+        assert_eq!(host.requests[0].to_vec().unwrap(), dns_packet);
+
+        let mut request_message = Message::from_vec(&request("www.example.com.", None)).unwrap();
+        request_message.set_checking_disabled(true);
+        let dns_packet = request_message.to_vec().unwrap();
+
+        let response = validate(&mut host, &config(8), &dns_packet, NOW);
+
+        assert_eq!(host.requests[1].to_vec().unwrap(), dns_packet);
+        assert!(!response.authentic_data());
+        assert_eq!(response.answers().len(), 2);
+    }
+}
@@ -0,0 +1,324 @@
+use std::collections::HashMap;
+
+use trust_dns_proto::op::{Edns, Message, MessageType, OpCode, Query};
+use trust_dns_proto::rr::dnssec::rdata::{dnskey, DNSSECRData, DNSKEY, DS, SIG};
+use trust_dns_proto::rr::dnssec::tbs;
+use trust_dns_proto::rr::{DNSClass, Name, RData, Record, RecordType};
+use trust_dns_proto::serialize::binary::{BinEncodable, BinEncoder};
+
+use crate::call_next;
+use crate::helper::{dnssec_digest, dnssec_verify, random_u64};
+use crate::plugin::Error;
+
+/// the EDNS payload size of the DS and DNSKEY queries, the key sets are large
+const EDNS_PAYLOAD_SIZE: u16 = 1232;
+
+/// the host side of the validation, the queries go through the next plugins and the
+/// signatures are verified by the host
+pub trait Host {
+    fn exchange(&mut self, dns_packet: &[u8]) -> Result<Vec<u8>, Error>;
+
+    fn verify(&self, algorithm: u8, public_key: &[u8], data: &[u8], signature: &[u8]) -> bool;
+
+    fn digest(&self, digest_type: u8, data: &[u8]) -> Option<Vec<u8>>;
+
+    fn random_u64(&self) -> u64;
+}
+
+pub struct PluginHost;
+
+impl Host for PluginHost {
+    fn exchange(&mut self, dns_packet: &[u8]) -> Result<Vec<u8>, Error> {
+        call_next(dns_packet)
+    }
+
+    fn verify(&self, algorithm: u8, public_key: &[u8], data: &[u8], signature: &[u8]) -> bool {
+        dnssec_verify(algorithm, public_key, data, signature)
+    }
+
+    fn digest(&self, digest_type: u8, data: &[u8]) -> Option<Vec<u8>> {
+        dnssec_digest(digest_type, data)
+    }
+
+    fn random_u64(&self) -> u64 {
+        random_u64()
+    }
+}
+
+/// validate the RRSIG chain from the answer RRsets up to the trust anchors of the zone
+pub struct Validator<'a, H> {
+    host: &'a mut H,
+    zone: &'a Name,
+    trust_anchors: &'a [DS],
+    max_depth: u8,
+    /// the unix time in seconds, the signatures must be valid at it
+    now: u32,
+    /// the validated keys of the signers, so a chain is walked once per query
+    keys: HashMap<Name, Vec<DNSKEY>>,
+}
+
+impl<'a, H: Host> Validator<'a, H> {
+    pub fn new(
+        host: &'a mut H,
+        zone: &'a Name,
+        trust_anchors: &'a [DS],
+        max_depth: u8,
+        now: u32,
+    ) -> Self {
+        Self {
+            host,
+            zone,
+            trust_anchors,
+            max_depth,
+            now,
+            keys: HashMap::new(),
+        }
+    }
+
+    /// every answer RRset must have a valid signature, the error is the reason of the failure
+    pub fn validate_answers(&mut self, answers: &[Record]) -> Result<(), String> {
+        let (rrsigs, records) = answers
+            .iter()
+            .cloned()
+            .partition::<Vec<_>, _>(|record| record.record_type() == RecordType::RRSIG);
+
+        let mut rrsets: Vec<Vec<Record>> = vec![];
+        for record in records {
+            match rrsets.iter_mut().find(|rrset| {
+                rrset[0].name() == record.name() && rrset[0].record_type() == record.record_type()
+            }) {
+                None => rrsets.push(vec![record]),
+                Some(rrset) => rrset.push(record),
+            }
+        }
+
+        for rrset in &rrsets {
+            self.validate_rrset(rrset, &rrsigs, 0)?;
+        }
+
+        Ok(())
+    }
+
+    fn validate_rrset(
+        &mut self,
+        rrset: &[Record],
+        rrsigs: &[Record],
+        depth: u8,
+    ) -> Result<(), String> {
+        let name = rrset[0].name();
+        let record_type = rrset[0].record_type();
+
+        let mut last_err = format!("no rrsig covers {name} {record_type}");
+        for (rrsig_name, sig) in rrsigs.iter().filter_map(rrsig) {
+            if rrsig_name != name || sig.type_covered() != record_type {
+                continue;
+            }
+
+            let signer = sig.signer_name();
+            if !self.zone.zone_of(signer) || !signer.zone_of(name) {
+                last_err = format!("signer {signer} of {name} {record_type} is out of zone");
+
+                continue;
+            }
+
+            let result = self
+                .validated_keys(signer, depth)
+                .and_then(|keys| self.verify(sig, rrset, &keys));
+            match result {
+                Err(err) => last_err = err,
+                Ok(()) => return Ok(()),
+            }
+        }
+
+        Err(last_err)
+    }
+
+    /// the keys of the signer, the key set is signed by a key matching the DS set of the
+    /// signer, which is the trust anchors for the zone apex, or the DS RRset validated by
+    /// the parent
+    fn validated_keys(&mut self, signer: &Name, depth: u8) -> Result<Vec<DNSKEY>, String> {
+        if let Some(keys) = self.keys.get(signer) {
+            return Ok(keys.clone());
+        }
+
+        if depth >= self.max_depth {
+            return Err(format!(
+                "chain of {signer} is deeper than {}",
+                self.max_depth
+            ));
+        }
+
+        let ds_set = if signer == self.zone {
+            self.trust_anchors.to_vec()
+        } else {
+            let (ds_records, ds_rrsigs) = self.query(signer, RecordType::DS)?;
+            if ds_records.is_empty() {
+                return Err(format!("no DS of {signer}"));
+            }
+
+            self.validate_rrset(&ds_records, &ds_rrsigs, depth + 1)?;
+
+            ds_records
+                .iter()
+                .filter_map(|record| match record.data() {
+                    Some(RData::DNSSEC(DNSSECRData::DS(ds))) => Some(ds.clone()),
+                    _ => None,
+                })
+                .collect()
+        };
+
+        let (key_records, key_rrsigs) = self.query(signer, RecordType::DNSKEY)?;
+        let keys = key_records
+            .iter()
+            .filter_map(|record| match record.data() {
+                Some(RData::DNSSEC(DNSSECRData::DNSKEY(key))) => Some(key.clone()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        let entry_keys = keys
+            .iter()
+            .filter(|key| ds_set.iter().any(|ds| self.ds_matches(ds, signer, key)))
+            .cloned()
+            .collect::<Vec<_>>();
+        if entry_keys.is_empty() {
+            return Err(format!("no DNSKEY of {signer} matches the DS"));
+        }
+
+        let verified = key_rrsigs
+            .iter()
+            .filter_map(rrsig)
+            .filter(|(_, sig)| sig.type_covered() == RecordType::DNSKEY)
+            .filter(|(_, sig)| sig.signer_name() == signer)
+            .any(|(_, sig)| self.verify(sig, &key_records, &entry_keys).is_ok());
+        if !verified {
+            return Err(format!(
+                "DNSKEY set of {signer} is not signed by the entry keys"
+            ));
+        }
+
+        let keys = keys
+            .into_iter()
+            .filter(|key| key.zone_key() && !key.revoke())
+            .collect::<Vec<_>>();
+
+        self.keys.insert(signer.clone(), keys.clone());
+
+        Ok(keys)
+    }
+
+    fn verify(&self, sig: &SIG, rrset: &[Record], keys: &[DNSKEY]) -> Result<(), String> {
+        let name = rrset[0].name();
+        let record_type = rrset[0].record_type();
+
+        if self.now < sig.sig_inception() || self.now > sig.sig_expiration() {
+            return Err(format!(
+                "rrsig of {name} {record_type} is expired or not valid yet"
+            ));
+        }
+
+        let tbs = tbs::rrset_tbs_with_sig(name, DNSClass::IN, sig, rrset)
+            .map_err(|err| format!("build signed data of {name} {record_type} failed: {err}"))?;
+
+        let verified = keys
+            .iter()
+            .filter(|key| key.algorithm() == sig.algorithm())
+            .filter(|key| key.calculate_key_tag().ok() == Some(sig.key_tag()))
+            .any(|key| {
+                self.host.verify(
+                    u8::from(sig.algorithm()),
+                    key.public_key(),
+                    tbs.as_ref(),
+                    sig.sig(),
+                )
+            });
+        if !verified {
+            return Err(format!("rrsig of {name} {record_type} is bogus"));
+        }
+
+        Ok(())
+    }
+
+    fn ds_matches(&self, ds: &DS, name: &Name, key: &DNSKEY) -> bool {
+        if ds.algorithm() != key.algorithm() || key.calculate_key_tag().ok() != Some(ds.key_tag()) {
+            return false;
+        }
+
+        let data = match ds_digest_data(name, key) {
+            None => return false,
+            Some(data) => data,
+        };
+
+        self.host
+            .digest(u8::from(ds.digest_type()), &data)
+            .map(|digest| digest == ds.digest())
+            .unwrap_or(false)
+    }
+
+    /// query the RRset and its RRSIGs through the next plugins, the upstream validation is
+    /// disabled so the bogus data is returned to be checked here
+    fn query(
+        &mut self,
+        name: &Name,
+        record_type: RecordType,
+    ) -> Result<(Vec<Record>, Vec<Record>), String> {
+        let mut edns = Edns::new();
+        edns.set_max_payload(EDNS_PAYLOAD_SIZE).set_dnssec_ok(true);
+
+        let mut request_message = Message::new();
+        request_message
+            .set_id(self.host.random_u64() as _)
+            .set_message_type(MessageType::Query)
+            .set_op_code(OpCode::Query)
+            .set_recursion_desired(true)
+            .set_checking_disabled(true)
+            .add_query(Query::query(name.clone(), record_type))
+            .set_edns(edns);
+
+        let request_packet = request_message
+            .to_vec()
+            .map_err(|err| format!("encode {name} {record_type} query failed: {err}"))?;
+        let response_packet = self
+            .host
+            .exchange(&request_packet)
+            .map_err(|err| format!("query {name} {record_type} failed: {}", err.msg))?;
+        let response_message = Message::from_vec(&response_packet)
+            .map_err(|err| format!("decode {name} {record_type} response failed: {err}"))?;
+
+        let (rrsigs, records) = response_message
+            .answers()
+            .iter()
+            .filter(|record| record.name() == name)
+            .cloned()
+            .partition::<Vec<_>, _>(|record| record.record_type() == RecordType::RRSIG);
+        let records = records
+            .into_iter()
+            .filter(|record| record.record_type() == record_type)
+            .collect();
+
+        Ok((records, rrsigs))
+    }
+}
+
+fn rrsig(record: &Record) -> Option<(&Name, &SIG)> {
+    match record.data() {
+        Some(RData::DNSSEC(DNSSECRData::SIG(sig))) if record.record_type() == RecordType::RRSIG => {
+            Some((record.name(), sig))
+        }
+
+        _ => None,
+    }
+}
+
+/// the DS digest is over the canonical owner name and the DNSKEY rdata, RFC 4034
+pub fn ds_digest_data(name: &Name, key: &DNSKEY) -> Option<Vec<u8>> {
+    let mut buf = vec![];
+    let mut encoder = BinEncoder::new(&mut buf);
+    encoder.set_canonical_names(true);
+    name.to_lowercase()
+        .emit(&mut encoder)
+        .and_then(|_| dnskey::emit(&mut encoder, key))
+        .ok()?;
+
+    Some(buf)
+}
@@ -0,0 +1,270 @@
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use plugin_utils::net::client_addr;
+use serde::Deserialize;
+use tracing::{error, warn};
+use trust_dns_proto::op::{Message, MessageType, Query, ResponseCode};
+use trust_dns_proto::rr::{Name, RData, Record, RecordType};
+
+use crate::helper::{call_next_plugin, load_config, log_event, LogLevel};
+use crate::plugin::{Error, Plugin};
+
+wit_bindgen::generate!("rubydns");
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    /// the malicious domains, their subdomains are matched too
+    domains: Vec<String>,
+    #[serde(default = "default_sinkhole_v4")]
+    sinkhole_v4: Ipv4Addr,
+    #[serde(default = "default_sinkhole_v6")]
+    sinkhole_v6: Ipv6Addr,
+    #[serde(default = "default_ttl")]
+    ttl: u32,
+    /// still pass the matched query to the next plugin for analysis, its response is dropped
+    #[serde(default)]
+    forward: bool,
+}
+
+fn default_sinkhole_v4() -> Ipv4Addr {
+    Ipv4Addr::UNSPECIFIED
+}
+
+fn default_sinkhole_v6() -> Ipv6Addr {
+    Ipv6Addr::UNSPECIFIED
+}
+
+fn default_ttl() -> u32 {
+    60
+}
+
+impl Config {
+    fn domains(&self) -> Result<Vec<Name>, Error> {
+        self.domains
+            .iter()
+            .map(|domain| {
+                Name::from_ascii(domain).map_err(|err| {
+                    error!(%err, domain, "invalid honeypot domain");
+
+                    Error {
+                        code: 1,
+                        msg: format!("invalid domain {domain}: {err}"),
+                    }
+                })
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug)]
+struct HoneypotRunner;
+
+impl Plugin for HoneypotRunner {
+    fn run(dns_packet: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let config = load_config();
+        let config: Config = serde_yaml::from_str(&config).map_err(|err| {
+            error!(%err, "load honeypot config failed");
+
+            Error {
+                code: 1,
+                msg: err.to_string(),
+            }
+        })?;
+
+        let request_message = Message::from_vec(&dns_packet).map_err(|err| {
+            error!(%err, "decode dns request packet failed");
+
+            Error {
+                code: 1,
+                msg: err.to_string(),
+            }
+        })?;
+
+        let query = match honeypot_query(&config.domains()?, &request_message) {
+            None => return call_next(&dns_packet),
+            Some(query) => query,
+        };
+
+        let fields = log_fields(client_addr(), query);
+        let fields = fields
+            .iter()
+            .map(|(key, value)| (*key, value.as_str()))
+            .collect::<Vec<_>>();
+        log_event(LogLevel::Warn, "honeypot domain queried", &fields);
+
+        if config.forward {
+            if let Err(err) = call_next(&dns_packet) {
+                warn!(?err, "forward honeypot query failed");
+            }
+        }
+
+        sinkhole_response(&config, &request_message, query)
+            .to_vec()
+            .map_err(|err| {
+                error!(%err, "encode dns response packet failed");
+
+                Error {
+                    code: 1,
+                    msg: err.to_string(),
+                }
+            })
+    }
+
+    fn valid_config() -> Result<(), Error> {
+        let config: Config = serde_yaml::from_str(&load_config()).map_err(|err| {
+            error!(%err, "load honeypot config failed");
+
+            Error {
+                code: 1,
+                msg: err.to_string(),
+            }
+        })?;
+
+        config.domains()?;
+
+        Ok(())
+    }
+
+    fn version() -> String {
+        env!("CARGO_PKG_VERSION").to_string()
+    }
+}
+
+/// the query of the request when its name is under a honeypot domain
+fn honeypot_query<'a>(domains: &[Name], request_message: &'a Message) -> Option<&'a Query> {
+    request_message
+        .query()
+        .filter(|query| domains.iter().any(|domain| domain.zone_of(query.name())))
+}
+
+/// the fields of the log event of a honeypot query
+fn log_fields(client_addr: Option<SocketAddr>, query: &Query) -> Vec<(&'static str, String)> {
+    vec![
+        (
+            "client_addr",
+            client_addr.map_or_else(|| "unknown".to_string(), |addr| addr.to_string()),
+        ),
+        ("name", query.name().to_string()),
+        ("query_type", query.query_type().to_string()),
+    ]
+}
+
+/// answer A and AAAA queries with the sinkhole addrs, other query types get an empty answer
+fn sinkhole_response(config: &Config, request_message: &Message, query: &Query) -> Message {
+    let rdata = match query.query_type() {
+        RecordType::A => Some(RData::A(config.sinkhole_v4)),
+        RecordType::AAAA => Some(RData::AAAA(config.sinkhole_v6)),
+        _ => None,
+    };
+
+    let mut response_message = Message::new();
+    response_message
+        .set_id(request_message.id())
+        .set_message_type(MessageType::Response)
+        .set_op_code(request_message.op_code())
+        .set_recursion_desired(request_message.recursion_desired())
+        .set_recursion_available(true)
+        .set_response_code(ResponseCode::NoError)
+        .add_queries(request_message.queries().iter().cloned());
+    if let Some(rdata) = rdata {
+        response_message.add_answer(Record::from_rdata(query.name().clone(), config.ttl, rdata));
+    }
+
+    response_message
+}
+
+fn call_next(dns_packet: &[u8]) -> Result<Vec<u8>, Error> {
+    match call_next_plugin(dns_packet) {
+        None => Err(Error {
+            code: 1,
+            msg: "no next plugin".to_string(),
+        }),
+
+        Some(result) => result,
+    }
+}
+
+export_rubydns!(HoneypotRunner);
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    fn request(name: &str, query_type: RecordType) -> Message {
+        let mut message = Message::new();
+        message
+            .set_id(1234)
+            .add_query(Query::query(Name::from_str(name).unwrap(), query_type));
+
+        message
+    }
+
+    fn config(config: &str) -> Config {
+        serde_yaml::from_str(config).unwrap()
+    }
+
+    #[test]
+    fn log_and_sinkhole_honeypot_query() {
+        let config = config("{ domains: [evil.example], sinkhole_v4: 192.0.2.53, ttl: 30 }");
+        let request_message = request("c2.evil.example.", RecordType::A);
+
+        let query = honeypot_query(&config.domains().unwrap(), &request_message).unwrap();
+        assert_eq!(
+            log_fields(Some("192.0.2.10:5353".parse().unwrap()), query),
+            [
+                ("client_addr", "192.0.2.10:5353".to_string()),
+                ("name", "c2.evil.example.".to_string()),
+                ("query_type", "A".to_string()),
+            ]
+        );
+        assert_eq!(
+            log_fields(None, query)[0],
+            ("client_addr", "unknown".to_string())
+        );
+
+        let response_message = sinkhole_response(&config, &request_message, query);
+        assert_eq!(response_message.id(), 1234);
+        assert_eq!(response_message.message_type(), MessageType::Response);
+        assert_eq!(response_message.response_code(), ResponseCode::NoError);
+
+        let answer = &response_message.answers()[0];
+        assert_eq!(answer.ttl(), 30);
+        assert_eq!(answer.data(), Some(&RData::A(Ipv4Addr::new(192, 0, 2, 53))));
+    }
+
+    #[test]
+    fn sinkhole_by_query_type() {
+        let config = config("{ domains: [evil.example] }");
+
+        let request_message = request("evil.example.", RecordType::AAAA);
+        let query = honeypot_query(&config.domains().unwrap(), &request_message).unwrap();
+        let response_message = sinkhole_response(&config, &request_message, query);
+        assert_eq!(
+            response_message.answers()[0].data(),
+            Some(&RData::AAAA(Ipv6Addr::UNSPECIFIED))
+        );
+
+        let request_message = request("evil.example.", RecordType::MX);
+        let query = honeypot_query(&config.domains().unwrap(), &request_message).unwrap();
+        assert!(sinkhole_response(&config, &request_message, query)
+            .answers()
+            .is_empty());
+    }
+
+    #[test]
+    fn pass_through_other_domains() {
+        let config = config("{ domains: [evil.example] }");
+        let domains = config.domains().unwrap();
+
+        assert!(honeypot_query(&domains, &request("www.example.com.", RecordType::A)).is_none());
+        assert!(honeypot_query(&domains, &request("notevil.example.", RecordType::A)).is_none());
+    }
+
+    #[test]
+    fn reject_invalid_domain() {
+        assert!(config("{ domains: [\"bad..example\"] }").domains().is_err());
+    }
+}
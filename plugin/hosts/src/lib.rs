@@ -0,0 +1,280 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+use serde::Deserialize;
+use tracing::{error, info};
+use trust_dns_proto::op::{Message, MessageType, ResponseCode};
+use trust_dns_proto::rr::{Name, RData, Record, RecordType};
+
+use crate::helper::{call_next_plugin, load_config};
+use crate::plugin::{Error, Plugin};
+
+wit_bindgen::generate!("rubydns");
+
+/// answer the A and AAAA queries of the configured names from a local mapping, like the
+/// hosts file
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    /// the names and their addresses, a name without the address of the queried family is
+    /// answered with no data
+    hosts: HashMap<String, Vec<IpAddr>>,
+    #[serde(default = "default_ttl")]
+    ttl: u32,
+}
+
+fn default_ttl() -> u32 {
+    300
+}
+
+impl Config {
+    /// the names must be lowercase without the trailing dot, as they are looked up
+    fn validate(&self) -> Result<(), Error> {
+        for (name, addrs) in &self.hosts {
+            Name::from_ascii(name).map_err(|err| {
+                error!(%err, name, "invalid hosts name");
+
+                Error {
+                    code: 1,
+                    msg: format!("invalid name {name}: {err}"),
+                }
+            })?;
+
+            if name.ends_with('.') || name.to_lowercase() != *name {
+                error!(
+                    name,
+                    "hosts name must be lowercase without the trailing dot"
+                );
+
+                return Err(Error {
+                    code: 1,
+                    msg: format!("invalid name {name}"),
+                });
+            }
+
+            if addrs.is_empty() {
+                error!(name, "hosts name has no address");
+
+                return Err(Error {
+                    code: 1,
+                    msg: format!("hosts name {name} has no address"),
+                });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct HostsRunner;
+
+impl Plugin for HostsRunner {
+    fn run(dns_packet: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let config = load_config();
+        let config: Config = serde_yaml::from_str(&config).map_err(|err| {
+            error!(%err, "load hosts config failed");
+
+            Error {
+                code: 1,
+                msg: err.to_string(),
+            }
+        })?;
+
+        let request_message = Message::from_vec(&dns_packet).map_err(|err| {
+            error!(%err, "decode dns request packet failed");
+
+            Error {
+                code: 1,
+                msg: err.to_string(),
+            }
+        })?;
+
+        let response_message = match hosts_response(&config, &request_message) {
+            None => return call_next(&dns_packet),
+            Some(response_message) => response_message,
+        };
+
+        response_message.to_vec().map_err(|err| {
+            error!(%err, "encode dns response packet failed");
+
+            Error {
+                code: 1,
+                msg: err.to_string(),
+            }
+        })
+    }
+
+    fn valid_config() -> Result<(), Error> {
+        let config: Config = serde_yaml::from_str(&load_config()).map_err(|err| {
+            error!(%err, "load hosts config failed");
+
+            Error {
+                code: 1,
+                msg: err.to_string(),
+            }
+        })?;
+
+        config.validate()
+    }
+
+    fn version() -> String {
+        env!("CARGO_PKG_VERSION").to_string()
+    }
+}
+
+/// the response of the A or AAAA query whose name is in the hosts, [`None`] means the query
+/// is passed to the next plugin
+fn hosts_response(config: &Config, request_message: &Message) -> Option<Message> {
+    let query = match request_message.query() {
+        Some(query) if matches!(query.query_type(), RecordType::A | RecordType::AAAA) => query,
+        _ => return None,
+    };
+
+    let name = query.name().to_lowercase().to_ascii();
+    let addrs = config.hosts.get(name.trim_end_matches('.'))?;
+
+    let answers = addrs
+        .iter()
+        .filter_map(|addr| match (query.query_type(), addr) {
+            (RecordType::A, IpAddr::V4(addr)) => Some(RData::A(*addr)),
+            (RecordType::AAAA, IpAddr::V6(addr)) => Some(RData::AAAA(*addr)),
+            _ => None,
+        })
+        .map(|rdata| Record::from_rdata(query.name().clone(), config.ttl, rdata))
+        .collect::<Vec<_>>();
+
+    info!(
+        name,
+        query_type = %query.query_type(),
+        answers = answers.len(),
+        "answer from hosts"
+    );
+
+    let mut response_message = Message::new();
+    response_message
+        .set_id(request_message.id())
+        .set_message_type(MessageType::Response)
+        .set_op_code(request_message.op_code())
+        .set_recursion_desired(request_message.recursion_desired())
+        .set_recursion_available(true)
+        .set_response_code(ResponseCode::NoError)
+        .add_queries(request_message.queries().iter().cloned())
+        .add_answers(answers);
+
+    Some(response_message)
+}
+
+fn call_next(dns_packet: &[u8]) -> Result<Vec<u8>, Error> {
+    match call_next_plugin(dns_packet) {
+        None => Err(Error {
+            code: 1,
+            msg: "no next plugin".to_string(),
+        }),
+
+        Some(result) => result,
+    }
+}
+
+export_rubydns!(HostsRunner);
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+    use std::str::FromStr;
+
+    use trust_dns_proto::op::Query;
+
+    use super::*;
+
+    fn config(config: &str) -> Config {
+        serde_yaml::from_str(config).unwrap()
+    }
+
+    fn request(name: &str, query_type: RecordType) -> Message {
+        let mut message = Message::new();
+        message
+            .set_id(1234)
+            .set_recursion_desired(true)
+            .add_query(Query::query(Name::from_str(name).unwrap(), query_type));
+
+        message
+    }
+
+    const HOSTS: &str = "
+hosts:
+  nas.home: [192.168.1.10, 'fd00::10']
+  printer.home: [192.168.1.20]
+ttl: 60
+";
+
+    #[test]
+    fn answer_a_and_aaaa() {
+        let config = config(HOSTS);
+
+        let response_message =
+            hosts_response(&config, &request("NAS.home.", RecordType::A)).unwrap();
+        assert_eq!(response_message.id(), 1234);
+        assert_eq!(response_message.message_type(), MessageType::Response);
+        assert_eq!(response_message.response_code(), ResponseCode::NoError);
+        assert_eq!(response_message.answers().len(), 1);
+        assert_eq!(response_message.answers()[0].ttl(), 60);
+        assert_eq!(
+            response_message.answers()[0].name(),
+            &Name::from_str("NAS.home.").unwrap()
+        );
+        assert_eq!(
+            response_message.answers()[0].data(),
+            Some(&RData::A(Ipv4Addr::new(192, 168, 1, 10)))
+        );
+
+        let response_message =
+            hosts_response(&config, &request("nas.home.", RecordType::AAAA)).unwrap();
+        assert_eq!(
+            response_message.answers()[0].data(),
+            Some(&RData::AAAA(Ipv6Addr::from_str("fd00::10").unwrap()))
+        );
+    }
+
+    #[test]
+    fn answer_no_data_without_address_of_family() {
+        let config = config(HOSTS);
+
+        let response_message =
+            hosts_response(&config, &request("printer.home.", RecordType::AAAA)).unwrap();
+
+        assert_eq!(response_message.response_code(), ResponseCode::NoError);
+        assert!(response_message.answers().is_empty());
+    }
+
+    #[test]
+    fn pass_through_other_names_and_types() {
+        let config = config(HOSTS);
+
+        assert!(hosts_response(&config, &request("www.example.com.", RecordType::A)).is_none());
+        assert!(hosts_response(&config, &request("nas.home.", RecordType::MX)).is_none());
+    }
+
+    #[test]
+    fn default_ttl_is_300() {
+        let config = config("hosts: { nas.home: [192.168.1.10] }");
+
+        let response_message =
+            hosts_response(&config, &request("nas.home.", RecordType::A)).unwrap();
+
+        assert_eq!(response_message.answers()[0].ttl(), 300);
+    }
+
+    #[test]
+    fn validate_hosts_names() {
+        assert!(config(HOSTS).validate().is_ok());
+
+        assert!(config("hosts: { NAS.home: [192.168.1.10] }")
+            .validate()
+            .is_err());
+        assert!(config("hosts: { nas.home.: [192.168.1.10] }")
+            .validate()
+            .is_err());
+        assert!(config("hosts: { nas.home: [] }").validate().is_err());
+    }
+}
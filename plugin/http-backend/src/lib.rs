@@ -0,0 +1,312 @@
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use plugin_utils::http;
+use plugin_utils::http::Url;
+use serde::Deserialize;
+use tracing::{error, info};
+use trust_dns_proto::op::{Message, MessageType, Query, ResponseCode};
+use trust_dns_proto::rr::rdata::TXT;
+use trust_dns_proto::rr::{Name, RData, Record};
+
+use crate::helper::{call_next_plugin, load_config};
+use crate::plugin::{Error, Plugin};
+
+wit_bindgen::generate!("rubydns");
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    url: Url,
+}
+
+/// the json body returned by the backend
+#[derive(Debug, Deserialize)]
+struct BackendResponse {
+    #[serde(default)]
+    answers: Vec<BackendRecord>,
+}
+
+#[derive(Debug, Deserialize)]
+struct BackendRecord {
+    #[serde(rename = "type")]
+    record_type: String,
+    data: String,
+    #[serde(default = "default_ttl")]
+    ttl: u32,
+}
+
+fn default_ttl() -> u32 {
+    60
+}
+
+#[derive(Debug)]
+struct HttpBackendRunner;
+
+impl Plugin for HttpBackendRunner {
+    fn run(dns_packet: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let config = load_config();
+        let config: Config = serde_yaml::from_str(&config).map_err(|err| {
+            error!(%err, "load http backend config failed");
+
+            Error {
+                code: 1,
+                msg: err.to_string(),
+            }
+        })?;
+
+        let request_message = Message::from_vec(&dns_packet).map_err(|err| {
+            error!(%err, "decode dns request packet failed");
+
+            Error {
+                code: 1,
+                msg: err.to_string(),
+            }
+        })?;
+
+        let response_message = match answer(&config, &request_message, http::get)? {
+            None => return call_next(&dns_packet),
+            Some(response_message) => response_message,
+        };
+
+        response_message.to_vec().map_err(|err| {
+            error!(%err, "encode dns response packet failed");
+
+            Error {
+                code: 1,
+                msg: err.to_string(),
+            }
+        })
+    }
+
+    fn valid_config() -> Result<(), Error> {
+        serde_yaml::from_str::<Config>(&load_config()).map_err(|err| {
+            error!(%err, "load http backend config failed");
+
+            Error {
+                code: 1,
+                msg: err.to_string(),
+            }
+        })?;
+
+        Ok(())
+    }
+
+    fn version() -> String {
+        env!("CARGO_PKG_VERSION").to_string()
+    }
+}
+
+/// ask the backend for the records of the query, [`None`] means the query falls through to
+/// the next plugin
+fn answer(
+    config: &Config,
+    request_message: &Message,
+    get: impl FnOnce(&Url, &[(&str, &str)]) -> io::Result<http::Response>,
+) -> Result<Option<Message>, Error> {
+    let query = match request_message.query() {
+        None => return Ok(None),
+        Some(query) => query,
+    };
+
+    let name = query.name().to_ascii();
+    let query_type = query.query_type().to_string();
+    let response = get(
+        &config.url,
+        &[("name", name.as_str()), ("type", query_type.as_str())],
+    )
+    .map_err(|err| {
+        error!(%err, url = ?config.url, "request http backend failed");
+
+        Error {
+            code: err.raw_os_error().unwrap_or(1) as _,
+            msg: err.to_string(),
+        }
+    })?;
+
+    match response.status {
+        404 => {
+            info!(%name, %query_type, "http backend has no record, fall through");
+
+            return Ok(None);
+        }
+
+        200 => {}
+
+        status => {
+            error!(status, %name, %query_type, "http backend response failed");
+
+            return Err(Error {
+                code: 1,
+                msg: format!("http backend response status {status}"),
+            });
+        }
+    }
+
+    let backend_response: BackendResponse =
+        serde_json::from_slice(&response.body).map_err(|err| {
+            error!(%err, "decode http backend response failed");
+
+            Error {
+                code: 1,
+                msg: err.to_string(),
+            }
+        })?;
+
+    create_response(request_message, query, backend_response).map(Some)
+}
+
+fn call_next(dns_packet: &[u8]) -> Result<Vec<u8>, Error> {
+    match call_next_plugin(dns_packet) {
+        None => Err(Error {
+            code: 1,
+            msg: "no next plugin".to_string(),
+        }),
+
+        Some(result) => result,
+    }
+}
+
+fn create_response(
+    request_message: &Message,
+    query: &Query,
+    backend_response: BackendResponse,
+) -> Result<Message, Error> {
+    let answers = backend_response
+        .answers
+        .into_iter()
+        .map(|record| {
+            let rdata = parse_rdata(&record).map_err(|err| {
+                error!(%err, ?record, "invalid http backend record");
+
+                Error { code: 1, msg: err }
+            })?;
+
+            Ok(Record::from_rdata(query.name().clone(), record.ttl, rdata))
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+
+    let mut response_message = Message::new();
+    response_message
+        .set_id(request_message.id())
+        .set_message_type(MessageType::Response)
+        .set_op_code(request_message.op_code())
+        .set_recursion_desired(request_message.recursion_desired())
+        .set_recursion_available(true)
+        .set_response_code(ResponseCode::NoError)
+        .add_queries(request_message.queries().iter().cloned())
+        .add_answers(answers);
+
+    Ok(response_message)
+}
+
+fn parse_rdata(record: &BackendRecord) -> Result<RData, String> {
+    let rdata = match record.record_type.to_ascii_uppercase().as_str() {
+        "A" => RData::A(
+            record
+                .data
+                .parse::<Ipv4Addr>()
+                .map_err(|err| err.to_string())?,
+        ),
+        "AAAA" => RData::AAAA(
+            record
+                .data
+                .parse::<Ipv6Addr>()
+                .map_err(|err| err.to_string())?,
+        ),
+        "CNAME" => RData::CNAME(Name::from_ascii(&record.data).map_err(|err| err.to_string())?),
+        "TXT" => RData::TXT(TXT::new(vec![record.data.clone()])),
+        record_type => return Err(format!("unsupported record type {record_type}")),
+    };
+
+    Ok(rdata)
+}
+
+export_rubydns!(HttpBackendRunner);
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use trust_dns_proto::rr::RecordType;
+
+    use super::*;
+
+    fn config() -> Config {
+        serde_yaml::from_str("url: http://127.0.0.1:8080/records").unwrap()
+    }
+
+    fn request(name: &str, query_type: RecordType) -> Message {
+        let mut message = Message::new();
+        message
+            .set_id(1)
+            .add_query(Query::query(Name::from_str(name).unwrap(), query_type));
+
+        message
+    }
+
+    /// the backend knows the A record of www.example.com. only
+    fn mock_backend(_url: &Url, params: &[(&str, &str)]) -> io::Result<http::Response> {
+        let response = match params {
+            [("name", "www.example.com."), ("type", "A")] => http::Response {
+                status: 200,
+                body: br#"{"answers": [{"type": "A", "data": "192.0.2.1", "ttl": 30}]}"#.to_vec(),
+            },
+
+            [("name", "broken.example.com."), _] => http::Response {
+                status: 500,
+                body: vec![],
+            },
+
+            _ => http::Response {
+                status: 404,
+                body: vec![],
+            },
+        };
+
+        Ok(response)
+    }
+
+    #[test]
+    fn answer_from_backend() {
+        let response_message = answer(
+            &config(),
+            &request("www.example.com.", RecordType::A),
+            mock_backend,
+        )
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(response_message.id(), 1);
+        assert_eq!(response_message.message_type(), MessageType::Response);
+        assert_eq!(response_message.answers().len(), 1);
+
+        let record = &response_message.answers()[0];
+        assert_eq!(record.name(), &Name::from_str("www.example.com.").unwrap());
+        assert_eq!(record.ttl(), 30);
+        assert_eq!(record.data(), Some(&RData::A(Ipv4Addr::new(192, 0, 2, 1))));
+    }
+
+    #[test]
+    fn fall_through_on_not_found() {
+        let response_message = answer(
+            &config(),
+            &request("www.example.com.", RecordType::AAAA),
+            mock_backend,
+        )
+        .unwrap();
+
+        assert!(response_message.is_none());
+    }
+
+    #[test]
+    fn backend_error() {
+        let result = answer(
+            &config(),
+            &request("broken.example.com.", RecordType::A),
+            mock_backend,
+        );
+
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,291 @@
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use serde::Deserialize;
+use tracing::{error, info};
+use trust_dns_proto::op::{Message, ResponseCode};
+use trust_dns_proto::rr::rdata::svcb::{Alpn, IpHint, SvcParamKey, SvcParamValue, SVCB};
+use trust_dns_proto::rr::{Name, RData, Record, RecordType};
+
+use crate::helper::{call_next_plugin, load_config};
+use crate::plugin::{Error, Plugin};
+
+wit_bindgen::generate!("rubydns");
+
+/// add synthesized HTTPS records to the A and AAAA responses of the configured names, which
+/// hint the clients to upgrade to HTTPS
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    /// the names and their HTTPS service parameters
+    hints: HashMap<String, Hint>,
+    #[serde(default = "default_ttl")]
+    ttl: u32,
+}
+
+fn default_ttl() -> u32 {
+    300
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Hint {
+    #[serde(default = "default_priority")]
+    priority: u16,
+    /// the service target, `.` means the owner name itself
+    #[serde(default = "default_target")]
+    target: String,
+    #[serde(default)]
+    alpn: Vec<String>,
+    port: Option<u16>,
+    #[serde(default)]
+    ipv4_hint: Vec<Ipv4Addr>,
+    #[serde(default)]
+    ipv6_hint: Vec<Ipv6Addr>,
+}
+
+fn default_priority() -> u16 {
+    1
+}
+
+fn default_target() -> String {
+    ".".to_string()
+}
+
+impl Hint {
+    fn to_svcb(&self) -> Result<SVCB, Error> {
+        let target = Name::from_ascii(&self.target).map_err(|err| {
+            error!(%err, target = self.target, "invalid https hint target");
+
+            Error {
+                code: 1,
+                msg: format!("invalid target {}: {err}", self.target),
+            }
+        })?;
+
+        // the params must be in the key order
+        let mut params = vec![];
+        if !self.alpn.is_empty() {
+            params.push((
+                SvcParamKey::Alpn,
+                SvcParamValue::Alpn(Alpn(self.alpn.clone())),
+            ));
+        }
+        if let Some(port) = self.port {
+            params.push((SvcParamKey::Port, SvcParamValue::Port(port)));
+        }
+        if !self.ipv4_hint.is_empty() {
+            params.push((
+                SvcParamKey::Ipv4Hint,
+                SvcParamValue::Ipv4Hint(IpHint(self.ipv4_hint.clone())),
+            ));
+        }
+        if !self.ipv6_hint.is_empty() {
+            params.push((
+                SvcParamKey::Ipv6Hint,
+                SvcParamValue::Ipv6Hint(IpHint(self.ipv6_hint.clone())),
+            ));
+        }
+
+        Ok(SVCB::new(self.priority, target, params))
+    }
+}
+
+#[derive(Debug)]
+struct HttpsHintRunner;
+
+impl Plugin for HttpsHintRunner {
+    fn run(dns_packet: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let config = load_config();
+        let config: Config = serde_yaml::from_str(&config).map_err(|err| {
+            error!(%err, "load https hint config failed");
+
+            Error {
+                code: 1,
+                msg: err.to_string(),
+            }
+        })?;
+
+        add_hint(&config, call_next(&dns_packet)?)
+    }
+
+    fn valid_config() -> Result<(), Error> {
+        let config: Config = serde_yaml::from_str(&load_config()).map_err(|err| {
+            error!(%err, "load https hint config failed");
+
+            Error {
+                code: 1,
+                msg: err.to_string(),
+            }
+        })?;
+
+        for (name, hint) in &config.hints {
+            if name.ends_with('.') || name.to_lowercase() != *name {
+                error!(
+                    name,
+                    "https hint name must be lowercase without the trailing dot"
+                );
+
+                return Err(Error {
+                    code: 1,
+                    msg: format!("invalid name {name}"),
+                });
+            }
+
+            hint.to_svcb()?;
+        }
+
+        Ok(())
+    }
+
+    fn version() -> String {
+        env!("CARGO_PKG_VERSION").to_string()
+    }
+}
+
+/// add the HTTPS record to the additional section of the A or AAAA response of a configured
+/// name, the other responses are returned as they are
+fn add_hint(config: &Config, response_packet: Vec<u8>) -> Result<Vec<u8>, Error> {
+    let mut response_message = Message::from_vec(&response_packet).map_err(|err| {
+        error!(%err, "decode dns response packet failed");
+
+        Error {
+            code: 1,
+            msg: err.to_string(),
+        }
+    })?;
+
+    let query = match response_message.query() {
+        Some(query)
+            if matches!(query.query_type(), RecordType::A | RecordType::AAAA)
+                && response_message.response_code() == ResponseCode::NoError =>
+        {
+            query.clone()
+        }
+
+        _ => return Ok(response_packet),
+    };
+
+    let name = query.name().to_lowercase().to_ascii();
+    let hint = match config.hints.get(name.trim_end_matches('.')) {
+        None => return Ok(response_packet),
+        Some(hint) => hint,
+    };
+
+    info!(name, "add https hint");
+
+    response_message.add_additional(Record::from_rdata(
+        query.name().clone(),
+        config.ttl,
+        RData::HTTPS(hint.to_svcb()?),
+    ));
+
+    // the section counts are recomputed by the encoder
+    response_message.to_vec().map_err(|err| {
+        error!(%err, "encode dns response packet failed");
+
+        Error {
+            code: 1,
+            msg: err.to_string(),
+        }
+    })
+}
+
+fn call_next(dns_packet: &[u8]) -> Result<Vec<u8>, Error> {
+    match call_next_plugin(dns_packet) {
+        None => Err(Error {
+            code: 1,
+            msg: "no next plugin".to_string(),
+        }),
+
+        Some(result) => result,
+    }
+}
+
+export_rubydns!(HttpsHintRunner);
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use trust_dns_proto::op::{MessageType, Query};
+
+    use super::*;
+
+    fn config() -> Config {
+        serde_yaml::from_str(
+            "
+hints:
+  www.example.com: { alpn: [h2, h3], port: 8443, ipv4_hint: [192.0.2.1] }
+ttl: 60
+",
+        )
+        .unwrap()
+    }
+
+    fn response(name: &str, query_type: RecordType, response_code: ResponseCode) -> Vec<u8> {
+        let name = Name::from_str(name).unwrap();
+        let mut message = Message::new();
+        message
+            .set_id(1234)
+            .set_message_type(MessageType::Response)
+            .set_response_code(response_code)
+            .add_query(Query::query(name.clone(), query_type));
+        if response_code == ResponseCode::NoError && query_type == RecordType::A {
+            message.add_answer(Record::from_rdata(
+                name,
+                300,
+                RData::A(Ipv4Addr::new(192, 0, 2, 1)),
+            ));
+        }
+
+        message.to_vec().unwrap()
+    }
+
+    #[test]
+    fn add_https_record_for_configured_name() {
+        let response_packet = add_hint(
+            &config(),
+            response("WWW.example.com.", RecordType::A, ResponseCode::NoError),
+        )
+        .unwrap();
+
+        // ARCOUNT
+        assert_eq!(&response_packet[10..12], &[0, 1]);
+
+        let response_message = Message::from_vec(&response_packet).unwrap();
+        assert_eq!(response_message.answers().len(), 1);
+
+        let record = &response_message.additionals()[0];
+        assert_eq!(record.name(), &Name::from_str("WWW.example.com.").unwrap());
+        assert_eq!(record.ttl(), 60);
+
+        let svcb = match record.data() {
+            Some(RData::HTTPS(svcb)) => svcb,
+            data => panic!("unexpected additional {data:?}"),
+        };
+        assert_eq!(svcb.svc_priority(), 1);
+        assert_eq!(svcb.target_name(), &Name::root());
+        assert_eq!(
+            svcb.svc_params()
+                .iter()
+                .map(|(key, _)| *key)
+                .collect::<Vec<_>>(),
+            [SvcParamKey::Alpn, SvcParamKey::Port, SvcParamKey::Ipv4Hint]
+        );
+    }
+
+    #[test]
+    fn keep_other_responses() {
+        for response_packet in [
+            response("other.example.com.", RecordType::A, ResponseCode::NoError),
+            response("www.example.com.", RecordType::MX, ResponseCode::NoError),
+            response("www.example.com.", RecordType::A, ResponseCode::NXDomain),
+        ] {
+            assert_eq!(
+                add_hint(&config(), response_packet.clone()).unwrap(),
+                response_packet
+            );
+        }
+    }
+}
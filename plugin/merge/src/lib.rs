@@ -0,0 +1,277 @@
+use serde::Deserialize;
+use tracing::{error, info, warn};
+use trust_dns_proto::op::{Message, MessageType, ResponseCode};
+use trust_dns_proto::rr::Record;
+
+use crate::helper::{call_plugin, load_config};
+use crate::plugin::{Error, Plugin};
+
+wit_bindgen::generate!("rubydns");
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    /// the branches to query, in priority order, the branches themselves are declared in the
+    /// `branches` of the plugin
+    sources: Vec<String>,
+    #[serde(default)]
+    strategy: Strategy,
+}
+
+/// how to merge the answers when several branches answer the same name and type
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Strategy {
+    /// keep the answers of all branches
+    #[default]
+    Union,
+
+    /// keep the answers of the first branch which answers the name and type
+    PreferFirst,
+}
+
+#[derive(Debug)]
+struct MergeRunner;
+
+impl Plugin for MergeRunner {
+    fn run(dns_packet: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let config = load_config();
+        let config: Config = serde_yaml::from_str(&config).map_err(|err| {
+            error!(%err, "load merge config failed");
+
+            Error {
+                code: 1,
+                msg: err.to_string(),
+            }
+        })?;
+
+        let request_message = Message::from_vec(&dns_packet).map_err(|err| {
+            error!(%err, "decode dns request packet failed");
+
+            Error {
+                code: 1,
+                msg: err.to_string(),
+            }
+        })?;
+
+        let responses = query_branches(&config.sources, &dns_packet, call_plugin);
+        if responses.is_empty() {
+            return Err(Error {
+                code: 1,
+                msg: "all branches failed".to_string(),
+            });
+        }
+
+        let answers = merge_answers(&responses, config.strategy);
+        let response_code = if answers.is_empty() {
+            responses[0].response_code()
+        } else {
+            ResponseCode::NoError
+        };
+
+        info!(
+            branches = responses.len(),
+            answers = answers.len(),
+            "merge branch answers done"
+        );
+
+        let mut response_message = Message::new();
+        response_message
+            .set_id(request_message.id())
+            .set_message_type(MessageType::Response)
+            .set_op_code(request_message.op_code())
+            .set_recursion_desired(request_message.recursion_desired())
+            .set_recursion_available(true)
+            .set_response_code(response_code)
+            .add_queries(request_message.queries().iter().cloned())
+            .add_answers(answers);
+
+        response_message.to_vec().map_err(|err| {
+            error!(%err, "encode dns response packet failed");
+
+            Error {
+                code: 1,
+                msg: err.to_string(),
+            }
+        })
+    }
+
+    fn valid_config() -> Result<(), Error> {
+        let config: Config = serde_yaml::from_str(&load_config()).map_err(|err| {
+            error!(%err, "load merge config failed");
+
+            Error {
+                code: 1,
+                msg: err.to_string(),
+            }
+        })?;
+
+        if config.sources.is_empty() {
+            return Err(Error {
+                code: 1,
+                msg: "no branch to merge".to_string(),
+            });
+        }
+
+        Ok(())
+    }
+
+    fn version() -> String {
+        env!("CARGO_PKG_VERSION").to_string()
+    }
+}
+
+/// call the branches in order, the failed branches are skipped
+fn query_branches<F>(branches: &[String], dns_packet: &[u8], call: F) -> Vec<Message>
+where
+    F: Fn(&str, &[u8]) -> Option<Result<Vec<u8>, Error>>,
+{
+    branches
+        .iter()
+        .filter_map(|branch| call_branch(branch, dns_packet, &call))
+        .collect()
+}
+
+fn call_branch<F>(branch: &str, dns_packet: &[u8], call: F) -> Option<Message>
+where
+    F: Fn(&str, &[u8]) -> Option<Result<Vec<u8>, Error>>,
+{
+    let response_packet = match call(branch, dns_packet) {
+        None => {
+            warn!(branch, "branch not exists");
+
+            return None;
+        }
+
+        Some(Err(err)) => {
+            warn!(branch, ?err, "branch handle dns failed");
+
+            return None;
+        }
+
+        Some(Ok(response_packet)) => response_packet,
+    };
+
+    Message::from_vec(&response_packet)
+        .map_err(|err| warn!(branch, %err, "decode branch response packet failed"))
+        .ok()
+}
+
+fn merge_answers(responses: &[Message], strategy: Strategy) -> Vec<Record> {
+    let mut answers: Vec<Record> = vec![];
+
+    for response in responses {
+        // the answers of the former branches
+        let answered = answers.len();
+
+        for answer in response.answers() {
+            if strategy == Strategy::PreferFirst
+                && answers[..answered].iter().any(|record| {
+                    record.name() == answer.name() && record.record_type() == answer.record_type()
+                })
+            {
+                continue;
+            }
+
+            let duplicated = answers.iter().any(|record| {
+                record.name() == answer.name()
+                    && record.record_type() == answer.record_type()
+                    && record.dns_class() == answer.dns_class()
+                    && record.data() == answer.data()
+            });
+            if !duplicated {
+                answers.push(answer.clone());
+            }
+        }
+    }
+
+    answers
+}
+
+export_rubydns!(MergeRunner);
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::str::FromStr;
+
+    use trust_dns_proto::op::Query;
+    use trust_dns_proto::rr::{Name, RData, RecordType};
+
+    use super::*;
+
+    fn request() -> Message {
+        let mut message = Message::new();
+        message.add_query(Query::query(
+            Name::from_str("www.example.com.").unwrap(),
+            RecordType::A,
+        ));
+
+        message
+    }
+
+    fn response(addrs: &[Ipv4Addr]) -> Vec<u8> {
+        let mut message = request();
+        message
+            .set_message_type(MessageType::Response)
+            .add_answers(addrs.iter().map(|addr| {
+                Record::from_rdata(
+                    Name::from_str("www.example.com.").unwrap(),
+                    60,
+                    RData::A(*addr),
+                )
+            }));
+
+        message.to_vec().unwrap()
+    }
+
+    /// the internal and external branches answer, the broken branch fails and the missing
+    /// branch is not declared
+    fn mock_branch(branch: &str, _dns_packet: &[u8]) -> Option<Result<Vec<u8>, Error>> {
+        match branch {
+            "internal" => Some(Ok(response(&[Ipv4Addr::new(10, 0, 0, 1)]))),
+            "external" => Some(Ok(response(&[
+                Ipv4Addr::new(1, 1, 1, 1),
+                Ipv4Addr::new(10, 0, 0, 1),
+            ]))),
+            "broken" => Some(Err(Error {
+                code: 1,
+                msg: "broken".to_string(),
+            })),
+            _ => None,
+        }
+    }
+
+    fn answer_addrs(answers: &[Record]) -> Vec<Ipv4Addr> {
+        answers
+            .iter()
+            .map(|record| match record.data() {
+                Some(RData::A(addr)) => *addr,
+                data => panic!("unexpected answer {data:?}"),
+            })
+            .collect()
+    }
+
+    fn merge(strategy: Strategy) -> Vec<Ipv4Addr> {
+        let branches = ["broken", "internal", "missing", "external"].map(String::from);
+        let dns_packet = request().to_vec().unwrap();
+
+        let responses = query_branches(&branches, &dns_packet, mock_branch);
+        assert_eq!(responses.len(), 2);
+
+        answer_addrs(&merge_answers(&responses, strategy))
+    }
+
+    #[test]
+    fn merge_union() {
+        assert_eq!(
+            merge(Strategy::Union),
+            [Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(1, 1, 1, 1)]
+        );
+    }
+
+    #[test]
+    fn merge_prefer_first() {
+        assert_eq!(merge(Strategy::PreferFirst), [Ipv4Addr::new(10, 0, 0, 1)]);
+    }
+}
@@ -0,0 +1,285 @@
+use std::collections::HashMap;
+
+use serde::Deserialize;
+use tracing::{error, info};
+use trust_dns_proto::op::{Message, MessageType, ResponseCode};
+use trust_dns_proto::rr::rdata::NAPTR;
+use trust_dns_proto::rr::{Name, RData, Record, RecordType};
+
+use crate::helper::{call_next_plugin, load_config};
+use crate::plugin::{Error, Plugin};
+
+wit_bindgen::generate!("rubydns");
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    #[serde(default = "default_ttl")]
+    ttl: u32,
+    /// the NAPTR records of the names, the names are usually E.164 numbers under e164.arpa
+    records: HashMap<String, Vec<NaptrRecord>>,
+}
+
+fn default_ttl() -> u32 {
+    300
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NaptrRecord {
+    order: u16,
+    preference: u16,
+    #[serde(default)]
+    flags: String,
+    #[serde(default)]
+    service: String,
+    #[serde(default)]
+    regexp: String,
+    #[serde(default = "default_replacement")]
+    replacement: String,
+}
+
+fn default_replacement() -> String {
+    ".".to_string()
+}
+
+impl NaptrRecord {
+    fn to_rdata(&self) -> Result<RData, Error> {
+        let replacement = parse_name(&self.replacement)?;
+
+        Ok(RData::NAPTR(NAPTR::new(
+            self.order,
+            self.preference,
+            self.flags.as_bytes().into(),
+            self.service.as_bytes().into(),
+            self.regexp.as_bytes().into(),
+            replacement,
+        )))
+    }
+}
+
+impl Config {
+    fn find_records(&self, name: &Name) -> Result<Option<&[NaptrRecord]>, Error> {
+        for (record_name, records) in &self.records {
+            if parse_name(record_name)? == *name {
+                return Ok(Some(records));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+#[derive(Debug)]
+struct NaptrRunner;
+
+impl Plugin for NaptrRunner {
+    fn run(dns_packet: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let config = load_config();
+        let config: Config = serde_yaml::from_str(&config).map_err(|err| {
+            error!(%err, "load naptr config failed");
+
+            Error {
+                code: 1,
+                msg: err.to_string(),
+            }
+        })?;
+
+        let request_message = Message::from_vec(&dns_packet).map_err(|err| {
+            error!(%err, "decode dns request packet failed");
+
+            Error {
+                code: 1,
+                msg: err.to_string(),
+            }
+        })?;
+
+        match naptr_response(&config, &request_message)? {
+            None => call_next(&dns_packet),
+
+            Some(response_message) => response_message.to_vec().map_err(|err| {
+                error!(%err, "encode dns response packet failed");
+
+                Error {
+                    code: 1,
+                    msg: err.to_string(),
+                }
+            }),
+        }
+    }
+
+    fn valid_config() -> Result<(), Error> {
+        let config: Config = serde_yaml::from_str(&load_config()).map_err(|err| {
+            error!(%err, "load naptr config failed");
+
+            Error {
+                code: 1,
+                msg: err.to_string(),
+            }
+        })?;
+
+        for (name, records) in &config.records {
+            parse_name(name)?;
+
+            for record in records {
+                record.to_rdata()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn version() -> String {
+        env!("CARGO_PKG_VERSION").to_string()
+    }
+}
+
+/// answer the NAPTR query of the configured names, [`None`] means the query is passed to the
+/// next plugin
+fn naptr_response(config: &Config, request_message: &Message) -> Result<Option<Message>, Error> {
+    let query = match request_message.query() {
+        Some(query) if query.query_type() == RecordType::NAPTR => query,
+        _ => return Ok(None),
+    };
+
+    let records = match config.find_records(query.name())? {
+        None => return Ok(None),
+        Some(records) => records,
+    };
+
+    let answers = records
+        .iter()
+        .map(|record| {
+            let rdata = record.to_rdata()?;
+
+            Ok(Record::from_rdata(query.name().clone(), config.ttl, rdata))
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+
+    info!(name = %query.name(), count = answers.len(), "answer naptr records");
+
+    let mut response_message = Message::new();
+    response_message
+        .set_id(request_message.id())
+        .set_message_type(MessageType::Response)
+        .set_op_code(request_message.op_code())
+        .set_authoritative(true)
+        .set_recursion_desired(request_message.recursion_desired())
+        .set_recursion_available(true)
+        .set_response_code(ResponseCode::NoError)
+        .add_queries(request_message.queries().iter().cloned())
+        .add_answers(answers);
+
+    Ok(Some(response_message))
+}
+
+fn parse_name(name: &str) -> Result<Name, Error> {
+    Name::from_ascii(name).map_err(|err| {
+        error!(%err, name, "invalid name");
+
+        Error {
+            code: 1,
+            msg: format!("invalid name {name}: {err}"),
+        }
+    })
+}
+
+fn call_next(dns_packet: &[u8]) -> Result<Vec<u8>, Error> {
+    match call_next_plugin(dns_packet) {
+        None => Err(Error {
+            code: 1,
+            msg: "no next plugin".to_string(),
+        }),
+
+        Some(result) => result,
+    }
+}
+
+export_rubydns!(NaptrRunner);
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use trust_dns_proto::op::Query;
+
+    use super::*;
+
+    const CONFIG: &str = r#"
+ttl: 60
+records:
+  4.3.2.1.5.5.5.1.e164.arpa:
+    - order: 100
+      preference: 10
+      flags: u
+      service: E2U+sip
+      regexp: "!^.*$!sip:info@example.com!"
+    - order: 102
+      preference: 20
+      flags: s
+      service: SIP+D2U
+      replacement: _sip._udp.example.com.
+"#;
+
+    fn request(name: &str, query_type: RecordType) -> Message {
+        let mut message = Message::new();
+        message
+            .set_id(9)
+            .add_query(Query::query(Name::from_str(name).unwrap(), query_type));
+
+        message
+    }
+
+    fn answer(name: &str, query_type: RecordType) -> Option<Message> {
+        let config = serde_yaml::from_str::<Config>(CONFIG).unwrap();
+
+        naptr_response(&config, &request(name, query_type)).unwrap()
+    }
+
+    #[test]
+    fn answer_configured_records() {
+        let response = answer("4.3.2.1.5.5.5.1.e164.arpa.", RecordType::NAPTR).unwrap();
+        // the records survive the wire format
+        let response = Message::from_vec(&response.to_vec().unwrap()).unwrap();
+
+        assert_eq!(response.id(), 9);
+        assert!(response.authoritative());
+        assert_eq!(response.answers().len(), 2);
+
+        let naptrs = response
+            .answers()
+            .iter()
+            .map(|record| {
+                assert_eq!(record.ttl(), 60);
+
+                match record.data() {
+                    Some(RData::NAPTR(naptr)) => naptr.clone(),
+                    data => panic!("unexpected answer {data:?}"),
+                }
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(naptrs[0].order(), 100);
+        assert_eq!(naptrs[0].preference(), 10);
+        assert_eq!(naptrs[0].flags(), b"u");
+        assert_eq!(naptrs[0].services(), b"E2U+sip");
+        assert_eq!(naptrs[0].regexp(), b"!^.*$!sip:info@example.com!");
+        assert!(naptrs[0].replacement().is_root());
+
+        assert_eq!(naptrs[1].order(), 102);
+        assert_eq!(naptrs[1].preference(), 20);
+        assert_eq!(naptrs[1].flags(), b"s");
+        assert_eq!(naptrs[1].services(), b"SIP+D2U");
+        assert!(naptrs[1].regexp().is_empty());
+        assert_eq!(
+            naptrs[1].replacement(),
+            &Name::from_str("_sip._udp.example.com.").unwrap()
+        );
+    }
+
+    #[test]
+    fn pass_other_queries() {
+        assert!(answer("4.3.2.1.5.5.5.1.e164.arpa.", RecordType::A).is_none());
+        assert!(answer("5.3.2.1.5.5.5.1.e164.arpa.", RecordType::NAPTR).is_none());
+    }
+}
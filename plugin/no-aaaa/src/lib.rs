@@ -0,0 +1,272 @@
+use serde::Deserialize;
+use tracing::{error, info};
+use trust_dns_proto::op::{Message, MessageType, ResponseCode};
+use trust_dns_proto::rr::rdata::SOA;
+use trust_dns_proto::rr::{Name, RData, Record, RecordType};
+
+use crate::helper::{call_next_plugin, load_config};
+use crate::plugin::{Error, Plugin};
+
+wit_bindgen::generate!("rubydns");
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    #[serde(default = "default_enabled")]
+    enabled: bool,
+    /// only suppress the AAAA queries under the suffixes, all AAAA queries when empty
+    #[serde(default)]
+    suffixes: Vec<String>,
+    /// the SOA added to the authority section, so the clients can cache the NODATA
+    soa: Option<Soa>,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Soa {
+    mname: String,
+    rname: String,
+    /// the SOA ttl and minimum, which is the negative cache ttl
+    #[serde(default = "default_soa_ttl")]
+    ttl: u32,
+}
+
+fn default_soa_ttl() -> u32 {
+    300
+}
+
+impl Config {
+    fn suffixes(&self) -> Result<Vec<Name>, Error> {
+        self.suffixes
+            .iter()
+            .map(|suffix| parse_name(suffix))
+            .collect()
+    }
+}
+
+fn parse_name(name: &str) -> Result<Name, Error> {
+    Name::from_ascii(name).map_err(|err| {
+        error!(%err, name, "invalid name");
+
+        Error {
+            code: 1,
+            msg: format!("invalid name {name}: {err}"),
+        }
+    })
+}
+
+#[derive(Debug)]
+struct NoAaaaRunner;
+
+impl Plugin for NoAaaaRunner {
+    fn run(dns_packet: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let config = load_config();
+        let config: Config = serde_yaml::from_str(&config).map_err(|err| {
+            error!(%err, "load no aaaa config failed");
+
+            Error {
+                code: 1,
+                msg: err.to_string(),
+            }
+        })?;
+
+        let request_message = Message::from_vec(&dns_packet).map_err(|err| {
+            error!(%err, "decode dns request packet failed");
+
+            Error {
+                code: 1,
+                msg: err.to_string(),
+            }
+        })?;
+
+        let response_message = match nodata_response(&config, &request_message)? {
+            None => return call_next(&dns_packet),
+            Some(response_message) => response_message,
+        };
+
+        response_message.to_vec().map_err(|err| {
+            error!(%err, "encode dns response packet failed");
+
+            Error {
+                code: 1,
+                msg: err.to_string(),
+            }
+        })
+    }
+
+    fn valid_config() -> Result<(), Error> {
+        let config: Config = serde_yaml::from_str(&load_config()).map_err(|err| {
+            error!(%err, "load no aaaa config failed");
+
+            Error {
+                code: 1,
+                msg: err.to_string(),
+            }
+        })?;
+
+        config.suffixes()?;
+        if let Some(soa) = &config.soa {
+            parse_name(&soa.mname)?;
+            parse_name(&soa.rname)?;
+        }
+
+        Ok(())
+    }
+
+    fn version() -> String {
+        env!("CARGO_PKG_VERSION").to_string()
+    }
+}
+
+/// the NODATA response to the AAAA query which is suppressed, [`None`] means the query is
+/// passed through
+fn nodata_response(config: &Config, request_message: &Message) -> Result<Option<Message>, Error> {
+    if !config.enabled {
+        return Ok(None);
+    }
+
+    let query = match request_message.query() {
+        Some(query) if query.query_type() == RecordType::AAAA => query,
+        _ => return Ok(None),
+    };
+
+    let suffixes = config.suffixes()?;
+    // the SOA owner, the root when no suffix is configured
+    let zone = if suffixes.is_empty() {
+        Name::root()
+    } else {
+        match suffixes
+            .into_iter()
+            .find(|suffix| suffix.zone_of(query.name()))
+        {
+            None => return Ok(None),
+            Some(suffix) => suffix,
+        }
+    };
+
+    info!(name = %query.name(), "suppress AAAA query");
+
+    let mut response_message = Message::new();
+    response_message
+        .set_id(request_message.id())
+        .set_message_type(MessageType::Response)
+        .set_op_code(request_message.op_code())
+        .set_recursion_desired(request_message.recursion_desired())
+        .set_recursion_available(true)
+        .set_response_code(ResponseCode::NoError)
+        .add_queries(request_message.queries().iter().cloned());
+    if let Some(soa) = &config.soa {
+        let rdata = SOA::new(
+            parse_name(&soa.mname)?,
+            parse_name(&soa.rname)?,
+            1,
+            3600,
+            600,
+            86400,
+            soa.ttl,
+        );
+
+        response_message.add_name_server(Record::from_rdata(zone, soa.ttl, RData::SOA(rdata)));
+    }
+
+    Ok(Some(response_message))
+}
+
+fn call_next(dns_packet: &[u8]) -> Result<Vec<u8>, Error> {
+    match call_next_plugin(dns_packet) {
+        None => Err(Error {
+            code: 1,
+            msg: "no next plugin".to_string(),
+        }),
+
+        Some(result) => result,
+    }
+}
+
+export_rubydns!(NoAaaaRunner);
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use trust_dns_proto::op::Query;
+
+    use super::*;
+
+    fn request(name: &str, query_type: RecordType) -> Message {
+        let mut message = Message::new();
+        message
+            .set_id(1234)
+            .set_recursion_desired(true)
+            .add_query(Query::query(Name::from_str(name).unwrap(), query_type));
+
+        message
+    }
+
+    fn config(config: &str) -> Config {
+        serde_yaml::from_str(config).unwrap()
+    }
+
+    #[test]
+    fn answer_nodata_to_aaaa_and_pass_through_a() {
+        let config = config("{}");
+
+        let response_message =
+            nodata_response(&config, &request("www.example.com.", RecordType::AAAA))
+                .unwrap()
+                .unwrap();
+
+        assert_eq!(response_message.id(), 1234);
+        assert_eq!(response_message.message_type(), MessageType::Response);
+        assert_eq!(response_message.response_code(), ResponseCode::NoError);
+        assert!(response_message.answers().is_empty());
+        assert!(response_message.name_servers().is_empty());
+
+        assert!(
+            nodata_response(&config, &request("www.example.com.", RecordType::A))
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn only_suppress_under_suffixes() {
+        let config = config(
+            "
+suffixes: [corp.example]
+soa: { mname: ns.corp.example, rname: admin.corp.example, ttl: 60 }
+",
+        );
+
+        let response_message =
+            nodata_response(&config, &request("www.corp.example.", RecordType::AAAA))
+                .unwrap()
+                .unwrap();
+
+        let soa = &response_message.name_servers()[0];
+        assert_eq!(soa.name(), &Name::from_str("corp.example").unwrap());
+        assert_eq!(soa.ttl(), 60);
+        assert_eq!(soa.record_type(), RecordType::SOA);
+
+        assert!(
+            nodata_response(&config, &request("www.example.com.", RecordType::AAAA))
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn pass_through_when_disabled() {
+        let config = config("enabled: false");
+
+        assert!(
+            nodata_response(&config, &request("www.example.com.", RecordType::AAAA))
+                .unwrap()
+                .is_none()
+        );
+    }
+}
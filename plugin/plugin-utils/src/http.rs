@@ -0,0 +1,189 @@
+use std::fmt::Write as _;
+use std::io;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::SocketAddr;
+
+use serde::Deserialize;
+
+use crate::net::tcp::TcpStream;
+
+/// a `http://ip:port/path` url, the plugin can't resolve domain names by itself
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Url {
+    addr: SocketAddr,
+    host: String,
+    path: String,
+}
+
+impl TryFrom<String> for Url {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let rest = value
+            .strip_prefix("http://")
+            .ok_or_else(|| format!("url {value} is not a http url"))?;
+
+        let (host, path) = match rest.find('/') {
+            None => (rest, "/"),
+            Some(index) => rest.split_at(index),
+        };
+
+        let addr = match host.parse::<SocketAddr>() {
+            Ok(addr) => addr,
+            Err(_) => format!("{host}:80")
+                .parse::<SocketAddr>()
+                .map_err(|err| format!("url {value} host is not an ip address: {err}"))?,
+        };
+
+        Ok(Self {
+            addr,
+            host: host.to_string(),
+            path: path.to_string(),
+        })
+    }
+}
+
+impl Url {
+    /// append a path segment, the segment is percent encoded
+    pub fn join(&self, segment: &str) -> Self {
+        let mut path = self.path.trim_end_matches('/').to_string();
+        path.push('/');
+        percent_encode(&mut path, segment);
+
+        Self {
+            addr: self.addr,
+            host: self.host.clone(),
+            path,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Response {
+    pub status: u16,
+    pub body: Vec<u8>,
+}
+
+/// send a `GET` request with the query params, HTTP/1.0 is used so the server closes the
+/// connection after the response and never uses chunked encoding
+pub fn get(url: &Url, params: &[(&str, &str)]) -> io::Result<Response> {
+    let request = format_get_request(url, params);
+
+    let mut tcp_stream = TcpStream::connect(url.addr)?;
+    tcp_stream.write_all(request.as_bytes())?;
+    tcp_stream.flush()?;
+
+    let mut data = vec![];
+    tcp_stream.read_to_end(&mut data)?;
+
+    parse_response(data)
+}
+
+fn format_get_request(url: &Url, params: &[(&str, &str)]) -> String {
+    let mut path = url.path.clone();
+    for (i, (key, value)) in params.iter().enumerate() {
+        path.push(if i == 0 && !url.path.contains('?') {
+            '?'
+        } else {
+            '&'
+        });
+        percent_encode(&mut path, key);
+        path.push('=');
+        percent_encode(&mut path, value);
+    }
+
+    format!(
+        "GET {path} HTTP/1.0\r\nHost: {}\r\nAccept: application/json\r\nConnection: close\r\n\r\n",
+        url.host
+    )
+}
+
+fn parse_response(mut data: Vec<u8>) -> io::Result<Response> {
+    let header_end = data
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "http response header incomplete"))?;
+
+    let header = std::str::from_utf8(&data[..header_end])
+        .map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+    let mut lines = header.split("\r\n");
+
+    let status = lines
+        .next()
+        .and_then(|status_line| status_line.split(' ').nth(1))
+        .and_then(|status| status.parse::<u16>().ok())
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "invalid http status line"))?;
+
+    let content_length = lines
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.trim().parse::<usize>().ok());
+
+    let mut body = data.split_off(header_end + 4);
+    if let Some(content_length) = content_length {
+        body.truncate(content_length);
+    }
+
+    Ok(Response { status, body })
+}
+
+fn percent_encode(buf: &mut String, value: &str) {
+    for b in value.bytes() {
+        if b.is_ascii_alphanumeric() || matches!(b, b'-' | b'.' | b'_' | b'~') {
+            buf.push(b as char);
+        } else {
+            let _ = write!(buf, "%{b:02X}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn url(url: &str) -> Url {
+        Url::try_from(url.to_string()).unwrap()
+    }
+
+    #[test]
+    fn parse_url() {
+        let url = url("http://192.0.2.1/v1");
+        assert_eq!(url.addr, "192.0.2.1:80".parse().unwrap());
+        assert_eq!(url.host, "192.0.2.1");
+        assert_eq!(url.path, "/v1");
+
+        assert_eq!(url.join("a b").path, "/v1/a%20b");
+        assert!(Url::try_from("https://192.0.2.1/".to_string()).is_err());
+        assert!(Url::try_from("http://example.com/".to_string()).is_err());
+    }
+
+    #[test]
+    fn format_request_with_params() {
+        let request = format_get_request(
+            &url("http://192.0.2.1:8080/records"),
+            &[("name", "www.example.com."), ("type", "A")],
+        );
+
+        assert_eq!(
+            request,
+            "GET /records?name=www.example.com.&type=A HTTP/1.0\r\nHost: 192.0.2.1:8080\r\n\
+             Accept: application/json\r\nConnection: close\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn parse_response_body() {
+        let response =
+            parse_response(b"HTTP/1.0 200 OK\r\nContent-Length: 2\r\n\r\n{}trailing".to_vec())
+                .unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, b"{}");
+
+        let response = parse_response(b"HTTP/1.1 404 Not Found\r\n\r\n".to_vec()).unwrap();
+        assert_eq!(response.status, 404);
+        assert!(response.body.is_empty());
+
+        assert!(parse_response(b"HTTP/1.0 200 OK\r\n".to_vec()).is_err());
+    }
+}
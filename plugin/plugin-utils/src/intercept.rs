@@ -0,0 +1,91 @@
+//! the two shapes of a plugin in the chain
+//!
+//! a plugin sees the request before the downstream plugins and the response after them, so
+//! where it acts decides what it sees:
+//!
+//! - a request interceptor acts before the next plugin, it can answer the query itself or
+//!   forward a request, which may be rewritten, the plugins before it in the chain see its
+//!   answer as the response
+//! - a response interceptor acts after the next plugin, it sees the response which all the
+//!   downstream plugins have processed, and the plugins before it see its result
+//!
+//! the wrappers call the next plugin exactly once when the query is forwarded, so a plugin
+//! doesn't hand-roll the pattern
+
+use std::fmt::{Display, Formatter};
+
+use crate::gen::helper;
+
+/// the next plugin can't answer the query
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum NextError {
+    /// the plugin is the last one in the chain
+    NoNextPlugin,
+
+    /// the next plugin returns an error, the code [`u32::MAX`] means to drop the query
+    Failed { code: u32, msg: String },
+}
+
+impl Display for NextError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NextError::NoNextPlugin => f.write_str("no next plugin"),
+            NextError::Failed { code, msg } => write!(f, "next plugin failed, code {code}: {msg}"),
+        }
+    }
+}
+
+impl std::error::Error for NextError {}
+
+impl NextError {
+    pub fn code(&self) -> u32 {
+        match self {
+            NextError::NoNextPlugin => 1,
+            NextError::Failed { code, .. } => *code,
+        }
+    }
+}
+
+/// what a request interceptor does with the query
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum RequestAction {
+    /// answer the query with the response, the next plugin isn't called
+    Answer(Vec<u8>),
+
+    /// forward the request to the next plugin, its response is returned as is
+    Forward(Vec<u8>),
+}
+
+/// act on the request before the next plugin
+pub fn intercept_request<E, F>(dns_packet: Vec<u8>, f: F) -> Result<Vec<u8>, E>
+where
+    E: From<NextError>,
+    F: FnOnce(Vec<u8>) -> Result<RequestAction, E>,
+{
+    match f(dns_packet)? {
+        RequestAction::Answer(response_packet) => Ok(response_packet),
+        RequestAction::Forward(request_packet) => Ok(call_next(&request_packet)?),
+    }
+}
+
+/// forward the request to the next plugin and act on its response, the error of the next
+/// plugin is returned without calling `f`
+pub fn intercept_response<E, F>(dns_packet: &[u8], f: F) -> Result<Vec<u8>, E>
+where
+    E: From<NextError>,
+    F: FnOnce(Vec<u8>) -> Result<Vec<u8>, E>,
+{
+    let response_packet = call_next(dns_packet)?;
+
+    f(response_packet)
+}
+
+fn call_next(dns_packet: &[u8]) -> Result<Vec<u8>, NextError> {
+    match helper::call_next_plugin(dns_packet) {
+        None => Err(NextError::NoNextPlugin),
+        Some(result) => result.map_err(|err| NextError::Failed {
+            code: err.code,
+            msg: err.msg,
+        }),
+    }
+}
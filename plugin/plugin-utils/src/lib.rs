@@ -1,4 +1,12 @@
+pub mod http;
+pub mod intercept;
 pub mod net;
+pub mod store;
+pub mod subnet;
+pub mod time;
+
+/// the error code a plugin returns to drop the query without any response
+pub const DROP_ERROR_CODE: u32 = u32::MAX;
 
 #[allow(unused_macros)]
 mod gen {
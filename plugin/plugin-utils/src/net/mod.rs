@@ -1,13 +1,38 @@
-use std::io;
-use std::io::{Error, ErrorKind};
-use std::net::{IpAddr, SocketAddr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use crate::gen::helper;
+use crate::gen::udp_helper::{Addr, Ip};
 
 pub mod tcp;
 pub mod udp;
 
-fn get_ipv4_be(addr: &SocketAddr) -> io::Result<u32> {
-    match addr.ip() {
-        IpAddr::V4(ip) => Ok(u32::from_be_bytes(ip.octets()).to_be()),
-        IpAddr::V6(_) => Err(Error::from(ErrorKind::Unsupported)),
+/// the address of the client sending the query, [`None`] when the query has no client, such
+/// as a query from the host itself
+pub fn client_addr() -> Option<SocketAddr> {
+    helper::client_addr().map(from_addr)
+}
+
+fn to_addr(addr: &SocketAddr) -> Addr {
+    let ip = match addr.ip() {
+        IpAddr::V4(ip) => Ip::V4(u32::from(ip).to_be()),
+        IpAddr::V6(ip) => {
+            let ip = u128::from(ip);
+
+            Ip::V6(((ip >> 64) as u64, ip as u64))
+        }
+    };
+
+    Addr {
+        addr: ip,
+        port: addr.port().to_be(),
     }
 }
+
+fn from_addr(addr: Addr) -> SocketAddr {
+    let ip = match addr.addr {
+        Ip::V4(ip) => IpAddr::V4(Ipv4Addr::from(u32::from_be(ip))),
+        Ip::V6((high, low)) => IpAddr::V6(Ipv6Addr::from(((high as u128) << 64) | low as u128)),
+    };
+
+    SocketAddr::new(ip, u16::from_be(addr.port))
+}
@@ -1,32 +1,86 @@
 use std::io;
 use std::io::{Error, Read, Write};
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::mem;
+use std::net::SocketAddr;
+use std::time::Duration;
 
-use super::get_ipv4_be;
+use super::{from_addr, to_addr};
 use crate::gen::tcp_helper;
-use crate::gen::tcp_helper::Addr;
 
 #[derive(Debug)]
 pub struct TcpStream {
     fd: u32,
+    read_timeout: Option<Duration>,
 }
 
 impl TcpStream {
+    fn new(fd: u32) -> Self {
+        Self {
+            fd,
+            read_timeout: None,
+        }
+    }
+
     pub fn connect(addr: SocketAddr) -> io::Result<Self> {
-        let ip = get_ipv4_be(&addr)?;
+        let fd = tcp_helper::connect(to_addr(&addr))
+            .map_err(|errno| Error::from_raw_os_error(errno as _))?;
+
+        Ok(Self::new(fd))
+    }
+
+    pub fn connect_timeout(addr: SocketAddr, timeout: Duration) -> io::Result<Self> {
+        let fd = tcp_helper::connect_timeout(to_addr(&addr), timeout.as_millis() as _)
+            .map_err(|errno| Error::from_raw_os_error(errno as _))?;
+
+        Ok(Self::new(fd))
+    }
 
-        let fd = tcp_helper::connect(Addr {
-            addr: ip,
-            port: addr.port().to_be(),
-        })
+    /// connect over TLS, the server cert is verified for the server name against the CA
+    /// configured for the plugin by the name, or the webpki roots when the CA is unset
+    ///
+    /// the timeout bounds both the connect and the handshake
+    pub fn connect_tls(
+        addr: SocketAddr,
+        server_name: &str,
+        ca: Option<&str>,
+        timeout: Option<Duration>,
+    ) -> io::Result<Self> {
+        let fd = tcp_helper::connect_tls(
+            to_addr(&addr),
+            server_name,
+            ca,
+            timeout.map(|timeout| timeout.as_millis() as _),
+        )
         .map_err(|errno| Error::from_raw_os_error(errno as _))?;
 
-        Ok(Self { fd })
+        Ok(Self::new(fd))
+    }
+
+    /// close the stream, the host keeps a TLS stream idle and reuses it for the next
+    /// [`TcpStream::connect_tls`] to the same server, so only release a stream which has no
+    /// unread data, such as after a whole response is read
+    pub fn release(self) {
+        tcp_helper::release(self.fd);
+        mem::forget(self);
+    }
+
+    /// a read which doesn't finish in the timeout fails with [`io::ErrorKind::TimedOut`],
+    /// [`None`] means the reads block forever
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.read_timeout = timeout;
     }
 
     fn inner_read(&self, buf: &mut [u8]) -> io::Result<usize> {
-        let data = tcp_helper::read(self.fd, buf.len() as _)
-            .map_err(|errno| Error::from_raw_os_error(errno as _))?;
+        let data = match self.read_timeout {
+            None => tcp_helper::read(self.fd, buf.len() as _)
+                .map_err(|errno| Error::from_raw_os_error(errno as _))?,
+
+            Some(timeout) => {
+                tcp_helper::read_timeout(self.fd, buf.len() as _, timeout.as_millis() as _)
+                    .map_err(|errno| Error::from_raw_os_error(errno as _))?
+                    .ok_or_else(|| Error::from(io::ErrorKind::TimedOut))?
+            }
+        };
         let n = data.len().min(buf.len());
         buf[..n].copy_from_slice(&data[..n]);
 
@@ -96,13 +150,8 @@ pub struct TcpListener {
 
 impl TcpListener {
     pub fn listen(addr: SocketAddr) -> io::Result<Self> {
-        let ip = get_ipv4_be(&addr)?;
-
-        let fd = tcp_helper::bind(Addr {
-            addr: ip,
-            port: addr.port().to_be(),
-        })
-        .map_err(|errno| Error::from_raw_os_error(errno as _))?;
+        let fd = tcp_helper::bind(to_addr(&addr))
+            .map_err(|errno| Error::from_raw_os_error(errno as _))?;
 
         Ok(Self { fd })
     }
@@ -111,12 +160,9 @@ impl TcpListener {
         let (fd, addr) =
             tcp_helper::accept(self.fd).map_err(|errno| Error::from_raw_os_error(errno as _))?;
 
-        let addr = SocketAddr::new(
-            IpAddr::V4(Ipv4Addr::from(u32::from_be(addr.addr))),
-            u16::from_be(addr.port),
-        );
+        let addr = from_addr(addr);
 
-        Ok((TcpStream { fd }, addr))
+        Ok((TcpStream::new(fd), addr))
     }
 }
 
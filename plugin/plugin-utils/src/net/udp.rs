@@ -1,10 +1,10 @@
 use std::io;
 use std::io::Error;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::net::SocketAddr;
+use std::time::Duration;
 
-use super::get_ipv4_be;
+use super::{from_addr, to_addr};
 use crate::gen::udp_helper;
-use crate::gen::udp_helper::Addr;
 
 #[derive(Debug)]
 pub struct UdpSocket {
@@ -13,28 +13,15 @@ pub struct UdpSocket {
 
 impl UdpSocket {
     pub fn bind(addr: SocketAddr) -> io::Result<Self> {
-        let ip = get_ipv4_be(&addr)?;
-
-        let fd = udp_helper::bind(Addr {
-            addr: ip,
-            port: addr.port().to_be(),
-        })
-        .map_err(|errno| Error::from_raw_os_error(errno as _))?;
+        let fd = udp_helper::bind(to_addr(&addr))
+            .map_err(|errno| Error::from_raw_os_error(errno as _))?;
 
         Ok(Self { fd })
     }
 
     pub fn connect(&self, addr: SocketAddr) -> io::Result<()> {
-        let ip = get_ipv4_be(&addr)?;
-
-        udp_helper::connect(
-            self.fd,
-            Addr {
-                addr: ip,
-                port: addr.port().to_be(),
-            },
-        )
-        .map_err(|errno| Error::from_raw_os_error(errno as _))
+        udp_helper::connect(self.fd, to_addr(&addr))
+            .map_err(|errno| Error::from_raw_os_error(errno as _))
     }
 
     pub fn send(&self, buf: &[u8]) -> io::Result<usize> {
@@ -48,29 +35,27 @@ impl UdpSocket {
             .map_err(|errno| Error::from_raw_os_error(errno as _))
     }
 
-    pub fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
-        let ip = get_ipv4_be(&addr)?;
+    /// [`None`] means nothing is received in the timeout
+    pub fn recv_size_timeout(
+        &self,
+        buf_size: usize,
+        timeout: Duration,
+    ) -> io::Result<Option<Vec<u8>>> {
+        udp_helper::recv_timeout(self.fd, buf_size as _, timeout.as_millis() as _)
+            .map_err(|errno| Error::from_raw_os_error(errno as _))
+    }
 
-        udp_helper::send_to(
-            self.fd,
-            buf,
-            Addr {
-                addr: ip,
-                port: addr.port().to_be(),
-            },
-        )
-        .map_err(|errno| Error::from_raw_os_error(errno as _))
-        .map(|n| n as _)
+    pub fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        udp_helper::send_to(self.fd, buf, to_addr(&addr))
+            .map_err(|errno| Error::from_raw_os_error(errno as _))
+            .map(|n| n as _)
     }
 
     pub fn recv_from(&self, buf_size: usize) -> io::Result<(Vec<u8>, SocketAddr)> {
         let (data, addr) = udp_helper::recv_from(self.fd, buf_size as _)
             .map_err(|errno| Error::from_raw_os_error(errno as _))?;
 
-        let addr = SocketAddr::new(
-            IpAddr::V4(Ipv4Addr::from(u32::from_be(addr.addr))),
-            u16::from_be(addr.port),
-        );
+        let addr = from_addr(addr);
 
         Ok((data, addr))
     }
@@ -0,0 +1,12 @@
+use std::time::Duration;
+
+use crate::gen::helper;
+
+/// add the delta to the counter in the plugin store atomically and return the new value, so
+/// the concurrent queries don't lose the updates like a `map_get` then `map_set`
+///
+/// the counter starts from 0 when it is absent or expired, the timeout is only set when it
+/// starts, its precision is seconds
+pub fn map_increment(key: &[u8], delta: i64, timeout: Option<Duration>) -> i64 {
+    helper::map_increment(key, delta, timeout.map(|timeout| timeout.as_secs()))
+}
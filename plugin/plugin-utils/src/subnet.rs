@@ -0,0 +1,130 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+/// a CIDR subnet, such as `10.0.0.0/8` or `fd00::/8`, a bare address is a single host subnet
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Subnet {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Subnet {
+    pub fn prefix(&self) -> u8 {
+        self.prefix
+    }
+
+    /// an ipv4 mapped ipv6 address is matched as ipv4
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(addr), IpAddr::V4(ip)) => {
+                mask_v4(addr, self.prefix) == mask_v4(ip, self.prefix)
+            }
+
+            (IpAddr::V6(addr), IpAddr::V6(ip)) => {
+                mask_v6(addr, self.prefix) == mask_v6(ip, self.prefix)
+            }
+
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Subnet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            None => (s, None),
+            Some((addr, prefix)) => (addr, Some(prefix)),
+        };
+
+        let addr = addr
+            .parse::<IpAddr>()
+            .map_err(|err| format!("invalid subnet address {addr}: {err}"))?;
+        let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            None => max_prefix,
+            Some(prefix) => prefix
+                .parse::<u8>()
+                .map_err(|err| format!("invalid subnet prefix {prefix}: {err}"))?,
+        };
+
+        if prefix > max_prefix {
+            return Err(format!(
+                "subnet prefix {prefix} is longer than {max_prefix}"
+            ));
+        }
+
+        Ok(Self { addr, prefix })
+    }
+}
+
+/// mask the ip to the subnet with the prefix length of its address family, an ipv4 mapped
+/// ipv6 address is masked as ipv4, a prefix longer than the address keeps the whole address
+pub fn mask(ip: IpAddr, v4_prefix: u8, v6_prefix: u8) -> IpAddr {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => mask_v4(ip, v4_prefix).into(),
+        IpAddr::V6(ip) => mask_v6(ip, v6_prefix).into(),
+    }
+}
+
+fn mask_v4(ip: Ipv4Addr, prefix: u8) -> Ipv4Addr {
+    let mask = u32::MAX
+        .checked_shl(32 - prefix.min(32) as u32)
+        .unwrap_or(0);
+
+    Ipv4Addr::from(u32::from(ip) & mask)
+}
+
+fn mask_v6(ip: Ipv6Addr, prefix: u8) -> Ipv6Addr {
+    let mask = u128::MAX
+        .checked_shl(128 - prefix.min(128) as u32)
+        .unwrap_or(0);
+
+    Ipv6Addr::from(u128::from(ip) & mask)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn mask_by_address_family() {
+        assert_eq!(mask(ip("192.0.2.130"), 24, 64), ip("192.0.2.0"));
+        assert_eq!(mask(ip("192.0.2.130"), 32, 64), ip("192.0.2.130"));
+        assert_eq!(mask(ip("192.0.2.130"), 0, 64), ip("0.0.0.0"));
+        assert_eq!(mask(ip("::ffff:192.0.2.130"), 16, 64), ip("192.0.0.0"));
+        assert_eq!(mask(ip("2001:db8:1:2:3:4:5:6"), 24, 48), ip("2001:db8:1::"));
+        assert_eq!(mask(ip("2001:db8::1"), 24, 128), ip("2001:db8::1"));
+    }
+
+    #[test]
+    fn subnet_contains() {
+        let subnet = "10.1.0.0/16".parse::<Subnet>().unwrap();
+        assert_eq!(subnet.prefix(), 16);
+        assert!(subnet.contains(ip("10.1.255.1")));
+        assert!(subnet.contains(ip("::ffff:10.1.0.1")));
+        assert!(!subnet.contains(ip("10.2.0.1")));
+        assert!(!subnet.contains(ip("2001:db8::1")));
+
+        let subnet = "fd00::/8".parse::<Subnet>().unwrap();
+        assert!(subnet.contains(ip("fd12::1")));
+        assert!(!subnet.contains(ip("fe80::1")));
+
+        let host = "192.0.2.1".parse::<Subnet>().unwrap();
+        assert_eq!(host.prefix(), 32);
+        assert!(host.contains(ip("192.0.2.1")));
+        assert!(!host.contains(ip("192.0.2.2")));
+    }
+
+    #[test]
+    fn parse_invalid_subnet() {
+        assert!("10.0.0.0/33".parse::<Subnet>().is_err());
+        assert!("10.0.0/8".parse::<Subnet>().is_err());
+        assert!("fd00::/129".parse::<Subnet>().is_err());
+    }
+}
@@ -0,0 +1,18 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::gen::helper;
+
+/// the current time of the host clock, it works without the wasi clock access
+pub fn now() -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(helper::now_millis())
+}
+
+/// the current unix time of the host clock in seconds
+pub fn now_unix_secs() -> u64 {
+    helper::now_unix_secs()
+}
+
+/// the current unix time of the host clock in milliseconds
+pub fn now_millis() -> u64 {
+    helper::now_millis()
+}
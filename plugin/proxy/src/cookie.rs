@@ -0,0 +1,335 @@
+use std::net::SocketAddr;
+
+use plugin_utils::time;
+use tracing::{error, warn};
+use trust_dns_proto::op::{Edns, Message, ResponseCode};
+use trust_dns_proto::rr::rdata::opt::{EdnsCode, EdnsOption};
+
+use crate::helper::{map_get, map_set, random_u64};
+use crate::plugin::Error;
+
+const COOKIE_OPTION_CODE: u16 = 10;
+const CLIENT_COOKIE_LEN: usize = 8;
+
+/// the client cookie of a nameserver is regenerated after it, RFC 7873 suggests changing it
+/// periodically
+const COOKIE_LIFETIME_SECS: u64 = 24 * 60 * 60;
+
+/// the cookies of a nameserver, the server cookie is empty until the nameserver returns one
+#[derive(Debug)]
+struct Cookies {
+    created: u64,
+    client: [u8; CLIENT_COOKIE_LEN],
+    server: Vec<u8>,
+}
+
+impl Cookies {
+    fn decode(data: &[u8]) -> Option<Self> {
+        if data.len() < 8 + CLIENT_COOKIE_LEN {
+            return None;
+        }
+
+        let (created, data) = data.split_at(8);
+        let (client, server) = data.split_at(CLIENT_COOKIE_LEN);
+
+        Some(Self {
+            created: u64::from_be_bytes(created.try_into().unwrap()),
+            client: client.try_into().unwrap(),
+            server: server.to_vec(),
+        })
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut data = self.created.to_be_bytes().to_vec();
+        data.extend_from_slice(&self.client);
+        data.extend_from_slice(&self.server);
+
+        data
+    }
+
+    fn save(&self, nameserver: SocketAddr, now: u64) {
+        let timeout = (self.created + COOKIE_LIFETIME_SECS).saturating_sub(now);
+
+        map_set(
+            cookie_key(nameserver).as_bytes(),
+            &self.encode(),
+            Some(timeout),
+        );
+    }
+}
+
+/// replace the COOKIE option of the request with the cookies of the nameserver, the client
+/// cookie is generated when the nameserver has none, the request without EDNS is kept as is
+pub fn add_cookie(dns_packet: &[u8], nameserver: SocketAddr) -> Result<Vec<u8>, Error> {
+    let mut message = Message::from_vec(dns_packet).map_err(|err| {
+        error!(%err, "decode dns request packet failed");
+
+        Error {
+            code: 1,
+            msg: err.to_string(),
+        }
+    })?;
+
+    let edns = match message.extensions_mut() {
+        None => return Ok(dns_packet.to_vec()),
+        Some(edns) => edns,
+    };
+
+    let now = time::now_unix_secs();
+    let cookies = match load_cookies(nameserver, now) {
+        Some(cookies) => cookies,
+        None => {
+            let cookies = Cookies {
+                created: now,
+                client: random_u64().to_be_bytes(),
+                server: vec![],
+            };
+            cookies.save(nameserver, now);
+
+            cookies
+        }
+    };
+
+    insert_cookie(edns, &cookies);
+
+    message.to_vec().map_err(|err| {
+        error!(%err, "encode dns request packet failed");
+
+        Error {
+            code: 1,
+            msg: err.to_string(),
+        }
+    })
+}
+
+/// check the client cookie echoed by the nameserver and save its server cookie, the COOKIE
+/// option is stripped because the cookies belong to the proxy, not the client.
+///
+/// a response with a mismatched client cookie may be spoofed, so it is an error
+pub fn check_cookie(response_packet: Vec<u8>, nameserver: SocketAddr) -> Result<Vec<u8>, Error> {
+    let mut message = Message::from_vec(&response_packet).map_err(|err| {
+        error!(%err, %nameserver, "decode dns response packet failed");
+
+        Error {
+            code: 1,
+            msg: err.to_string(),
+        }
+    })?;
+
+    let edns = match message.extensions_mut() {
+        None => return Ok(response_packet),
+        Some(edns) => edns,
+    };
+
+    let option_data = match edns.option(EdnsCode::Cookie) {
+        None => return Ok(response_packet),
+        Some(option) => Vec::<u8>::from(option),
+    };
+
+    let now = time::now_unix_secs();
+    let mut cookies = match load_cookies(nameserver, now) {
+        None => {
+            warn!(%nameserver, "nameserver returns cookie but no cookie is sent");
+
+            return Err(Error {
+                code: 1,
+                msg: "unexpected cookie".to_string(),
+            });
+        }
+
+        Some(cookies) => cookies,
+    };
+
+    if update_server_cookie(&mut cookies, &option_data, nameserver)? {
+        cookies.save(nameserver, now);
+    }
+
+    edns.options_mut().remove(EdnsCode::Cookie);
+
+    message.to_vec().map_err(|err| {
+        error!(%err, "encode dns response packet failed");
+
+        Error {
+            code: 1,
+            msg: err.to_string(),
+        }
+    })
+}
+
+/// send the request by `exchange` again when the nameserver answers BADCOOKIE, the retry
+/// carries the new server cookie saved from the BADCOOKIE response, RFC 7873 5.3
+pub fn retry_bad_cookie(
+    nameserver: SocketAddr,
+    mut exchange: impl FnMut() -> Result<Vec<u8>, Error>,
+) -> Result<Vec<u8>, Error> {
+    let response_packet = exchange()?;
+    if !is_bad_cookie(&response_packet) {
+        return Ok(response_packet);
+    }
+
+    warn!(%nameserver, "nameserver returns BADCOOKIE, retry with the new server cookie");
+
+    exchange()
+}
+
+fn is_bad_cookie(response_packet: &[u8]) -> bool {
+    Message::from_vec(response_packet)
+        .map(|message| message.response_code() == ResponseCode::BADCOOKIE)
+        .unwrap_or(false)
+}
+
+fn insert_cookie(edns: &mut Edns, cookies: &Cookies) {
+    let mut option_data = cookies.client.to_vec();
+    option_data.extend_from_slice(&cookies.server);
+    edns.options_mut()
+        .insert(EdnsOption::Unknown(COOKIE_OPTION_CODE, option_data));
+}
+
+/// check the client cookie of the COOKIE option returned by the nameserver and keep its
+/// server cookie, true means the server cookie is changed
+fn update_server_cookie(
+    cookies: &mut Cookies,
+    option_data: &[u8],
+    nameserver: SocketAddr,
+) -> Result<bool, Error> {
+    let (client, server) = option_data.split_at(option_data.len().min(CLIENT_COOKIE_LEN));
+    if client != cookies.client {
+        warn!(%nameserver, "nameserver returns mismatched client cookie");
+
+        return Err(Error {
+            code: 1,
+            msg: "mismatched client cookie".to_string(),
+        });
+    }
+
+    // RFC 7873 server cookie is 8 to 32 bytes
+    if !(8..=32).contains(&server.len()) || server == cookies.server {
+        return Ok(false);
+    }
+
+    cookies.server = server.to_vec();
+
+    Ok(true)
+}
+
+fn load_cookies(nameserver: SocketAddr, now: u64) -> Option<Cookies> {
+    map_get(cookie_key(nameserver).as_bytes())
+        .as_deref()
+        .and_then(Cookies::decode)
+        .filter(|cookies| now < cookies.created + COOKIE_LIFETIME_SECS)
+}
+
+fn cookie_key(nameserver: SocketAddr) -> String {
+    format!("proxy:cookie:{nameserver}")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+
+    const NAMESERVER: &str = "192.0.2.53:53";
+
+    fn cookies() -> Cookies {
+        Cookies {
+            created: 100,
+            client: *b"client01",
+            server: vec![],
+        }
+    }
+
+    fn response(response_code: ResponseCode) -> Vec<u8> {
+        let mut message = Message::new();
+        message
+            .set_id(1234)
+            .set_response_code(response_code)
+            .set_edns(Edns::new());
+
+        message.to_vec().unwrap()
+    }
+
+    #[test]
+    fn reject_mismatched_client_cookie() {
+        let mut cookies = cookies();
+
+        let result = update_server_cookie(
+            &mut cookies,
+            b"client02server-cookie",
+            NAMESERVER.parse().unwrap(),
+        );
+
+        assert_eq!(result.err().unwrap().msg, "mismatched client cookie");
+        assert!(cookies.server.is_empty());
+    }
+
+    #[test]
+    fn persist_server_cookie() {
+        let mut cookies = cookies();
+
+        assert!(update_server_cookie(
+            &mut cookies,
+            b"client01server01",
+            NAMESERVER.parse().unwrap()
+        )
+        .unwrap());
+        // the same server cookie needn't be saved again
+        assert!(!update_server_cookie(
+            &mut cookies,
+            b"client01server01",
+            NAMESERVER.parse().unwrap()
+        )
+        .unwrap());
+
+        // the saved cookies are sent with the next request
+        let cookies = Cookies::decode(&cookies.encode()).unwrap();
+        assert_eq!(cookies.created, 100);
+        assert_eq!(cookies.server, b"server01");
+
+        let mut edns = Edns::new();
+        insert_cookie(&mut edns, &cookies);
+        assert_eq!(
+            Vec::<u8>::from(edns.option(EdnsCode::Cookie).unwrap()),
+            b"client01server01"
+        );
+    }
+
+    #[test]
+    fn ignore_invalid_server_cookie() {
+        let mut cookies = cookies();
+
+        assert!(
+            !update_server_cookie(&mut cookies, b"client01short", NAMESERVER.parse().unwrap())
+                .unwrap()
+        );
+        assert!(cookies.server.is_empty());
+    }
+
+    #[test]
+    fn retry_once_on_bad_cookie() {
+        let exchanges = Cell::new(0);
+
+        let response_packet = retry_bad_cookie(NAMESERVER.parse().unwrap(), || {
+            exchanges.set(exchanges.get() + 1);
+
+            match exchanges.get() {
+                1 => Ok(response(ResponseCode::BADCOOKIE)),
+                _ => Ok(response(ResponseCode::NoError)),
+            }
+        })
+        .unwrap();
+
+        assert_eq!(exchanges.get(), 2);
+        assert_eq!(
+            Message::from_vec(&response_packet).unwrap().response_code(),
+            ResponseCode::NoError
+        );
+
+        // the second BADCOOKIE is returned as it is
+        let response_packet = retry_bad_cookie(NAMESERVER.parse().unwrap(), || {
+            Ok(response(ResponseCode::BADCOOKIE))
+        })
+        .unwrap();
+        assert!(is_bad_cookie(&response_packet));
+    }
+}
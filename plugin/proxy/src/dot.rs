@@ -0,0 +1,44 @@
+use plugin_utils::net::tcp::TcpStream;
+use tracing::error;
+
+use crate::nameserver::Nameserver;
+use crate::plugin::Error;
+use crate::tcp;
+
+/// send the query over TLS, the TLS is done by the host, the plugin only sees a connected
+/// stream, the connect, the handshake and the reads give up at the deadline
+///
+/// the stream is released after the response, so the host reuses it for the next queries to
+/// the nameserver
+pub fn handle_dns(
+    dns_packet: &[u8],
+    nameserver: &Nameserver,
+    deadline: Option<u64>,
+) -> Result<Vec<u8>, Error> {
+    let addr = nameserver.addr;
+    let server_name = nameserver
+        .server_name
+        .clone()
+        .unwrap_or_else(|| addr.ip().to_string());
+
+    let mut tcp_stream = TcpStream::connect_tls(
+        addr,
+        &server_name,
+        nameserver.ca.as_deref(),
+        tcp::remaining(deadline),
+    )
+    .map_err(|err| {
+        error!(%err, nameserver = %addr, server_name, "connect nameserver over tls failed");
+
+        Error {
+            code: err.raw_os_error().unwrap_or(1) as _,
+            msg: err.to_string(),
+        }
+    })?;
+
+    let response = tcp::exchange(&mut tcp_stream, dns_packet, addr, deadline)?;
+    // the whole response is read, the next query reuses the stream without a handshake
+    tcp_stream.release();
+
+    Ok(response)
+}
@@ -0,0 +1,167 @@
+use tracing::{error, info};
+use trust_dns_proto::op::Message;
+
+use crate::plugin::Error;
+
+/// remove the EDNS options which are not allowed from the request, the packet is returned as is
+/// when nothing is removed
+pub fn filter_options(dns_packet: Vec<u8>, allowed_options: &[u16]) -> Result<Vec<u8>, Error> {
+    let mut message = Message::from_vec(&dns_packet).map_err(|err| {
+        error!(%err, "decode dns request packet failed");
+
+        Error {
+            code: 1,
+            msg: err.to_string(),
+        }
+    })?;
+
+    let options = match message.extensions_mut() {
+        None => return Ok(dns_packet),
+        Some(edns) => edns.options_mut().as_mut(),
+    };
+
+    let count = options.len();
+    options.retain(|code, _| allowed_options.contains(&u16::from(*code)));
+    if options.len() == count {
+        return Ok(dns_packet);
+    }
+
+    info!(
+        removed = count - options.len(),
+        "strip not allowed edns options"
+    );
+
+    message.to_vec().map_err(|err| {
+        error!(%err, "encode dns request packet failed");
+
+        Error {
+            code: 1,
+            msg: err.to_string(),
+        }
+    })
+}
+
+/// rewrite the EDNS payload size of the request, 0 removes the OPT record, the request
+/// without EDNS is kept as is
+pub fn set_payload_size(dns_packet: &[u8], payload_size: u16) -> Result<Vec<u8>, Error> {
+    let mut message = Message::from_vec(dns_packet).map_err(|err| {
+        error!(%err, "decode dns request packet failed");
+
+        Error {
+            code: 1,
+            msg: err.to_string(),
+        }
+    })?;
+
+    match message.extensions_mut() {
+        None => return Ok(dns_packet.to_vec()),
+        Some(_) if payload_size == 0 => *message.extensions_mut() = None,
+        Some(edns) => {
+            edns.set_max_payload(payload_size);
+        }
+    }
+
+    message.to_vec().map_err(|err| {
+        error!(%err, "encode dns request packet failed");
+
+        Error {
+            code: 1,
+            msg: err.to_string(),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use trust_dns_proto::op::{Edns, Query};
+    use trust_dns_proto::rr::rdata::opt::{EdnsCode, EdnsOption};
+    use trust_dns_proto::rr::{Name, RecordType};
+
+    use super::*;
+
+    const COOKIE_OPTION_CODE: u16 = 10;
+    const PADDING_OPTION_CODE: u16 = 12;
+
+    fn request(options: &[(u16, &[u8])]) -> Vec<u8> {
+        let mut message = Message::new();
+        message.add_query(Query::query(
+            Name::from_str("www.example.com.").unwrap(),
+            RecordType::A,
+        ));
+
+        let mut edns = Edns::new();
+        for (code, data) in options {
+            edns.options_mut()
+                .insert(EdnsOption::Unknown(*code, data.to_vec()));
+        }
+        message.set_edns(edns);
+
+        message.to_vec().unwrap()
+    }
+
+    #[test]
+    fn strip_not_allowed_options() {
+        let dns_packet = request(&[
+            (COOKIE_OPTION_CODE, &[1; 8]),
+            (PADDING_OPTION_CODE, &[0; 4]),
+        ]);
+
+        let dns_packet = filter_options(dns_packet, &[COOKIE_OPTION_CODE]).unwrap();
+
+        let message = Message::from_vec(&dns_packet).unwrap();
+        let edns = message.extensions().as_ref().unwrap();
+        assert_eq!(
+            edns.option(EdnsCode::Cookie),
+            Some(&EdnsOption::Unknown(COOKIE_OPTION_CODE, vec![1; 8]))
+        );
+        assert_eq!(edns.option(EdnsCode::Padding), None);
+        assert_eq!(
+            message.queries(),
+            Message::from_vec(&request(&[])).unwrap().queries()
+        );
+    }
+
+    #[test]
+    fn keep_packet_when_all_allowed() {
+        let dns_packet = request(&[(COOKIE_OPTION_CODE, &[1; 8])]);
+
+        assert_eq!(
+            filter_options(dns_packet.clone(), &[COOKIE_OPTION_CODE]).unwrap(),
+            dns_packet
+        );
+    }
+    #[test]
+    fn strip_edns_for_zero_payload_size() {
+        let dns_packet = set_payload_size(&request(&[(COOKIE_OPTION_CODE, &[1; 8])]), 0).unwrap();
+
+        let message = Message::from_vec(&dns_packet).unwrap();
+        assert!(message.extensions().is_none());
+        assert!(message.additionals().is_empty());
+    }
+
+    #[test]
+    fn rewrite_payload_size() {
+        let dns_packet =
+            set_payload_size(&request(&[(COOKIE_OPTION_CODE, &[1; 8])]), 1232).unwrap();
+
+        let message = Message::from_vec(&dns_packet).unwrap();
+        let edns = message.extensions().as_ref().unwrap();
+        assert_eq!(edns.max_payload(), 1232);
+        assert!(edns.option(EdnsCode::Cookie).is_some());
+    }
+
+    #[test]
+    fn keep_request_without_edns() {
+        let mut message = Message::new();
+        message.add_query(Query::query(
+            Name::from_str("www.example.com.").unwrap(),
+            RecordType::A,
+        ));
+        let dns_packet = message.to_vec().unwrap();
+
+        assert_eq!(set_payload_size(&dns_packet, 1232).unwrap(), dns_packet);
+        assert_eq!(set_payload_size(&dns_packet, 0).unwrap(), dns_packet);
+    }
+}
@@ -1,17 +1,110 @@
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::borrow::Cow;
+use std::net::SocketAddr;
 
-use plugin_utils::net::udp::UdpSocket;
+use plugin_utils::time;
 use serde::Deserialize;
-use tracing::error;
+use tracing::{error, warn};
+use trust_dns_proto::op::{Message, ResponseCode};
+use trust_dns_proto::rr::RecordType;
 
-use crate::helper::load_config;
+use crate::helper::{load_config, random_u64, sleep};
+use crate::nameserver::{Nameserver, Protocol};
 use crate::plugin::{Error, Plugin};
+use crate::source_port::SourcePortRange;
+use crate::strategy::Strategy;
+
+mod cookie;
+mod dot;
+mod edns;
+mod load_shed;
+mod nameserver;
+mod parallel;
+mod retry;
+mod source_port;
+mod strategy;
+mod tcp;
 
 wit_bindgen::generate!("rubydns");
 
 #[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
 struct Config {
-    nameservers: Vec<SocketAddr>,
+    nameservers: Vec<Nameserver>,
+    #[serde(default)]
+    strategy: Strategy,
+    /// EDNS option codes which can be forwarded, other options are stripped from the request,
+    /// all options are forwarded when unset
+    allowed_edns_options: Option<Vec<u16>>,
+    /// a referral response to a recursive query means the nameserver is lame, try the next one
+    #[serde(default)]
+    treat_referral_as_failure: bool,
+    /// extra rounds over all the nameservers after they all failed
+    #[serde(default)]
+    retries: u32,
+    /// the delay before the first retry round, it is doubled every round
+    #[serde(default = "default_retry_delay_ms")]
+    retry_delay_ms: u64,
+    /// randomize the retry delays by up to this percentage, so instances which share the
+    /// nameservers don't retry in lockstep
+    #[serde(default)]
+    retry_jitter: u8,
+    /// pick the source port of every query from the range, instead of letting the OS pick it
+    source_port_range: Option<SourcePortRange>,
+    /// how long to wait for the response of a nameserver before trying the next one, it
+    /// also bounds the TCP fallback and the DoT exchange, wait forever when unset
+    timeout_ms: Option<u64>,
+    /// how long the parallel strategy waits for a successful response in a round
+    #[serde(default = "default_parallel_timeout_ms")]
+    parallel_timeout_ms: u64,
+    /// shed the queries when the ratio of the slow or failed upstream queries exceeds it,
+    /// disabled when unset
+    load_shed_threshold: Option<f64>,
+    /// the fraction of the queries shed when all the upstream queries are slow or failed,
+    /// less are shed under less pressure
+    #[serde(default = "default_shed_fraction")]
+    shed_fraction: f64,
+    /// the upstream queries slower than it count as pressure
+    #[serde(default = "default_load_shed_slow_ms")]
+    load_shed_slow_ms: u64,
+    /// retry the query over TCP when the UDP response is truncated
+    #[serde(default = "default_tcp_fallback")]
+    tcp_fallback: bool,
+    /// send DNS cookies to the nameservers and drop the responses with mismatched cookies
+    #[serde(default)]
+    upstream_cookies: bool,
+}
+
+fn default_retry_delay_ms() -> u64 {
+    100
+}
+
+fn default_parallel_timeout_ms() -> u64 {
+    2000
+}
+
+fn default_tcp_fallback() -> bool {
+    true
+}
+
+fn default_shed_fraction() -> f64 {
+    0.5
+}
+
+fn default_load_shed_slow_ms() -> u64 {
+    1000
+}
+
+/// the unknown fields are rejected, the error tells which field is wrong, so a typo isn't
+/// ignored silently
+fn parse_config(config: &str) -> Result<Config, Error> {
+    serde_yaml::from_str(config).map_err(|err| {
+        error!(%err, "load proxy config failed");
+
+        Error {
+            code: 1,
+            msg: err.to_string(),
+        }
+    })
 }
 
 #[derive(Debug)]
@@ -19,54 +112,236 @@ struct ProxyRunner;
 
 impl Plugin for ProxyRunner {
     fn run(dns_packet: Vec<u8>) -> Result<Vec<u8>, Error> {
-        let config = load_config();
-        let config: Config = serde_yaml::from_str(&config).map_err(|err| {
-            error!(%err, "load proxy config failed");
+        let config = parse_config(&load_config())?;
 
-            Error {
-                code: 1,
-                msg: err.to_string(),
-            }
-        })?;
+        let load_shed_threshold = match config.load_shed_threshold {
+            None => return forward(dns_packet, &config),
+            Some(load_shed_threshold) => load_shed_threshold,
+        };
 
-        for nameserver in config.nameservers {
-            match handle_dns(&dns_packet, nameserver) {
-                Err(_) => continue,
-                Ok(action) => return Ok(action),
-            }
+        if load_shed::should_shed(load_shed_threshold, config.shed_fraction)? {
+            return load_shed::servfail_response(&dns_packet);
         }
 
-        Err(Error {
-            code: 1,
-            msg: "all nameserver failed".to_string(),
-        })
+        let start = time::now_millis();
+        let result = forward(dns_packet, &config);
+        let pressured = load_shed::is_pressured(
+            result.is_err(),
+            time::now_millis().saturating_sub(start),
+            config.load_shed_slow_ms,
+        );
+        load_shed::record(pressured)?;
+
+        result
     }
 
     fn valid_config() -> Result<(), Error> {
-        serde_yaml::from_str::<Config>(&load_config()).map_err(|err| {
-            error!(%err, "load proxy config failed");
+        let config = parse_config(&load_config())?;
 
-            Error {
+        if config.nameservers.is_empty() {
+            error!("no proxy nameserver");
+
+            return Err(Error {
                 code: 1,
-                msg: err.to_string(),
+                msg: "no nameserver".to_string(),
+            });
+        }
+
+        for nameserver in &config.nameservers {
+            nameserver.validate(config.strategy)?;
+        }
+
+        if config.retry_jitter > 100 {
+            error!(
+                retry_jitter = config.retry_jitter,
+                "invalid proxy retry jitter"
+            );
+
+            return Err(Error {
+                code: 1,
+                msg: "retry_jitter must not be greater than 100".to_string(),
+            });
+        }
+
+        if let Some(source_port_range) = config.source_port_range {
+            if source_port_range.start == 0 || source_port_range.start > source_port_range.end {
+                error!(?source_port_range, "invalid proxy source port range");
+
+                return Err(Error {
+                    code: 1,
+                    msg: "source_port_range must be a non-empty range of non-zero ports"
+                        .to_string(),
+                });
             }
-        })?;
+        }
+
+        let ratios = [config.load_shed_threshold, Some(config.shed_fraction)];
+        if ratios
+            .into_iter()
+            .flatten()
+            .any(|ratio| !(0.0..=1.0).contains(&ratio))
+        {
+            error!(
+                load_shed_threshold = config.load_shed_threshold,
+                shed_fraction = config.shed_fraction,
+                "invalid proxy load shed config"
+            );
+
+            return Err(Error {
+                code: 1,
+                msg: "load_shed_threshold and shed_fraction must be in [0, 1]".to_string(),
+            });
+        }
 
         Ok(())
     }
+
+    fn version() -> String {
+        env!("CARGO_PKG_VERSION").to_string()
+    }
 }
 
-fn handle_dns(dns_packet: &[u8], nameserver: SocketAddr) -> Result<Vec<u8>, Error> {
-    let udp_socket = UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0))
-        .map_err(|err| {
-            error!(%err, "bind udp socket failed");
+/// forward the request to the nameservers by the strategy, retry when they all fail
+fn forward(dns_packet: Vec<u8>, config: &Config) -> Result<Vec<u8>, Error> {
+    let dns_packet = match &config.allowed_edns_options {
+        None => dns_packet,
+        Some(allowed_options) => edns::filter_options(dns_packet, allowed_options)?,
+    };
+
+    let check_referral = if config.treat_referral_as_failure {
+        let request_message = Message::from_vec(&dns_packet).map_err(|err| {
+            error!(%err, "decode dns request packet failed");
 
             Error {
-                code: err.raw_os_error().unwrap_or(1) as _,
+                code: 1,
                 msg: err.to_string(),
             }
         })?;
 
+        request_message.recursion_desired()
+    } else {
+        false
+    };
+
+    let first_index = config
+        .strategy
+        .first_index(&config.nameservers, strategy::next_rotation);
+
+    for round in 0..=config.retries {
+        if round > 0 {
+            let delay = retry::delay(
+                config.retry_delay_ms,
+                round,
+                config.retry_jitter,
+                random_u64(),
+            );
+
+            warn!(round, delay, "all nameservers failed, retry later");
+
+            sleep(delay);
+        }
+
+        if config.strategy == Strategy::Parallel {
+            if let Some(response) = parallel::handle_dns(&dns_packet, config, check_referral)? {
+                return Ok(response);
+            }
+
+            continue;
+        }
+
+        if let Some(response) = try_nameservers(
+            &config.nameservers,
+            first_index,
+            check_referral,
+            |nameserver| exchange(&dns_packet, nameserver, config),
+        ) {
+            return Ok(response);
+        }
+    }
+
+    Err(Error {
+        code: 1,
+        msg: "all nameserver failed".to_string(),
+    })
+}
+
+/// try the nameservers in turn from the first index, until one returns a usable response
+fn try_nameservers(
+    nameservers: &[Nameserver],
+    first_index: usize,
+    check_referral: bool,
+    mut exchange: impl FnMut(&Nameserver) -> Result<Vec<u8>, Error>,
+) -> Option<Vec<u8>> {
+    for nameserver in nameservers
+        .iter()
+        .cycle()
+        .skip(first_index)
+        .take(nameservers.len())
+    {
+        let response = match exchange(nameserver) {
+            Err(_) => continue,
+            Ok(response) => response,
+        };
+
+        if check_referral && is_referral(&response) {
+            warn!(nameserver = %nameserver.addr, "nameserver returns referral for recursive query");
+
+            continue;
+        }
+
+        return Some(response);
+    }
+
+    None
+}
+
+/// send the request to the nameserver with its own EDNS payload size and cookie
+fn exchange(dns_packet: &[u8], nameserver: &Nameserver, config: &Config) -> Result<Vec<u8>, Error> {
+    let request_packet = match nameserver.edns_payload_size {
+        None => Cow::Borrowed(dns_packet),
+        Some(payload_size) => Cow::Owned(edns::set_payload_size(dns_packet, payload_size)?),
+    };
+
+    if !config.upstream_cookies {
+        return send(&request_packet, nameserver, config);
+    }
+
+    // the cookies are loaded again by the retry, so it carries the new server cookie
+    cookie::retry_bad_cookie(nameserver.addr, || {
+        let request_packet = cookie::add_cookie(&request_packet, nameserver.addr)?;
+        let response = send(&request_packet, nameserver, config)?;
+
+        cookie::check_cookie(response, nameserver.addr)
+    })
+}
+
+fn send(dns_packet: &[u8], nameserver: &Nameserver, config: &Config) -> Result<Vec<u8>, Error> {
+    let deadline = config
+        .timeout_ms
+        .map(|timeout_ms| time::now_millis() + timeout_ms);
+
+    match nameserver.protocol {
+        Protocol::Udp => handle_dns(dns_packet, nameserver.addr, config, deadline),
+        Protocol::Dot => dot::handle_dns(dns_packet, nameserver, deadline),
+    }
+}
+
+fn handle_dns(
+    dns_packet: &[u8],
+    nameserver: SocketAddr,
+    config: &Config,
+    deadline: Option<u64>,
+) -> Result<Vec<u8>, Error> {
+    // never reuse the socket of another query, a fixed source port makes the spoofing easier
+    let udp_socket = source_port::bind(config.source_port_range).map_err(|err| {
+        error!(%err, "bind udp socket failed");
+
+        Error {
+            code: err.raw_os_error().unwrap_or(1) as _,
+            msg: err.to_string(),
+        }
+    })?;
+
     udp_socket.connect(nameserver).map_err(|err| {
         error!(%err, %nameserver, "connect nameserver failed");
 
@@ -85,7 +360,12 @@ fn handle_dns(dns_packet: &[u8], nameserver: SocketAddr) -> Result<Vec<u8>, Erro
         }
     })?;
 
-    let data = udp_socket.recv_size(4096).map_err(|err| {
+    let result = match tcp::remaining(deadline) {
+        None => udp_socket.recv_size(4096).map(Some),
+        Some(timeout) => udp_socket.recv_size_timeout(4096, timeout),
+    };
+
+    let data = result.map_err(|err| {
         error!(%err, %nameserver, "recv dns packet failed");
 
         Error {
@@ -94,7 +374,184 @@ fn handle_dns(dns_packet: &[u8], nameserver: SocketAddr) -> Result<Vec<u8>, Erro
         }
     })?;
 
+    let data = data.ok_or_else(|| {
+        warn!(
+            %nameserver,
+            timeout_ms = config.timeout_ms,
+            "nameserver doesn't respond in timeout"
+        );
+
+        Error {
+            code: 1,
+            msg: "recv dns packet timeout".to_string(),
+        }
+    })?;
+
+    if config.tcp_fallback && tcp::is_truncated(&data) {
+        return tcp::handle_dns(dns_packet, nameserver, deadline);
+    }
+
     Ok(data)
 }
 
+/// a referral has no answer but delegates the name with NS records in the authority section
+fn is_referral(response_packet: &[u8]) -> bool {
+    let response_message = match Message::from_vec(response_packet) {
+        Err(err) => {
+            error!(%err, "decode dns response packet failed");
+
+            return false;
+        }
+
+        Ok(response_message) => response_message,
+    };
+
+    response_message.response_code() == ResponseCode::NoError
+        && response_message.answers().is_empty()
+        && response_message
+            .name_servers()
+            .iter()
+            .any(|record| record.record_type() == RecordType::NS)
+        && !response_message
+            .name_servers()
+            .iter()
+            .any(|record| record.record_type() == RecordType::SOA)
+}
+
 export_rubydns!(ProxyRunner);
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::str::FromStr;
+
+    use trust_dns_proto::op::{MessageType, Query};
+    use trust_dns_proto::rr::rdata::SOA;
+    use trust_dns_proto::rr::{Name, RData, Record};
+
+    use super::*;
+
+    fn nameserver(addr: &str) -> Nameserver {
+        Nameserver {
+            addr: addr.parse().unwrap(),
+            edns_payload_size: None,
+            weight: 1,
+            protocol: Protocol::Udp,
+            server_name: None,
+            ca: None,
+        }
+    }
+
+    fn name(name: &str) -> Name {
+        Name::from_str(name).unwrap()
+    }
+
+    fn response(answers: &[Record], authorities: &[Record]) -> Vec<u8> {
+        let mut message = Message::new();
+        message
+            .set_message_type(MessageType::Response)
+            .set_recursion_desired(true)
+            .add_query(Query::query(name("www.example.com."), RecordType::A))
+            .add_answers(answers.iter().cloned())
+            .add_name_servers(authorities.iter().cloned());
+
+        message.to_vec().unwrap()
+    }
+
+    fn answer() -> Record {
+        Record::from_rdata(
+            name("www.example.com."),
+            60,
+            RData::A(Ipv4Addr::new(192, 0, 2, 1)),
+        )
+    }
+
+    fn delegation() -> Record {
+        Record::from_rdata(
+            name("example.com."),
+            3600,
+            RData::NS(name("ns.example.com.")),
+        )
+    }
+
+    fn soa() -> Record {
+        Record::from_rdata(
+            name("example.com."),
+            3600,
+            RData::SOA(SOA::new(
+                name("ns.example.com."),
+                name("admin.example.com."),
+                1,
+                3600,
+                600,
+                86400,
+                60,
+            )),
+        )
+    }
+
+    #[test]
+    fn detect_referral() {
+        assert!(is_referral(&response(&[], &[delegation()])));
+        assert!(!is_referral(&response(&[answer()], &[delegation()])));
+        // a NODATA response carries the SOA of the zone
+        assert!(!is_referral(&response(&[], &[delegation(), soa()])));
+        assert!(!is_referral(&response(&[], &[])));
+    }
+
+    #[test]
+    fn fall_through_on_referral() {
+        let nameservers = [nameserver("192.0.2.53:53"), nameserver("198.51.100.53:53")];
+        let mut queried = vec![];
+
+        let result = try_nameservers(&nameservers, 0, true, |nameserver| {
+            queried.push(nameserver.addr);
+
+            if nameserver.addr == nameservers[0].addr {
+                Ok(response(&[], &[delegation()]))
+            } else {
+                Ok(response(&[answer()], &[]))
+            }
+        });
+
+        assert_eq!(result, Some(response(&[answer()], &[])));
+        assert_eq!(queried, [nameservers[0].addr, nameservers[1].addr]);
+    }
+
+    #[test]
+    fn accept_referral_when_not_checked() {
+        let nameservers = [nameserver("192.0.2.53:53"), nameserver("198.51.100.53:53")];
+
+        let result = try_nameservers(&nameservers, 0, false, |_| {
+            Ok(response(&[], &[delegation()]))
+        });
+
+        assert_eq!(result, Some(response(&[], &[delegation()])));
+    }
+
+    #[test]
+    fn fail_when_all_nameservers_return_referral() {
+        let nameservers = [nameserver("192.0.2.53:53"), nameserver("198.51.100.53:53")];
+
+        let result = try_nameservers(&nameservers, 1, true, |_| {
+            Ok(response(&[], &[delegation()]))
+        });
+
+        assert_eq!(result, None);
+    }
+    #[test]
+    fn parse_config_with_defaults() {
+        let config = parse_config("nameservers: [192.0.2.53:53]").unwrap();
+
+        assert_eq!(config.nameservers[0].addr, "192.0.2.53:53".parse().unwrap());
+        assert_eq!(config.strategy, Strategy::Ordered);
+        assert!(config.tcp_fallback);
+    }
+
+    #[test]
+    fn reject_unknown_config_field() {
+        let err = parse_config("nameservers: [192.0.2.53:53]\nretry: 3").unwrap_err();
+
+        assert!(err.msg.contains("unknown field `retry`"), "{}", err.msg);
+    }
+}
@@ -0,0 +1,229 @@
+use plugin_utils::time;
+use tracing::{error, warn};
+use trust_dns_proto::op::{Message, MessageType, ResponseCode};
+
+use crate::helper::{map_cas, map_get, random_u64};
+use crate::plugin::Error;
+
+const LOAD_KEY: &[u8] = b"proxy:load-shed";
+
+/// the upstream queries are counted in windows of it
+const WINDOW_SECS: u64 = 10;
+
+/// don't shed before the window has enough upstream queries to tell the pressure
+const MIN_QUERIES: u64 = 20;
+
+/// the upstream queries of the current window, the pressured ones are slow or failed
+#[derive(Debug, Copy, Clone)]
+struct Load {
+    window_start: u64,
+    queries: u64,
+    pressured: u64,
+}
+
+impl Load {
+    fn decode(data: &[u8]) -> Option<Self> {
+        if data.len() != 24 {
+            return None;
+        }
+
+        let read = |index: usize| u64::from_be_bytes(data[index * 8..][..8].try_into().unwrap());
+
+        Some(Self {
+            window_start: read(0),
+            queries: read(1),
+            pressured: read(2),
+        })
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        [self.window_start, self.queries, self.pressured]
+            .iter()
+            .flat_map(|value| value.to_be_bytes())
+            .collect()
+    }
+
+    fn in_window(&self, now: u64) -> bool {
+        now < self.window_start + WINDOW_SECS
+    }
+
+    /// count an upstream query, a new window is started when the load is over
+    fn count(load: Option<Self>, now: u64, pressured: bool) -> Self {
+        let mut load = load.filter(|load| load.in_window(now)).unwrap_or(Self {
+            window_start: now,
+            queries: 0,
+            pressured: 0,
+        });
+
+        load.queries += 1;
+        if pressured {
+            load.pressured += 1;
+        }
+
+        load
+    }
+
+    /// the probability grows with the pressure above the threshold, it reaches the shed
+    /// fraction when all the upstream queries are pressured
+    fn shed_probability(&self, now: u64, threshold: f64, shed_fraction: f64) -> f64 {
+        if !self.in_window(now) || self.queries < MIN_QUERIES {
+            return 0.0;
+        }
+
+        let pressure = self.pressured as f64 / self.queries as f64;
+        if pressure <= threshold {
+            return 0.0;
+        }
+
+        if threshold < 1.0 {
+            shed_fraction * (pressure - threshold) / (1.0 - threshold)
+        } else {
+            shed_fraction
+        }
+    }
+}
+
+/// the upstream query is pressured when it fails or is slower than the slow threshold
+pub fn is_pressured(failed: bool, elapsed_ms: u64, slow_ms: u64) -> bool {
+    failed || elapsed_ms > slow_ms
+}
+
+fn is_shed(probability: f64, random: u64) -> bool {
+    (random as f64 / u64::MAX as f64) < probability
+}
+
+/// shed the query with the probability of the current load
+pub fn should_shed(threshold: f64, shed_fraction: f64) -> Result<bool, Error> {
+    let now = time::now_unix_secs();
+    let probability = match map_get(LOAD_KEY).as_deref().and_then(Load::decode) {
+        None => return Ok(false),
+        Some(load) => load.shed_probability(now, threshold, shed_fraction),
+    };
+    if probability <= 0.0 {
+        return Ok(false);
+    }
+
+    let shed = is_shed(probability, random_u64());
+    if shed {
+        warn!(probability, "upstreams are under pressure, shed query");
+    }
+
+    Ok(shed)
+}
+
+/// count an upstream query in the current window
+pub fn record(pressured: bool) -> Result<(), Error> {
+    let now = time::now_unix_secs();
+
+    loop {
+        let current = map_get(LOAD_KEY);
+        let load = Load::count(current.as_deref().and_then(Load::decode), now, pressured);
+
+        // another instance updates the load at the same time, retry
+        if map_cas(
+            LOAD_KEY,
+            current.as_deref(),
+            &load.encode(),
+            Some(WINDOW_SECS),
+        ) {
+            return Ok(());
+        }
+    }
+}
+
+pub fn servfail_response(dns_packet: &[u8]) -> Result<Vec<u8>, Error> {
+    let request_message = Message::from_vec(dns_packet).map_err(|err| {
+        error!(%err, "decode dns request packet failed");
+
+        Error {
+            code: 1,
+            msg: err.to_string(),
+        }
+    })?;
+
+    let mut response_message = Message::new();
+    response_message
+        .set_id(request_message.id())
+        .set_message_type(MessageType::Response)
+        .set_op_code(request_message.op_code())
+        .set_recursion_desired(request_message.recursion_desired())
+        .set_recursion_available(true)
+        .set_response_code(ResponseCode::ServFail)
+        .add_queries(request_message.queries().iter().cloned());
+
+    response_message.to_vec().map_err(|err| {
+        error!(%err, "encode dns response packet failed");
+
+        Error {
+            code: 1,
+            msg: err.to_string(),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// the load of the upstream queries with the latencies in ms, the failed ones are
+    /// `None`
+    fn load(latencies: impl IntoIterator<Item = Option<u64>>, now: u64) -> Option<Load> {
+        latencies.into_iter().fold(None, |load, latency| {
+            let pressured = is_pressured(latency.is_none(), latency.unwrap_or(0), 200);
+
+            Some(Load::count(load, now, pressured))
+        })
+    }
+
+    /// the fraction of the queries shed with randoms evenly spread in the u64 range
+    fn shed_fraction(probability: f64) -> f64 {
+        let shed = (0..1000)
+            .filter(|i| is_shed(probability, u64::MAX / 1000 * i))
+            .count();
+
+        shed as f64 / 1000.0
+    }
+
+    #[test]
+    fn shed_part_of_queries_under_high_latency() {
+        // 70 of the 100 upstream queries are slow or fail
+        let latencies = (0..100).map(|i| match i % 10 {
+            0..=5 => Some(500),
+            6 => None,
+            _ => Some(20),
+        });
+        let load = load(latencies, 100).unwrap();
+
+        let probability = load.shed_probability(105, 0.5, 0.5);
+        assert!((probability - 0.2).abs() < 1e-9, "{probability}");
+
+        let shed = shed_fraction(probability);
+        assert!((0.19..=0.21).contains(&shed), "{shed}");
+    }
+
+    #[test]
+    fn shed_fraction_at_full_pressure() {
+        let load = load((0..50).map(|_| Some(1000)), 100).unwrap();
+
+        assert_eq!(load.shed_probability(100, 0.5, 0.3), 0.3);
+    }
+
+    #[test]
+    fn no_shed_under_threshold_or_few_queries() {
+        let load_under_threshold = load((0..100).map(|i| Some(i * 4)), 100).unwrap();
+        assert_eq!(load_under_threshold.shed_probability(100, 0.5, 0.5), 0.0);
+
+        let few_queries = load((0..MIN_QUERIES - 1).map(|_| None), 100).unwrap();
+        assert_eq!(few_queries.shed_probability(100, 0.0, 1.0), 0.0);
+    }
+
+    #[test]
+    fn start_new_window() {
+        let load = load((0..100).map(|_| None), 100).unwrap();
+        assert_eq!(load.shed_probability(100 + WINDOW_SECS, 0.5, 0.5), 0.0);
+
+        let load = Load::count(Some(load), 100 + WINDOW_SECS, false);
+        assert_eq!(load.window_start, 100 + WINDOW_SECS);
+        assert_eq!((load.queries, load.pressured), (1, 0));
+    }
+}
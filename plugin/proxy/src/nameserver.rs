@@ -0,0 +1,115 @@
+use std::net::SocketAddr;
+
+use serde::Deserialize;
+use tracing::error;
+
+use crate::plugin::Error;
+use crate::strategy::Strategy;
+
+/// a nameserver is configured as a socket addr, or a map when it needs extra options
+#[derive(Debug, Deserialize)]
+#[serde(from = "NameserverConfig")]
+pub struct Nameserver {
+    pub addr: SocketAddr,
+    /// rewrite the EDNS payload size advertised to this nameserver, 0 means strip EDNS
+    pub edns_payload_size: Option<u16>,
+    /// the share of the queries tried on this nameserver first, used by the weighted strategy
+    pub weight: u32,
+    pub protocol: Protocol,
+    /// the name to verify the DoT server cert, the addr ip is verified when unset
+    pub server_name: Option<String>,
+    /// the name of the CA in the `tls_cas` of the plugin to verify the DoT server cert, the
+    /// webpki roots are used when unset
+    pub ca: Option<String>,
+}
+
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Protocol {
+    #[default]
+    Udp,
+
+    /// DNS over TLS, the nameserver addr is usually on port 853
+    Dot,
+}
+
+impl Nameserver {
+    pub fn validate(&self, strategy: Strategy) -> Result<(), Error> {
+        match self.protocol {
+            Protocol::Udp if self.server_name.is_some() || self.ca.is_some() => {
+                error!(nameserver = %self.addr, "server_name and ca are only for dot");
+
+                Err(Error {
+                    code: 1,
+                    msg: format!(
+                        "nameserver {} sets server_name or ca without dot",
+                        self.addr
+                    ),
+                })
+            }
+
+            // the parallel strategy polls the udp sockets in turn
+            Protocol::Dot if strategy == Strategy::Parallel => {
+                error!(nameserver = %self.addr, "dot nameserver doesn't support parallel strategy");
+
+                Err(Error {
+                    code: 1,
+                    msg: format!(
+                        "dot nameserver {} doesn't support parallel strategy",
+                        self.addr
+                    ),
+                })
+            }
+
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum NameserverConfig {
+    Addr(SocketAddr),
+    Detailed(DetailedNameserver),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DetailedNameserver {
+    addr: SocketAddr,
+    edns_payload_size: Option<u16>,
+    #[serde(default = "default_weight")]
+    weight: u32,
+    #[serde(default)]
+    protocol: Protocol,
+    server_name: Option<String>,
+    ca: Option<String>,
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+impl From<NameserverConfig> for Nameserver {
+    fn from(value: NameserverConfig) -> Self {
+        match value {
+            NameserverConfig::Addr(addr) => Self {
+                addr,
+                edns_payload_size: None,
+                weight: default_weight(),
+                protocol: Protocol::Udp,
+                server_name: None,
+                ca: None,
+            },
+
+            NameserverConfig::Detailed(nameserver) => Self {
+                addr: nameserver.addr,
+                edns_payload_size: nameserver.edns_payload_size,
+                weight: nameserver.weight,
+                protocol: nameserver.protocol,
+                server_name: nameserver.server_name,
+                ca: nameserver.ca,
+            },
+        }
+    }
+}
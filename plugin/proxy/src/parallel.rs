@@ -0,0 +1,102 @@
+use std::borrow::Cow;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use plugin_utils::net::udp::UdpSocket;
+use plugin_utils::time;
+use tracing::{error, warn};
+
+use crate::plugin::Error;
+use crate::{cookie, edns, is_referral, source_port, tcp, Config};
+
+/// the wait on a socket before polling the next one, the wasm instance is single threaded so
+/// the sockets are polled in turn
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// send the request to all the nameservers, return the first successful response, [`None`]
+/// means all the nameservers failed or timed out
+pub fn handle_dns(
+    dns_packet: &[u8],
+    config: &Config,
+    check_referral: bool,
+) -> Result<Option<Vec<u8>>, Error> {
+    let mut pending = Vec::with_capacity(config.nameservers.len());
+    for nameserver in &config.nameservers {
+        let request_packet = match nameserver.edns_payload_size {
+            None => Cow::Borrowed(dns_packet),
+            Some(payload_size) => Cow::Owned(edns::set_payload_size(dns_packet, payload_size)?),
+        };
+        let request_packet = if config.upstream_cookies {
+            Cow::Owned(cookie::add_cookie(&request_packet, nameserver.addr)?)
+        } else {
+            request_packet
+        };
+
+        match send(&request_packet, nameserver.addr, config) {
+            Err(err) => error!(%err, nameserver = %nameserver.addr, "send dns packet failed"),
+            Ok(udp_socket) => pending.push((nameserver.addr, udp_socket, request_packet)),
+        }
+    }
+
+    let deadline = time::now_millis() + config.parallel_timeout_ms;
+    while !pending.is_empty() && time::now_millis() < deadline {
+        let mut index = 0;
+        while index < pending.len() {
+            let (nameserver, udp_socket, request_packet) = &pending[index];
+            match udp_socket.recv_size_timeout(4096, POLL_INTERVAL) {
+                Ok(None) => {
+                    index += 1;
+
+                    continue;
+                }
+
+                Err(err) => {
+                    error!(%err, %nameserver, "recv dns packet failed");
+                }
+
+                Ok(Some(response)) => {
+                    let response = if config.tcp_fallback && tcp::is_truncated(&response) {
+                        tcp::handle_dns(request_packet, *nameserver, Some(deadline)).ok()
+                    } else {
+                        Some(response)
+                    };
+                    let response = if config.upstream_cookies {
+                        response
+                            .and_then(|response| cookie::check_cookie(response, *nameserver).ok())
+                    } else {
+                        response
+                    };
+
+                    match response {
+                        None => {}
+                        Some(response) if !(check_referral && is_referral(&response)) => {
+                            return Ok(Some(response));
+                        }
+                        Some(_) => {
+                            warn!(%nameserver, "nameserver returns referral for recursive query");
+                        }
+                    }
+                }
+            }
+
+            pending.swap_remove(index);
+        }
+    }
+
+    if !pending.is_empty() {
+        warn!(
+            nameservers = pending.len(),
+            "nameservers don't respond in parallel timeout"
+        );
+    }
+
+    Ok(None)
+}
+
+fn send(dns_packet: &[u8], nameserver: SocketAddr, config: &Config) -> std::io::Result<UdpSocket> {
+    let udp_socket = source_port::bind(config.source_port_range)?;
+    udp_socket.connect(nameserver)?;
+    udp_socket.send(dns_packet)?;
+
+    Ok(udp_socket)
+}
@@ -0,0 +1,48 @@
+/// the delay before the retry round, it is doubled every round and randomized by the jitter
+/// percentage with the random number
+pub fn delay(base_ms: u64, round: u32, jitter: u8, random: u64) -> u64 {
+    let delay = base_ms.saturating_mul(1 << round.saturating_sub(1).min(16));
+    if jitter == 0 {
+        return delay;
+    }
+
+    let jitter = jitter.min(100) as u64;
+    // pick a factor in [100 - jitter, 100 + jitter] percent
+    let factor = 100 - jitter + random % (jitter * 2 + 1);
+
+    delay.saturating_mul(factor) / 100
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn double_every_round() {
+        assert_eq!(delay(100, 1, 0, 0), 100);
+        assert_eq!(delay(100, 2, 0, 0), 200);
+        assert_eq!(delay(100, 3, 0, 0), 400);
+        // the doubling stops growing after 16 rounds
+        assert_eq!(delay(1, 100, 0, 0), 1 << 16);
+    }
+
+    #[test]
+    fn jitter_in_bounds() {
+        assert_eq!(delay(1000, 1, 10, 0), 900);
+        assert_eq!(delay(1000, 1, 10, 20), 1100);
+        assert_eq!(delay(1000, 2, 10, 20), 2200);
+
+        let delays = (0..1000)
+            .map(|random| delay(1000, 1, 10, random * 7919))
+            .collect::<Vec<_>>();
+        assert!(delays.iter().all(|delay| (900..=1100).contains(delay)));
+        // the delays vary instead of being in lockstep
+        assert!(delays.iter().any(|delay| *delay != delays[0]));
+    }
+
+    #[test]
+    fn cap_jitter_to_the_delay() {
+        assert_eq!(delay(1000, 1, 200, 0), 0);
+        assert_eq!(delay(1000, 1, 200, 200), 2000);
+    }
+}
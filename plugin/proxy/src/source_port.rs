@@ -0,0 +1,106 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+use plugin_utils::net::udp::UdpSocket;
+use serde::Deserialize;
+use tracing::warn;
+
+use crate::helper::random_u64;
+
+/// the ports tried before giving up, the picked port may be used by another socket
+const BIND_ATTEMPTS: usize = 8;
+
+/// the source ports of the queries, both ends are inclusive
+#[derive(Debug, Copy, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SourcePortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl SourcePortRange {
+    /// the port in the range picked by the random number
+    fn pick(&self, random: u64) -> u16 {
+        let size = (self.end - self.start) as u64 + 1;
+
+        self.start + (random % size) as u16
+    }
+}
+
+/// bind a fresh socket for a query, so every query uses its own random source port
+///
+/// without the range, the port is picked by the OS from its ephemeral ports
+pub fn bind(source_port_range: Option<SourcePortRange>) -> io::Result<UdpSocket> {
+    let source_port_range = match source_port_range {
+        None => return UdpSocket::bind(unspecified_addr(0)),
+        Some(source_port_range) => source_port_range,
+    };
+
+    let mut attempts = 1;
+    loop {
+        let port = source_port_range.pick(random_u64());
+        match UdpSocket::bind(unspecified_addr(port)) {
+            Err(err) if attempts < BIND_ATTEMPTS => {
+                warn!(%err, port, "bind udp source port failed, try another one");
+
+                attempts += 1;
+            }
+
+            result => return result,
+        }
+    }
+}
+
+fn unspecified_addr(port: u16) -> SocketAddr {
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), port)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    #[test]
+    fn vary_ports_in_range() {
+        let source_port_range = SourcePortRange {
+            start: 20000,
+            end: 29999,
+        };
+
+        // xorshift, so the picks are reproducible
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let ports = (0..100)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+
+                source_port_range.pick(state)
+            })
+            .collect::<HashSet<_>>();
+
+        assert!(ports.len() > 90, "only {} ports", ports.len());
+        assert!(ports
+            .iter()
+            .all(|port| (source_port_range.start..=source_port_range.end).contains(port)));
+    }
+
+    #[test]
+    fn pick_both_ends() {
+        let source_port_range = SourcePortRange {
+            start: 53000,
+            end: 53001,
+        };
+
+        assert_eq!(source_port_range.pick(0), 53000);
+        assert_eq!(source_port_range.pick(1), 53001);
+        assert_eq!(source_port_range.pick(2), 53000);
+
+        let full_range = SourcePortRange {
+            start: 1,
+            end: u16::MAX,
+        };
+        assert_eq!(full_range.pick(u16::MAX as u64 - 1), u16::MAX);
+    }
+}
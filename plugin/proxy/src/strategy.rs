@@ -0,0 +1,140 @@
+use serde::Deserialize;
+
+use crate::helper::{map_get, map_set};
+use crate::nameserver::Nameserver;
+
+const ROTATE_KEY: &[u8] = b"proxy:rotate";
+
+/// how to pick the nameserver tried first, the others are tried in order when it fails
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Strategy {
+    /// always start from the first configured nameserver
+    #[default]
+    Ordered,
+
+    /// start from the nameserver after the one used by the last query
+    Rotate,
+
+    /// rotate the nameservers in proportion to their weights
+    Weighted,
+
+    /// query all the nameservers at once, use the first successful response
+    Parallel,
+}
+
+impl Strategy {
+    /// the index of the nameserver to try first, the rotating strategies advance the rotation
+    pub fn first_index(
+        &self,
+        nameservers: &[Nameserver],
+        next_rotation: impl FnOnce() -> u64,
+    ) -> usize {
+        match self {
+            Strategy::Ordered | Strategy::Parallel => 0,
+
+            Strategy::Rotate => (next_rotation() % nameservers.len() as u64) as usize,
+
+            Strategy::Weighted => {
+                let total_weight = nameservers
+                    .iter()
+                    .map(|nameserver| nameserver.weight as u64)
+                    .sum::<u64>();
+                if total_weight == 0 {
+                    return 0;
+                }
+
+                // every nameserver owns a slot range as wide as its weight
+                let mut slot = next_rotation() % total_weight;
+                for (index, nameserver) in nameservers.iter().enumerate() {
+                    if slot < nameserver.weight as u64 {
+                        return index;
+                    }
+
+                    slot -= nameserver.weight as u64;
+                }
+
+                0
+            }
+        }
+    }
+}
+
+/// the rotation is kept in the store, because every query runs in a fresh instance
+pub fn next_rotation() -> u64 {
+    let rotation = map_get(ROTATE_KEY)
+        .and_then(|data| data.try_into().ok())
+        .map(u64::from_be_bytes)
+        .unwrap_or(0);
+
+    map_set(ROTATE_KEY, &rotation.wrapping_add(1).to_be_bytes(), None);
+
+    rotation
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::nameserver::Protocol;
+
+    use super::*;
+
+    fn nameservers(weights: &[u32]) -> Vec<Nameserver> {
+        weights
+            .iter()
+            .enumerate()
+            .map(|(index, weight)| Nameserver {
+                addr: format!("192.0.2.{}:53", index + 1).parse().unwrap(),
+                edns_payload_size: None,
+                weight: *weight,
+                protocol: Protocol::Udp,
+                server_name: None,
+                ca: None,
+            })
+            .collect()
+    }
+
+    /// the first indexes of the successive queries, the rotation counts up like the stored one
+    fn first_indexes(strategy: Strategy, nameservers: &[Nameserver], queries: u64) -> Vec<usize> {
+        (0..queries)
+            .map(|rotation| strategy.first_index(nameservers, || rotation))
+            .collect()
+    }
+
+    #[test]
+    fn rotate_through_nameservers() {
+        let indexes = first_indexes(Strategy::Rotate, &nameservers(&[1, 1, 1]), 6);
+
+        assert_eq!(indexes, [0, 1, 2, 0, 1, 2]);
+        assert!(indexes.windows(2).all(|pair| pair[0] != pair[1]));
+    }
+
+    #[test]
+    fn weighted_rotation_follows_weights() {
+        let indexes = first_indexes(Strategy::Weighted, &nameservers(&[3, 1, 2]), 12);
+
+        assert_eq!(indexes, [0, 0, 0, 1, 2, 2, 0, 0, 0, 1, 2, 2]);
+        for (index, weight) in [3, 1, 2].into_iter().enumerate() {
+            let count = indexes.iter().filter(|first| **first == index).count();
+            assert_eq!(count, weight * 2);
+        }
+    }
+
+    #[test]
+    fn weighted_skips_zero_weight() {
+        let indexes = first_indexes(Strategy::Weighted, &nameservers(&[0, 1, 1]), 4);
+        assert_eq!(indexes, [1, 2, 1, 2]);
+
+        // no weight at all falls back to the first nameserver
+        let indexes = first_indexes(Strategy::Weighted, &nameservers(&[0, 0]), 3);
+        assert_eq!(indexes, [0, 0, 0]);
+    }
+
+    #[test]
+    fn ordered_starts_from_first() {
+        let indexes = [Strategy::Ordered, Strategy::Parallel].map(|strategy| {
+            strategy.first_index(&nameservers(&[1, 1]), || unreachable!("no rotation"))
+        });
+
+        assert_eq!(indexes, [0, 0]);
+    }
+}
@@ -0,0 +1,110 @@
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use plugin_utils::net::tcp::TcpStream;
+use plugin_utils::time;
+use tracing::{error, info};
+use trust_dns_proto::op::Message;
+
+use crate::plugin::Error;
+
+/// a truncated response means the answer doesn't fit in UDP, the client should retry over TCP
+pub fn is_truncated(response_packet: &[u8]) -> bool {
+    match Message::from_vec(response_packet) {
+        Err(err) => {
+            error!(%err, "decode dns response packet failed");
+
+            false
+        }
+
+        Ok(response_message) => response_message.truncated(),
+    }
+}
+
+/// the time left before the deadline in unix millis, [`None`] means no deadline
+pub fn remaining(deadline: Option<u64>) -> Option<Duration> {
+    deadline.map(|deadline| Duration::from_millis(deadline.saturating_sub(time::now_millis())))
+}
+
+/// reissue the query over TCP, the connect and the reads give up at the deadline
+pub fn handle_dns(
+    dns_packet: &[u8],
+    nameserver: SocketAddr,
+    deadline: Option<u64>,
+) -> Result<Vec<u8>, Error> {
+    info!(%nameserver, "response is truncated, retry over tcp");
+
+    let result = match remaining(deadline) {
+        None => TcpStream::connect(nameserver),
+        Some(timeout) => TcpStream::connect_timeout(nameserver, timeout),
+    };
+    let mut tcp_stream = result.map_err(|err| {
+        error!(%err, %nameserver, "connect nameserver over tcp failed");
+
+        Error {
+            code: err.raw_os_error().unwrap_or(1) as _,
+            msg: err.to_string(),
+        }
+    })?;
+
+    exchange(&mut tcp_stream, dns_packet, nameserver, deadline)
+}
+
+/// send the query and read the response on a stream, the packets are prefixed with their 2
+/// bytes length, which is the same over TCP and TLS
+pub fn exchange(
+    tcp_stream: &mut TcpStream,
+    dns_packet: &[u8],
+    nameserver: SocketAddr,
+    deadline: Option<u64>,
+) -> Result<Vec<u8>, Error> {
+    let len = u16::try_from(dns_packet.len()).map_err(|err| {
+        error!(%err, "dns packet is too large for tcp");
+
+        Error {
+            code: 1,
+            msg: err.to_string(),
+        }
+    })?;
+
+    let mut buf = Vec::with_capacity(2 + dns_packet.len());
+    buf.extend_from_slice(&len.to_be_bytes());
+    buf.extend_from_slice(dns_packet);
+
+    tcp_stream
+        .write_all(&buf)
+        .and_then(|_| tcp_stream.flush())
+        .map_err(|err| {
+            error!(%err, %nameserver, "send dns packet over tcp failed");
+
+            Error {
+                code: err.raw_os_error().unwrap_or(1) as _,
+                msg: err.to_string(),
+            }
+        })?;
+
+    let mut len = [0; 2];
+    tcp_stream.set_read_timeout(remaining(deadline));
+    tcp_stream.read_exact(&mut len).map_err(|err| {
+        error!(%err, %nameserver, "read dns packet length over tcp failed");
+
+        Error {
+            code: err.raw_os_error().unwrap_or(1) as _,
+            msg: err.to_string(),
+        }
+    })?;
+
+    let mut data = vec![0; u16::from_be_bytes(len) as usize];
+    tcp_stream.set_read_timeout(remaining(deadline));
+    tcp_stream.read_exact(&mut data).map_err(|err| {
+        error!(%err, %nameserver, "read dns packet over tcp failed");
+
+        Error {
+            code: err.raw_os_error().unwrap_or(1) as _,
+            msg: err.to_string(),
+        }
+    })?;
+
+    Ok(data)
+}
@@ -0,0 +1,478 @@
+use regex::Regex;
+use serde::Deserialize;
+use tracing::{error, info};
+use trust_dns_proto::op::Message;
+use trust_dns_proto::rr::{Name, RData, Record};
+
+use crate::helper::{call_next_plugin, load_config};
+use crate::plugin::{Error, Plugin};
+
+wit_bindgen::generate!("rubydns");
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    /// the first rule which matches the query name is used
+    rules: Vec<Rule>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Rule {
+    Suffix(SuffixRule),
+    Regex(RegexRule),
+}
+
+/// rewrite the names under the from suffix to the same names under the to suffix
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SuffixRule {
+    from_suffix: String,
+    to_suffix: String,
+}
+
+/// rewrite the name matching the pattern to the replacement, which can refer to the capture
+/// groups as `$1`, the pattern is matched against the lowercase query name without the
+/// trailing dot
+///
+/// a regex can't be reversed, so only the records owned by or pointing to the rewritten
+/// name are restored in the response
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RegexRule {
+    pattern: String,
+    replacement: String,
+}
+
+enum Rewrite {
+    Suffix { from_suffix: Name, to_suffix: Name },
+    Regex { regex: Regex, replacement: String },
+}
+
+impl Config {
+    fn rules(&self) -> Result<Vec<Rewrite>, Error> {
+        self.rules
+            .iter()
+            .map(|rule| match rule {
+                Rule::Suffix(rule) => Ok(Rewrite::Suffix {
+                    from_suffix: parse_suffix(&rule.from_suffix)?,
+                    to_suffix: parse_suffix(&rule.to_suffix)?,
+                }),
+
+                Rule::Regex(rule) => {
+                    let regex = Regex::new(&rule.pattern).map_err(|err| {
+                        error!(%err, pattern = rule.pattern, "compile regex pattern failed");
+
+                        Error {
+                            code: 1,
+                            msg: format!("invalid pattern {}: {err}", rule.pattern),
+                        }
+                    })?;
+
+                    Ok(Rewrite::Regex {
+                        regex,
+                        replacement: rule.replacement.clone(),
+                    })
+                }
+            })
+            .collect()
+    }
+}
+
+impl Rewrite {
+    /// [`None`] means the rule doesn't match the name
+    fn rewrite(&self, name: &Name) -> Result<Option<Name>, Error> {
+        match self {
+            Rewrite::Suffix {
+                from_suffix,
+                to_suffix,
+            } => {
+                if !from_suffix.zone_of(name) {
+                    return Ok(None);
+                }
+
+                replace_suffix(name, from_suffix, to_suffix).map(Some)
+            }
+
+            Rewrite::Regex { regex, replacement } => {
+                let name = name.to_lowercase().to_ascii();
+                let name = name.trim_end_matches('.');
+                if !regex.is_match(name) {
+                    return Ok(None);
+                }
+
+                let rewritten_name = regex.replace(name, replacement.as_str());
+
+                parse_suffix(&rewritten_name).map(Some)
+            }
+        }
+    }
+
+    /// rewrite the owner name and the CNAME target back to the names the client queries
+    fn restore(
+        &self,
+        record: &mut Record,
+        query_name: &Name,
+        rewritten_name: &Name,
+    ) -> Result<(), Error> {
+        match self {
+            Rewrite::Suffix {
+                from_suffix,
+                to_suffix,
+            } => restore_record(record, from_suffix, to_suffix),
+
+            Rewrite::Regex { .. } => {
+                if record.name() == rewritten_name {
+                    record.set_name(query_name.clone());
+                }
+
+                if let Some(RData::CNAME(target)) = record.data() {
+                    if target == rewritten_name {
+                        record.set_data(Some(RData::CNAME(query_name.clone())));
+                    }
+                }
+
+                Ok(())
+            }
+        }
+    }
+}
+
+fn parse_suffix(suffix: &str) -> Result<Name, Error> {
+    Name::from_ascii(suffix)
+        .map(|mut name| {
+            name.set_fqdn(true);
+
+            name
+        })
+        .map_err(|err| {
+            error!(%err, suffix, "invalid rewrite suffix");
+
+            Error {
+                code: 1,
+                msg: format!("invalid suffix {suffix}: {err}"),
+            }
+        })
+}
+
+#[derive(Debug)]
+struct QueryRewriteRunner;
+
+impl Plugin for QueryRewriteRunner {
+    fn run(dns_packet: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let config = load_config();
+        let config: Config = serde_yaml::from_str(&config).map_err(|err| {
+            error!(%err, "load query rewrite config failed");
+
+            Error {
+                code: 1,
+                msg: err.to_string(),
+            }
+        })?;
+
+        rewrite_query(&config.rules()?, &dns_packet, call_next)
+    }
+
+    fn valid_config() -> Result<(), Error> {
+        let config: Config = serde_yaml::from_str(&load_config()).map_err(|err| {
+            error!(%err, "load query rewrite config failed");
+
+            Error {
+                code: 1,
+                msg: err.to_string(),
+            }
+        })?;
+
+        config.rules()?;
+
+        Ok(())
+    }
+
+    fn version() -> String {
+        env!("CARGO_PKG_VERSION").to_string()
+    }
+}
+
+/// rewrite the query name by the first matched rule, send the rewritten query by `exchange`
+/// and restore the names of the response, the query which no rule matches is sent as it is
+fn rewrite_query(
+    rules: &[Rewrite],
+    dns_packet: &[u8],
+    mut exchange: impl FnMut(&[u8]) -> Result<Vec<u8>, Error>,
+) -> Result<Vec<u8>, Error> {
+    let mut request_message = Message::from_vec(dns_packet).map_err(|err| {
+        error!(%err, "decode dns request packet failed");
+
+        Error {
+            code: 1,
+            msg: err.to_string(),
+        }
+    })?;
+
+    let query_name = match request_message.query() {
+        None => return exchange(dns_packet),
+        Some(query) => query.name().clone(),
+    };
+
+    let mut matched = None;
+    for rule in rules {
+        if let Some(rewritten_name) = rule.rewrite(&query_name)? {
+            matched = Some((rule, rewritten_name));
+
+            break;
+        }
+    }
+
+    let (rule, rewritten_name) = match matched {
+        None => return exchange(dns_packet),
+        Some(matched) => matched,
+    };
+
+    info!(%query_name, %rewritten_name, "rewrite query name");
+
+    request_message.queries_mut()[0].set_name(rewritten_name.clone());
+    let request_packet = request_message.to_vec().map_err(|err| {
+        error!(%err, "encode rewritten dns request packet failed");
+
+        Error {
+            code: 1,
+            msg: err.to_string(),
+        }
+    })?;
+
+    let response_packet = exchange(&request_packet)?;
+    let mut response_message = Message::from_vec(&response_packet).map_err(|err| {
+        error!(%err, "decode dns response packet failed");
+
+        Error {
+            code: 1,
+            msg: err.to_string(),
+        }
+    })?;
+
+    // restore the names, so the client sees the names it queries
+    if let Some(query) = response_message.queries_mut().first_mut() {
+        query.set_name(query_name.clone());
+    }
+    for answer in response_message.answers_mut() {
+        rule.restore(answer, &query_name, &rewritten_name)?;
+    }
+
+    response_message.to_vec().map_err(|err| {
+        error!(%err, "encode dns response packet failed");
+
+        Error {
+            code: 1,
+            msg: err.to_string(),
+        }
+    })
+}
+
+/// rewrite the owner name and the CNAME target under the to suffix back to the from suffix
+fn restore_record(record: &mut Record, from_suffix: &Name, to_suffix: &Name) -> Result<(), Error> {
+    if to_suffix.zone_of(record.name()) {
+        let name = replace_suffix(record.name(), to_suffix, from_suffix)?;
+        record.set_name(name);
+    }
+
+    if let Some(RData::CNAME(target)) = record.data() {
+        if to_suffix.zone_of(target) {
+            let target = replace_suffix(target, to_suffix, from_suffix)?;
+            record.set_data(Some(RData::CNAME(target)));
+        }
+    }
+
+    Ok(())
+}
+
+/// the name must be under the old suffix
+fn replace_suffix(name: &Name, old_suffix: &Name, new_suffix: &Name) -> Result<Name, Error> {
+    let prefix_labels = (name.num_labels() - old_suffix.num_labels()) as usize;
+
+    Name::from_labels(name.iter().take(prefix_labels))
+        .and_then(|prefix| prefix.append_domain(new_suffix))
+        .map_err(|err| {
+            error!(%err, %name, %new_suffix, "replace name suffix failed");
+
+            Error {
+                code: 1,
+                msg: err.to_string(),
+            }
+        })
+}
+
+fn call_next(dns_packet: &[u8]) -> Result<Vec<u8>, Error> {
+    match call_next_plugin(dns_packet) {
+        None => Err(Error {
+            code: 1,
+            msg: "no next plugin".to_string(),
+        }),
+
+        Some(result) => result,
+    }
+}
+
+export_rubydns!(QueryRewriteRunner);
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::str::FromStr;
+
+    use trust_dns_proto::op::{MessageType, Query};
+    use trust_dns_proto::rr::RecordType;
+
+    use super::*;
+
+    fn name(name: &str) -> Name {
+        Name::from_str(name).unwrap()
+    }
+
+    fn rules(config: &str) -> Vec<Rewrite> {
+        serde_yaml::from_str::<Config>(config)
+            .unwrap()
+            .rules()
+            .unwrap()
+    }
+
+    fn request(query_name: &str) -> Vec<u8> {
+        let mut message = Message::new();
+        message
+            .set_id(1234)
+            .add_query(Query::query(name(query_name), RecordType::A));
+
+        message.to_vec().unwrap()
+    }
+
+    /// answer the query with a CNAME to `web.<query name>` and the A record of the target
+    fn answer(dns_packet: &[u8]) -> Vec<u8> {
+        let mut message = Message::from_vec(dns_packet).unwrap();
+        let query_name = message.query().unwrap().name().clone();
+        let target = Name::from_labels(vec!["web"])
+            .unwrap()
+            .append_domain(&query_name)
+            .unwrap();
+
+        message
+            .set_message_type(MessageType::Response)
+            .add_answer(Record::from_rdata(
+                query_name,
+                60,
+                RData::CNAME(target.clone()),
+            ))
+            .add_answer(Record::from_rdata(
+                target,
+                60,
+                RData::A(Ipv4Addr::new(192, 0, 2, 1)),
+            ));
+
+        message.to_vec().unwrap()
+    }
+
+    #[test]
+    fn rewrite_suffix_and_restore_answers() {
+        let rules = rules("rules: [{ from_suffix: old.example, to_suffix: new.example }]");
+        let mut forwarded = vec![];
+
+        let response = rewrite_query(&rules, &request("x.old.example."), |dns_packet| {
+            forwarded.push(Message::from_vec(dns_packet).unwrap());
+
+            Ok(answer(dns_packet))
+        })
+        .unwrap();
+
+        assert_eq!(forwarded.len(), 1);
+        assert_eq!(
+            forwarded[0].query().unwrap().name(),
+            &name("x.new.example.")
+        );
+
+        let response = Message::from_vec(&response).unwrap();
+        assert_eq!(response.query().unwrap().name(), &name("x.old.example."));
+        assert_eq!(response.answers()[0].name(), &name("x.old.example."));
+        assert_eq!(
+            response.answers()[0].data(),
+            Some(&RData::CNAME(name("web.x.old.example.")))
+        );
+        assert_eq!(response.answers()[1].name(), &name("web.x.old.example."));
+    }
+
+    #[test]
+    fn rewrite_regex_and_restore_query_name() {
+        let rules =
+            rules(r#"rules: [{ pattern: '^(.+)\.corp$', replacement: '$1.corp.example' }]"#);
+        let mut forwarded = vec![];
+
+        let response = rewrite_query(&rules, &request("www.corp."), |dns_packet| {
+            forwarded.push(Message::from_vec(dns_packet).unwrap());
+
+            Ok(answer(dns_packet))
+        })
+        .unwrap();
+
+        assert_eq!(
+            forwarded[0].query().unwrap().name(),
+            &name("www.corp.example.")
+        );
+
+        let response = Message::from_vec(&response).unwrap();
+        assert_eq!(response.query().unwrap().name(), &name("www.corp."));
+        assert_eq!(response.answers()[0].name(), &name("www.corp."));
+        // a regex can't be reversed, the other names are kept
+        assert_eq!(response.answers()[1].name(), &name("web.www.corp.example."));
+    }
+
+    #[test]
+    fn forward_unmatched_query_as_it_is() {
+        let rules = rules("rules: [{ from_suffix: old.example, to_suffix: new.example }]");
+        let dns_packet = request("x.other.example.");
+
+        let response = rewrite_query(&rules, &dns_packet, |forwarded| {
+            assert_eq!(forwarded, dns_packet);
+
+            Ok(answer(forwarded))
+        })
+        .unwrap();
+
+        assert_eq!(response, answer(&dns_packet));
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let rules = rules(
+            r#"
+rules:
+  - { pattern: '^(.+)\.internal$', replacement: '$1.corp.example.com' }
+  - { from_suffix: internal, to_suffix: other.example }
+"#,
+        );
+        let mut forwarded = vec![];
+
+        let response = rewrite_query(&rules, &request("db.internal."), |dns_packet| {
+            forwarded.push(Message::from_vec(dns_packet).unwrap());
+
+            Ok(answer(dns_packet))
+        })
+        .unwrap();
+
+        assert_eq!(
+            forwarded[0].query().unwrap().name(),
+            &name("db.corp.example.com.")
+        );
+
+        // the transaction id and the question the client sent are kept
+        let response = Message::from_vec(&response).unwrap();
+        assert_eq!(response.id(), 1234);
+        assert_eq!(response.queries().len(), 1);
+        assert_eq!(response.query().unwrap().name(), &name("db.internal."));
+        assert_eq!(response.query().unwrap().query_type(), RecordType::A);
+    }
+
+    #[test]
+    fn reject_invalid_regex_pattern() {
+        let config: Config =
+            serde_yaml::from_str("rules: [{ pattern: '(unclosed', replacement: x }]").unwrap();
+
+        assert!(config.rules().is_err());
+    }
+}
@@ -0,0 +1,297 @@
+use std::net::SocketAddr;
+
+use plugin_utils::net::client_addr;
+use serde::Deserialize;
+use tracing::error;
+use trust_dns_proto::op::Message;
+
+use crate::helper::{call_next_plugin, load_config, log_event, LogLevel};
+use crate::plugin::{Error, Plugin};
+
+wit_bindgen::generate!("rubydns");
+
+/// log the queries and their responses through the server log, the response is returned
+/// unchanged
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    #[serde(default)]
+    format: Format,
+    #[serde(default)]
+    level: Level,
+}
+
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Format {
+    /// log the fields as `key=value`
+    #[default]
+    Text,
+
+    /// log the fields as a json object in the message
+    Json,
+}
+
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Level {
+    Trace,
+    Debug,
+    #[default]
+    Info,
+    Warn,
+    Error,
+}
+
+impl From<Level> for LogLevel {
+    fn from(level: Level) -> Self {
+        match level {
+            Level::Trace => LogLevel::Trace,
+            Level::Debug => LogLevel::Debug,
+            Level::Info => LogLevel::Info,
+            Level::Warn => LogLevel::Warn,
+            Level::Error => LogLevel::Error,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct QuerylogRunner;
+
+impl Plugin for QuerylogRunner {
+    fn run(dns_packet: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let config = load_config();
+        let config: Config = serde_yaml::from_str(&config).map_err(|err| {
+            error!(%err, "load querylog config failed");
+
+            Error {
+                code: 1,
+                msg: err.to_string(),
+            }
+        })?;
+
+        let request_message = Message::from_vec(&dns_packet).map_err(|err| {
+            error!(%err, "decode dns request packet failed");
+
+            Error {
+                code: 1,
+                msg: err.to_string(),
+            }
+        })?;
+
+        let result = call_next(&dns_packet);
+        let fields = query_fields(client_addr(), &request_message, &result);
+        log_query(&config, &fields);
+
+        result
+    }
+
+    fn valid_config() -> Result<(), Error> {
+        serde_yaml::from_str::<Config>(&load_config()).map_err(|err| {
+            error!(%err, "load querylog config failed");
+
+            Error {
+                code: 1,
+                msg: err.to_string(),
+            }
+        })?;
+
+        Ok(())
+    }
+
+    fn version() -> String {
+        env!("CARGO_PKG_VERSION").to_string()
+    }
+}
+
+/// the fields of the query and its response, the response is only read for the log
+fn query_fields(
+    client: Option<SocketAddr>,
+    request_message: &Message,
+    result: &Result<Vec<u8>, Error>,
+) -> Vec<(&'static str, String)> {
+    let mut fields = vec![(
+        "client",
+        client.map_or_else(|| "-".to_string(), |client| client.to_string()),
+    )];
+    if let Some(query) = request_message.query() {
+        fields.push(("name", query.name().to_string()));
+        fields.push(("query_type", query.query_type().to_string()));
+        fields.push(("query_class", query.query_class().to_string()));
+    }
+
+    match result {
+        Ok(response_packet) => match Message::from_vec(response_packet) {
+            // the response is still returned, only the log misses the response fields
+            Err(err) => {
+                error!(%err, "decode dns response packet failed");
+            }
+
+            Ok(response_message) => {
+                fields.push(("rcode", response_message.response_code().to_string()));
+                fields.push(("answers", response_message.answer_count().to_string()));
+            }
+        },
+
+        Err(err) => {
+            fields.push(("error_code", err.code.to_string()));
+            fields.push(("error", err.msg.clone()));
+        }
+    }
+
+    fields
+}
+
+fn log_query(config: &Config, fields: &[(&str, String)]) {
+    let (message, fields) = log_message(config.format, fields);
+
+    log_event(config.level.into(), &message, &fields);
+}
+
+/// the message and the fields of the log event in the format
+fn log_message<'a>(
+    format: Format,
+    fields: &'a [(&str, String)],
+) -> (String, Vec<(&'a str, &'a str)>) {
+    match format {
+        Format::Text => {
+            let fields = fields
+                .iter()
+                .map(|(key, value)| (*key, value.as_str()))
+                .collect::<Vec<_>>();
+
+            ("query".to_string(), fields)
+        }
+
+        Format::Json => {
+            let object = fields
+                .iter()
+                .map(|(key, value)| (key.to_string(), serde_json::Value::from(value.as_str())))
+                .collect::<serde_json::Map<_, _>>();
+
+            (serde_json::Value::Object(object).to_string(), vec![])
+        }
+    }
+}
+
+fn call_next(dns_packet: &[u8]) -> Result<Vec<u8>, Error> {
+    match call_next_plugin(dns_packet) {
+        None => Err(Error {
+            code: 1,
+            msg: "no next plugin".to_string(),
+        }),
+
+        Some(result) => result,
+    }
+}
+
+export_rubydns!(QuerylogRunner);
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::str::FromStr;
+
+    use trust_dns_proto::op::{MessageType, Query, ResponseCode};
+    use trust_dns_proto::rr::{Name, RData, Record, RecordType};
+
+    use super::*;
+
+    fn request() -> Message {
+        let mut message = Message::new();
+        message.set_id(1234).add_query(Query::query(
+            Name::from_str("www.example.com.").unwrap(),
+            RecordType::AAAA,
+        ));
+
+        message
+    }
+
+    fn response(request_message: &Message) -> Vec<u8> {
+        let mut message = request_message.clone();
+        message
+            .set_message_type(MessageType::Response)
+            .set_response_code(ResponseCode::NXDomain)
+            .add_answer(Record::from_rdata(
+                Name::from_str("www.example.com.").unwrap(),
+                60,
+                RData::A(Ipv4Addr::new(192, 0, 2, 1)),
+            ));
+
+        message.to_vec().unwrap()
+    }
+
+    #[test]
+    fn log_query_and_response_fields() {
+        let request_message = request();
+
+        let fields = query_fields(
+            Some("192.0.2.10:5353".parse().unwrap()),
+            &request_message,
+            &Ok(response(&request_message)),
+        );
+
+        assert_eq!(
+            fields,
+            [
+                ("client", "192.0.2.10:5353".to_string()),
+                ("name", "www.example.com.".to_string()),
+                ("query_type", "AAAA".to_string()),
+                ("query_class", "IN".to_string()),
+                ("rcode", "Non-Existent Domain".to_string()),
+                ("answers", "1".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn log_error_without_client() {
+        let fields = query_fields(
+            None,
+            &request(),
+            &Err(Error {
+                code: 1,
+                msg: "no next plugin".to_string(),
+            }),
+        );
+
+        assert_eq!(fields[0], ("client", "-".to_string()));
+        assert_eq!(
+            fields[4..],
+            [
+                ("error_code", "1".to_string()),
+                ("error", "no next plugin".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn format_text_and_json() {
+        let fields = [
+            ("name", "www.example.com.".to_string()),
+            ("answers", "1".to_string()),
+        ];
+
+        let (message, text_fields) = log_message(Format::Text, &fields);
+        assert_eq!(message, "query");
+        assert_eq!(
+            text_fields,
+            [("name", "www.example.com."), ("answers", "1")]
+        );
+
+        let (message, json_fields) = log_message(Format::Json, &fields);
+        assert!(json_fields.is_empty());
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&message).unwrap(),
+            serde_json::json!({"name": "www.example.com.", "answers": "1"})
+        );
+    }
+
+    #[test]
+    fn default_text_format_at_info() {
+        let config = serde_yaml::from_str::<Config>("{}").unwrap();
+
+        assert_eq!(config.format, Format::Text);
+        assert_eq!(config.level, Level::Info);
+    }
+}
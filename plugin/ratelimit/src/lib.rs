@@ -0,0 +1,304 @@
+use std::net::IpAddr;
+
+use plugin_utils::net::client_addr;
+use plugin_utils::{subnet, time, DROP_ERROR_CODE};
+use serde::Deserialize;
+use tracing::{error, info};
+use trust_dns_proto::op::{Message, MessageType, ResponseCode};
+
+use crate::helper::{call_next_plugin, load_config, map_cas, map_get, map_increment};
+use crate::plugin::{Error, Plugin};
+
+wit_bindgen::generate!("rubydns");
+
+/// limit the queries of the clients with token buckets, the clients in the same subnet
+/// share a bucket
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    /// the tokens refilled per second
+    qps: f64,
+    /// the bucket size, which is the queries a client can send at once, the qps when unset
+    burst: Option<f64>,
+    /// count the queries in fixed windows of this length instead of the token bucket, a
+    /// window allows the larger one of the qps over the window and the burst, the counting
+    /// is cheaper than the bucket because it doesn't retry on contention
+    window_ms: Option<u64>,
+    /// the ipv4 clients share a bucket by the subnet with this prefix length, 32 means per
+    /// address
+    #[serde(default = "default_v4_prefix")]
+    v4_prefix: u8,
+    /// the ipv6 clients share a bucket by the subnet with this prefix length, 128 means per
+    /// address
+    #[serde(default = "default_v6_prefix")]
+    v6_prefix: u8,
+    #[serde(default)]
+    action: Action,
+}
+
+fn default_v4_prefix() -> u8 {
+    32
+}
+
+fn default_v6_prefix() -> u8 {
+    128
+}
+
+impl Config {
+    fn burst(&self) -> f64 {
+        self.burst.unwrap_or(self.qps)
+    }
+
+    /// the subnet of the client, which shares the bucket
+    fn subnet(&self, client_ip: IpAddr) -> IpAddr {
+        subnet::mask(client_ip, self.v4_prefix, self.v6_prefix)
+    }
+}
+
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Action {
+    #[default]
+    Refuse,
+
+    /// drop the query without any response
+    Drop,
+}
+
+#[derive(Debug)]
+struct RatelimitRunner;
+
+impl Plugin for RatelimitRunner {
+    fn run(dns_packet: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let config = load_config();
+        let config: Config = serde_yaml::from_str(&config).map_err(|err| {
+            error!(%err, "load ratelimit config failed");
+
+            Error {
+                code: 1,
+                msg: err.to_string(),
+            }
+        })?;
+
+        // the queries without client, such as the prewarm queries, are not limited
+        let client_ip = match client_addr() {
+            None => return call_next(&dns_packet),
+            Some(client_addr) => client_addr.ip(),
+        };
+        let subnet = config.subnet(client_ip);
+
+        let allowed = match config.window_ms {
+            None => take_token(subnet, &config)?,
+            Some(window_ms) => count_window(subnet, &config, window_ms)?,
+        };
+
+        if allowed {
+            return call_next(&dns_packet);
+        }
+
+        info!(%client_ip, %subnet, action = ?config.action, "client exceeds rate limit");
+
+        match config.action {
+            Action::Drop => Err(Error {
+                code: DROP_ERROR_CODE,
+                msg: format!("drop query of rate limited client {client_ip}"),
+            }),
+
+            Action::Refuse => {
+                let request_message = Message::from_vec(&dns_packet).map_err(|err| {
+                    error!(%err, "decode dns request packet failed");
+
+                    Error {
+                        code: 1,
+                        msg: err.to_string(),
+                    }
+                })?;
+
+                refused_response(&request_message)
+            }
+        }
+    }
+
+    fn valid_config() -> Result<(), Error> {
+        let config: Config = serde_yaml::from_str(&load_config()).map_err(|err| {
+            error!(%err, "load ratelimit config failed");
+
+            Error {
+                code: 1,
+                msg: err.to_string(),
+            }
+        })?;
+
+        if !(config.qps.is_finite()
+            && config.qps > 0.0
+            && config.burst().is_finite()
+            && config.burst() >= 1.0)
+        {
+            error!(
+                qps = config.qps,
+                burst = config.burst,
+                "invalid ratelimit rate"
+            );
+
+            return Err(Error {
+                code: 1,
+                msg: "qps must be positive and burst must be at least 1".to_string(),
+            });
+        }
+
+        if config.window_ms == Some(0) {
+            error!("invalid ratelimit window");
+
+            return Err(Error {
+                code: 1,
+                msg: "window_ms must be positive".to_string(),
+            });
+        }
+
+        if config.v4_prefix > 32 || config.v6_prefix > 128 {
+            error!(
+                v4_prefix = config.v4_prefix,
+                v6_prefix = config.v6_prefix,
+                "invalid ratelimit prefix length"
+            );
+
+            return Err(Error {
+                code: 1,
+                msg: "invalid ratelimit prefix length".to_string(),
+            });
+        }
+
+        Ok(())
+    }
+
+    fn version() -> String {
+        env!("CARGO_PKG_VERSION").to_string()
+    }
+}
+
+/// refill the bucket of the subnet by the elapsed time and take a token from it, return
+/// whether the query is allowed
+///
+/// the stored value is the tokens as f64 bits and the last refill millis, both u64 big
+/// endian
+fn take_token(subnet: IpAddr, config: &Config) -> Result<bool, Error> {
+    let key = bucket_key(subnet);
+    let burst = config.burst();
+    // a bucket which is refilled to full is the same as a missing one
+    let timeout = (burst / config.qps).ceil() as u64 + 1;
+    let now = time::now_millis();
+
+    loop {
+        let current = map_get(key.as_bytes());
+        let (tokens, last_refill) = current
+            .as_deref()
+            .filter(|data| data.len() == 16)
+            .map(|data| {
+                (
+                    f64::from_bits(u64::from_be_bytes(data[..8].try_into().unwrap())),
+                    u64::from_be_bytes(data[8..].try_into().unwrap()),
+                )
+            })
+            .unwrap_or((burst, now));
+
+        let elapsed = now.saturating_sub(last_refill) as f64 / 1000.0;
+        let tokens = (tokens + elapsed * config.qps).min(burst);
+        let (tokens, allowed) = if tokens >= 1.0 {
+            (tokens - 1.0, true)
+        } else {
+            (tokens, false)
+        };
+
+        let mut new = tokens.to_bits().to_be_bytes().to_vec();
+        new.extend_from_slice(&now.max(last_refill).to_be_bytes());
+
+        // another instance updates the bucket at the same time, retry
+        if map_cas(key.as_bytes(), current.as_deref(), &new, Some(timeout)) {
+            return Ok(allowed);
+        }
+    }
+}
+
+fn bucket_key(subnet: IpAddr) -> String {
+    format!("ratelimit:{subnet}")
+}
+
+/// count the query in the current window of the subnet, return whether the query is
+/// allowed, the counter of a window expires after the window ends
+fn count_window(subnet: IpAddr, config: &Config, window_ms: u64) -> Result<bool, Error> {
+    let window = time::now_millis() / window_ms;
+    let key = format!("ratelimit:{subnet}:{window}");
+    let limit = (config.qps * window_ms as f64 / 1000.0).max(config.burst());
+    let timeout = window_ms.div_ceil(1000) + 1;
+
+    let count = map_increment(key.as_bytes(), 1, Some(timeout));
+
+    Ok(count as f64 <= limit)
+}
+
+fn refused_response(request_message: &Message) -> Result<Vec<u8>, Error> {
+    let mut response_message = Message::new();
+    response_message
+        .set_id(request_message.id())
+        .set_message_type(MessageType::Response)
+        .set_op_code(request_message.op_code())
+        .set_recursion_desired(request_message.recursion_desired())
+        .set_recursion_available(true)
+        .set_response_code(ResponseCode::Refused)
+        .add_queries(request_message.queries().iter().cloned());
+
+    response_message.to_vec().map_err(|err| {
+        error!(%err, "encode dns response packet failed");
+
+        Error {
+            code: 1,
+            msg: err.to_string(),
+        }
+    })
+}
+
+fn call_next(dns_packet: &[u8]) -> Result<Vec<u8>, Error> {
+    match call_next_plugin(dns_packet) {
+        None => Err(Error {
+            code: 1,
+            msg: "no next plugin".to_string(),
+        }),
+
+        Some(result) => result,
+    }
+}
+
+export_rubydns!(RatelimitRunner);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn same_subnet_shares_bucket() {
+        let config: Config =
+            serde_yaml::from_str("{qps: 10, v4_prefix: 24, v6_prefix: 64}").unwrap();
+
+        let bucket = |client_ip| bucket_key(config.subnet(ip(client_ip)));
+
+        assert_eq!(bucket("192.0.2.1"), bucket("192.0.2.254"));
+        assert_eq!(bucket("192.0.2.1"), bucket("::ffff:192.0.2.2"));
+        assert_ne!(bucket("192.0.2.1"), bucket("192.0.3.1"));
+        assert_eq!(bucket("2001:db8::1"), bucket("2001:db8::ffff:1"));
+        assert_ne!(bucket("2001:db8::1"), bucket("2001:db8:0:1::1"));
+    }
+
+    #[test]
+    fn default_prefix_is_per_address() {
+        let config: Config = serde_yaml::from_str("qps: 10").unwrap();
+
+        assert_ne!(
+            bucket_key(config.subnet(ip("192.0.2.1"))),
+            bucket_key(config.subnet(ip("192.0.2.2")))
+        );
+    }
+}
@@ -0,0 +1,321 @@
+use std::cell::RefCell;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::rc::Rc;
+
+use regex::Regex;
+use serde::Deserialize;
+use tracing::{error, info};
+use trust_dns_proto::op::{Message, MessageType, Query, ResponseCode};
+use trust_dns_proto::rr::{RData, Record, RecordType};
+
+use crate::helper::{call_next_plugin, load_config};
+use crate::plugin::{Error, Plugin};
+
+wit_bindgen::generate!("rubydns");
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    /// the rules are matched in order, the first matched rule decides the action
+    rules: Vec<Rule>,
+    #[serde(default = "default_sinkhole_v4")]
+    sinkhole_v4: Ipv4Addr,
+    #[serde(default = "default_sinkhole_v6")]
+    sinkhole_v6: Ipv6Addr,
+    #[serde(default = "default_ttl")]
+    ttl: u32,
+}
+
+fn default_sinkhole_v4() -> Ipv4Addr {
+    Ipv4Addr::UNSPECIFIED
+}
+
+fn default_sinkhole_v6() -> Ipv6Addr {
+    Ipv6Addr::UNSPECIFIED
+}
+
+fn default_ttl() -> u32 {
+    60
+}
+
+/// the pattern is matched against the lowercase query name without the trailing dot
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Rule {
+    pattern: String,
+    action: Action,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Action {
+    Nxdomain,
+
+    /// answer A and AAAA queries with the sinkhole addrs, other query types get an empty
+    /// answer
+    Sinkhole,
+
+    /// pass the query to the next plugin, the following rules are skipped
+    FallThrough,
+}
+
+type CompiledRules = Rc<Vec<(Regex, Action)>>;
+
+thread_local! {
+    /// the compiled rules of the last loaded config, the config of a plugin instance won't
+    /// change, so the rules are only compiled once
+    static COMPILED_RULES: RefCell<Option<(String, CompiledRules)>> = const { RefCell::new(None) };
+}
+
+impl Config {
+    fn compile_rules(&self) -> Result<Vec<(Regex, Action)>, Error> {
+        self.rules
+            .iter()
+            .map(|rule| {
+                let regex = Regex::new(&rule.pattern).map_err(|err| {
+                    error!(%err, pattern = rule.pattern, "compile regex pattern failed");
+
+                    Error {
+                        code: 1,
+                        msg: format!("invalid pattern {}: {err}", rule.pattern),
+                    }
+                })?;
+
+                Ok((regex, rule.action))
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug)]
+struct RegexMatchRunner;
+
+impl Plugin for RegexMatchRunner {
+    fn run(dns_packet: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let raw_config = load_config();
+        let config: Config = serde_yaml::from_str(&raw_config).map_err(|err| {
+            error!(%err, "load regex match config failed");
+
+            Error {
+                code: 1,
+                msg: err.to_string(),
+            }
+        })?;
+
+        let request_message = Message::from_vec(&dns_packet).map_err(|err| {
+            error!(%err, "decode dns request packet failed");
+
+            Error {
+                code: 1,
+                msg: err.to_string(),
+            }
+        })?;
+
+        let query = match request_message.query() {
+            None => return call_next(&dns_packet),
+            Some(query) => query,
+        };
+
+        let rules = compiled_rules(&raw_config, &config)?;
+        let response_message = match match_action(&rules, query)
+            .and_then(|action| action_response(&config, &request_message, query, action))
+        {
+            None => return call_next(&dns_packet),
+            Some(response_message) => response_message,
+        };
+
+        response_message.to_vec().map_err(|err| {
+            error!(%err, "encode dns response packet failed");
+
+            Error {
+                code: 1,
+                msg: err.to_string(),
+            }
+        })
+    }
+
+    fn valid_config() -> Result<(), Error> {
+        let config: Config = serde_yaml::from_str(&load_config()).map_err(|err| {
+            error!(%err, "load regex match config failed");
+
+            Error {
+                code: 1,
+                msg: err.to_string(),
+            }
+        })?;
+
+        config.compile_rules()?;
+
+        Ok(())
+    }
+
+    fn version() -> String {
+        env!("CARGO_PKG_VERSION").to_string()
+    }
+}
+
+/// the action of the first rule matching the query name, [`None`] means no rule matches
+fn match_action(rules: &[(Regex, Action)], query: &Query) -> Option<Action> {
+    let name = query.name().to_lowercase().to_ascii();
+    let name = name.trim_end_matches('.');
+    let (regex, action) = rules.iter().find(|(regex, _)| regex.is_match(name))?;
+
+    info!(
+        name,
+        pattern = regex.as_str(),
+        ?action,
+        "query name matched"
+    );
+
+    Some(*action)
+}
+
+/// the response of the action, [`None`] means the query falls through
+fn action_response(
+    config: &Config,
+    request_message: &Message,
+    query: &Query,
+    action: Action,
+) -> Option<Message> {
+    let (response_code, rdata) = match action {
+        Action::FallThrough => return None,
+        Action::Nxdomain => (ResponseCode::NXDomain, None),
+        Action::Sinkhole => match query.query_type() {
+            RecordType::A => (ResponseCode::NoError, Some(RData::A(config.sinkhole_v4))),
+            RecordType::AAAA => (ResponseCode::NoError, Some(RData::AAAA(config.sinkhole_v6))),
+            _ => (ResponseCode::NoError, None),
+        },
+    };
+
+    let mut response_message = Message::new();
+    response_message
+        .set_id(request_message.id())
+        .set_message_type(MessageType::Response)
+        .set_op_code(request_message.op_code())
+        .set_recursion_desired(request_message.recursion_desired())
+        .set_recursion_available(true)
+        .set_response_code(response_code)
+        .add_queries(request_message.queries().iter().cloned());
+    if let Some(rdata) = rdata {
+        response_message.add_answer(Record::from_rdata(query.name().clone(), config.ttl, rdata));
+    }
+
+    Some(response_message)
+}
+
+fn compiled_rules(raw_config: &str, config: &Config) -> Result<CompiledRules, Error> {
+    COMPILED_RULES.with(|compiled_rules| {
+        let mut compiled_rules = compiled_rules.borrow_mut();
+        if let Some((cached_config, rules)) = compiled_rules.as_ref() {
+            if cached_config == raw_config {
+                return Ok(rules.clone());
+            }
+        }
+
+        let rules = Rc::new(config.compile_rules()?);
+        *compiled_rules = Some((raw_config.to_string(), rules.clone()));
+
+        Ok(rules)
+    })
+}
+
+fn call_next(dns_packet: &[u8]) -> Result<Vec<u8>, Error> {
+    match call_next_plugin(dns_packet) {
+        None => Err(Error {
+            code: 1,
+            msg: "no next plugin".to_string(),
+        }),
+
+        Some(result) => result,
+    }
+}
+
+export_rubydns!(RegexMatchRunner);
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use trust_dns_proto::rr::Name;
+
+    use super::*;
+
+    /// names of 16 or more random looking letters and digits, as generated by a DGA
+    const DGA_CONFIG: &str = r#"
+rules:
+  - { pattern: '^www\.', action: fall_through }
+  - { pattern: '^[a-z0-9]{16,}\.(com|net)$', action: nxdomain }
+  - { pattern: '\.sinkhole\.example$', action: sinkhole }
+sinkhole_v4: 192.0.2.53
+"#;
+
+    fn request(name: &str, query_type: RecordType) -> Message {
+        let mut message = Message::new();
+        message
+            .set_id(1234)
+            .add_query(Query::query(Name::from_str(name).unwrap(), query_type));
+
+        message
+    }
+
+    fn respond(config: &Config, request_message: &Message) -> Option<Message> {
+        let rules = config.compile_rules().unwrap();
+        let query = request_message.query().unwrap();
+
+        match_action(&rules, query)
+            .and_then(|action| action_response(config, request_message, query, action))
+    }
+
+    #[test]
+    fn answer_nxdomain_to_dga_names() {
+        let config: Config = serde_yaml::from_str(DGA_CONFIG).unwrap();
+
+        let response_message =
+            respond(&config, &request("X7K2P9Q4M1Z8W3V6.com.", RecordType::A)).unwrap();
+        assert_eq!(response_message.id(), 1234);
+        assert_eq!(response_message.response_code(), ResponseCode::NXDomain);
+        assert!(response_message.answers().is_empty());
+
+        assert!(respond(&config, &request("example.com.", RecordType::A)).is_none());
+        assert!(respond(&config, &request("x7k2p9q4m1z8w3v6.org.", RecordType::A)).is_none());
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let config: Config = serde_yaml::from_str(DGA_CONFIG).unwrap();
+
+        // the fall through rule comes before the DGA rule
+        assert!(respond(
+            &config,
+            &request("www.x7k2p9q4m1z8w3v6.com.", RecordType::A)
+        )
+        .is_none());
+
+        let response_message =
+            respond(&config, &request("c2.sinkhole.example.", RecordType::A)).unwrap();
+        assert_eq!(response_message.response_code(), ResponseCode::NoError);
+        assert_eq!(
+            response_message.answers()[0].data(),
+            Some(&RData::A(Ipv4Addr::new(192, 0, 2, 53)))
+        );
+    }
+
+    #[test]
+    fn reject_invalid_pattern() {
+        let config: Config =
+            serde_yaml::from_str("rules: [{ pattern: '[a-z', action: nxdomain }]").unwrap();
+
+        assert!(config.compile_rules().is_err());
+    }
+
+    #[test]
+    fn compile_rules_once_per_config() {
+        let config: Config = serde_yaml::from_str(DGA_CONFIG).unwrap();
+
+        let rules = compiled_rules(DGA_CONFIG, &config).unwrap();
+        assert!(Rc::ptr_eq(
+            &rules,
+            &compiled_rules(DGA_CONFIG, &config).unwrap()
+        ));
+    }
+}
@@ -0,0 +1,281 @@
+use serde::Deserialize;
+use tracing::{error, info};
+use trust_dns_proto::op::Message;
+use trust_dns_proto::rr::Record;
+
+use crate::helper::{call_next_plugin, load_config, map_get, map_set, random_u64};
+use crate::plugin::{Error, Plugin};
+
+wit_bindgen::generate!("rubydns");
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    #[serde(default)]
+    mode: Mode,
+}
+
+/// how to reorder the records of the same name and type, the record set is never changed
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Mode {
+    /// move the records by an offset which increases every query
+    #[default]
+    Rotate,
+
+    /// shuffle the records randomly
+    Shuffle,
+}
+
+#[derive(Debug)]
+struct RotateRunner;
+
+impl Plugin for RotateRunner {
+    fn run(dns_packet: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let config = load_config();
+        let config: Config = serde_yaml::from_str(&config).map_err(|err| {
+            error!(%err, "load rotate config failed");
+
+            Error {
+                code: 1,
+                msg: err.to_string(),
+            }
+        })?;
+
+        let response_packet = match call_next_plugin(&dns_packet) {
+            None => {
+                return Err(Error {
+                    code: 1,
+                    msg: "no next plugin".to_string(),
+                })
+            }
+
+            Some(result) => result?,
+        };
+
+        let mut response_message = Message::from_vec(&response_packet).map_err(|err| {
+            error!(%err, "decode dns response packet failed");
+
+            Error {
+                code: 1,
+                msg: err.to_string(),
+            }
+        })?;
+
+        if response_message.answers().len() < 2 {
+            return Ok(response_packet);
+        }
+
+        let offset = match (config.mode, response_message.query()) {
+            (Mode::Rotate, Some(query)) => next_offset(&format!(
+                "rotate:{}:{}",
+                query.name().to_lowercase(),
+                query.query_type()
+            )),
+            _ => 0,
+        };
+
+        reorder(
+            response_message.answers_mut(),
+            config.mode,
+            offset,
+            random_u64,
+        );
+
+        info!(mode = ?config.mode, offset, "reorder answers done");
+
+        response_message.to_vec().map_err(|err| {
+            error!(%err, "encode dns response packet failed");
+
+            Error {
+                code: 1,
+                msg: err.to_string(),
+            }
+        })
+    }
+
+    fn valid_config() -> Result<(), Error> {
+        serde_yaml::from_str::<Config>(&load_config()).map_err(|err| {
+            error!(%err, "load rotate config failed");
+
+            Error {
+                code: 1,
+                msg: err.to_string(),
+            }
+        })?;
+
+        Ok(())
+    }
+
+    fn version() -> String {
+        env!("CARGO_PKG_VERSION").to_string()
+    }
+}
+
+/// load the rotation offset of the query and increase the stored one
+fn next_offset(key: &str) -> u64 {
+    let offset = map_get(key.as_bytes())
+        .and_then(|data| data.try_into().ok())
+        .map(u64::from_be_bytes)
+        .unwrap_or(0);
+
+    map_set(key.as_bytes(), &offset.wrapping_add(1).to_be_bytes(), None);
+
+    offset
+}
+
+/// reorder the records of each record set in place, the positions of the record sets are
+/// kept, so a CNAME chain stays in front of its targets
+///
+/// the shuffle mode draws the random numbers from `random`
+fn reorder(answers: &mut [Record], mode: Mode, offset: u64, mut random: impl FnMut() -> u64) {
+    let mut record_sets: Vec<Vec<usize>> = vec![];
+    for (index, answer) in answers.iter().enumerate() {
+        let record_set = record_sets.iter_mut().find(|record_set| {
+            let record = &answers[record_set[0]];
+
+            record.name() == answer.name() && record.record_type() == answer.record_type()
+        });
+
+        match record_set {
+            None => record_sets.push(vec![index]),
+            Some(record_set) => record_set.push(index),
+        }
+    }
+
+    for indexes in record_sets.into_iter().filter(|indexes| indexes.len() > 1) {
+        let len = indexes.len();
+        let order = match mode {
+            Mode::Rotate => (0..len)
+                .map(|slot| (slot + (offset % len as u64) as usize) % len)
+                .collect::<Vec<_>>(),
+
+            Mode::Shuffle => {
+                // Fisher-Yates
+                let mut order = (0..len).collect::<Vec<_>>();
+                for slot in (1..len).rev() {
+                    let other = (random() % (slot as u64 + 1)) as usize;
+                    order.swap(slot, other);
+                }
+
+                order
+            }
+        };
+
+        let records = indexes
+            .iter()
+            .map(|&index| answers[index].clone())
+            .collect::<Vec<_>>();
+        for (slot, &index) in indexes.iter().enumerate() {
+            answers[index] = records[order[slot]].clone();
+        }
+    }
+}
+
+export_rubydns!(RotateRunner);
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::net::Ipv4Addr;
+    use std::str::FromStr;
+
+    use trust_dns_proto::rr::{Name, RData, RecordType};
+
+    use super::*;
+
+    fn a(name: &str, last: u8) -> Record {
+        Record::from_rdata(
+            Name::from_str(name).unwrap(),
+            60,
+            RData::A(Ipv4Addr::new(192, 0, 2, last)),
+        )
+    }
+
+    fn cname(name: &str, target: &str) -> Record {
+        Record::from_rdata(
+            Name::from_str(name).unwrap(),
+            60,
+            RData::CNAME(Name::from_str(target).unwrap()),
+        )
+    }
+
+    /// the last octets of the A records in order
+    fn order(answers: &[Record]) -> Vec<u8> {
+        answers
+            .iter()
+            .filter_map(|answer| match answer.data() {
+                Some(RData::A(ip)) => Some(ip.octets()[3]),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn answers() -> Vec<Record> {
+        vec![
+            cname("www.example.com.", "web.example.com."),
+            a("web.example.com.", 1),
+            a("web.example.com.", 2),
+            a("web.example.com.", 3),
+            a("web.example.com.", 4),
+        ]
+    }
+
+    /// xorshift, so the shuffles are reproducible
+    fn random(mut state: u64) -> impl FnMut() -> u64 {
+        move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+
+            state
+        }
+    }
+
+    #[test]
+    fn rotate_by_offset() {
+        for (offset, expected) in [(0, [1, 2, 3, 4]), (1, [2, 3, 4, 1]), (6, [3, 4, 1, 2])] {
+            let mut answers = answers();
+            reorder(&mut answers, Mode::Rotate, offset, || unreachable!());
+
+            assert_eq!(order(&answers), expected);
+            assert_eq!(answers[0].record_type(), RecordType::CNAME);
+        }
+    }
+
+    #[test]
+    fn shuffle_varies_order_and_keeps_set() {
+        let mut random = random(0x2545_f491_4f6c_dd1d);
+        let mut orders = HashSet::new();
+
+        for _ in 0..100 {
+            let mut answers = answers();
+            reorder(&mut answers, Mode::Shuffle, 0, &mut random);
+
+            let order = order(&answers);
+            let mut sorted = order.clone();
+            sorted.sort_unstable();
+            assert_eq!(sorted, [1, 2, 3, 4]);
+            // the CNAME stays in front of its targets
+            assert_eq!(answers[0].record_type(), RecordType::CNAME);
+
+            orders.insert(order);
+        }
+
+        assert!(orders.len() > 10, "only {} orders", orders.len());
+    }
+
+    #[test]
+    fn reorder_record_sets_separately() {
+        let mut answers = vec![
+            a("a.example.com.", 1),
+            a("a.example.com.", 2),
+            a("b.example.com.", 3),
+            a("b.example.com.", 4),
+        ];
+
+        reorder(&mut answers, Mode::Rotate, 1, || unreachable!());
+
+        assert_eq!(order(&answers), [2, 1, 4, 3]);
+    }
+}
@@ -0,0 +1,85 @@
+use std::net::IpAddr;
+
+use serde::Deserialize;
+use trust_dns_proto::op::{Message, MessageType, ResponseCode};
+use trust_dns_proto::rr::{RData, Record, RecordType};
+
+/// a window action is selected by the `type` field, it is either a [`Route`] or a [`Response`]
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum Action {
+    Route(Route),
+    Respond(Response),
+}
+
+/// the actions passing the query to another plugin
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Route {
+    /// pass the query to the next plugin
+    Next,
+
+    /// pass the query to the named branch of this plugin
+    Branch { name: String },
+}
+
+/// the actions answering the query by this plugin
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Response {
+    Refused,
+
+    Nxdomain,
+
+    /// answer A and AAAA queries with the static addrs
+    Answer {
+        addrs: Vec<IpAddr>,
+        #[serde(default = "default_ttl")]
+        ttl: u32,
+    },
+}
+
+fn default_ttl() -> u32 {
+    60
+}
+
+pub fn create_response(request_message: &Message, response: &Response) -> Message {
+    let mut response_message = Message::new();
+    response_message
+        .set_id(request_message.id())
+        .set_message_type(MessageType::Response)
+        .set_op_code(request_message.op_code())
+        .set_recursion_desired(request_message.recursion_desired())
+        .set_recursion_available(true)
+        .add_queries(request_message.queries().iter().cloned());
+
+    match response {
+        Response::Refused => {
+            response_message.set_response_code(ResponseCode::Refused);
+        }
+
+        Response::Nxdomain => {
+            response_message.set_response_code(ResponseCode::NXDomain);
+        }
+
+        Response::Answer { addrs, ttl } => {
+            for query in request_message.queries() {
+                let answers = addrs.iter().filter_map(|addr| {
+                    let rdata = match (query.query_type(), addr) {
+                        (RecordType::A, IpAddr::V4(addr)) => RData::A(*addr),
+                        (RecordType::AAAA, IpAddr::V6(addr)) => RData::AAAA(*addr),
+                        _ => return None,
+                    };
+
+                    Some(Record::from_rdata(query.name().clone(), *ttl, rdata))
+                });
+
+                response_message.add_answers(answers);
+            }
+
+            response_message.set_response_code(ResponseCode::NoError);
+        }
+    }
+
+    response_message
+}
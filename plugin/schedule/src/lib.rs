@@ -0,0 +1,201 @@
+use plugin_utils::time;
+use serde::Deserialize;
+use tracing::{error, info};
+use trust_dns_proto::op::Message;
+
+use crate::action::{create_response, Action, Route};
+use crate::helper::{call_next_plugin, call_plugin, load_config};
+use crate::plugin::{Error, Plugin};
+use crate::window::{TimeOfDay, Window};
+
+mod action;
+mod window;
+
+wit_bindgen::generate!("rubydns");
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    /// minutes east of UTC used to evaluate the windows
+    #[serde(default)]
+    utc_offset: i32,
+    #[serde(default)]
+    windows: Vec<Window>,
+    #[serde(default = "default_action")]
+    default: Action,
+}
+
+fn default_action() -> Action {
+    Action::Route(Route::Next)
+}
+
+impl Config {
+    fn select_action(&self, now_unix_secs: u64) -> &Action {
+        let now = TimeOfDay::from_unix_secs(now_unix_secs, self.utc_offset);
+
+        self.windows
+            .iter()
+            .find(|window| window.contains(now))
+            .map(|window| &window.action)
+            .unwrap_or(&self.default)
+    }
+}
+
+#[derive(Debug)]
+struct ScheduleRunner;
+
+impl Plugin for ScheduleRunner {
+    fn run(dns_packet: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let config = load_config();
+        let config: Config = serde_yaml::from_str(&config).map_err(|err| {
+            error!(%err, "load schedule config failed");
+
+            Error {
+                code: 1,
+                msg: err.to_string(),
+            }
+        })?;
+
+        let now = time::now_unix_secs();
+
+        let action = config.select_action(now);
+
+        info!(now, ?action, "select schedule action done");
+
+        let response = match action {
+            Action::Route(Route::Next) => {
+                return match call_next_plugin(&dns_packet) {
+                    None => Err(Error {
+                        code: 1,
+                        msg: "no next plugin".to_string(),
+                    }),
+
+                    Some(result) => result,
+                };
+            }
+
+            Action::Route(Route::Branch { name }) => {
+                return match call_plugin(name, &dns_packet) {
+                    None => Err(Error {
+                        code: 1,
+                        msg: format!("branch {name} not exists"),
+                    }),
+
+                    Some(result) => result,
+                };
+            }
+
+            Action::Respond(response) => response,
+        };
+
+        let request_message = Message::from_vec(&dns_packet).map_err(|err| {
+            error!(%err, "decode dns request packet failed");
+
+            Error {
+                code: 1,
+                msg: err.to_string(),
+            }
+        })?;
+
+        let response_message = create_response(&request_message, response);
+
+        response_message.to_vec().map_err(|err| {
+            error!(%err, "encode dns response packet failed");
+
+            Error {
+                code: 1,
+                msg: err.to_string(),
+            }
+        })
+    }
+
+    fn valid_config() -> Result<(), Error> {
+        serde_yaml::from_str::<Config>(&load_config()).map_err(|err| {
+            error!(%err, "load schedule config failed");
+
+            Error {
+                code: 1,
+                msg: err.to_string(),
+            }
+        })?;
+
+        Ok(())
+    }
+
+    fn version() -> String {
+        env!("CARGO_PKG_VERSION").to_string()
+    }
+}
+
+export_rubydns!(ScheduleRunner);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::action::Response;
+
+    const CONFIG: &str = r#"
+utc_offset: 480
+windows:
+  - start: "22:00"
+    end: "06:00"
+    action:
+      type: branch
+      name: maintenance
+  - start: "12:00"
+    end: "13:00"
+    action:
+      type: refused
+default:
+  type: branch
+  name: normal
+"#;
+
+    /// the unix time of the hour and minute on 2023-01-01 in UTC+8
+    fn local_time(hour: u64, minute: u64) -> u64 {
+        1672502400 + hour * 3600 + minute * 60
+    }
+
+    fn branch_name(action: &Action) -> &str {
+        match action {
+            Action::Route(Route::Branch { name }) => name,
+            _ => panic!("action {action:?} is not a branch"),
+        }
+    }
+
+    #[test]
+    fn select_action_by_mocked_clock() {
+        let config: Config = serde_yaml::from_str(CONFIG).unwrap();
+
+        assert_eq!(
+            branch_name(config.select_action(local_time(23, 30))),
+            "maintenance"
+        );
+        assert_eq!(
+            branch_name(config.select_action(local_time(5, 59))),
+            "maintenance"
+        );
+        assert_eq!(
+            branch_name(config.select_action(local_time(6, 0))),
+            "normal"
+        );
+        assert!(matches!(
+            config.select_action(local_time(12, 30)),
+            Action::Respond(Response::Refused)
+        ));
+        assert_eq!(
+            branch_name(config.select_action(local_time(13, 0))),
+            "normal"
+        );
+    }
+
+    #[test]
+    fn default_action_is_next() {
+        let config: Config = serde_yaml::from_str("windows: []").unwrap();
+
+        assert!(matches!(
+            config.select_action(local_time(0, 0)),
+            Action::Route(Route::Next)
+        ));
+    }
+}
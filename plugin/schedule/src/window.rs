@@ -0,0 +1,81 @@
+use std::fmt;
+use std::fmt::{Debug, Formatter};
+
+use serde::de::{Error, Visitor};
+use serde::{Deserialize, Deserializer};
+
+use crate::action::Action;
+
+const MINUTES_PER_DAY: u16 = 24 * 60;
+
+/// minutes since midnight, parsed from `HH:MM`
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub struct TimeOfDay(u16);
+
+impl TimeOfDay {
+    pub fn from_unix_secs(secs: u64, utc_offset: i32) -> Self {
+        let minutes = (secs / 60) as i64 + utc_offset as i64;
+
+        Self(minutes.rem_euclid(MINUTES_PER_DAY as _) as _)
+    }
+}
+
+impl Debug for TimeOfDay {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02}:{:02}", self.0 / 60, self.0 % 60)
+    }
+}
+
+impl<'de> Deserialize<'de> for TimeOfDay {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_str(TimeOfDayVisitor)
+    }
+}
+
+struct TimeOfDayVisitor;
+
+impl<'a> Visitor<'a> for TimeOfDayVisitor {
+    type Value = TimeOfDay;
+
+    fn expecting(&self, formatter: &mut Formatter) -> fmt::Result {
+        formatter.write_str("a time of day like HH:MM")
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+    where
+        E: Error,
+    {
+        let (hour, minute) = v
+            .split_once(':')
+            .ok_or_else(|| Error::custom(format!("invalid time of day {v}")))?;
+        let hour = hour.parse::<u16>().map_err(Error::custom)?;
+        let minute = minute.parse::<u16>().map_err(Error::custom)?;
+
+        if hour > 23 || minute > 59 {
+            return Err(Error::custom(format!("invalid time of day {v}")));
+        }
+
+        Ok(TimeOfDay(hour * 60 + minute))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Window {
+    pub start: TimeOfDay,
+    pub end: TimeOfDay,
+    pub action: Action,
+}
+
+impl Window {
+    /// the window is `[start, end)`, when start is after end, the window crosses midnight
+    pub fn contains(&self, now: TimeOfDay) -> bool {
+        if self.start <= self.end {
+            self.start <= now && now < self.end
+        } else {
+            self.start <= now || now < self.end
+        }
+    }
+}
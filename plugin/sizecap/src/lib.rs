@@ -0,0 +1,244 @@
+use serde::Deserialize;
+use tracing::{error, info};
+use trust_dns_proto::op::Message;
+
+use crate::helper::{call_next_plugin, load_config, request_transport, Transport};
+use crate::plugin::{Error, Plugin};
+
+wit_bindgen::generate!("rubydns");
+
+/// the max udp response size when the client doesn't use EDNS
+const CLASSIC_UDP_SIZE: u16 = 512;
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    /// override the udp response size limit instead of using the client EDNS payload size
+    max_udp_size: Option<u16>,
+}
+
+#[derive(Debug)]
+struct SizecapRunner;
+
+impl Plugin for SizecapRunner {
+    fn run(dns_packet: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let config = load_config();
+        let config: Config = serde_yaml::from_str(&config).map_err(|err| {
+            error!(%err, "load sizecap config failed");
+
+            Error {
+                code: 1,
+                msg: err.to_string(),
+            }
+        })?;
+
+        let response_packet = match call_next_plugin(&dns_packet) {
+            None => {
+                return Err(Error {
+                    code: 1,
+                    msg: "no next plugin".to_string(),
+                })
+            }
+
+            Some(result) => result?,
+        };
+
+        cap_response(&config, request_transport(), &dns_packet, response_packet)
+    }
+
+    fn valid_config() -> Result<(), Error> {
+        serde_yaml::from_str::<Config>(&load_config()).map_err(|err| {
+            error!(%err, "load sizecap config failed");
+
+            Error {
+                code: 1,
+                msg: err.to_string(),
+            }
+        })?;
+
+        Ok(())
+    }
+
+    fn version() -> String {
+        env!("CARGO_PKG_VERSION").to_string()
+    }
+}
+
+/// truncate the UDP response exceeding the size limit, the responses of other transports are
+/// returned as they are
+fn cap_response(
+    config: &Config,
+    transport: Transport,
+    dns_packet: &[u8],
+    response_packet: Vec<u8>,
+) -> Result<Vec<u8>, Error> {
+    if transport != Transport::Udp {
+        return Ok(response_packet);
+    }
+
+    let max_size = match config.max_udp_size {
+        Some(max_size) => max_size,
+        None => {
+            let request_message = Message::from_vec(dns_packet).map_err(|err| {
+                error!(%err, "decode dns request packet failed");
+
+                Error {
+                    code: 1,
+                    msg: err.to_string(),
+                }
+            })?;
+
+            request_message
+                .extensions()
+                .as_ref()
+                .map(|edns| edns.max_payload())
+                .unwrap_or(CLASSIC_UDP_SIZE)
+                .max(CLASSIC_UDP_SIZE)
+        }
+    };
+
+    if response_packet.len() <= max_size as usize {
+        return Ok(response_packet);
+    }
+
+    info!(
+        size = response_packet.len(),
+        max_size, "response exceeds the udp size limit, truncate it"
+    );
+
+    truncate(&response_packet)
+}
+
+/// keep the header, question and OPT record only, and set the TC bit so the client retries
+/// with tcp
+fn truncate(response_packet: &[u8]) -> Result<Vec<u8>, Error> {
+    let response_message = Message::from_vec(response_packet).map_err(|err| {
+        error!(%err, "decode dns response packet failed");
+
+        Error {
+            code: 1,
+            msg: err.to_string(),
+        }
+    })?;
+
+    let mut truncated_message = Message::new();
+    truncated_message
+        .set_header(*response_message.header())
+        .set_truncated(true)
+        .add_queries(response_message.queries().iter().cloned());
+    if let Some(edns) = response_message.extensions() {
+        truncated_message.set_edns(edns.clone());
+    }
+
+    truncated_message.to_vec().map_err(|err| {
+        error!(%err, "encode truncated dns response packet failed");
+
+        Error {
+            code: 1,
+            msg: err.to_string(),
+        }
+    })
+}
+
+export_rubydns!(SizecapRunner);
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::str::FromStr;
+
+    use trust_dns_proto::op::{Edns, MessageType, Query};
+    use trust_dns_proto::rr::{Name, RData, Record, RecordType};
+
+    use super::*;
+
+    fn request(max_payload: Option<u16>) -> Message {
+        let mut message = Message::new();
+        message.set_id(1).add_query(Query::query(
+            Name::from_str("www.example.com.").unwrap(),
+            RecordType::A,
+        ));
+        if let Some(max_payload) = max_payload {
+            let mut edns = Edns::new();
+            edns.set_max_payload(max_payload);
+            message.set_edns(edns);
+        }
+
+        message
+    }
+
+    /// a response of about 16 bytes per answer
+    fn response(request: &Message, answers: u8) -> Vec<u8> {
+        let mut message = request.clone();
+        message
+            .set_message_type(MessageType::Response)
+            .add_answers((0..answers).map(|i| {
+                Record::from_rdata(
+                    Name::from_str("www.example.com.").unwrap(),
+                    60,
+                    RData::A(Ipv4Addr::new(192, 0, 2, i)),
+                )
+            }));
+
+        message.to_vec().unwrap()
+    }
+
+    fn config(max_udp_size: Option<u16>) -> Config {
+        Config { max_udp_size }
+    }
+
+    fn cap(config: &Config, transport: Transport, request: &Message, answers: u8) -> Message {
+        let response_packet = cap_response(
+            config,
+            transport,
+            &request.to_vec().unwrap(),
+            response(request, answers),
+        )
+        .unwrap();
+
+        Message::from_vec(&response_packet).unwrap()
+    }
+
+    #[test]
+    fn truncate_large_udp_response() {
+        let request = request(None);
+
+        let response = cap(&config(None), Transport::Udp, &request, 100);
+
+        assert!(response.truncated());
+        assert!(response.answers().is_empty());
+        assert_eq!(response.id(), 1);
+        assert_eq!(response.queries(), request.queries());
+    }
+
+    #[test]
+    fn keep_udp_response_in_edns_payload_size() {
+        let request = request(Some(4096));
+
+        let response = cap(&config(None), Transport::Udp, &request, 100);
+
+        assert!(!response.truncated());
+        assert_eq!(response.answers().len(), 100);
+    }
+
+    #[test]
+    fn max_udp_size_overrides_edns_payload_size() {
+        let request = request(Some(4096));
+
+        let response = cap(&config(Some(512)), Transport::Udp, &request, 100);
+
+        assert!(response.truncated());
+        assert!(response.answers().is_empty());
+        assert!(response.extensions().is_some());
+    }
+
+    #[test]
+    fn pass_through_tcp_response() {
+        let request = request(None);
+
+        let response = cap(&config(None), Transport::Tcp, &request, 100);
+
+        assert!(!response.truncated());
+        assert_eq!(response.answers().len(), 100);
+    }
+}
@@ -0,0 +1,205 @@
+use serde::Deserialize;
+use tracing::{error, info};
+use trust_dns_proto::op::{Message, MessageType};
+
+use crate::helper::{call_next_plugin, load_config};
+use crate::plugin::{Error, Plugin};
+use crate::zone::Zone;
+
+mod zone;
+
+wit_bindgen::generate!("rubydns");
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    /// the special-use zones answered locally, other names are passed to the next plugin
+    #[serde(default = "default_zones")]
+    zones: Vec<Zone>,
+}
+
+fn default_zones() -> Vec<Zone> {
+    vec![Zone::Localhost, Zone::LocalhostReverse]
+}
+
+#[derive(Debug)]
+struct SpecialUseRunner;
+
+impl Plugin for SpecialUseRunner {
+    fn run(dns_packet: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let config = load_config();
+        let config: Config = serde_yaml::from_str(&config).map_err(|err| {
+            error!(%err, "load special-use config failed");
+
+            Error {
+                code: 1,
+                msg: err.to_string(),
+            }
+        })?;
+
+        let request_message = Message::from_vec(&dns_packet).map_err(|err| {
+            error!(%err, "decode dns request packet failed");
+
+            Error {
+                code: 1,
+                msg: err.to_string(),
+            }
+        })?;
+
+        let response_message = match local_response(&config.zones, &request_message) {
+            None => {
+                return match call_next_plugin(&dns_packet) {
+                    None => Err(Error {
+                        code: 1,
+                        msg: "no next plugin".to_string(),
+                    }),
+
+                    Some(result) => result,
+                }
+            }
+
+            Some(response_message) => response_message,
+        };
+
+        response_message.to_vec().map_err(|err| {
+            error!(%err, "encode dns response packet failed");
+
+            Error {
+                code: 1,
+                msg: err.to_string(),
+            }
+        })
+    }
+
+    fn valid_config() -> Result<(), Error> {
+        serde_yaml::from_str::<Config>(&load_config()).map_err(|err| {
+            error!(%err, "load special-use config failed");
+
+            Error {
+                code: 1,
+                msg: err.to_string(),
+            }
+        })?;
+
+        Ok(())
+    }
+
+    fn version() -> String {
+        env!("CARGO_PKG_VERSION").to_string()
+    }
+}
+
+/// answer the query in the special-use zones, [`None`] means the query is passed to the next
+/// plugin
+fn local_response(zones: &[Zone], request_message: &Message) -> Option<Message> {
+    let query = request_message.query()?;
+    let zone = zones.iter().find(|zone| zone.contains(query.name()))?;
+
+    let (response_code, answers) = zone.answer(query);
+
+    info!(
+        ?query,
+        ?zone,
+        ?response_code,
+        "answer special-use name locally"
+    );
+
+    let mut response_message = Message::new();
+    response_message
+        .set_id(request_message.id())
+        .set_message_type(MessageType::Response)
+        .set_op_code(request_message.op_code())
+        .set_authoritative(true)
+        .set_recursion_desired(request_message.recursion_desired())
+        .set_recursion_available(true)
+        .set_response_code(response_code)
+        .add_queries(request_message.queries().iter().cloned())
+        .add_answers(answers);
+
+    Some(response_message)
+}
+
+export_rubydns!(SpecialUseRunner);
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+    use std::str::FromStr;
+
+    use trust_dns_proto::op::{Query, ResponseCode};
+    use trust_dns_proto::rr::{Name, RData, RecordType};
+
+    use super::*;
+
+    fn request(name: &str, query_type: RecordType) -> Message {
+        let mut message = Message::new();
+        message
+            .set_id(7)
+            .add_query(Query::query(Name::from_str(name).unwrap(), query_type));
+
+        message
+    }
+
+    fn answer(zones: &[Zone], name: &str, query_type: RecordType) -> Option<Message> {
+        local_response(zones, &request(name, query_type))
+    }
+
+    #[test]
+    fn answer_localhost() {
+        let response = answer(&default_zones(), "localhost.", RecordType::A).unwrap();
+
+        assert_eq!(response.id(), 7);
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        assert!(response.authoritative());
+        assert_eq!(response.answers().len(), 1);
+        assert_eq!(
+            response.answers()[0].data(),
+            Some(&RData::A(Ipv4Addr::LOCALHOST))
+        );
+
+        let response = answer(&default_zones(), "www.localhost.", RecordType::AAAA).unwrap();
+        assert_eq!(
+            response.answers()[0].data(),
+            Some(&RData::AAAA(Ipv6Addr::LOCALHOST))
+        );
+    }
+
+    #[test]
+    fn answer_localhost_reverse() {
+        let response =
+            answer(&default_zones(), "1.0.0.127.in-addr.arpa.", RecordType::PTR).unwrap();
+
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        assert_eq!(
+            response.answers()[0].data(),
+            Some(&RData::PTR(Name::from_str("localhost.").unwrap()))
+        );
+    }
+
+    #[test]
+    fn no_data_for_other_localhost_types() {
+        let response = answer(&default_zones(), "localhost.", RecordType::MX).unwrap();
+
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        assert!(response.answers().is_empty());
+    }
+
+    #[test]
+    fn pass_other_names() {
+        assert!(answer(&default_zones(), "www.example.net.", RecordType::A).is_none());
+        // the other zones are only handled when configured
+        assert!(answer(&default_zones(), "foo.invalid.", RecordType::A).is_none());
+    }
+
+    #[test]
+    fn nxdomain_for_configured_zones() {
+        let zones = [Zone::Invalid, Zone::Test, Zone::Example];
+
+        for name in ["foo.invalid.", "foo.test.", "www.example.com."] {
+            let response = answer(&zones, name, RecordType::A).unwrap();
+
+            assert_eq!(response.response_code(), ResponseCode::NXDomain);
+            assert!(response.answers().is_empty());
+        }
+    }
+}
@@ -0,0 +1,73 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use serde::Deserialize;
+use trust_dns_proto::op::{Query, ResponseCode};
+use trust_dns_proto::rr::{Name, RData, Record, RecordType};
+
+/// the ttl of the local answers
+const TTL: u32 = 86400;
+
+/// the special-use zones defined by RFC 6761
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Zone {
+    /// `localhost` and its subdomains
+    Localhost,
+
+    /// `127.in-addr.arpa` and the ::1 reverse name
+    LocalhostReverse,
+
+    Invalid,
+
+    Test,
+
+    Example,
+}
+
+impl Zone {
+    fn origins(&self) -> Vec<Name> {
+        let origins: &[&str] = match self {
+            Zone::Localhost => &["localhost."],
+            Zone::LocalhostReverse => &[
+                "127.in-addr.arpa.",
+                "1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.ip6.arpa.",
+            ],
+            Zone::Invalid => &["invalid."],
+            Zone::Test => &["test."],
+            Zone::Example => &["example.", "example.com.", "example.net.", "example.org."],
+        };
+
+        origins
+            .iter()
+            .map(|origin| Name::from_ascii(origin).expect("special-use origin is valid"))
+            .collect()
+    }
+
+    pub fn contains(&self, name: &Name) -> bool {
+        self.origins().iter().any(|origin| origin.zone_of(name))
+    }
+
+    /// answer the query locally, the names which don't exist in the real DNS get NXDOMAIN
+    pub fn answer(&self, query: &Query) -> (ResponseCode, Vec<Record>) {
+        let rdata = match (self, query.query_type()) {
+            (Zone::Localhost, RecordType::A) => RData::A(Ipv4Addr::LOCALHOST),
+            (Zone::Localhost, RecordType::AAAA) => RData::AAAA(Ipv6Addr::LOCALHOST),
+            (Zone::LocalhostReverse, RecordType::PTR) => {
+                RData::PTR(Name::from_ascii("localhost.").expect("localhost is valid"))
+            }
+
+            (Zone::Localhost | Zone::LocalhostReverse, _) => {
+                return (ResponseCode::NoError, vec![]);
+            }
+
+            (Zone::Invalid | Zone::Test | Zone::Example, _) => {
+                return (ResponseCode::NXDomain, vec![]);
+            }
+        };
+
+        (
+            ResponseCode::NoError,
+            vec![Record::from_rdata(query.name().clone(), TTL, rdata)],
+        )
+    }
+}
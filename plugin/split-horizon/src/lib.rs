@@ -0,0 +1,342 @@
+use std::net::IpAddr;
+
+use plugin_utils::net::client_addr;
+use plugin_utils::subnet::Subnet;
+use plugin_utils::DROP_ERROR_CODE;
+use serde::Deserialize;
+use tracing::{error, info};
+use trust_dns_proto::op::{Message, MessageType, ResponseCode};
+use trust_dns_proto::rr::{RData, Record, RecordType};
+
+use crate::helper::{call_next_plugin, call_plugin, load_config};
+use crate::plugin::{Error, Plugin};
+
+wit_bindgen::generate!("rubydns");
+
+/// handle the queries differently by the client subnet, such as answering the internal
+/// clients with the internal addresses
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    /// the most specific subnet containing the client wins, the first rule wins among the
+    /// rules with the same subnet prefix length
+    rules: Vec<Rule>,
+    /// the action of the clients matching no rule and the queries without client, such as
+    /// the prewarm queries
+    #[serde(default = "default_action")]
+    default: Action,
+}
+
+fn default_action() -> Action {
+    Action::Next
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Rule {
+    subnet: String,
+    action: Action,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Action {
+    /// pass the query to the next plugin
+    Next,
+
+    /// pass the query to the named branch of this plugin
+    Branch {
+        name: String,
+    },
+
+    /// drop the query without any response
+    Drop,
+
+    Refused,
+
+    /// answer A and AAAA queries with the static addrs, other query types get an empty
+    /// answer
+    Answer {
+        addrs: Vec<IpAddr>,
+        #[serde(default = "default_ttl")]
+        ttl: u32,
+    },
+}
+
+fn default_ttl() -> u32 {
+    60
+}
+
+impl Config {
+    fn rules(&self) -> Result<Vec<(Subnet, &Action)>, Error> {
+        self.rules
+            .iter()
+            .map(|rule| {
+                let subnet = rule.subnet.parse::<Subnet>().map_err(|err| {
+                    error!(%err, subnet = rule.subnet, "invalid split horizon subnet");
+
+                    Error { code: 1, msg: err }
+                })?;
+
+                Ok((subnet, &rule.action))
+            })
+            .collect()
+    }
+
+    fn select_action(&self, client_ip: Option<IpAddr>) -> Result<&Action, Error> {
+        let client_ip = match client_ip {
+            None => return Ok(&self.default),
+            Some(client_ip) => client_ip,
+        };
+
+        // max_by_key returns the last max one, reverse so the first rule wins the tie
+        let action = self
+            .rules()?
+            .into_iter()
+            .rev()
+            .filter(|(subnet, _)| subnet.contains(client_ip))
+            .max_by_key(|(subnet, _)| subnet.prefix())
+            .map(|(_, action)| action)
+            .unwrap_or(&self.default);
+
+        Ok(action)
+    }
+}
+
+#[derive(Debug)]
+struct SplitHorizonRunner;
+
+impl Plugin for SplitHorizonRunner {
+    fn run(dns_packet: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let config = load_config();
+        let config: Config = serde_yaml::from_str(&config).map_err(|err| {
+            error!(%err, "load split horizon config failed");
+
+            Error {
+                code: 1,
+                msg: err.to_string(),
+            }
+        })?;
+
+        let client_ip = client_addr().map(|client_addr| client_addr.ip());
+
+        let action = config.select_action(client_ip)?;
+
+        info!(?client_ip, ?action, "select split horizon action done");
+
+        match action {
+            Action::Next => call_next(&dns_packet),
+
+            Action::Branch { name } => match call_plugin(name, &dns_packet) {
+                None => Err(Error {
+                    code: 1,
+                    msg: format!("branch {name} not exists"),
+                }),
+
+                Some(result) => result,
+            },
+
+            Action::Drop => Err(Error {
+                code: DROP_ERROR_CODE,
+                msg: format!("drop query of client {client_ip:?}"),
+            }),
+
+            Action::Refused | Action::Answer { .. } => {
+                let request_message = Message::from_vec(&dns_packet).map_err(|err| {
+                    error!(%err, "decode dns request packet failed");
+
+                    Error {
+                        code: 1,
+                        msg: err.to_string(),
+                    }
+                })?;
+
+                create_response(&request_message, action)
+                    .to_vec()
+                    .map_err(|err| {
+                        error!(%err, "encode dns response packet failed");
+
+                        Error {
+                            code: 1,
+                            msg: err.to_string(),
+                        }
+                    })
+            }
+        }
+    }
+
+    fn valid_config() -> Result<(), Error> {
+        let config: Config = serde_yaml::from_str(&load_config()).map_err(|err| {
+            error!(%err, "load split horizon config failed");
+
+            Error {
+                code: 1,
+                msg: err.to_string(),
+            }
+        })?;
+
+        config.rules()?;
+
+        Ok(())
+    }
+
+    fn version() -> String {
+        env!("CARGO_PKG_VERSION").to_string()
+    }
+}
+
+fn create_response(request_message: &Message, action: &Action) -> Message {
+    let mut response_message = Message::new();
+    response_message
+        .set_id(request_message.id())
+        .set_message_type(MessageType::Response)
+        .set_op_code(request_message.op_code())
+        .set_recursion_desired(request_message.recursion_desired())
+        .set_recursion_available(true)
+        .add_queries(request_message.queries().iter().cloned());
+
+    match action {
+        Action::Next | Action::Branch { .. } | Action::Drop => {
+            unreachable!("action {action:?} doesn't create response")
+        }
+
+        Action::Refused => {
+            response_message.set_response_code(ResponseCode::Refused);
+        }
+
+        Action::Answer { addrs, ttl } => {
+            for query in request_message.queries() {
+                let answers = addrs.iter().filter_map(|addr| {
+                    let rdata = match (query.query_type(), addr) {
+                        (RecordType::A, IpAddr::V4(addr)) => RData::A(*addr),
+                        (RecordType::AAAA, IpAddr::V6(addr)) => RData::AAAA(*addr),
+                        _ => return None,
+                    };
+
+                    Some(Record::from_rdata(query.name().clone(), *ttl, rdata))
+                });
+
+                response_message.add_answers(answers);
+            }
+
+            response_message.set_response_code(ResponseCode::NoError);
+        }
+    }
+
+    response_message
+}
+
+fn call_next(dns_packet: &[u8]) -> Result<Vec<u8>, Error> {
+    match call_next_plugin(dns_packet) {
+        None => Err(Error {
+            code: 1,
+            msg: "no next plugin".to_string(),
+        }),
+
+        Some(result) => result,
+    }
+}
+
+export_rubydns!(SplitHorizonRunner);
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::str::FromStr;
+
+    use trust_dns_proto::op::Query;
+    use trust_dns_proto::rr::Name;
+
+    use super::*;
+
+    const CONFIG: &str = "
+rules:
+  - { subnet: 10.0.0.0/8, action: { type: refused } }
+  - { subnet: 10.1.0.0/16, action: { type: answer, addrs: [10.1.0.1] } }
+  - { subnet: 10.1.2.0/24, action: { type: branch, name: lab } }
+  - { subnet: 10.1.2.0/24, action: { type: drop } }
+  - { subnet: 'fd00::/8', action: { type: drop } }
+";
+
+    fn select<'a>(config: &'a Config, client_ip: Option<&str>) -> &'a Action {
+        config
+            .select_action(client_ip.map(|client_ip| client_ip.parse().unwrap()))
+            .unwrap()
+    }
+
+    #[test]
+    fn most_specific_subnet_wins() {
+        let config = serde_yaml::from_str::<Config>(CONFIG).unwrap();
+
+        assert!(matches!(select(&config, Some("10.9.0.1")), Action::Refused));
+        assert!(matches!(
+            select(&config, Some("10.1.9.1")),
+            Action::Answer { .. }
+        ));
+        assert!(matches!(select(&config, Some("fd00::1")), Action::Drop));
+    }
+
+    #[test]
+    fn first_rule_wins_among_same_prefix() {
+        let config = serde_yaml::from_str::<Config>(CONFIG).unwrap();
+
+        assert!(matches!(
+            select(&config, Some("10.1.2.3")),
+            Action::Branch { name } if name == "lab"
+        ));
+    }
+
+    #[test]
+    fn default_action_without_match_or_client() {
+        let config = serde_yaml::from_str::<Config>(CONFIG).unwrap();
+
+        assert!(matches!(select(&config, Some("192.0.2.1")), Action::Next));
+        assert!(matches!(select(&config, None), Action::Next));
+
+        let config = serde_yaml::from_str::<Config>("{rules: [], default: {type: drop}}").unwrap();
+        assert!(matches!(select(&config, Some("192.0.2.1")), Action::Drop));
+    }
+
+    #[test]
+    fn reject_invalid_subnet() {
+        let config = serde_yaml::from_str::<Config>(
+            "rules: [{ subnet: 10.0.0.0/33, action: { type: drop } }]",
+        )
+        .unwrap();
+
+        assert!(config
+            .select_action(Some(IpAddr::from([10, 0, 0, 1])))
+            .is_err());
+    }
+
+    #[test]
+    fn answer_the_static_addrs() {
+        let mut request_message = Message::new();
+        request_message.set_id(1234).add_query(Query::query(
+            Name::from_str("www.example.com.").unwrap(),
+            RecordType::A,
+        ));
+        let action = Action::Answer {
+            addrs: vec![
+                IpAddr::from([192, 0, 2, 1]),
+                IpAddr::from_str("2001:db8::1").unwrap(),
+            ],
+            ttl: 30,
+        };
+
+        let response_message = create_response(&request_message, &action);
+
+        assert_eq!(response_message.id(), 1234);
+        assert_eq!(response_message.response_code(), ResponseCode::NoError);
+        assert_eq!(response_message.answers().len(), 1);
+        assert_eq!(response_message.answers()[0].ttl(), 30);
+        assert_eq!(
+            response_message.answers()[0].data(),
+            Some(&RData::A(Ipv4Addr::new(192, 0, 2, 1)))
+        );
+
+        let response_message = create_response(&request_message, &Action::Refused);
+        assert_eq!(response_message.response_code(), ResponseCode::Refused);
+        assert!(response_message.answers().is_empty());
+    }
+}
@@ -0,0 +1,157 @@
+use std::net::IpAddr;
+
+use serde::Deserialize;
+use tracing::{error, info, warn};
+use trust_dns_proto::op::{Message, MessageType, ResponseCode};
+use trust_dns_proto::rr::{RData, Record, RecordType};
+
+use crate::helper::{call_next_plugin, load_config, static_map_get};
+use crate::plugin::{Error, Plugin};
+
+wit_bindgen::generate!("rubydns");
+
+/// answer A and AAAA queries from a precompiled FST, which is built offline
+///
+/// the FST keys are the lowercase names without the trailing dot, the values are the
+/// offsets of whitespace separated addresses in the `<fst path>.data` file
+///
+/// the FST is opened by the host, it is configured in the `static_maps` of the plugin, such
+/// as `static_maps: { zone: corp.fst }`
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    /// the name of the static map
+    #[serde(default = "default_map")]
+    map: String,
+    #[serde(default = "default_ttl")]
+    ttl: u32,
+}
+
+fn default_map() -> String {
+    "zone".to_string()
+}
+
+fn default_ttl() -> u32 {
+    300
+}
+
+#[derive(Debug)]
+struct StaticZoneRunner;
+
+impl Plugin for StaticZoneRunner {
+    fn run(dns_packet: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let config = load_config();
+        let config: Config = serde_yaml::from_str(&config).map_err(|err| {
+            error!(%err, "load static zone config failed");
+
+            Error {
+                code: 1,
+                msg: err.to_string(),
+            }
+        })?;
+
+        let request_message = Message::from_vec(&dns_packet).map_err(|err| {
+            error!(%err, "decode dns request packet failed");
+
+            Error {
+                code: 1,
+                msg: err.to_string(),
+            }
+        })?;
+
+        let query = match request_message.query() {
+            Some(query) if matches!(query.query_type(), RecordType::A | RecordType::AAAA) => query,
+            _ => return call_next(&dns_packet),
+        };
+
+        let key = query.name().to_lowercase().to_ascii();
+        let key = key.trim_end_matches('.');
+        let addrs = match static_map_get(&config.map, key.as_bytes())? {
+            None => return call_next(&dns_packet),
+            Some(addrs) => addrs,
+        };
+
+        let addrs = String::from_utf8_lossy(&addrs);
+        let answers = addrs
+            .split_whitespace()
+            .filter_map(|addr| match addr.parse::<IpAddr>() {
+                Err(err) => {
+                    warn!(%err, addr, name = key, "invalid address in static zone");
+
+                    None
+                }
+
+                Ok(addr) => Some(addr),
+            })
+            .filter_map(|addr| {
+                let rdata = match (query.query_type(), addr) {
+                    (RecordType::A, IpAddr::V4(addr)) => RData::A(addr),
+                    (RecordType::AAAA, IpAddr::V6(addr)) => RData::AAAA(addr),
+                    _ => return None,
+                };
+
+                Some(Record::from_rdata(query.name().clone(), config.ttl, rdata))
+            })
+            .collect::<Vec<_>>();
+
+        info!(
+            name = key,
+            answers = answers.len(),
+            "answer from static zone"
+        );
+
+        let mut response_message = Message::new();
+        response_message
+            .set_id(request_message.id())
+            .set_message_type(MessageType::Response)
+            .set_op_code(request_message.op_code())
+            .set_authoritative(true)
+            .set_recursion_desired(request_message.recursion_desired())
+            .set_recursion_available(true)
+            .set_response_code(ResponseCode::NoError)
+            .add_queries(request_message.queries().iter().cloned())
+            .add_answers(answers);
+
+        response_message.to_vec().map_err(|err| {
+            error!(%err, "encode dns response packet failed");
+
+            Error {
+                code: 1,
+                msg: err.to_string(),
+            }
+        })
+    }
+
+    fn valid_config() -> Result<(), Error> {
+        let config: Config = serde_yaml::from_str(&load_config()).map_err(|err| {
+            error!(%err, "load static zone config failed");
+
+            Error {
+                code: 1,
+                msg: err.to_string(),
+            }
+        })?;
+
+        // a map which isn't configured fails at startup
+        static_map_get(&config.map, b"")?;
+
+        Ok(())
+    }
+
+    fn version() -> String {
+        env!("CARGO_PKG_VERSION").to_string()
+    }
+}
+
+fn call_next(dns_packet: &[u8]) -> Result<Vec<u8>, Error> {
+    match call_next_plugin(dns_packet) {
+        None => Err(Error {
+            code: 1,
+            msg: "no next plugin".to_string(),
+        }),
+
+        Some(result) => result,
+    }
+}
+
+export_rubydns!(StaticZoneRunner);
@@ -0,0 +1,288 @@
+use std::net::IpAddr;
+
+use plugin_utils::net::client_addr;
+use serde::Deserialize;
+use tracing::{error, info};
+use trust_dns_proto::op::{Message, MessageType, ResponseCode};
+
+use crate::helper::{
+    call_next_plugin, load_config, map_get, map_set, request_transport, Transport,
+};
+use crate::plugin::{Error, Plugin};
+
+wit_bindgen::generate!("rubydns");
+
+/// a lightweight anti-spoofing measure without cookies: the UDP queries of a client are
+/// answered with TC=1 until the client queries over TCP, which proves the client owns the
+/// source address
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    #[serde(default = "default_require_tcp_first")]
+    require_tcp_first: bool,
+    /// how long a client is trusted over UDP after its TCP query
+    #[serde(default = "default_verification_ttl")]
+    verification_ttl: u64,
+}
+
+fn default_require_tcp_first() -> bool {
+    true
+}
+
+fn default_verification_ttl() -> u64 {
+    3600
+}
+
+#[derive(Debug)]
+struct TcpFirstRunner;
+
+impl Plugin for TcpFirstRunner {
+    fn run(dns_packet: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let config = load_config();
+        let config: Config = serde_yaml::from_str(&config).map_err(|err| {
+            error!(%err, "load tcp first config failed");
+
+            Error {
+                code: 1,
+                msg: err.to_string(),
+            }
+        })?;
+
+        // the queries without client, such as the prewarm queries, are always answered
+        let client_ip = client_addr().map(|client_addr| client_addr.ip().to_canonical());
+
+        guard_query(
+            &config,
+            &dns_packet,
+            client_ip,
+            request_transport(),
+            &HostStore,
+            call_next,
+        )
+    }
+
+    fn valid_config() -> Result<(), Error> {
+        let config: Config = serde_yaml::from_str(&load_config()).map_err(|err| {
+            error!(%err, "load tcp first config failed");
+
+            Error {
+                code: 1,
+                msg: err.to_string(),
+            }
+        })?;
+
+        if config.verification_ttl == 0 {
+            return Err(Error {
+                code: 1,
+                msg: "verification_ttl must be positive".to_string(),
+            });
+        }
+
+        Ok(())
+    }
+
+    fn version() -> String {
+        env!("CARGO_PKG_VERSION").to_string()
+    }
+}
+
+trait Store {
+    fn contains(&self, key: &str) -> bool;
+
+    fn set(&self, key: &str, timeout_secs: u64);
+}
+
+struct HostStore;
+
+impl Store for HostStore {
+    fn contains(&self, key: &str) -> bool {
+        map_get(key.as_bytes()).is_some()
+    }
+
+    fn set(&self, key: &str, timeout_secs: u64) {
+        map_set(key.as_bytes(), &[], Some(timeout_secs))
+    }
+}
+
+/// answer the UDP query of an unverified client with TC=1, a TCP query verifies its client,
+/// the other queries are passed by `exchange`
+fn guard_query(
+    config: &Config,
+    dns_packet: &[u8],
+    client_ip: Option<IpAddr>,
+    transport: Transport,
+    store: &impl Store,
+    exchange: impl FnOnce(&[u8]) -> Result<Vec<u8>, Error>,
+) -> Result<Vec<u8>, Error> {
+    if !config.require_tcp_first {
+        return exchange(dns_packet);
+    }
+
+    let client_ip = match client_ip {
+        None => return exchange(dns_packet),
+        Some(client_ip) => client_ip,
+    };
+    let key = format!("tcp-first:verified:{client_ip}");
+
+    match transport {
+        Transport::Tcp => {
+            store.set(&key, config.verification_ttl);
+
+            exchange(dns_packet)
+        }
+
+        Transport::Udp if store.contains(&key) => exchange(dns_packet),
+
+        Transport::Udp => {
+            info!(%client_ip, "unverified udp client, ask it to retry over tcp");
+
+            let request_message = Message::from_vec(dns_packet).map_err(|err| {
+                error!(%err, "decode dns request packet failed");
+
+                Error {
+                    code: 1,
+                    msg: err.to_string(),
+                }
+            })?;
+
+            truncated_response(&request_message)
+        }
+    }
+}
+
+/// an empty response with TC=1, the client retries the query over TCP
+fn truncated_response(request_message: &Message) -> Result<Vec<u8>, Error> {
+    let mut response_message = Message::new();
+    response_message
+        .set_id(request_message.id())
+        .set_message_type(MessageType::Response)
+        .set_op_code(request_message.op_code())
+        .set_recursion_desired(request_message.recursion_desired())
+        .set_recursion_available(true)
+        .set_truncated(true)
+        .set_response_code(ResponseCode::NoError)
+        .add_queries(request_message.queries().iter().cloned());
+
+    response_message.to_vec().map_err(|err| {
+        error!(%err, "encode dns response packet failed");
+
+        Error {
+            code: 1,
+            msg: err.to_string(),
+        }
+    })
+}
+
+fn call_next(dns_packet: &[u8]) -> Result<Vec<u8>, Error> {
+    match call_next_plugin(dns_packet) {
+        None => Err(Error {
+            code: 1,
+            msg: "no next plugin".to_string(),
+        }),
+
+        Some(result) => result,
+    }
+}
+
+export_rubydns!(TcpFirstRunner);
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::collections::HashSet;
+    use std::str::FromStr;
+
+    use trust_dns_proto::op::Query;
+    use trust_dns_proto::rr::{Name, RecordType};
+
+    use super::*;
+
+    #[derive(Default)]
+    struct SetStore(RefCell<HashSet<String>>);
+
+    impl Store for SetStore {
+        fn contains(&self, key: &str) -> bool {
+            self.0.borrow().contains(key)
+        }
+
+        fn set(&self, key: &str, _timeout_secs: u64) {
+            self.0.borrow_mut().insert(key.to_string());
+        }
+    }
+
+    fn config() -> Config {
+        serde_yaml::from_str("{}").unwrap()
+    }
+
+    fn request() -> Vec<u8> {
+        let mut message = Message::new();
+        message.set_id(1234).add_query(Query::query(
+            Name::from_str("www.example.com.").unwrap(),
+            RecordType::A,
+        ));
+
+        message.to_vec().unwrap()
+    }
+
+    fn upstream(dns_packet: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut message = Message::from_vec(dns_packet).unwrap();
+        message.set_message_type(MessageType::Response);
+
+        Ok(message.to_vec().unwrap())
+    }
+
+    fn query(store: &SetStore, client_ip: &str, transport: Transport) -> Message {
+        let response = guard_query(
+            &config(),
+            &request(),
+            Some(client_ip.parse().unwrap()),
+            transport,
+            store,
+            upstream,
+        )
+        .unwrap();
+
+        Message::from_vec(&response).unwrap()
+    }
+
+    #[test]
+    fn truncate_new_client_until_tcp_retry() {
+        let store = SetStore::default();
+
+        let response = query(&store, "192.0.2.1", Transport::Udp);
+        assert!(response.truncated());
+        assert_eq!(response.id(), 1234);
+        assert!(response.answers().is_empty());
+
+        // the client retries over tcp, then it is answered over udp
+        assert!(!query(&store, "192.0.2.1", Transport::Tcp).truncated());
+        assert!(!query(&store, "192.0.2.1", Transport::Udp).truncated());
+
+        // another client is still unverified
+        assert!(query(&store, "192.0.2.2", Transport::Udp).truncated());
+    }
+
+    #[test]
+    fn pass_through_when_not_required_or_no_client() {
+        let store = SetStore::default();
+        let disabled = serde_yaml::from_str("require_tcp_first: false").unwrap();
+
+        for (config, client_ip) in [
+            (disabled, Some("192.0.2.1".parse().unwrap())),
+            (config(), None),
+        ] {
+            let response = guard_query(
+                &config,
+                &request(),
+                client_ip,
+                Transport::Udp,
+                &store,
+                upstream,
+            )
+            .unwrap();
+
+            assert!(!Message::from_vec(&response).unwrap().truncated());
+        }
+        assert!(store.0.borrow().is_empty());
+    }
+}
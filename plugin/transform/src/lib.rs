@@ -0,0 +1,501 @@
+use serde::Deserialize;
+use tracing::{error, warn};
+use trust_dns_proto::op::{Message, ResponseCode};
+use trust_dns_proto::rr::{RData, Record, RecordType};
+
+use crate::helper::{call_next_plugin, load_config};
+use crate::plugin::{Error, Plugin};
+
+wit_bindgen::generate!("rubydns");
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    /// applied to the response in order
+    transforms: Vec<Transform>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum Transform {
+    /// clamp the ttl of all records into [min, max]
+    TtlClamp { min: Option<u32>, max: Option<u32> },
+
+    /// remove the DNSSEC records, except the ones of the queried type
+    StripDnssec,
+
+    /// set the recursion available flag
+    SetRa,
+
+    /// remove the duplicate records, which only differ in ttl
+    DedupRecords,
+
+    /// sort the answers of each record set, the record set order is kept
+    Sort,
+
+    /// guard the clients against the overlong CNAME chains, which may loop
+    MaxCnameChain {
+        #[serde(default = "default_max_cname_chain")]
+        max: usize,
+        #[serde(default)]
+        action: CnameChainAction,
+    },
+}
+
+fn default_max_cname_chain() -> usize {
+    16
+}
+
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum CnameChainAction {
+    /// replace the response with SERVFAIL
+    #[default]
+    Servfail,
+
+    /// keep the first CNAMEs of the chain, the client resolves the last target itself
+    Truncate,
+}
+
+impl Transform {
+    fn apply(&self, message: &mut Message) {
+        match *self {
+            Transform::TtlClamp { min, max } => {
+                for_each_section(message, |records| {
+                    for record in records {
+                        let mut ttl = record.ttl();
+                        if let Some(min) = min {
+                            ttl = ttl.max(min);
+                        }
+                        if let Some(max) = max {
+                            ttl = ttl.min(max);
+                        }
+
+                        record.set_ttl(ttl);
+                    }
+                });
+            }
+
+            Transform::StripDnssec => {
+                let query_type = message.query().map(|query| query.query_type());
+                let keep = |record: &Record| {
+                    !is_dnssec(record.record_type()) || Some(record.record_type()) == query_type
+                };
+
+                for_each_section(message, |records| records.retain(keep));
+            }
+
+            Transform::SetRa => {
+                message.set_recursion_available(true);
+            }
+
+            Transform::DedupRecords => {
+                for_each_section(message, dedup);
+            }
+
+            Transform::Sort => {
+                let answers = message.answers_mut();
+                let rrset_index = |record: &Record| {
+                    answers
+                        .iter()
+                        .position(|first| {
+                            first.name() == record.name()
+                                && first.record_type() == record.record_type()
+                        })
+                        .unwrap_or_default()
+                };
+
+                let mut sorted = answers
+                    .iter()
+                    .map(|record| (rrset_index(record), record.clone()))
+                    .collect::<Vec<_>>();
+                sorted.sort_by(|(a_index, a), (b_index, b)| {
+                    a_index.cmp(b_index).then_with(|| a.data().cmp(&b.data()))
+                });
+
+                *answers = sorted.into_iter().map(|(_, record)| record).collect();
+            }
+
+            Transform::MaxCnameChain { max, action } => {
+                let chain = cname_chain(message);
+                if chain.len() <= max {
+                    return;
+                }
+
+                warn!(chain = chain.len(), max, ?action, "CNAME chain is too long");
+
+                match action {
+                    CnameChainAction::Servfail => {
+                        message.take_answers();
+                        message.take_name_servers();
+                        message.take_additionals();
+                        message.set_response_code(ResponseCode::ServFail);
+                    }
+
+                    CnameChainAction::Truncate => {
+                        let kept = chain[..max].to_vec();
+                        message.answers_mut().retain(|record| kept.contains(record));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// follow the CNAMEs in the answers from the queried name, a loop ends the chain when it
+/// is longer than the answers
+fn cname_chain(message: &Message) -> Vec<Record> {
+    let mut name = match message.query() {
+        None => return vec![],
+        Some(query) => query.name().clone(),
+    };
+
+    let mut chain = vec![];
+    while chain.len() <= message.answers().len() {
+        let record = message
+            .answers()
+            .iter()
+            .find(|record| record.record_type() == RecordType::CNAME && record.name() == &name);
+
+        match record.and_then(|record| record.data().map(|data| (record, data))) {
+            Some((record, RData::CNAME(target))) => {
+                name = target.clone();
+                chain.push(record.clone());
+            }
+
+            _ => break,
+        }
+    }
+
+    chain
+}
+
+/// the answer, authority and additional sections
+fn for_each_section(message: &mut Message, mut f: impl FnMut(&mut Vec<Record>)) {
+    f(message.answers_mut());
+    f(message.name_servers_mut());
+    f(message.additionals_mut());
+}
+
+fn is_dnssec(record_type: RecordType) -> bool {
+    matches!(
+        record_type,
+        RecordType::DNSKEY
+            | RecordType::DS
+            | RecordType::NSEC
+            | RecordType::NSEC3
+            | RecordType::NSEC3PARAM
+            | RecordType::RRSIG
+            | RecordType::SIG
+    )
+}
+
+/// keep the first one of the equal records, the record equality ignores the ttl
+fn dedup(records: &mut Vec<Record>) {
+    let mut kept: Vec<Record> = Vec::with_capacity(records.len());
+    for record in records.drain(..) {
+        if !kept.contains(&record) {
+            kept.push(record);
+        }
+    }
+
+    *records = kept;
+}
+
+#[derive(Debug)]
+struct TransformRunner;
+
+impl Plugin for TransformRunner {
+    fn run(dns_packet: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let config = load_config();
+        let config: Config = serde_yaml::from_str(&config).map_err(|err| {
+            error!(%err, "load transform config failed");
+
+            Error {
+                code: 1,
+                msg: err.to_string(),
+            }
+        })?;
+
+        let response_packet = call_next(&dns_packet)?;
+        if config.transforms.is_empty() {
+            return Ok(response_packet);
+        }
+
+        let mut response_message = Message::from_vec(&response_packet).map_err(|err| {
+            error!(%err, "decode dns response packet failed");
+
+            Error {
+                code: 1,
+                msg: err.to_string(),
+            }
+        })?;
+
+        for transform in &config.transforms {
+            transform.apply(&mut response_message);
+        }
+
+        response_message.to_vec().map_err(|err| {
+            error!(%err, "encode dns response packet failed");
+
+            Error {
+                code: 1,
+                msg: err.to_string(),
+            }
+        })
+    }
+
+    fn valid_config() -> Result<(), Error> {
+        let config: Config = serde_yaml::from_str(&load_config()).map_err(|err| {
+            error!(%err, "load transform config failed");
+
+            Error {
+                code: 1,
+                msg: err.to_string(),
+            }
+        })?;
+
+        for transform in &config.transforms {
+            if let Transform::TtlClamp {
+                min: Some(min),
+                max: Some(max),
+            } = transform
+            {
+                if min > max {
+                    return Err(Error {
+                        code: 1,
+                        msg: format!("ttl clamp min {min} is larger than max {max}"),
+                    });
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn version() -> String {
+        env!("CARGO_PKG_VERSION").to_string()
+    }
+}
+
+fn call_next(dns_packet: &[u8]) -> Result<Vec<u8>, Error> {
+    match call_next_plugin(dns_packet) {
+        None => Err(Error {
+            code: 1,
+            msg: "no next plugin".to_string(),
+        }),
+
+        Some(result) => result,
+    }
+}
+
+export_rubydns!(TransformRunner);
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::str::FromStr;
+
+    use trust_dns_proto::op::Query;
+    use trust_dns_proto::rr::rdata::NULL;
+    use trust_dns_proto::rr::Name;
+
+    use super::*;
+
+    fn name(name: &str) -> Name {
+        Name::from_str(name).unwrap()
+    }
+
+    fn a(name: &str, ttl: u32, last: u8) -> Record {
+        Record::from_rdata(
+            self::name(name),
+            ttl,
+            RData::A(Ipv4Addr::new(192, 0, 2, last)),
+        )
+    }
+
+    /// the DNSSEC records are unknown records without the dnssec feature
+    fn rrsig(name: &str) -> Record {
+        let mut record = Record::with(self::name(name), RecordType::RRSIG, 60);
+        record.set_data(Some(RData::Unknown {
+            code: 46,
+            rdata: NULL::with(vec![0; 32]),
+        }));
+
+        record
+    }
+
+    fn response(answers: Vec<Record>) -> Message {
+        let mut message = Message::new();
+        message
+            .add_query(Query::query(name("www.example.com."), RecordType::A))
+            .add_answers(answers);
+
+        message
+    }
+
+    /// a chain of `len` CNAMEs from the query name, the last target has an A record
+    fn cname_response(len: usize) -> Message {
+        let mut answers = vec![];
+        let mut owner = "www.example.com.".to_string();
+        for index in 0..len {
+            let target = format!("c{index}.example.com.");
+            answers.push(Record::from_rdata(
+                name(&owner),
+                60,
+                RData::CNAME(name(&target)),
+            ));
+            owner = target;
+        }
+        answers.push(a(&owner, 60, 1));
+
+        response(answers)
+    }
+
+    fn transforms(config: &str) -> Vec<Transform> {
+        serde_yaml::from_str::<Config>(config).unwrap().transforms
+    }
+
+    fn apply(transforms: &[Transform], message: &mut Message) {
+        for transform in transforms {
+            transform.apply(message);
+        }
+    }
+
+    /// the ttl and the last octet of the A answers in order
+    fn answers(message: &Message) -> Vec<(u32, u8)> {
+        message
+            .answers()
+            .iter()
+            .filter_map(|record| match record.data() {
+                Some(RData::A(ip)) => Some((record.ttl(), ip.octets()[3])),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn compose_transforms_in_order() {
+        let transforms = transforms(
+            "
+transforms:
+  - type: strip_dnssec
+  - type: dedup_records
+  - type: sort
+  - type: ttl_clamp
+    min: 30
+    max: 300
+  - type: set_ra
+",
+        );
+        let mut message = response(vec![
+            a("www.example.com.", 10, 3),
+            rrsig("www.example.com."),
+            a("www.example.com.", 20, 1),
+            a("www.example.com.", 5000, 3),
+            a("www.example.com.", 60, 2),
+        ]);
+
+        apply(&transforms, &mut message);
+
+        // the duplicate keeps the first one, then the ttl is clamped
+        assert_eq!(answers(&message), [(30, 1), (60, 2), (30, 3)]);
+        assert_eq!(message.answers().len(), 3);
+        assert!(message.recursion_available());
+    }
+
+    #[test]
+    fn keep_queried_dnssec_records() {
+        let mut message = response(vec![
+            rrsig("www.example.com."),
+            a("www.example.com.", 60, 1),
+        ]);
+        message.queries_mut()[0].set_query_type(RecordType::RRSIG);
+
+        apply(
+            &transforms("transforms: [{ type: strip_dnssec }]"),
+            &mut message,
+        );
+
+        assert_eq!(message.answers().len(), 2);
+    }
+
+    #[test]
+    fn reject_overlong_cname_chain() {
+        let transforms = transforms("transforms: [{ type: max_cname_chain, max: 3 }]");
+
+        let mut message = cname_response(4);
+        apply(&transforms, &mut message);
+
+        assert_eq!(message.response_code(), ResponseCode::ServFail);
+        assert!(message.answers().is_empty());
+
+        // a chain at the limit is kept
+        let mut message = cname_response(3);
+        apply(&transforms, &mut message);
+
+        assert_eq!(message.response_code(), ResponseCode::NoError);
+        assert_eq!(message.answers().len(), 4);
+    }
+
+    #[test]
+    fn truncate_overlong_cname_chain() {
+        let mut message = cname_response(5);
+
+        apply(
+            &transforms("transforms: [{ type: max_cname_chain, max: 2, action: truncate }]"),
+            &mut message,
+        );
+
+        assert_eq!(message.response_code(), ResponseCode::NoError);
+        assert_eq!(
+            message
+                .answers()
+                .iter()
+                .map(|record| record.data().cloned())
+                .collect::<Vec<_>>(),
+            [
+                Some(RData::CNAME(name("c0.example.com."))),
+                Some(RData::CNAME(name("c1.example.com.")))
+            ]
+        );
+    }
+
+    #[test]
+    fn reject_cname_loop() {
+        let mut message = response(vec![
+            Record::from_rdata(
+                name("www.example.com."),
+                60,
+                RData::CNAME(name("loop.example.com.")),
+            ),
+            Record::from_rdata(
+                name("loop.example.com."),
+                60,
+                RData::CNAME(name("www.example.com.")),
+            ),
+        ]);
+
+        // the loop is followed until it is longer than the answers
+        assert_eq!(cname_chain(&message).len(), 3);
+
+        apply(
+            &transforms("transforms: [{ type: max_cname_chain, max: 2 }]"),
+            &mut message,
+        );
+        assert_eq!(message.response_code(), ResponseCode::ServFail);
+    }
+
+    #[test]
+    fn default_max_cname_chain_is_16() {
+        let transforms = transforms("transforms: [{ type: max_cname_chain }]");
+
+        let mut message = cname_response(16);
+        apply(&transforms, &mut message);
+        assert_eq!(message.response_code(), ResponseCode::NoError);
+
+        let mut message = cname_response(17);
+        apply(&transforms, &mut message);
+        assert_eq!(message.response_code(), ResponseCode::ServFail);
+    }
+}
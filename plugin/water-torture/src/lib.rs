@@ -0,0 +1,444 @@
+use plugin_utils::{time, DROP_ERROR_CODE};
+use serde::Deserialize;
+use tracing::{error, info, warn};
+use trust_dns_proto::op::{Message, MessageType, ResponseCode};
+
+use crate::helper::{call_next_plugin, load_config, map_cas, map_get, map_set};
+use crate::plugin::{Error, Plugin};
+
+wit_bindgen::generate!("rubydns");
+
+/// mitigate the random subdomain attack, which floods a registered domain with the
+/// queries of never existing subdomains to bust the cache
+///
+/// when the NXDOMAIN responses of a registered domain exceed the threshold in a window, its
+/// subdomains are blocked, except the ones which have been answered before
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    #[serde(default = "default_nxdomain_threshold")]
+    nxdomain_threshold: u64,
+    #[serde(default = "default_window_secs")]
+    window_secs: u64,
+    /// how long the subdomains are blocked after the threshold is exceeded
+    #[serde(default = "default_block_secs")]
+    block_secs: u64,
+    /// how long an answered subdomain is kept passing when blocking
+    #[serde(default = "default_known_name_secs")]
+    known_name_secs: u64,
+    #[serde(default)]
+    action: Action,
+}
+
+fn default_nxdomain_threshold() -> u64 {
+    100
+}
+
+fn default_window_secs() -> u64 {
+    10
+}
+
+fn default_block_secs() -> u64 {
+    300
+}
+
+fn default_known_name_secs() -> u64 {
+    3600
+}
+
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Action {
+    #[default]
+    Refuse,
+
+    /// drop the query without any response
+    Drop,
+}
+
+#[derive(Debug)]
+struct WaterTortureRunner;
+
+impl Plugin for WaterTortureRunner {
+    fn run(dns_packet: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let config = load_config();
+        let config: Config = serde_yaml::from_str(&config).map_err(|err| {
+            error!(%err, "load water torture config failed");
+
+            Error {
+                code: 1,
+                msg: err.to_string(),
+            }
+        })?;
+
+        let request_message = Message::from_vec(&dns_packet).map_err(|err| {
+            error!(%err, "decode dns request packet failed");
+
+            Error {
+                code: 1,
+                msg: err.to_string(),
+            }
+        })?;
+
+        guard_query(
+            &config,
+            &request_message,
+            &dns_packet,
+            &HostStore,
+            time::now_unix_secs(),
+            call_next,
+        )
+    }
+
+    fn valid_config() -> Result<(), Error> {
+        let config: Config = serde_yaml::from_str(&load_config()).map_err(|err| {
+            error!(%err, "load water torture config failed");
+
+            Error {
+                code: 1,
+                msg: err.to_string(),
+            }
+        })?;
+
+        if config.window_secs == 0 {
+            return Err(Error {
+                code: 1,
+                msg: "window_secs must be positive".to_string(),
+            });
+        }
+
+        Ok(())
+    }
+
+    fn version() -> String {
+        env!("CARGO_PKG_VERSION").to_string()
+    }
+}
+
+/// the plugin store, the tests replace it with a map
+trait Store {
+    fn get(&self, key: &str) -> Option<Vec<u8>>;
+
+    fn set(&self, key: &str, value: &[u8], timeout_secs: u64);
+
+    fn cas(&self, key: &str, expected: Option<&[u8]>, new: &[u8], timeout_secs: u64) -> bool;
+}
+
+struct HostStore;
+
+impl Store for HostStore {
+    fn get(&self, key: &str) -> Option<Vec<u8>> {
+        map_get(key.as_bytes())
+    }
+
+    fn set(&self, key: &str, value: &[u8], timeout_secs: u64) {
+        map_set(key.as_bytes(), value, Some(timeout_secs))
+    }
+
+    fn cas(&self, key: &str, expected: Option<&[u8]>, new: &[u8], timeout_secs: u64) -> bool {
+        map_cas(key.as_bytes(), expected, new, Some(timeout_secs))
+    }
+}
+
+fn block_key(domain: &str) -> String {
+    format!("water-torture:block:{domain}")
+}
+
+fn known_name_key(name: &str) -> String {
+    format!("water-torture:known:{name}")
+}
+
+/// block the subdomain queries of the attacked domains, pass the others by `exchange` and
+/// count their NXDOMAIN responses in the store
+fn guard_query(
+    config: &Config,
+    request_message: &Message,
+    dns_packet: &[u8],
+    store: &impl Store,
+    now: u64,
+    exchange: impl FnOnce(&[u8]) -> Result<Vec<u8>, Error>,
+) -> Result<Vec<u8>, Error> {
+    let name = match request_message.query() {
+        None => return exchange(dns_packet),
+        Some(query) => query.name().to_lowercase().to_ascii(),
+    };
+    let name = name.trim_end_matches('.');
+    let domain = match psl::domain_str(name) {
+        None => return exchange(dns_packet),
+        Some(domain) => domain,
+    };
+
+    if name != domain
+        && store.get(&block_key(domain)).is_some()
+        && store.get(&known_name_key(name)).is_none()
+    {
+        info!(name, domain, action = ?config.action, "block subdomain of attacked domain");
+
+        return match config.action {
+            Action::Drop => Err(Error {
+                code: DROP_ERROR_CODE,
+                msg: format!("drop subdomain of attacked domain {domain}"),
+            }),
+
+            Action::Refuse => refused_response(request_message),
+        };
+    }
+
+    let response_packet = exchange(dns_packet)?;
+    let response_message = Message::from_vec(&response_packet).map_err(|err| {
+        error!(%err, "decode dns response packet failed");
+
+        Error {
+            code: 1,
+            msg: err.to_string(),
+        }
+    })?;
+
+    match response_message.response_code() {
+        ResponseCode::NXDomain => {
+            let count = count_nxdomain(store, domain, now, config.window_secs);
+            if count == config.nxdomain_threshold + 1 {
+                warn!(domain, count, "NXDOMAIN flood detected, block subdomains");
+
+                store.set(&block_key(domain), &[], config.block_secs);
+            }
+        }
+
+        ResponseCode::NoError if !response_message.answers().is_empty() => {
+            store.set(&known_name_key(name), &[], config.known_name_secs);
+        }
+
+        _ => {}
+    }
+
+    Ok(response_packet)
+}
+
+/// increase the NXDOMAIN count of the domain in the current window, return the new count
+///
+/// the stored value is the window start secs and the count, both u64 big endian
+fn count_nxdomain(store: &impl Store, domain: &str, now: u64, window_secs: u64) -> u64 {
+    let key = format!("water-torture:nxdomain:{domain}");
+
+    loop {
+        let current = store.get(&key);
+        let (window_start, count) = current
+            .as_deref()
+            .filter(|data| data.len() == 16)
+            .map(|data| {
+                (
+                    u64::from_be_bytes(data[..8].try_into().unwrap()),
+                    u64::from_be_bytes(data[8..].try_into().unwrap()),
+                )
+            })
+            .filter(|(window_start, _)| now < window_start + window_secs)
+            .unwrap_or((now, 0));
+
+        let mut new = window_start.to_be_bytes().to_vec();
+        new.extend_from_slice(&(count + 1).to_be_bytes());
+
+        // another instance updates the count at the same time, retry
+        if store.cas(&key, current.as_deref(), &new, window_secs) {
+            return count + 1;
+        }
+    }
+}
+
+fn refused_response(request_message: &Message) -> Result<Vec<u8>, Error> {
+    let mut response_message = Message::new();
+    response_message
+        .set_id(request_message.id())
+        .set_message_type(MessageType::Response)
+        .set_op_code(request_message.op_code())
+        .set_recursion_desired(request_message.recursion_desired())
+        .set_recursion_available(true)
+        .set_response_code(ResponseCode::Refused)
+        .add_queries(request_message.queries().iter().cloned());
+
+    response_message.to_vec().map_err(|err| {
+        error!(%err, "encode dns response packet failed");
+
+        Error {
+            code: 1,
+            msg: err.to_string(),
+        }
+    })
+}
+
+fn call_next(dns_packet: &[u8]) -> Result<Vec<u8>, Error> {
+    match call_next_plugin(dns_packet) {
+        None => Err(Error {
+            code: 1,
+            msg: "no next plugin".to_string(),
+        }),
+
+        Some(result) => result,
+    }
+}
+
+export_rubydns!(WaterTortureRunner);
+
+#[cfg(test)]
+mod tests {
+    use std::cell::{Cell, RefCell};
+    use std::collections::HashMap;
+    use std::net::Ipv4Addr;
+    use std::str::FromStr;
+
+    use trust_dns_proto::op::Query;
+    use trust_dns_proto::rr::{Name, RData, Record, RecordType};
+
+    use super::*;
+
+    /// the store without expiry
+    #[derive(Default)]
+    struct MapStore(RefCell<HashMap<String, Vec<u8>>>);
+
+    impl Store for MapStore {
+        fn get(&self, key: &str) -> Option<Vec<u8>> {
+            self.0.borrow().get(key).cloned()
+        }
+
+        fn set(&self, key: &str, value: &[u8], _timeout_secs: u64) {
+            self.0.borrow_mut().insert(key.to_string(), value.to_vec());
+        }
+
+        fn cas(&self, key: &str, expected: Option<&[u8]>, new: &[u8], _timeout_secs: u64) -> bool {
+            let mut map = self.0.borrow_mut();
+            if map.get(key).map(Vec::as_slice) != expected {
+                return false;
+            }
+
+            map.insert(key.to_string(), new.to_vec());
+
+            true
+        }
+    }
+
+    fn config(action: &str) -> Config {
+        serde_yaml::from_str(&format!("{{ nxdomain_threshold: 10, action: {action} }}")).unwrap()
+    }
+
+    fn request(name: &str) -> Message {
+        let mut message = Message::new();
+        message
+            .set_id(1234)
+            .add_query(Query::query(Name::from_str(name).unwrap(), RecordType::A));
+
+        message
+    }
+
+    /// the upstream answers the names starting with `www.` and NXDOMAIN the others
+    fn upstream(dns_packet: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut message = Message::from_vec(dns_packet).unwrap();
+        let name = message.query().unwrap().name().clone();
+        message.set_message_type(MessageType::Response);
+        if name.to_ascii().starts_with("www.") {
+            message.add_answer(Record::from_rdata(
+                name,
+                60,
+                RData::A(Ipv4Addr::new(192, 0, 2, 1)),
+            ));
+        } else {
+            message.set_response_code(ResponseCode::NXDomain);
+        }
+
+        Ok(message.to_vec().unwrap())
+    }
+
+    /// run the query, return the response code and whether the upstream is asked
+    fn query(config: &Config, store: &MapStore, name: &str) -> (Result<ResponseCode, u32>, bool) {
+        let request_message = request(name);
+        let dns_packet = request_message.to_vec().unwrap();
+        let exchanged = Cell::new(false);
+
+        let result = guard_query(
+            config,
+            &request_message,
+            &dns_packet,
+            store,
+            1000,
+            |dns_packet| {
+                exchanged.set(true);
+
+                upstream(dns_packet)
+            },
+        );
+
+        let result = result
+            .map(|response_packet| Message::from_vec(&response_packet).unwrap().response_code())
+            .map_err(|err| err.code);
+
+        (result, exchanged.get())
+    }
+
+    #[test]
+    fn throttle_random_subdomain_flood() {
+        let config = config("refuse");
+        let store = MapStore::default();
+
+        // answered before the flood, it keeps passing
+        assert_eq!(
+            query(&config, &store, "www.victim.com."),
+            (Ok(ResponseCode::NoError), true)
+        );
+
+        for i in 0..=10 {
+            assert_eq!(
+                query(&config, &store, &format!("r{i}x7q.victim.com.")),
+                (Ok(ResponseCode::NXDomain), true),
+                "query {i} is blocked before the threshold"
+            );
+        }
+
+        for i in 11..50 {
+            assert_eq!(
+                query(&config, &store, &format!("r{i}x7q.victim.com.")),
+                (Ok(ResponseCode::Refused), false),
+                "query {i} reaches the upstream in the flood"
+            );
+        }
+
+        assert_eq!(
+            query(&config, &store, "www.victim.com."),
+            (Ok(ResponseCode::NoError), true)
+        );
+        // the registered domain itself and the other domains aren't blocked
+        assert_eq!(
+            query(&config, &store, "victim.com."),
+            (Ok(ResponseCode::NXDomain), true)
+        );
+        assert_eq!(
+            query(&config, &store, "r1x7q.other.com."),
+            (Ok(ResponseCode::NXDomain), true)
+        );
+    }
+
+    #[test]
+    fn drop_subdomains_of_attacked_domain() {
+        let config = config("drop");
+        let store = MapStore::default();
+
+        for i in 0..=10 {
+            assert_eq!(
+                query(&config, &store, &format!("r{i}x7q.victim.com.")),
+                (Ok(ResponseCode::NXDomain), true)
+            );
+        }
+
+        assert_eq!(
+            query(&config, &store, "r11x7q.victim.com."),
+            (Err(DROP_ERROR_CODE), false)
+        );
+    }
+
+    #[test]
+    fn count_nxdomain_in_window() {
+        let store = MapStore::default();
+
+        assert_eq!(count_nxdomain(&store, "victim.com", 1000, 10), 1);
+        assert_eq!(count_nxdomain(&store, "victim.com", 1009, 10), 2);
+        // a new window starts the count again
+        assert_eq!(count_nxdomain(&store, "victim.com", 1010, 10), 1);
+    }
+}
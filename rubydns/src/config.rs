@@ -1,27 +1,201 @@
+use std::collections::HashMap;
 use std::net::SocketAddr;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use serde::Deserialize;
 use tokio::fs;
 
-use crate::plugins::PluginConfig;
+use crate::plugins::{PluginConfig, RouteConfig};
 
 #[derive(Debug, Deserialize)]
 pub struct Config {
-    pub plugin_dir: String,
+    /// the directory of the plugins, the directory of the config file when unset
+    #[serde(default)]
+    pub plugin_dir: PathBuf,
+    /// unix socket path to administrate the running servers, disabled when unset
+    pub control_socket: Option<PathBuf>,
+    /// serve the Prometheus metrics over HTTP on it, disabled when unset
+    pub metrics_addr: Option<SocketAddr>,
+    /// the default max entries of the plugin stores, a plugin can override it with its own
+    /// `store_max_entries`, unbounded when unset
+    pub store_max_entries: Option<usize>,
+    #[serde(default)]
+    pub log: Log,
     pub servers: Vec<Server>,
 }
 
 impl Config {
     pub async fn parse(path: &Path) -> anyhow::Result<Self> {
         let data = fs::read(path).await?;
+        let mut config: Self = serde_yaml::from_slice(&data)?;
 
-        Ok(serde_yaml::from_slice(&data)?)
+        if config.plugin_dir.as_os_str().is_empty() {
+            config.plugin_dir = match path.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+                _ => PathBuf::from("."),
+            };
+        }
+
+        if let Some(store_max_entries) = config.store_max_entries {
+            for plugin in config.servers.iter_mut().flat_map(|server| {
+                server
+                    .plugins
+                    .iter_mut()
+                    .chain(server.chains.values_mut().flatten())
+            }) {
+                plugin.default_store_max_entries(store_max_entries);
+            }
+        }
+
+        Ok(config)
     }
 }
 
+#[derive(Debug, Deserialize)]
+pub struct Log {
+    /// the filter directives, such as `info` or `info,rubydns::plugins=debug`, `RUST_LOG`
+    /// overrides it when set
+    #[serde(default = "default_log_level")]
+    pub level: String,
+    #[serde(default)]
+    pub format: LogFormat,
+    /// log the module path of the events
+    #[serde(default = "default_log_target")]
+    pub target: bool,
+}
+
+impl Default for Log {
+    fn default() -> Self {
+        Self {
+            level: default_log_level(),
+            format: Default::default(),
+            target: default_log_target(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    #[default]
+    Pretty,
+    Compact,
+    /// one JSON object per line, for the structured log ingestion
+    Json,
+}
+
+fn default_log_level() -> String {
+    "info".to_string()
+}
+
+fn default_log_target() -> bool {
+    true
+}
+
 #[derive(Debug, Deserialize)]
 pub struct Server {
     pub listen_addr: SocketAddr,
+    /// the transports listening on the listen addr, they share the plugin chain, only UDP
+    /// by default
+    #[serde(default = "default_transports")]
+    pub transports: Vec<Transport>,
+    /// the plugin responses larger than it are replaced with SERVFAIL
+    #[serde(default = "default_max_response_bytes")]
+    pub max_response_bytes: usize,
+    /// drop the malformed EDNS options of a query instead of the whole query
+    #[serde(default)]
+    pub lenient_edns: bool,
+    /// close the TCP connections which don't send a query in it, it also bounds the TLS
+    /// handshake of the DoH connections and closes the DoH connections without a request in
+    /// flight for it
+    #[serde(default = "default_tcp_read_timeout_ms")]
+    pub tcp_read_timeout_ms: u64,
+    /// the TCP idle timeout advertised with the edns-tcp-keepalive option, RFC 7828, it
+    /// replaces `tcp_read_timeout_ms` of the TCP connections, the option isn't advertised
+    /// when unset
+    pub tcp_keepalive_timeout_ms: Option<u64>,
+    /// the path of the DoH endpoint
+    #[serde(default = "default_doh_path")]
+    pub doh_path: String,
+    /// how to handle the queries arriving before the plugin chain is ready
+    #[serde(default)]
+    pub not_ready: NotReady,
+    /// log the queries slower than it, disabled when unset
+    pub slow_query_threshold_ms: Option<u64>,
+    /// answer SERVFAIL when the plugin chain doesn't finish the query in it, unbounded when
+    /// unset
+    pub query_timeout_ms: Option<u64>,
+    /// drop the new queries when so many queries are handling, unbounded when unset
+    pub max_inflight: Option<usize>,
+    /// the cert and key for the TLS listeners, required by DoH
+    pub tls: Option<Tls>,
+    /// the names queried at startup to warm the cache
+    pub prewarm: Option<Prewarm>,
+    /// the default plugin chain, the queries matching no route use it
     pub plugins: Vec<PluginConfig>,
+    /// the named plugin chains selected by the routes
+    #[serde(default)]
+    pub chains: HashMap<String, Vec<PluginConfig>>,
+    /// the first route matching the query picks its chain
+    #[serde(default)]
+    pub routes: Vec<RouteConfig>,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Transport {
+    Udp,
+    Tcp,
+    /// DNS over HTTPS, it can't share the listen addr with TCP, use another server for it
+    Doh,
+}
+
+fn default_transports() -> Vec<Transport> {
+    vec![Transport::Udp]
+}
+
+#[derive(Debug, Default, Copy, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum NotReady {
+    /// answer SERVFAIL with the extended DNS error "Not Ready"
+    #[default]
+    Servfail,
+
+    /// wait for the plugin chain, answer SERVFAIL when it isn't ready in time
+    Queue { timeout_ms: u64 },
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Tls {
+    /// the PEM cert chain, the first cert is the server cert
+    pub cert_path: PathBuf,
+    /// the PEM private key, PKCS#8, PKCS#1 or SEC1 format
+    pub key_path: PathBuf,
+    /// require the client certs signed by the CA, mTLS is disabled when unset
+    pub client_ca_path: Option<PathBuf>,
+}
+
+fn default_max_response_bytes() -> usize {
+    65535
+}
+
+fn default_tcp_read_timeout_ms() -> u64 {
+    5000
+}
+
+fn default_doh_path() -> String {
+    "/dns-query".to_string()
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Prewarm {
+    pub names: Vec<String>,
+    #[serde(default = "default_prewarm_query_types")]
+    pub query_types: Vec<String>,
+    /// query the names again every interval, only at startup when unset
+    pub interval_secs: Option<u64>,
+}
+
+fn default_prewarm_query_types() -> Vec<String> {
+    vec!["A".to_string(), "AAAA".to_string()]
 }
@@ -0,0 +1,508 @@
+use std::fmt::Write as _;
+use std::fs::Permissions;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use futures_util::future::try_join_all;
+use tap::TapFallible;
+use tokio::fs;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{error, info, warn};
+use wasmtime::Engine;
+
+use crate::config::Config;
+use crate::server::ServerState;
+
+/// the plugin flushed by `flush-cache` without a plugin name
+const DEFAULT_FLUSH_PLUGIN: &str = "cache";
+
+/// a unix socket accepting line commands to administrate the running servers
+///
+/// - `flush-cache [plugin]`: clear the stores of the plugins with the name, `cache` by default,
+///   the stores of the other plugins keep their state like the rate limits
+/// - `reload`: reload the config file and swap the plugin chains
+/// - `maintenance on|off`: refuse all queries or not
+/// - `stats`: dump the server stats
+///
+/// SIGHUP reloads the config too
+pub struct Control {
+    engine: Engine,
+    config_path: PathBuf,
+    servers: Vec<(SocketAddr, Arc<ServerState>)>,
+}
+
+impl Control {
+    pub fn new(
+        engine: Engine,
+        config_path: PathBuf,
+        servers: Vec<(SocketAddr, Arc<ServerState>)>,
+    ) -> Self {
+        Self {
+            engine,
+            config_path,
+            servers,
+        }
+    }
+
+    pub async fn serve(self: Arc<Self>, path: &Path) -> anyhow::Result<()> {
+        match fs::remove_file(path).await {
+            Err(err) if err.kind() != ErrorKind::NotFound => {
+                error!(%err, ?path, "remove old control socket failed");
+
+                return Err(err.into());
+            }
+
+            _ => {}
+        }
+
+        let listener = UnixListener::bind(path)
+            .tap_err(|err| error!(%err, ?path, "bind control socket failed"))?;
+
+        // only the owner can administrate the server
+        fs::set_permissions(path, Permissions::from_mode(0o600))
+            .await
+            .tap_err(|err| error!(%err, ?path, "restrict control socket permissions failed"))?;
+
+        info!(?path, "control socket listening");
+
+        loop {
+            let (stream, _) = match listener.accept().await {
+                Err(err) => {
+                    error!(%err, "accept control connection failed");
+
+                    continue;
+                }
+
+                Ok(conn) => conn,
+            };
+
+            let control = self.clone();
+            tokio::spawn(async move {
+                if let Err(err) = control.handle_conn(stream).await {
+                    error!(%err, "handle control connection failed");
+                }
+            });
+        }
+    }
+
+    /// reload the config on every SIGHUP, the listeners are kept, a failed reload keeps the
+    /// old plugin chains
+    pub async fn reload_on_sighup(self: Arc<Self>) -> anyhow::Result<()> {
+        let mut sighup =
+            signal(SignalKind::hangup()).tap_err(|err| error!(%err, "listen SIGHUP failed"))?;
+
+        while sighup.recv().await.is_some() {
+            info!("receive SIGHUP, reload config");
+
+            // the error is logged by reload
+            let _ = self.reload().await;
+        }
+
+        Ok(())
+    }
+
+    async fn handle_conn(&self, stream: UnixStream) -> anyhow::Result<()> {
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+
+        while let Some(line) = lines.next_line().await? {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let mut response = self.handle_command(line).await;
+            response.push('\n');
+
+            writer.write_all(response.as_bytes()).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn handle_command(&self, command: &str) -> String {
+        info!(command, "handle control command");
+
+        match command.split_whitespace().collect::<Vec<_>>().as_slice() {
+            ["flush-cache", plugin @ ..] if plugin.len() <= 1 => {
+                let plugin = plugin.first().copied().unwrap_or(DEFAULT_FLUSH_PLUGIN);
+
+                let cleared = self
+                    .servers
+                    .iter()
+                    .filter_map(|(_, state)| state.plugin_chain())
+                    .map(|plugin_chain| plugin_chain.clear_store(plugin))
+                    .sum::<usize>();
+
+                if cleared == 0 {
+                    format!("error: no plugin {plugin}")
+                } else {
+                    "ok".to_string()
+                }
+            }
+
+            ["reload"] => match self.reload().await {
+                Err(err) => format!("error: {err}"),
+                Ok(_) => "ok".to_string(),
+            },
+
+            ["maintenance", mode @ ("on" | "off")] => {
+                for (_, state) in &self.servers {
+                    state.set_maintenance(*mode == "on");
+                }
+
+                "ok".to_string()
+            }
+
+            ["stats"] => {
+                let mut stats = String::new();
+                for (listen_addr, state) in &self.servers {
+                    let plugin_chain = state.plugin_chain();
+                    let _ = writeln!(
+                        stats,
+                        "{listen_addr} queries={} dropped={} maintenance={} store_entries={} store_bytes={}",
+                        state.queries(),
+                        state.dropped(),
+                        if state.maintenance() { "on" } else { "off" },
+                        plugin_chain
+                            .as_ref()
+                            .map_or(0, |plugin_chain| plugin_chain.store_entries()),
+                        plugin_chain
+                            .as_ref()
+                            .map_or(0, |plugin_chain| plugin_chain.store_bytes())
+                    );
+                }
+
+                stats.push_str("ok");
+
+                stats
+            }
+
+            _ => format!("error: unknown command {command}"),
+        }
+    }
+
+    /// reload the config and swap the plugin chains, the old chains are kept if any new chain
+    /// fails to load
+    pub async fn reload(&self) -> anyhow::Result<()> {
+        let config = Config::parse(&self.config_path)
+            .await
+            .tap_err(|err| error!(%err, "parse config failed"))?;
+        let plugin_dir = config.plugin_dir.as_path();
+
+        let mut reloads = vec![];
+        for server in config.servers {
+            match self
+                .servers
+                .iter()
+                .find(|(listen_addr, _)| *listen_addr == server.listen_addr)
+            {
+                None => {
+                    warn!(listen_addr = %server.listen_addr, "new server can't be added by reload, ignore it");
+                }
+
+                Some((_, state)) => reloads.push(async move {
+                    let plugin_chain =
+                        crate::create_plugin_chain(self.engine.clone(), plugin_dir, server).await?;
+
+                    Ok::<_, anyhow::Error>((state, plugin_chain))
+                }),
+            }
+        }
+
+        let reloads = try_join_all(reloads)
+            .await
+            .tap_err(|err| error!(%err, "load new plugin chains failed, keep the old ones"))?;
+
+        for (state, plugin_chain) in reloads {
+            state.set_plugin_chain(plugin_chain);
+        }
+
+        info!("reload config done");
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::env;
+    use std::str::FromStr;
+    use std::time::Duration;
+
+    use tokio::io::{Lines, ReadHalf, WriteHalf};
+    use tokio::time;
+    use trust_dns_proto::op::{Message, Query};
+    use trust_dns_proto::rr::{Name, RecordType};
+
+    use super::*;
+    use crate::config::NotReady;
+    use crate::plugins::{test_plugin, PluginChain, Transport};
+
+    /// a path under the temp dir which is unique to the test
+    fn temp_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("rubydns-{}-{name}", std::process::id()))
+    }
+
+    struct Client {
+        lines: Lines<BufReader<ReadHalf<UnixStream>>>,
+        writer: WriteHalf<UnixStream>,
+    }
+
+    impl Client {
+        /// send the command and read the response lines until the `ok` or error line
+        async fn command(&mut self, command: &str) -> Vec<String> {
+            self.writer
+                .write_all(format!("{command}\n").as_bytes())
+                .await
+                .unwrap();
+
+            let mut response = vec![];
+            loop {
+                let line = self.lines.next_line().await.unwrap().unwrap();
+                let done = line == "ok" || line.starts_with("error: ");
+                response.push(line);
+
+                if done {
+                    return response;
+                }
+            }
+        }
+    }
+
+    /// serve a control socket for a server without plugin chain
+    async fn serve(name: &str, config_path: PathBuf) -> (Client, Arc<ServerState>, PathBuf) {
+        let state = Arc::new(ServerState::new(NotReady::default(), None, None));
+        let control = Arc::new(Control::new(
+            crate::plugins::new_engine().unwrap(),
+            config_path,
+            vec![("127.0.0.1:53".parse().unwrap(), state.clone())],
+        ));
+
+        let socket_path = temp_path(&format!("{name}.sock"));
+        tokio::spawn({
+            let socket_path = socket_path.clone();
+
+            async move { control.serve(&socket_path).await }
+        });
+
+        let stream = loop {
+            match UnixStream::connect(&socket_path).await {
+                Err(_) => time::sleep(Duration::from_millis(10)).await,
+                Ok(stream) => break stream,
+            }
+        };
+        let (reader, writer) = tokio::io::split(stream);
+        let client = Client {
+            lines: BufReader::new(reader).lines(),
+            writer,
+        };
+
+        (client, state, socket_path)
+    }
+
+    #[tokio::test]
+    async fn socket_is_owner_only() {
+        let (mut client, _, socket_path) = serve("permissions", temp_path("missing.yaml")).await;
+        // the socket is restricted before the connections are accepted
+        assert_eq!(client.command("maintenance off").await, ["ok"]);
+
+        let mode = std::fs::metadata(&socket_path)
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[tokio::test]
+    async fn maintenance_and_stats() {
+        let (mut client, state, _) = serve("maintenance", temp_path("missing.yaml")).await;
+
+        assert_eq!(client.command("maintenance on").await, ["ok"]);
+        assert!(state.maintenance());
+        assert_eq!(
+            client.command("stats").await,
+            [
+                "127.0.0.1:53 queries=0 dropped=0 maintenance=on store_entries=0 store_bytes=0",
+                "ok"
+            ]
+        );
+
+        assert_eq!(client.command("maintenance off").await, ["ok"]);
+        assert!(!state.maintenance());
+
+        let response = client.command("maintenance maybe").await;
+        assert!(response[0].starts_with("error: unknown command"));
+    }
+
+    /// a plugin chain of the plugin `name` which stores the questions it answers
+    async fn storing_plugin_chain(test: &str, name: &str) -> PluginChain {
+        let plugin_dir = test_plugin::plugin_dir(test, &[(name, test_plugin::STORE_QUESTION)]);
+
+        PluginChain::new(
+            crate::plugins::new_engine().unwrap(),
+            &plugin_dir,
+            vec![serde_yaml::from_str(&format!("name: {name}")).unwrap()],
+            HashMap::new(),
+            vec![],
+            65535,
+            None,
+        )
+        .await
+        .unwrap()
+    }
+
+    async fn query(plugin_chain: &PluginChain, name: &str) {
+        let mut message = Message::new();
+        message.add_query(Query::query(Name::from_str(name).unwrap(), RecordType::A));
+        let dns_packet = message.to_vec().unwrap();
+
+        plugin_chain
+            .handle_dns(Transport::Udp, None, message, dns_packet.into())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn flush_cache() {
+        let (mut client, state, _) = serve("flush-cache", temp_path("missing.yaml")).await;
+        assert_eq!(
+            client.command("flush-cache").await,
+            ["error: no plugin cache"]
+        );
+
+        state.set_plugin_chain(storing_plugin_chain("flush-cache", "cache").await);
+        let plugin_chain = state.plugin_chain().unwrap();
+        query(&plugin_chain, "www.example.com.").await;
+        query(&plugin_chain, "mail.example.com.").await;
+        assert_eq!(plugin_chain.store_entries(), 2);
+
+        assert_eq!(client.command("flush-cache").await, ["ok"]);
+        assert_eq!(plugin_chain.store_entries(), 0);
+    }
+
+    #[tokio::test]
+    async fn flush_cache_keeps_other_plugins() {
+        let (mut client, state, _) = serve("flush-other", temp_path("missing.yaml")).await;
+
+        state.set_plugin_chain(storing_plugin_chain("flush-other", "ratelimit").await);
+        let plugin_chain = state.plugin_chain().unwrap();
+        query(&plugin_chain, "www.example.com.").await;
+
+        assert_eq!(
+            client.command("flush-cache").await,
+            ["error: no plugin cache"]
+        );
+        assert_eq!(plugin_chain.store_entries(), 1);
+
+        assert_eq!(client.command("flush-cache ratelimit").await, ["ok"]);
+        assert_eq!(plugin_chain.store_entries(), 0);
+    }
+
+    #[tokio::test]
+    async fn reload() {
+        let plugin_dir =
+            test_plugin::plugin_dir("reload", &[("cache", test_plugin::STORE_QUESTION)]);
+        let config_path = temp_path("reload.yaml");
+        fs::write(
+            &config_path,
+            format!(
+                "plugin_dir: {}\nservers:\n  - listen_addr: 127.0.0.1:53\n    plugins:\n      - name: cache\n",
+                plugin_dir.display()
+            ),
+        )
+        .await
+        .unwrap();
+
+        let (mut client, state, _) = serve("reload", config_path.clone()).await;
+        assert!(state.plugin_chain().is_none());
+
+        assert_eq!(client.command("reload").await, ["ok"]);
+        let plugin_chain = state.plugin_chain().unwrap();
+        query(&plugin_chain, "www.example.com.").await;
+        assert_eq!(plugin_chain.store_entries(), 1);
+
+        // the new chain has its own plugin pools and stores
+        assert_eq!(client.command("reload").await, ["ok"]);
+        let new_plugin_chain = state.plugin_chain().unwrap();
+        assert!(!Arc::ptr_eq(&plugin_chain, &new_plugin_chain));
+        assert_eq!(new_plugin_chain.store_entries(), 0);
+
+        // a chain failing to load keeps the old one
+        fs::write(
+            &config_path,
+            "servers:\n  - listen_addr: 127.0.0.1:53\n    plugins:\n      - name: missing\n",
+        )
+        .await
+        .unwrap();
+        let response = client.command("reload").await;
+        assert!(response[0].starts_with("error: "));
+        assert!(Arc::ptr_eq(
+            &new_plugin_chain,
+            &state.plugin_chain().unwrap()
+        ));
+
+        let (mut client, _, _) = serve("reload-missing", temp_path("missing.yaml")).await;
+        let response = client.command("reload").await;
+        assert!(response[0].starts_with("error: "));
+    }
+
+    #[tokio::test]
+    async fn reload_on_sighup() {
+        let plugin_dir =
+            test_plugin::plugin_dir("sighup", &[("cache", test_plugin::STORE_QUESTION)]);
+        let config_path = temp_path("sighup.yaml");
+        fs::write(
+            &config_path,
+            format!(
+                "plugin_dir: {}\nservers:\n  - listen_addr: 127.0.0.1:53\n    plugins:\n      - name: cache\n",
+                plugin_dir.display()
+            ),
+        )
+        .await
+        .unwrap();
+
+        let state = Arc::new(ServerState::new(NotReady::default(), None, None));
+        let control = Arc::new(Control::new(
+            crate::plugins::new_engine().unwrap(),
+            config_path,
+            vec![("127.0.0.1:53".parse().unwrap(), state.clone())],
+        ));
+
+        // SIGHUP doesn't terminate the process once a handler is registered
+        let _sighup = signal(SignalKind::hangup()).unwrap();
+        tokio::spawn(control.reload_on_sighup());
+
+        // the reload task may not listen yet, signal until the chain is loaded
+        time::timeout(Duration::from_secs(10), async {
+            while state.plugin_chain().is_none() {
+                // safety: raise only sends the signal to the process
+                unsafe { libc::raise(libc::SIGHUP) };
+
+                time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        let plugin_chain = state.plugin_chain().unwrap();
+        query(&plugin_chain, "www.example.com.").await;
+        assert_eq!(plugin_chain.store_entries(), 1);
+    }
+
+    #[tokio::test]
+    async fn unknown_command() {
+        let (mut client, _, _) = serve("unknown", temp_path("missing.yaml")).await;
+
+        assert_eq!(
+            client.command("restart").await,
+            ["error: unknown command restart"]
+        );
+    }
+}
@@ -0,0 +1,674 @@
+use std::convert::Infallible;
+use std::fmt::{Debug, Formatter};
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::{Duration, Instant};
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use bytes::{Bytes, BytesMut};
+use hyper::body::HttpBody;
+use hyper::header::{CACHE_CONTROL, CONTENT_TYPE};
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use rustls::ServerConfig;
+use thiserror::Error;
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, oneshot, Mutex};
+use tokio::time;
+use tokio_rustls::TlsAcceptor;
+use tracing::{error, info, instrument, warn};
+use trust_dns_proto::op::{Message, ResponseCode};
+
+use super::{Accept, ClientIdentify, Respond};
+use crate::plugins::Transport;
+
+/// the decoded queries waiting for the server, the connections stop reading when it is full
+const QUERY_QUEUE_SIZE: usize = 1024;
+
+/// the media type of the DNS messages, RFC 8484
+const DNS_MESSAGE_TYPE: &str = "application/dns-message";
+
+/// a DNS message can't be larger than it
+const MAX_DNS_MESSAGE_SIZE: usize = 65535;
+
+type Query = (DohIdentify, Message, Bytes);
+
+/// serve the queries over DNS-over-HTTPS, RFC 8484, HTTP/2 and HTTP/1.1 are negotiated by
+/// ALPN
+///
+/// a query is decoded from the POST body or the base64url `dns` param of the GET request,
+/// the HTTP request waits until the server responds the query
+#[derive(Debug)]
+pub struct DohHandle {
+    queries: Mutex<mpsc::Receiver<Query>>,
+}
+
+impl DohHandle {
+    /// the connection which doesn't finish the TLS handshake in the idle timeout is closed,
+    /// so is the connection which has no request in flight for the idle timeout
+    pub async fn new(
+        listen_addr: SocketAddr,
+        server_config: Arc<ServerConfig>,
+        path: String,
+        lenient_edns: bool,
+        idle_timeout: Duration,
+    ) -> io::Result<Self> {
+        let tcp_listener = TcpListener::bind(listen_addr).await?;
+        let (sender, receiver) = mpsc::channel(QUERY_QUEUE_SIZE);
+
+        let mut server_config = ServerConfig::clone(&server_config);
+        server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+        tokio::spawn(accept_connections(
+            tcp_listener,
+            TlsAcceptor::from(Arc::new(server_config)),
+            Arc::new(DohOptions {
+                path,
+                lenient_edns,
+                idle_timeout,
+            }),
+            sender,
+        ));
+
+        Ok(Self {
+            queries: Mutex::new(receiver),
+        })
+    }
+}
+
+#[derive(Debug)]
+struct DohOptions {
+    /// the path of the DoH endpoint, such as `/dns-query`
+    path: String,
+    lenient_edns: bool,
+    idle_timeout: Duration,
+}
+
+/// the requests in flight on a connection, and when the connection is last active
+#[derive(Debug)]
+struct Activity {
+    in_flight: usize,
+    last_active: Instant,
+}
+
+/// a request in flight, the connection is active until it is dropped
+struct ActiveRequest(Arc<StdMutex<Activity>>);
+
+impl ActiveRequest {
+    fn new(activity: Arc<StdMutex<Activity>>) -> Self {
+        activity.lock().unwrap().in_flight += 1;
+
+        Self(activity)
+    }
+}
+
+impl Drop for ActiveRequest {
+    fn drop(&mut self) {
+        let mut activity = self.0.lock().unwrap();
+        activity.in_flight -= 1;
+        activity.last_active = Instant::now();
+    }
+}
+
+/// route the response to the HTTP request which the query arrives on
+pub struct DohIdentify {
+    /// a connection carries many requests, use the id to tell them apart
+    request_id: u64,
+    peer_addr: SocketAddr,
+    responder: oneshot::Sender<Bytes>,
+}
+
+impl Debug for DohIdentify {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DohIdentify")
+            .field("request_id", &self.request_id)
+            .field("peer_addr", &self.peer_addr)
+            .finish()
+    }
+}
+
+impl PartialEq for DohIdentify {
+    fn eq(&self, other: &Self) -> bool {
+        self.request_id == other.request_id
+    }
+}
+
+impl Eq for DohIdentify {}
+
+impl ClientIdentify for DohIdentify {
+    /// the plugins see DoH as TCP, the response isn't truncated
+    const TRANSPORT: Transport = Transport::Tcp;
+
+    fn client_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+}
+
+async fn accept_connections(
+    tcp_listener: TcpListener,
+    tls_acceptor: TlsAcceptor,
+    options: Arc<DohOptions>,
+    sender: mpsc::Sender<Query>,
+) {
+    let mut connection_id = 0;
+
+    loop {
+        let (tcp_stream, peer_addr) = match tcp_listener.accept().await {
+            Err(err) => {
+                error!(%err, "accept doh connection failed");
+
+                continue;
+            }
+
+            Ok(accepted) => accepted,
+        };
+
+        connection_id += 1;
+
+        let tls_acceptor = tls_acceptor.clone();
+        let options = options.clone();
+        let sender = sender.clone();
+
+        tokio::spawn(async move {
+            let handshake = tls_acceptor.accept(tcp_stream);
+            let tls_stream = match time::timeout(options.idle_timeout, handshake).await {
+                Err(_) => {
+                    info!(%peer_addr, "tls handshake timeout, close connection");
+
+                    return;
+                }
+
+                Ok(Err(err)) => {
+                    warn!(%err, %peer_addr, "tls handshake failed, close connection");
+
+                    return;
+                }
+
+                Ok(Ok(tls_stream)) => tls_stream,
+            };
+
+            serve_connection(connection_id, peer_addr, tls_stream, options, sender).await;
+        });
+    }
+}
+
+#[instrument(skip(tls_stream, options, sender))]
+async fn serve_connection(
+    connection_id: u64,
+    peer_addr: SocketAddr,
+    tls_stream: tokio_rustls::server::TlsStream<tokio::net::TcpStream>,
+    options: Arc<DohOptions>,
+    sender: mpsc::Sender<Query>,
+) {
+    let idle_timeout = options.idle_timeout;
+    let activity = Arc::new(StdMutex::new(Activity {
+        in_flight: 0,
+        last_active: Instant::now(),
+    }));
+
+    let mut request_id = connection_id << 32;
+    let request_activity = activity.clone();
+    let service = service_fn(move |request| {
+        request_id += 1;
+        let active_request = ActiveRequest::new(request_activity.clone());
+
+        let response = handle_request(
+            request_id,
+            peer_addr,
+            request,
+            options.clone(),
+            sender.clone(),
+        );
+
+        async move {
+            let _active_request = active_request;
+
+            response.await
+        }
+    });
+
+    tokio::select! {
+        result = Http::new().serve_connection(tls_stream, service) => {
+            if let Err(err) = result {
+                warn!(%err, "serve doh connection failed");
+            }
+        }
+
+        _ = wait_idle(&activity, idle_timeout) => {
+            info!("doh connection is idle, close it");
+        }
+    }
+}
+
+/// wait until the connection has no request in flight for the idle timeout
+async fn wait_idle(activity: &StdMutex<Activity>, idle_timeout: Duration) {
+    loop {
+        let idle_at = {
+            let activity = activity.lock().unwrap();
+
+            (activity.in_flight == 0).then(|| activity.last_active + idle_timeout)
+        };
+
+        match idle_at {
+            None => time::sleep(idle_timeout).await,
+            Some(idle_at) if idle_at <= Instant::now() => return,
+            Some(idle_at) => time::sleep_until(idle_at.into()).await,
+        }
+    }
+}
+
+async fn handle_request(
+    request_id: u64,
+    peer_addr: SocketAddr,
+    request: Request<Body>,
+    options: Arc<DohOptions>,
+    sender: mpsc::Sender<Query>,
+) -> Result<Response<Body>, Infallible> {
+    if request.uri().path() != options.path {
+        return Ok(status_response(StatusCode::NOT_FOUND));
+    }
+
+    let buf = match read_query(request).await {
+        Err(status) => return Ok(status_response(status)),
+        Ok(buf) => buf,
+    };
+
+    let (message, buf) = match super::decode(buf, options.lenient_edns) {
+        Err(err) => {
+            error!(%err, "decode doh query failed");
+
+            return Ok(status_response(StatusCode::BAD_REQUEST));
+        }
+
+        Ok(query) => query,
+    };
+
+    let (responder, response) = oneshot::channel();
+    let identify = DohIdentify {
+        request_id,
+        peer_addr,
+        responder,
+    };
+
+    if sender.send((identify, message, buf)).await.is_err() {
+        info!("doh handle is dropped");
+
+        return Ok(status_response(StatusCode::SERVICE_UNAVAILABLE));
+    }
+
+    // the responder is dropped without response when the query is dropped
+    let dns_packet = match response.await {
+        Err(_) => return Ok(status_response(StatusCode::SERVICE_UNAVAILABLE)),
+        Ok(dns_packet) => dns_packet,
+    };
+
+    let mut builder = Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, DNS_MESSAGE_TYPE);
+    if let Some(max_age) = max_age(&dns_packet) {
+        builder = builder.header(CACHE_CONTROL, format!("max-age={max_age}"));
+    }
+
+    Ok(builder
+        .body(Body::from(dns_packet))
+        .unwrap_or_else(|_| status_response(StatusCode::INTERNAL_SERVER_ERROR)))
+}
+
+/// read the query packet from the GET param or the POST body
+async fn read_query(request: Request<Body>) -> Result<Bytes, StatusCode> {
+    match *request.method() {
+        Method::GET => {
+            let dns_param = request
+                .uri()
+                .query()
+                .unwrap_or_default()
+                .split('&')
+                .find_map(|param| param.strip_prefix("dns="))
+                .ok_or(StatusCode::BAD_REQUEST)?;
+
+            URL_SAFE_NO_PAD
+                .decode(dns_param)
+                .map(Bytes::from)
+                .map_err(|err| {
+                    warn!(%err, "decode doh dns param failed");
+
+                    StatusCode::BAD_REQUEST
+                })
+        }
+
+        Method::POST => {
+            let content_type = request
+                .headers()
+                .get(CONTENT_TYPE)
+                .and_then(|content_type| content_type.to_str().ok());
+            if content_type != Some(DNS_MESSAGE_TYPE) {
+                return Err(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+            }
+
+            let mut body = request.into_body();
+            let mut buf = BytesMut::new();
+            while let Some(data) = body.data().await {
+                let data = data.map_err(|err| {
+                    warn!(%err, "read doh body failed");
+
+                    StatusCode::BAD_REQUEST
+                })?;
+
+                // don't buffer a body which can't be a DNS message
+                if buf.len() + data.len() > MAX_DNS_MESSAGE_SIZE {
+                    return Err(StatusCode::PAYLOAD_TOO_LARGE);
+                }
+
+                buf.extend_from_slice(&data);
+            }
+
+            Ok(buf.freeze())
+        }
+
+        _ => Err(StatusCode::METHOD_NOT_ALLOWED),
+    }
+}
+
+/// the freshness of the response is the min TTL of the answers, or the authority records of
+/// a negative response, RFC 8484 section 5.1, the errors are not cached
+fn max_age(dns_packet: &[u8]) -> Option<u32> {
+    let response_message = Message::from_vec(dns_packet).ok()?;
+    if !matches!(
+        response_message.response_code(),
+        ResponseCode::NoError | ResponseCode::NXDomain
+    ) {
+        return Some(0);
+    }
+
+    let records = if response_message.answers().is_empty() {
+        response_message.name_servers()
+    } else {
+        response_message.answers()
+    };
+
+    records.iter().map(|record| record.ttl()).min()
+}
+
+fn status_response(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+
+    response
+}
+
+#[derive(Debug, Error)]
+pub enum AcceptError {
+    #[error("doh listener is stopped")]
+    Stopped,
+}
+
+impl Accept for DohHandle {
+    type Error = AcceptError;
+    type Identify = DohIdentify;
+    type AcceptFuture<'a>
+        = impl Future<Output = Result<(Self::Identify, Message, Bytes), Self::Error>> + 'a + Send
+    where
+        Self: 'a;
+
+    fn accept(&self) -> Self::AcceptFuture<'_> {
+        async move {
+            let mut queries = self.queries.lock().await;
+
+            queries.recv().await.ok_or(AcceptError::Stopped)
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum RespondError {
+    #[error("doh request is closed")]
+    Closed,
+}
+
+impl Respond for DohHandle {
+    type Error = RespondError;
+    type Identify = DohIdentify;
+    type RespondFuture<'a>
+        = impl Future<Output = Result<(), Self::Error>> + 'a + Send
+    where
+        Self: 'a;
+
+    fn respond(&self, identify: Self::Identify, dns_packet: Bytes) -> Self::RespondFuture<'_> {
+        async move {
+            identify
+                .responder
+                .send(dns_packet)
+                .map_err(|_| RespondError::Closed)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::path::{Path, PathBuf};
+    use std::str::FromStr;
+
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpStream;
+    use tokio_rustls::TlsConnector;
+    use trust_dns_proto::op::{MessageType, Query as DnsQuery};
+    use trust_dns_proto::rr::rdata::SOA;
+    use trust_dns_proto::rr::{Name, RData, Record, RecordType};
+
+    use super::*;
+    use crate::config::Tls;
+    use crate::tls;
+
+    const PATH: &str = "/dns-query";
+
+    fn testdata(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("testdata")
+            .join(name)
+    }
+
+    fn options() -> Arc<DohOptions> {
+        Arc::new(DohOptions {
+            path: PATH.to_string(),
+            lenient_edns: false,
+            idle_timeout: Duration::from_secs(10),
+        })
+    }
+
+    fn query_packet() -> Vec<u8> {
+        let mut message = Message::new();
+        message.set_id(1234).add_query(DnsQuery::query(
+            Name::from_str("www.example.com.").unwrap(),
+            RecordType::A,
+        ));
+
+        message.to_vec().unwrap()
+    }
+
+    fn a_record(ttl: u32) -> Record {
+        Record::from_rdata(
+            Name::from_str("www.example.com.").unwrap(),
+            ttl,
+            RData::A(Ipv4Addr::new(192, 0, 2, 1)),
+        )
+    }
+
+    fn response(request_message: &Message) -> Message {
+        let mut message = Message::new();
+        message
+            .set_id(request_message.id())
+            .set_message_type(MessageType::Response)
+            .add_queries(request_message.queries().iter().cloned());
+
+        message
+    }
+
+    /// a server which answers the query with A records of the TTLs
+    fn answer_with(ttls: &'static [u32]) -> mpsc::Sender<Query> {
+        let (sender, mut receiver) = mpsc::channel::<Query>(1);
+        tokio::spawn(async move {
+            while let Some((identify, request_message, _)) = receiver.recv().await {
+                let mut response_message = response(&request_message);
+                response_message.add_answers(ttls.iter().map(|ttl| a_record(*ttl)));
+
+                let _ = identify
+                    .responder
+                    .send(response_message.to_vec().unwrap().into());
+            }
+        });
+
+        sender
+    }
+
+    async fn request(request: Request<Body>, sender: mpsc::Sender<Query>) -> Response<Body> {
+        handle_request(
+            1,
+            "127.0.0.1:5353".parse().unwrap(),
+            request,
+            options(),
+            sender,
+        )
+        .await
+        .unwrap()
+    }
+
+    async fn response_message(response: Response<Body>) -> Message {
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+        Message::from_vec(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn answer_get_query() {
+        let uri = format!("{PATH}?dns={}", URL_SAFE_NO_PAD.encode(query_packet()));
+        let response = request(
+            Request::get(uri).body(Body::empty()).unwrap(),
+            answer_with(&[300, 60]),
+        )
+        .await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CONTENT_TYPE], DNS_MESSAGE_TYPE);
+        assert_eq!(response.headers()[CACHE_CONTROL], "max-age=60");
+
+        let response_message = response_message(response).await;
+        assert_eq!(response_message.id(), 1234);
+        assert_eq!(response_message.answers().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn answer_post_query() {
+        let response = request(
+            Request::post(PATH)
+                .header(CONTENT_TYPE, DNS_MESSAGE_TYPE)
+                .body(Body::from(query_packet()))
+                .unwrap(),
+            answer_with(&[300]),
+        )
+        .await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CACHE_CONTROL], "max-age=300");
+        assert_eq!(response_message(response).await.id(), 1234);
+
+        let response = request(
+            Request::post(PATH)
+                .body(Body::from(query_packet()))
+                .unwrap(),
+            answer_with(&[300]),
+        )
+        .await;
+
+        assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+
+    #[tokio::test]
+    async fn reject_body_over_max_message_size() {
+        let response = request(
+            Request::post(PATH)
+                .header(CONTENT_TYPE, DNS_MESSAGE_TYPE)
+                .body(Body::from(vec![0; MAX_DNS_MESSAGE_SIZE + 1]))
+                .unwrap(),
+            answer_with(&[300]),
+        )
+        .await;
+
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[test]
+    fn max_age_of_negative_and_error_responses() {
+        let request_message = Message::from_vec(&query_packet()).unwrap();
+
+        let mut nxdomain = response(&request_message);
+        nxdomain
+            .set_response_code(ResponseCode::NXDomain)
+            .add_name_server(Record::from_rdata(
+                Name::from_str("example.com.").unwrap(),
+                30,
+                RData::SOA(SOA::new(
+                    Name::from_str("ns.example.com.").unwrap(),
+                    Name::from_str("admin.example.com.").unwrap(),
+                    1,
+                    3600,
+                    600,
+                    86400,
+                    30,
+                )),
+            ));
+        assert_eq!(max_age(&nxdomain.to_vec().unwrap()), Some(30));
+
+        let mut servfail = response(&request_message);
+        servfail
+            .set_response_code(ResponseCode::ServFail)
+            .add_answer(a_record(300));
+        assert_eq!(max_age(&servfail.to_vec().unwrap()), Some(0));
+
+        // no record tells the freshness
+        assert_eq!(max_age(&response(&request_message).to_vec().unwrap()), None);
+    }
+
+    #[tokio::test]
+    async fn close_idle_connection() {
+        let listen_addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let server_config = tls::load_server_config(&Tls {
+            cert_path: testdata("cert.pem"),
+            key_path: testdata("key.pem"),
+            client_ca_path: None,
+        })
+        .await
+        .unwrap();
+        let _doh_handle = DohHandle::new(
+            listen_addr,
+            server_config,
+            PATH.to_string(),
+            false,
+            Duration::from_millis(200),
+        )
+        .await
+        .unwrap();
+
+        let client_config = tls::load_client_config(Some(&testdata("cert.pem")))
+            .await
+            .unwrap();
+        let tcp_stream = TcpStream::connect(listen_addr).await.unwrap();
+        let mut tls_stream = TlsConnector::from(client_config)
+            .connect("dns.example.com".try_into().unwrap(), tcp_stream)
+            .await
+            .unwrap();
+
+        // the server closes the connection, the read gets EOF or an error instead of blocking
+        let mut buf = [0; 1];
+        let read = time::timeout(Duration::from_secs(5), tls_stream.read(&mut buf)).await;
+
+        assert!(matches!(read, Ok(Ok(0) | Err(_))), "{read:?}");
+    }
+}
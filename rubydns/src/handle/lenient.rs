@@ -0,0 +1,147 @@
+//! walk the raw dns packet to drop the malformed EDNS options, which make trust-dns reject
+//! the whole packet
+
+const HEADER_LEN: usize = 12;
+const OPT_RECORD_TYPE: u16 = 41;
+
+/// rebuild the packet keeping the well-formed EDNS options, the options after the first
+/// malformed one are dropped too
+///
+/// return None when the packet can't be walked or has no malformed option
+pub fn strip_malformed_edns_options(packet: &[u8]) -> Option<Vec<u8>> {
+    let question_count = read_u16(packet, 4)?;
+    let record_count = [6, 8, 10]
+        .into_iter()
+        .map(|offset| read_u16(packet, offset).map(usize::from))
+        .sum::<Option<usize>>()?;
+
+    let mut pos = HEADER_LEN;
+    for _ in 0..question_count {
+        // name, type and class
+        pos = skip_name(packet, pos)? + 4;
+    }
+
+    let mut sanitized = Vec::with_capacity(packet.len());
+    let mut copied = 0;
+    for _ in 0..record_count {
+        pos = skip_name(packet, pos)?;
+        let record_type = read_u16(packet, pos)?;
+        // type, class and ttl
+        let rdata_len_pos = pos + 8;
+        let rdata_len = read_u16(packet, rdata_len_pos)? as usize;
+        let rdata_pos = rdata_len_pos + 2;
+        let rdata = packet.get(rdata_pos..rdata_pos + rdata_len)?;
+        pos = rdata_pos + rdata_len;
+
+        if record_type != OPT_RECORD_TYPE {
+            continue;
+        }
+
+        let options_len = well_formed_options_len(rdata);
+        if options_len == rdata.len() {
+            continue;
+        }
+
+        sanitized.extend_from_slice(&packet[copied..rdata_len_pos]);
+        sanitized.extend_from_slice(&(options_len as u16).to_be_bytes());
+        sanitized.extend_from_slice(&rdata[..options_len]);
+        copied = pos;
+    }
+
+    if copied == 0 {
+        return None;
+    }
+
+    sanitized.extend_from_slice(&packet[copied..]);
+
+    Some(sanitized)
+}
+
+/// the length of the leading well-formed options
+fn well_formed_options_len(rdata: &[u8]) -> usize {
+    let mut pos = 0;
+    while let Some(option_len) = read_u16(rdata, pos + 2) {
+        let end = pos + 4 + option_len as usize;
+        if end > rdata.len() {
+            break;
+        }
+
+        pos = end;
+    }
+
+    pos
+}
+
+fn skip_name(packet: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *packet.get(pos)?;
+        match len {
+            0 => return Some(pos + 1),
+            // compression pointer
+            len if len & 0xc0 == 0xc0 => return Some(pos + 2),
+            len => pos += 1 + len as usize,
+        }
+    }
+}
+
+fn read_u16(data: &[u8], pos: usize) -> Option<u16> {
+    let data = data.get(pos..pos + 2)?;
+
+    Some(u16::from_be_bytes([data[0], data[1]]))
+}
+
+#[cfg(test)]
+mod tests {
+    use trust_dns_proto::op::Message;
+    use trust_dns_proto::rr::rdata::opt::{EdnsCode, EdnsOption};
+
+    use super::*;
+
+    const COOKIE_OPTION: [u8; 12] = [0, 10, 0, 8, 1, 2, 3, 4, 5, 6, 7, 8];
+
+    /// a www.example.com A query with an OPT record carrying the options
+    fn query(options: &[u8]) -> Vec<u8> {
+        let mut packet = vec![
+            0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 1, //
+            3, b'w', b'w', b'w', 7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3, b'c', b'o', b'm',
+            0, 0, 1, 0, 1, //
+            0, 0, 41, 0x04, 0xd0, 0, 0, 0, 0,
+        ];
+        packet.extend_from_slice(&(options.len() as u16).to_be_bytes());
+        packet.extend_from_slice(options);
+
+        packet
+    }
+
+    #[test]
+    fn strip_malformed_option() {
+        // the second option claims 10 bytes but has 2
+        let mut options = COOKIE_OPTION.to_vec();
+        options.extend_from_slice(&[0, 15, 0, 10, 0, 0]);
+
+        let packet = strip_malformed_edns_options(&query(&options)).unwrap();
+        assert_eq!(packet, query(&COOKIE_OPTION));
+
+        let message = Message::from_vec(&packet).unwrap();
+        let edns = message.extensions().as_ref().unwrap();
+        assert_eq!(edns.max_payload(), 1232);
+        assert_eq!(
+            edns.option(EdnsCode::Cookie),
+            Some(&EdnsOption::Unknown(10, vec![1, 2, 3, 4, 5, 6, 7, 8]))
+        );
+    }
+
+    #[test]
+    fn keep_well_formed_options() {
+        assert!(strip_malformed_edns_options(&query(&COOKIE_OPTION)).is_none());
+        assert!(strip_malformed_edns_options(&query(&[])).is_none());
+    }
+
+    #[test]
+    fn reject_incomplete_packet() {
+        let mut packet = query(&COOKIE_OPTION);
+        packet.truncate(20);
+
+        assert!(strip_malformed_edns_options(&packet).is_none());
+    }
+}
@@ -1 +1,159 @@
+use std::fmt::Debug;
+use std::future::Future;
+use std::net::SocketAddr;
+
+use bytes::Bytes;
+use tracing::warn;
+use trust_dns_proto::error::{ProtoError, ProtoErrorKind};
+use trust_dns_proto::op::{Header, Message};
+use trust_dns_proto::serialize::binary::{BinDecodable, BinDecoder};
+
+use crate::plugins::Transport;
+
+pub mod doh;
+mod lenient;
+pub mod tcp;
 pub mod udp;
+
+/// the error of trust-dns decoding a message with more than one OPT record
+const MULTIPLE_OPT_ERROR: &str = "more than one edns record present";
+
+pub trait Accept {
+    type Error: std::error::Error + Send + Sync + 'static;
+    type Identify: ClientIdentify;
+    type AcceptFuture<'a>: Future<Output = Result<(Self::Identify, Message, Bytes), Self::Error>>
+        + 'a
+        + Send
+    where
+        Self: 'a;
+
+    fn accept(&self) -> Self::AcceptFuture<'_>;
+}
+
+pub trait Respond {
+    type Error: std::error::Error + Send + Sync + 'static;
+    type Identify: ClientIdentify;
+    type RespondFuture<'a>: Future<Output = Result<(), Self::Error>> + 'a + Send
+    where
+        Self: 'a;
+
+    fn respond(&self, identify: Self::Identify, dns_packet: Bytes) -> Self::RespondFuture<'_>;
+}
+
+/// identify the client which sends the query, so the response can be sent back
+pub trait ClientIdentify: Debug + Eq + Send {
+    /// the transport the query arrives on
+    const TRANSPORT: Transport;
+
+    fn client_addr(&self) -> SocketAddr;
+}
+
+/// decode the query, the malformed EDNS options are dropped when lenient edns is enabled
+fn decode(buf: Bytes, lenient_edns: bool) -> Result<(Message, Bytes), ProtoError> {
+    let err = match Message::from_vec(&buf) {
+        Ok(message) => return Ok((message, buf)),
+        Err(err) => err,
+    };
+
+    if is_multiple_opt_error(&err) {
+        let message = decode_keep_opt_records(&buf)?;
+
+        return Ok((message, buf));
+    }
+
+    if lenient_edns {
+        if let Some(buf) = lenient::strip_malformed_edns_options(&buf) {
+            warn!(%err, "drop malformed edns options");
+
+            let message = Message::from_vec(&buf).map_err(|_| err)?;
+
+            return Ok((message, buf.into()));
+        }
+    }
+
+    Err(err)
+}
+
+/// trust-dns only tells this error by the message
+fn is_multiple_opt_error(err: &ProtoError) -> bool {
+    matches!(err.kind(), ProtoErrorKind::Message(msg) if *msg == MULTIPLE_OPT_ERROR)
+}
+
+/// trust-dns rejects the message with more than one OPT record, decode it again keeping the
+/// OPT records as plain additional records, so the server can answer FORMERR
+fn decode_keep_opt_records(buf: &[u8]) -> Result<Message, ProtoError> {
+    let mut decoder = BinDecoder::new(buf);
+    let header = Header::read(&mut decoder)?;
+    let queries = Message::read_queries(&mut decoder, header.query_count() as _)?;
+    let (answers, _, _) = Message::read_records(&mut decoder, header.answer_count() as _, false)?;
+    let (name_servers, _, _) =
+        Message::read_records(&mut decoder, header.name_server_count() as _, false)?;
+    let (additionals, _, _) =
+        Message::read_records(&mut decoder, header.additional_count() as _, false)?;
+
+    let mut message = Message::new();
+    message
+        .set_header(header)
+        .add_queries(queries)
+        .add_answers(answers)
+        .add_name_servers(name_servers)
+        .add_additionals(additionals);
+
+    Ok(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use trust_dns_proto::rr::RecordType;
+
+    use super::*;
+
+    /// a www.example.com A query with the OPT records
+    fn query(opt_records: usize) -> Bytes {
+        let mut packet = vec![
+            0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0, //
+            3, b'w', b'w', b'w', 7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3, b'c', b'o', b'm',
+            0, 0, 1, 0, 1,
+        ];
+        // the additional count
+        packet[11] = opt_records as u8;
+        for _ in 0..opt_records {
+            packet.extend_from_slice(&[0, 0, 41, 0x04, 0xd0, 0, 0, 0, 0, 0, 0]);
+        }
+
+        packet.into()
+    }
+
+    #[test]
+    fn keep_two_opt_records() {
+        let (message, _) = decode(query(2), false).unwrap();
+
+        assert_eq!(message.id(), 0x1234);
+        assert_eq!(message.queries().len(), 1);
+        assert_eq!(
+            message
+                .additionals()
+                .iter()
+                .filter(|record| record.record_type() == RecordType::OPT)
+                .count(),
+            2
+        );
+    }
+
+    #[test]
+    fn decode_single_opt_record_as_edns() {
+        let (message, _) = decode(query(1), false).unwrap();
+
+        assert_eq!(message.extensions().as_ref().unwrap().max_payload(), 1232);
+        assert!(message.additionals().is_empty());
+    }
+
+    #[test]
+    fn reject_malformed_packet() {
+        // the question is cut in the middle of the name
+        let packet = query(2).slice(..20);
+
+        assert!(decode(packet.clone(), false).is_err());
+        assert!(decode(packet, true).is_err());
+    }
+}
@@ -0,0 +1,407 @@
+use std::fmt::{Debug, Formatter};
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::{BufMut, Bytes, BytesMut};
+use thiserror::Error;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, Mutex};
+use tokio::time;
+use tracing::{error, info, instrument, warn};
+use trust_dns_proto::op::{Edns, Message};
+use trust_dns_proto::rr::rdata::opt::{EdnsCode, EdnsOption};
+
+use super::{Accept, ClientIdentify, Respond};
+use crate::plugins::Transport;
+
+/// the decoded queries waiting for the server, the connections stop reading when it is full
+const QUERY_QUEUE_SIZE: usize = 1024;
+
+/// the edns-tcp-keepalive option code, RFC 7828
+const KEEPALIVE_OPTION_CODE: u16 = 11;
+
+type Query = (TcpIdentify, Message, Bytes);
+
+/// serve the queries over TCP, each query is prefixed with its 2 bytes length
+///
+/// the connections are read by their own tasks, so a slow client doesn't block the others,
+/// the queries of a connection are handled concurrently and may be answered out of order
+#[derive(Debug)]
+pub struct TcpHandle {
+    queries: Mutex<mpsc::Receiver<Query>>,
+    /// the idle timeout advertised to the clients sending the edns-tcp-keepalive option
+    keepalive_timeout: Option<Duration>,
+}
+
+impl TcpHandle {
+    /// the connection which doesn't send a whole query in the read timeout is closed, when
+    /// the keepalive timeout is set, it replaces the read timeout and is advertised in the
+    /// responses to the clients sending the edns-tcp-keepalive option
+    pub async fn new(
+        listen_addr: SocketAddr,
+        lenient_edns: bool,
+        read_timeout: Duration,
+        keepalive_timeout: Option<Duration>,
+    ) -> io::Result<Self> {
+        let tcp_listener = TcpListener::bind(listen_addr).await?;
+        let (sender, receiver) = mpsc::channel(QUERY_QUEUE_SIZE);
+
+        tokio::spawn(accept_connections(
+            tcp_listener,
+            lenient_edns,
+            keepalive_timeout.unwrap_or(read_timeout),
+            sender,
+        ));
+
+        Ok(Self {
+            queries: Mutex::new(receiver),
+            keepalive_timeout,
+        })
+    }
+}
+
+/// route the response to the connection which the query arrives on
+#[derive(Clone)]
+pub struct TcpIdentify {
+    /// the peer address may be reused by a later connection, use the id to tell them apart
+    connection_id: u64,
+    peer_addr: SocketAddr,
+    writer: Arc<Mutex<OwnedWriteHalf>>,
+    /// the query carries the edns-tcp-keepalive option
+    keepalive: bool,
+}
+
+impl Debug for TcpIdentify {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TcpIdentify")
+            .field("connection_id", &self.connection_id)
+            .field("peer_addr", &self.peer_addr)
+            .field("keepalive", &self.keepalive)
+            .finish()
+    }
+}
+
+impl PartialEq for TcpIdentify {
+    fn eq(&self, other: &Self) -> bool {
+        self.connection_id == other.connection_id
+    }
+}
+
+impl Eq for TcpIdentify {}
+
+impl ClientIdentify for TcpIdentify {
+    const TRANSPORT: Transport = Transport::Tcp;
+
+    fn client_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+}
+
+async fn accept_connections(
+    tcp_listener: TcpListener,
+    lenient_edns: bool,
+    read_timeout: Duration,
+    sender: mpsc::Sender<Query>,
+) {
+    let mut connection_id = 0;
+
+    loop {
+        let (tcp_stream, peer_addr) = match tcp_listener.accept().await {
+            Err(err) => {
+                error!(%err, "accept tcp connection failed");
+
+                continue;
+            }
+
+            Ok(accepted) => accepted,
+        };
+
+        connection_id += 1;
+
+        tokio::spawn(read_queries(
+            connection_id,
+            peer_addr,
+            tcp_stream,
+            lenient_edns,
+            read_timeout,
+            sender.clone(),
+        ));
+    }
+}
+
+#[instrument(skip(tcp_stream, lenient_edns, read_timeout, sender))]
+async fn read_queries(
+    connection_id: u64,
+    peer_addr: SocketAddr,
+    tcp_stream: TcpStream,
+    lenient_edns: bool,
+    read_timeout: Duration,
+    sender: mpsc::Sender<Query>,
+) {
+    let (mut reader, writer) = tcp_stream.into_split();
+    let identify = TcpIdentify {
+        connection_id,
+        peer_addr,
+        writer: Arc::new(Mutex::new(writer)),
+        keepalive: false,
+    };
+
+    loop {
+        // an idle or slow client ties up the connection, don't wait for it forever
+        let buf = match time::timeout(read_timeout, read_query(&mut reader)).await {
+            Err(_) => {
+                info!(
+                    ?read_timeout,
+                    "no tcp query in read timeout, close connection"
+                );
+
+                return;
+            }
+
+            Ok(Err(err)) => {
+                warn!(%err, "read tcp query failed, close connection");
+
+                return;
+            }
+
+            Ok(Ok(None)) => return,
+            Ok(Ok(Some(buf))) => buf,
+        };
+
+        // the length prefix keeps the stream in sync, skip the malformed query only
+        let (message, buf) = match super::decode(buf, lenient_edns) {
+            Err(err) => {
+                error!(%err, "decode tcp query failed");
+
+                continue;
+            }
+
+            Ok(query) => query,
+        };
+
+        let identify = TcpIdentify {
+            keepalive: has_keepalive_option(&message),
+            ..identify.clone()
+        };
+
+        if sender.send((identify, message, buf)).await.is_err() {
+            info!("tcp handle is dropped, close connection");
+
+            return;
+        }
+    }
+}
+
+fn has_keepalive_option(message: &Message) -> bool {
+    message
+        .extensions()
+        .as_ref()
+        .map_or(false, |edns| edns.option(EdnsCode::Keepalive).is_some())
+}
+
+/// add the edns-tcp-keepalive option carrying the timeout in units of 100 milliseconds to the
+/// response, the response without OPT record gets one
+fn add_keepalive_option(dns_packet: &[u8], keepalive_timeout: Duration) -> io::Result<Bytes> {
+    let mut message = Message::from_vec(dns_packet)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+
+    let timeout = u16::try_from(keepalive_timeout.as_millis() / 100).unwrap_or(u16::MAX);
+    message
+        .extensions_mut()
+        .get_or_insert_with(Edns::new)
+        .options_mut()
+        .insert(EdnsOption::Unknown(
+            KEEPALIVE_OPTION_CODE,
+            timeout.to_be_bytes().to_vec(),
+        ));
+
+    let dns_packet = message
+        .to_vec()
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+
+    Ok(dns_packet.into())
+}
+
+/// read a length prefixed query, [`None`] means the client closes the connection
+async fn read_query(reader: &mut OwnedReadHalf) -> io::Result<Option<Bytes>> {
+    let len = match reader.read_u16().await {
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+        Ok(len) => len,
+    };
+
+    let mut buf = vec![0; len as usize];
+    reader.read_exact(&mut buf).await?;
+
+    Ok(Some(buf.into()))
+}
+
+#[derive(Debug, Error)]
+pub enum AcceptError {
+    #[error("tcp listener is stopped")]
+    Stopped,
+}
+
+impl Accept for TcpHandle {
+    type Error = AcceptError;
+    type Identify = TcpIdentify;
+    type AcceptFuture<'a> = impl Future<Output = Result<(Self::Identify, Message, Bytes), Self::Error>> + 'a + Send
+        where Self: 'a;
+
+    fn accept(&self) -> Self::AcceptFuture<'_> {
+        async move {
+            let mut queries = self.queries.lock().await;
+
+            queries.recv().await.ok_or(AcceptError::Stopped)
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum RespondError {
+    #[error("io error: {0}")]
+    IoError(#[from] io::Error),
+
+    #[error("dns packet is too large for tcp: {0} bytes")]
+    TooLarge(usize),
+}
+
+impl Respond for TcpHandle {
+    type Error = RespondError;
+    type Identify = TcpIdentify;
+    type RespondFuture<'a> = impl Future<Output = Result<(), Self::Error>> + 'a + Send
+        where
+            Self: 'a;
+
+    fn respond(&self, identify: Self::Identify, dns_packet: Bytes) -> Self::RespondFuture<'_> {
+        async move {
+            let dns_packet = match self.keepalive_timeout {
+                Some(keepalive_timeout) if identify.keepalive => {
+                    add_keepalive_option(&dns_packet, keepalive_timeout)?
+                }
+
+                _ => dns_packet,
+            };
+
+            let len = u16::try_from(dns_packet.len())
+                .map_err(|_| RespondError::TooLarge(dns_packet.len()))?;
+
+            let mut buf = BytesMut::with_capacity(2 + dns_packet.len());
+            buf.put_u16(len);
+            buf.put_slice(&dns_packet);
+
+            // the responses of the concurrent queries share the connection, write them one
+            // by one
+            let mut writer = identify.writer.lock().await;
+            writer.write_all(&buf).await?;
+
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use trust_dns_proto::op::{MessageType, Query as DnsQuery};
+    use trust_dns_proto::rr::{Name, RecordType};
+
+    use super::*;
+
+    /// connect a client to a connection served by [`read_queries`]
+    async fn connect(idle_timeout: Duration) -> (TcpStream, mpsc::Receiver<Query>) {
+        let tcp_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(tcp_listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (tcp_stream, peer_addr) = tcp_listener.accept().await.unwrap();
+        let (sender, receiver) = mpsc::channel(1);
+
+        tokio::spawn(read_queries(
+            1,
+            peer_addr,
+            tcp_stream,
+            false,
+            idle_timeout,
+            sender,
+        ));
+
+        (client, receiver)
+    }
+
+    async fn send_keepalive_query(client: &mut TcpStream) {
+        let mut message = Message::new();
+        message.add_query(DnsQuery::query(
+            Name::from_str("www.example.com.").unwrap(),
+            RecordType::A,
+        ));
+        let mut edns = Edns::new();
+        edns.options_mut()
+            .insert(EdnsOption::Unknown(KEEPALIVE_OPTION_CODE, vec![]));
+        message.set_edns(edns);
+
+        let buf = message.to_vec().unwrap();
+        client.write_u16(buf.len() as _).await.unwrap();
+        client.write_all(&buf).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn respond_keepalive_option() {
+        let (mut client, mut receiver) = connect(Duration::from_secs(5)).await;
+        send_keepalive_query(&mut client).await;
+
+        let (identify, mut message, _) = receiver.recv().await.unwrap();
+        assert!(identify.keepalive);
+
+        let (_sender, queries) = mpsc::channel(1);
+        let tcp_handle = TcpHandle {
+            queries: Mutex::new(queries),
+            keepalive_timeout: Some(Duration::from_secs(30)),
+        };
+        message.set_message_type(MessageType::Response);
+        tcp_handle
+            .respond(identify, message.to_vec().unwrap().into())
+            .await
+            .unwrap();
+
+        let len = client.read_u16().await.unwrap();
+        let mut buf = vec![0; len as usize];
+        client.read_exact(&mut buf).await.unwrap();
+        let response = Message::from_vec(&buf).unwrap();
+
+        // 30 seconds in units of 100 milliseconds
+        assert_eq!(
+            response
+                .extensions()
+                .as_ref()
+                .unwrap()
+                .option(EdnsCode::Keepalive),
+            Some(&EdnsOption::Unknown(
+                KEEPALIVE_OPTION_CODE,
+                300u16.to_be_bytes().to_vec()
+            ))
+        );
+    }
+
+    #[tokio::test]
+    async fn close_idle_connection() {
+        let (mut client, mut receiver) = connect(Duration::from_millis(100)).await;
+        send_keepalive_query(&mut client).await;
+
+        // the connection is closed when the queries holding its writer are dropped
+        drop(receiver.recv().await.unwrap());
+
+        let mut buf = [0; 1];
+        let n = time::timeout(Duration::from_secs(5), client.read(&mut buf))
+            .await
+            .expect("idle connection is not closed")
+            .unwrap();
+        assert_eq!(n, 0);
+    }
+}
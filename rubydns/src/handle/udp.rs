@@ -1,4 +1,3 @@
-use std::fmt::Debug;
 use std::future::Future;
 use std::io;
 use std::net::SocketAddr;
@@ -6,41 +5,50 @@ use std::net::SocketAddr;
 use bytes::{Bytes, BytesMut};
 use thiserror::Error;
 use tokio::net::UdpSocket;
+use tracing::{debug, error};
 use trust_dns_proto::error::ProtoError;
 use trust_dns_proto::op::Message;
 
-pub trait Accept {
-    type Error: std::error::Error + Send + Sync + 'static;
-    type Identify: Debug + Eq + Send;
-    type AcceptFuture<'a>: Future<Output = Result<(Self::Identify, Message, Bytes), Self::Error>>
-        + 'a
-        + Send
-    where
-        Self: 'a;
+use super::{Accept, ClientIdentify, Respond};
+use crate::plugins::Transport;
 
-    fn accept(&self) -> Self::AcceptFuture<'_>;
-}
-
-pub trait Respond {
-    type Error: std::error::Error + Send + Sync + 'static;
-    type Identify: Debug + Eq + Send;
-    type RespondFuture<'a>: Future<Output = Result<(), Self::Error>> + 'a + Send
-    where
-        Self: 'a;
+/// the max UDP payload size of the client without EDNS, it is also the lower bound of the
+/// advertised EDNS payload size, RFC 6891
+const CLASSIC_MAX_PAYLOAD: u16 = 512;
 
-    fn respond(&self, identify: Self::Identify, dns_packet: Bytes) -> Self::RespondFuture<'_>;
-}
+/// the size of the DNS header
+const HEADER_SIZE: usize = 12;
 
 #[derive(Debug)]
 pub struct UdpHandle {
     udp_socket: UdpSocket,
+    /// drop the malformed EDNS options instead of the whole query
+    lenient_edns: bool,
 }
 
 impl UdpHandle {
-    pub async fn new(listen_addr: SocketAddr) -> io::Result<Self> {
+    pub async fn new(listen_addr: SocketAddr, lenient_edns: bool) -> io::Result<Self> {
         let udp_socket = UdpSocket::bind(listen_addr).await?;
 
-        Ok(Self { udp_socket })
+        Ok(Self {
+            udp_socket,
+            lenient_edns,
+        })
+    }
+}
+
+/// the client and the max UDP payload size it accepts
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct UdpIdentify {
+    addr: SocketAddr,
+    max_payload: u16,
+}
+
+impl ClientIdentify for UdpIdentify {
+    const TRANSPORT: Transport = Transport::Udp;
+
+    fn client_addr(&self) -> SocketAddr {
+        self.addr
     }
 }
 
@@ -49,13 +57,13 @@ pub enum AcceptError {
     #[error("io error: {0}")]
     IoError(#[from] io::Error),
 
-    #[error("dns proto error: {0}")]
-    ProtoError(#[from] ProtoError),
+    #[error("malformed query from {client}: {err}")]
+    Malformed { client: SocketAddr, err: ProtoError },
 }
 
 impl Accept for UdpHandle {
     type Error = AcceptError;
-    type Identify = SocketAddr;
+    type Identify = UdpIdentify;
     type AcceptFuture<'a> = impl Future<Output = Result<(Self::Identify, Message, Bytes), Self::Error>> + 'a + Send
         where Self: 'a;
 
@@ -74,9 +82,34 @@ impl Accept for UdpHandle {
             }
             let buf = buf.split().freeze();
 
-            let message = Message::from_vec(&buf)?;
+            let (message, buf) = match super::decode(buf.clone(), self.lenient_edns) {
+                Err(err) => {
+                    // answer FORMERR, so the client doesn't retry the query until timeout
+                    if let Some(response) = format_error_response(&buf) {
+                        self.udp_socket.send_to(&response, source).await?;
+                    }
+
+                    return Err(AcceptError::Malformed {
+                        client: source,
+                        err,
+                    });
+                }
+
+                Ok(query) => query,
+            };
+
+            let max_payload = message
+                .extensions()
+                .as_ref()
+                .map(|edns| edns.max_payload())
+                .unwrap_or(CLASSIC_MAX_PAYLOAD)
+                .max(CLASSIC_MAX_PAYLOAD);
+            let identify = UdpIdentify {
+                addr: source,
+                max_payload,
+            };
 
-            Ok((source, message, buf))
+            Ok((identify, message, buf))
         }
     }
 }
@@ -89,16 +122,149 @@ pub enum RespondError {
 
 impl Respond for UdpHandle {
     type Error = RespondError;
-    type Identify = SocketAddr;
+    type Identify = UdpIdentify;
     type RespondFuture<'a> = impl Future<Output = Result<(), Self::Error>> + 'a + Send
         where
             Self: 'a;
 
     fn respond(&self, identify: Self::Identify, dns_packet: Bytes) -> Self::RespondFuture<'_> {
         async move {
-            self.udp_socket.send_to(&dns_packet, identify).await?;
+            let dns_packet = if dns_packet.len() > identify.max_payload as usize {
+                truncate(dns_packet, identify.max_payload)
+            } else {
+                dns_packet
+            };
+
+            self.udp_socket.send_to(&dns_packet, identify.addr).await?;
 
             Ok(())
         }
     }
 }
+
+/// build a FORMERR response with only the header from the raw query, the id, opcode and RD
+/// bit are kept, [`None`] means the header is incomplete or the packet is a response, which
+/// is never answered so two servers can't bounce the errors between them
+fn format_error_response(buf: &[u8]) -> Option<[u8; HEADER_SIZE]> {
+    if buf.len() < HEADER_SIZE || buf[2] & 0x80 != 0 {
+        return None;
+    }
+
+    let mut response = [0; HEADER_SIZE];
+    response[..2].copy_from_slice(&buf[..2]);
+    // QR, opcode and RD
+    response[2] = 0x80 | (buf[2] & 0x79);
+    // FORMERR, RFC 1035
+    response[3] = 1;
+
+    Some(response)
+}
+
+/// the response doesn't fit in the client payload size, drop the records and set the TC
+/// bit, so the client retries over TCP, the OPT record is kept
+fn truncate(dns_packet: Bytes, max_payload: u16) -> Bytes {
+    let mut response_message = match Message::from_vec(&dns_packet) {
+        Err(err) => {
+            error!(%err, "decode dns response packet failed, send it without truncation");
+
+            return dns_packet;
+        }
+
+        Ok(response_message) => response_message,
+    };
+
+    debug!(
+        size = dns_packet.len(),
+        max_payload, "response exceeds client payload size, truncate it"
+    );
+
+    response_message.take_answers();
+    response_message.take_name_servers();
+    response_message.take_additionals();
+    response_message.set_truncated(true);
+
+    match response_message.to_vec() {
+        Err(err) => {
+            error!(%err, "encode truncated dns response packet failed");
+
+            dns_packet
+        }
+
+        Ok(dns_packet) => dns_packet.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::str::FromStr;
+
+    use trust_dns_proto::op::{Edns, MessageType, Query};
+    use trust_dns_proto::rr::{Name, RData, Record, RecordType};
+
+    use super::*;
+
+    #[test]
+    fn format_error_keeps_opcode_and_rd() {
+        // opcode 15 with the AA, TC and RD bits, RA and rcode are set too
+        let mut query = [0; HEADER_SIZE + 4];
+        query[..4].copy_from_slice(&[0x12, 0x34, 0x7f, 0xff]);
+        query[5] = 1;
+
+        let response = format_error_response(&query).unwrap();
+
+        assert_eq!(response[..2], [0x12, 0x34]);
+        // QR is set, AA and TC are cleared by the 0x79 mask
+        assert_eq!(response[2], 0xf9);
+        assert_eq!(response[3], 1);
+        // no question is echoed
+        assert_eq!(response[4..], [0; 8]);
+    }
+
+    #[test]
+    fn format_error_ignores_response_and_short_header() {
+        let mut response = [0; HEADER_SIZE];
+        response[2] = 0x80;
+
+        assert!(format_error_response(&response).is_none());
+        assert!(format_error_response(&[0x12, 0x34, 0x01]).is_none());
+    }
+
+    #[test]
+    fn truncate_sets_tc_and_keeps_opt() {
+        let name = Name::from_str("www.example.com.").unwrap();
+        let mut response_message = Message::new();
+        response_message
+            .set_id(1234)
+            .set_message_type(MessageType::Response)
+            .add_query(Query::query(name.clone(), RecordType::A))
+            .add_answers((0..64).map(|i| {
+                Record::from_rdata(name.clone(), 60, RData::A(Ipv4Addr::new(10, 0, 0, i)))
+            }));
+        let mut edns = Edns::new();
+        edns.set_max_payload(1232);
+        response_message.set_edns(edns);
+        let dns_packet = Bytes::from(response_message.to_vec().unwrap());
+        assert!(dns_packet.len() > CLASSIC_MAX_PAYLOAD as usize);
+
+        let truncated = truncate(dns_packet, CLASSIC_MAX_PAYLOAD);
+        assert!(truncated.len() <= CLASSIC_MAX_PAYLOAD as usize);
+
+        let truncated = Message::from_vec(&truncated).unwrap();
+        assert_eq!(truncated.id(), 1234);
+        assert!(truncated.truncated());
+        assert!(truncated.answers().is_empty());
+        assert_eq!(truncated.queries(), response_message.queries());
+        assert_eq!(truncated.extensions().as_ref().unwrap().max_payload(), 1232);
+    }
+
+    #[test]
+    fn truncate_keeps_undecodable_response() {
+        let dns_packet = Bytes::from_static(&[0x12, 0x34, 0x80]);
+
+        assert_eq!(
+            truncate(dns_packet.clone(), CLASSIC_MAX_PAYLOAD),
+            dns_packet
+        );
+    }
+}
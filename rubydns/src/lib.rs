@@ -1,52 +1,129 @@
 #![feature(type_alias_impl_trait)]
+#![cfg_attr(test, feature(test))]
 
 extern crate core;
 
-use std::io;
-use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use std::{env, io};
 
+use anyhow::anyhow;
 use clap::Parser;
-use futures_util::{stream, StreamExt, TryStreamExt};
-use tracing::level_filters::LevelFilter;
-use tracing::subscriber;
+use tokio::task::JoinHandle;
+use tracing::{error, subscriber};
 use tracing_subscriber::layer::SubscriberExt;
-use tracing_subscriber::{fmt, Registry};
+use tracing_subscriber::{fmt, EnvFilter, Layer, Registry};
+use wasmtime::Engine;
 
-use crate::config::Config;
+use crate::config::{Config, LogFormat};
+use crate::control::Control;
+use crate::handle::doh::DohHandle;
+use crate::handle::tcp::TcpHandle;
 use crate::handle::udp::UdpHandle;
-use crate::plugins::{PluginChain, PluginConfig};
-use crate::server::Server;
+use crate::plugins::PluginChain;
+use crate::prewarm::Prewarm;
+use crate::server::{Server, ServerState};
 
 mod config;
+mod control;
 mod handle;
+mod metrics;
 mod plugins;
+mod prewarm;
 mod server;
+mod tls;
 
 #[derive(Debug, Parser)]
 struct Args {
     #[clap(short, long)]
     config: PathBuf,
+    /// load the config and every plugin chain without binding any socket, report the result
+    /// of each server and exit
+    #[clap(long, alias = "validate")]
+    check: bool,
 }
 
 pub async fn run() -> anyhow::Result<()> {
     let args = Args::parse();
 
-    init_log();
+    let mut config = Config::parse(&args.config).await?;
 
-    let config = Config::parse(&args.config).await?;
-    let plugin_dir = Path::new(&config.plugin_dir);
+    init_log(&config.log)?;
 
-    let servers = stream::iter(config.servers.into_iter())
-        .map(Ok::<_, anyhow::Error>)
-        .and_then(|server| create_server(Path::new(plugin_dir), server.listen_addr, server.plugins))
-        .try_collect::<Vec<_>>()
-        .await?;
+    let engine = plugins::new_engine()?;
 
-    let tasks = servers
-        .into_iter()
-        .map(|mut server| tokio::spawn(async move { server.serve().await }))
+    if args.check {
+        return check(engine, config).await;
+    }
+
+    let plugin_dir = config.plugin_dir.as_path();
+    let listen_addrs = config
+        .servers
+        .iter()
+        .map(|server| server.listen_addr)
         .collect::<Vec<_>>();
+    let prewarms = config
+        .servers
+        .iter_mut()
+        .map(|server| server.prewarm.take())
+        .collect::<Vec<_>>();
+
+    // bind the listeners before creating the plugin chains, the queries arriving before a
+    // chain is ready are handled by the not ready policy of the server
+    let mut states = Vec::with_capacity(config.servers.len());
+    let mut tasks = Vec::with_capacity(config.servers.len() * 2);
+    for server in &config.servers {
+        let (state, server_tasks) = create_server(server).await?;
+
+        states.push(state);
+        tasks.extend(server_tasks);
+    }
+
+    for (server, state) in config.servers.into_iter().zip(&states) {
+        state.set_plugin_chain(create_plugin_chain(engine.clone(), plugin_dir, server).await?);
+    }
+
+    for (prewarm, state) in prewarms.into_iter().zip(&states) {
+        if let Some(prewarm) = prewarm {
+            let prewarm = Prewarm::new(state.clone(), prewarm)?;
+
+            tokio::spawn(prewarm.run());
+        }
+    }
+
+    if let Some(metrics_addr) = config.metrics_addr {
+        tokio::spawn(async move {
+            if let Err(err) = metrics::serve(metrics_addr).await {
+                error!(%err, "metrics listener stopped");
+            }
+        });
+    }
+
+    let control = Arc::new(Control::new(
+        engine,
+        args.config.clone(),
+        listen_addrs.into_iter().zip(states).collect(),
+    ));
+
+    tokio::spawn({
+        let control = control.clone();
+
+        async move {
+            if let Err(err) = control.reload_on_sighup().await {
+                error!(%err, "SIGHUP reload stopped");
+            }
+        }
+    });
+
+    if let Some(control_socket) = config.control_socket {
+        tokio::spawn(async move {
+            if let Err(err) = control.serve(&control_socket).await {
+                error!(%err, "control socket stopped");
+            }
+        });
+    }
+
     for task in tasks {
         task.await.unwrap();
     }
@@ -54,24 +131,176 @@ pub async fn run() -> anyhow::Result<()> {
     Ok(())
 }
 
+/// create the plugin chains of all servers, which validates the plugin configs, the servers
+/// are reported one by one so every broken server is shown
+async fn check(engine: Engine, config: Config) -> anyhow::Result<()> {
+    let plugin_dir = config.plugin_dir.as_path();
+    let servers = config.servers.len();
+
+    let mut failed = 0;
+    for server in config.servers {
+        let listen_addr = server.listen_addr;
+        match create_plugin_chain(engine.clone(), plugin_dir, server).await {
+            Err(err) => {
+                failed += 1;
+
+                println!("{listen_addr} error: {err:#}");
+            }
+
+            Ok(_) => println!("{listen_addr} ok"),
+        }
+    }
+
+    if failed > 0 {
+        return Err(anyhow!("{failed} of {servers} servers are invalid"));
+    }
+
+    Ok(())
+}
+
+/// serve the listen address over the configured transports, the servers share one state so
+/// they use the same plugin chain
 async fn create_server(
+    server: &config::Server,
+) -> anyhow::Result<(Arc<ServerState>, Vec<JoinHandle<()>>)> {
+    if server.transports.is_empty() {
+        return Err(anyhow!("server {} has no transport", server.listen_addr));
+    }
+
+    let state = Arc::new(ServerState::new(
+        server.not_ready,
+        server.query_timeout_ms.map(Duration::from_millis),
+        server.max_inflight,
+    ));
+    let mut tasks = Vec::with_capacity(server.transports.len());
+    for transport in &server.transports {
+        let task = match transport {
+            config::Transport::Udp => {
+                let udp_handle = UdpHandle::new(server.listen_addr, server.lenient_edns).await?;
+                let mut udp_server = Server::new(udp_handle, state.clone());
+
+                tokio::spawn(async move { udp_server.serve().await })
+            }
+
+            config::Transport::Tcp => {
+                let tcp_handle = TcpHandle::new(
+                    server.listen_addr,
+                    server.lenient_edns,
+                    Duration::from_millis(server.tcp_read_timeout_ms),
+                    server.tcp_keepalive_timeout_ms.map(Duration::from_millis),
+                )
+                .await?;
+                let mut tcp_server = Server::new(tcp_handle, state.clone());
+
+                tokio::spawn(async move { tcp_server.serve().await })
+            }
+
+            config::Transport::Doh => {
+                let tls = server
+                    .tls
+                    .as_ref()
+                    .ok_or_else(|| anyhow!("server {} doh has no tls", server.listen_addr))?;
+
+                let doh_handle = DohHandle::new(
+                    server.listen_addr,
+                    tls::load_server_config(tls).await?,
+                    server.doh_path.clone(),
+                    server.lenient_edns,
+                    Duration::from_millis(server.tcp_read_timeout_ms),
+                )
+                .await?;
+                let mut doh_server = Server::new(doh_handle, state.clone());
+
+                tokio::spawn(async move { doh_server.serve().await })
+            }
+        };
+
+        tasks.push(task);
+    }
+
+    Ok((state, tasks))
+}
+
+async fn create_plugin_chain(
+    engine: Engine,
     plugin_dir: &Path,
-    listen_addr: SocketAddr,
-    plugins: Vec<PluginConfig>,
-) -> anyhow::Result<Server<UdpHandle>> {
-    let plugin_chain = PluginChain::new(plugin_dir, plugins).await?;
-    let udp_handle = UdpHandle::new(listen_addr).await?;
+    server: config::Server,
+) -> anyhow::Result<PluginChain> {
+    if let Some(tls) = &server.tls {
+        // the DoH listener loads it when binding, load it here too so a broken cert or key
+        // fails the check and the reload
+        tls::load_server_config(tls).await?;
+    }
 
-    Ok(Server::new(udp_handle, plugin_chain))
+    PluginChain::new(
+        engine,
+        plugin_dir,
+        server.plugins,
+        server.chains,
+        server.routes,
+        server.max_response_bytes,
+        server.slow_query_threshold_ms.map(Duration::from_millis),
+    )
+    .await
 }
 
-fn init_log() {
-    let layer = fmt::layer()
-        .pretty()
-        .with_target(true)
-        .with_writer(io::stderr);
+/// it can only be called once, the global subscriber can't be replaced
+fn init_log(log: &config::Log) -> anyhow::Result<()> {
+    let layer = fmt::layer().with_target(log.target).with_writer(io::stderr);
+    let layer = match log.format {
+        LogFormat::Pretty => layer.pretty().boxed(),
+        LogFormat::Compact => layer.compact().boxed(),
+        LogFormat::Json => layer.json().boxed(),
+    };
+
+    let filter = match env::var(EnvFilter::DEFAULT_ENV) {
+        Ok(_) => EnvFilter::try_from_default_env()?,
+        Err(_) => EnvFilter::try_new(&log.level)?,
+    };
+
+    let layered = Registry::default().with(layer).with(filter);
+
+    subscriber::set_global_default(layered)?;
+
+    Ok(())
+}
 
-    let layered = Registry::default().with(layer).with(LevelFilter::INFO);
+#[cfg(test)]
+mod tests {
+    use std::net::UdpSocket;
 
-    subscriber::set_global_default(layered).unwrap();
+    use tokio::fs;
+
+    use super::*;
+    use crate::plugins::test_plugin;
+
+    #[tokio::test]
+    async fn check_reports_invalid_servers() {
+        let plugin_dir = test_plugin::plugin_dir("check", &[("test", test_plugin::RESPOND)]);
+        // the listen address is in use, check doesn't bind it
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let listen_addr = socket.local_addr().unwrap();
+
+        let config_path = plugin_dir.join("config.yaml");
+        let config_yaml = |plugins: &str| {
+            format!(
+                "plugin_dir: {}\nservers:\n  - listen_addr: {listen_addr}\n    plugins: [{{name: test}}]\n  - listen_addr: 127.0.0.1:53\n    plugins: [{plugins}]\n",
+                plugin_dir.display()
+            )
+        };
+        let engine = plugins::new_engine().unwrap();
+
+        fs::write(&config_path, config_yaml("{name: test}"))
+            .await
+            .unwrap();
+        let config = Config::parse(&config_path).await.unwrap();
+        check(engine.clone(), config).await.unwrap();
+
+        fs::write(&config_path, config_yaml("{name: missing}"))
+            .await
+            .unwrap();
+        let config = Config::parse(&config_path).await.unwrap();
+        let err = check(engine, config).await.err().unwrap();
+        assert_eq!(err.to_string(), "1 of 2 servers are invalid");
+    }
 }
@@ -0,0 +1,229 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tap::TapFallible;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time;
+use tracing::{error, info};
+use trust_dns_proto::op::ResponseCode;
+
+/// the upper bounds of the plugin chain latency buckets, in seconds
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// a scraper must send its request in it
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// the request larger than it isn't a scrape
+const MAX_REQUEST_BYTES: usize = 8192;
+
+static METRICS: Metrics = Metrics::new();
+
+/// the process wide metrics, the servers and plugins update them, they are exposed in the
+/// Prometheus text format
+struct Metrics {
+    queries: AtomicU64,
+    /// keyed by the low 4 bits of the response code
+    responses: Mutex<BTreeMap<u8, u64>>,
+    latency_buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    latency_sum_micros: AtomicU64,
+    latency_count: AtomicU64,
+    /// the events reported by the plugins, keyed by the plugin name and the event name
+    plugin_events: Mutex<BTreeMap<(String, String), u64>>,
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const ZERO: AtomicU64 = AtomicU64::new(0);
+
+        Self {
+            queries: AtomicU64::new(0),
+            responses: Mutex::new(BTreeMap::new()),
+            latency_buckets: [ZERO; LATENCY_BUCKETS.len()],
+            latency_sum_micros: AtomicU64::new(0),
+            latency_count: AtomicU64::new(0),
+            plugin_events: Mutex::new(BTreeMap::new()),
+        }
+    }
+}
+
+pub fn record_query() {
+    METRICS.queries.fetch_add(1, Ordering::Relaxed);
+}
+
+/// the response code is read from the header of the response packet, the extended bits in
+/// the OPT record are ignored
+pub fn record_response(response_packet: &[u8]) {
+    let response_code = match response_packet.get(3) {
+        None => return,
+        Some(flags) => flags & 0x0f,
+    };
+
+    *METRICS
+        .responses
+        .lock()
+        .unwrap()
+        .entry(response_code)
+        .or_default() += 1;
+}
+
+pub fn record_plugin_chain_latency(elapsed: Duration) {
+    let seconds = elapsed.as_secs_f64();
+    for (bucket, le) in METRICS.latency_buckets.iter().zip(LATENCY_BUCKETS) {
+        if seconds <= le {
+            bucket.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    METRICS
+        .latency_sum_micros
+        .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    METRICS.latency_count.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn record_plugin_event(plugin: &str, event: &str) {
+    *METRICS
+        .plugin_events
+        .lock()
+        .unwrap()
+        .entry((plugin.to_string(), event.to_string()))
+        .or_default() += 1;
+}
+
+fn render() -> String {
+    let mut output = String::new();
+
+    let _ = writeln!(output, "# TYPE rubydns_queries_total counter");
+    let _ = writeln!(
+        output,
+        "rubydns_queries_total {}",
+        METRICS.queries.load(Ordering::Relaxed)
+    );
+
+    let _ = writeln!(output, "# TYPE rubydns_responses_total counter");
+    for (response_code, count) in METRICS.responses.lock().unwrap().iter() {
+        let _ = writeln!(
+            output,
+            "rubydns_responses_total{{rcode=\"{:?}\"}} {count}",
+            ResponseCode::from_low(*response_code)
+        );
+    }
+
+    let _ = writeln!(
+        output,
+        "# TYPE rubydns_plugin_chain_duration_seconds histogram"
+    );
+    for (bucket, le) in METRICS.latency_buckets.iter().zip(LATENCY_BUCKETS) {
+        let _ = writeln!(
+            output,
+            "rubydns_plugin_chain_duration_seconds_bucket{{le=\"{le}\"}} {}",
+            bucket.load(Ordering::Relaxed)
+        );
+    }
+    let count = METRICS.latency_count.load(Ordering::Relaxed);
+    let _ = writeln!(
+        output,
+        "rubydns_plugin_chain_duration_seconds_bucket{{le=\"+Inf\"}} {count}"
+    );
+    let _ = writeln!(
+        output,
+        "rubydns_plugin_chain_duration_seconds_sum {}",
+        METRICS.latency_sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0
+    );
+    let _ = writeln!(
+        output,
+        "rubydns_plugin_chain_duration_seconds_count {count}"
+    );
+
+    let _ = writeln!(output, "# TYPE rubydns_plugin_events_total counter");
+    for ((plugin, event), count) in METRICS.plugin_events.lock().unwrap().iter() {
+        let _ = writeln!(
+            output,
+            "rubydns_plugin_events_total{{plugin=\"{}\",event=\"{}\"}} {count}",
+            escape_label(plugin),
+            escape_label(event)
+        );
+    }
+
+    output
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// serve `GET /metrics` over plain HTTP, for the Prometheus scrapers
+pub async fn serve(addr: SocketAddr) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .tap_err(|err| error!(%err, %addr, "bind metrics listener failed"))?;
+
+    info!(%addr, "metrics listening");
+
+    loop {
+        let (stream, peer_addr) = match listener.accept().await {
+            Err(err) => {
+                error!(%err, "accept metrics connection failed");
+
+                continue;
+            }
+
+            Ok(conn) => conn,
+        };
+
+        tokio::spawn(async move {
+            if let Err(err) = handle_conn(stream).await {
+                error!(%err, %peer_addr, "handle metrics connection failed");
+            }
+        });
+    }
+}
+
+/// answer one request and close the connection
+async fn handle_conn(mut stream: TcpStream) -> anyhow::Result<()> {
+    let mut request = Vec::with_capacity(1024);
+    time::timeout(REQUEST_TIMEOUT, async {
+        let mut buf = [0; 1024];
+        while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+            if request.len() > MAX_REQUEST_BYTES {
+                return Err(anyhow::anyhow!("metrics request is too large"));
+            }
+
+            let n = stream.read(&mut buf).await?;
+            if n == 0 {
+                return Err(anyhow::anyhow!("metrics request is incomplete"));
+            }
+
+            request.extend_from_slice(&buf[..n]);
+        }
+
+        Ok(())
+    })
+    .await??;
+
+    let response = if request.starts_with(b"GET /metrics ") {
+        let body = render();
+
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        )
+    } else {
+        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+    };
+
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+
+    Ok(())
+}
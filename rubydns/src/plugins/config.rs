@@ -2,10 +2,90 @@ use std::collections::HashMap;
 
 use serde::Deserialize;
 
+use super::host_helper::Eviction;
+
 #[derive(Debug, Deserialize)]
 pub struct Plugin {
     pub name: String,
+    /// the plugin file, a relative path is under the plugin dir, the `.wasm` extension can
+    /// be omitted, it is `<name>.wasm` under the plugin dir when unset
     pub plugin_path: Option<String>,
+    /// bound a single run of this plugin, including the downstream plugins it calls
+    pub timeout_ms: Option<u64>,
+    /// the wasm fuel of a single run, the query is answered SERVFAIL when the plugin uses it
+    /// up, unbounded when unset, the plugin yields while it runs, so `timeout_ms` still
+    /// bounds the wall clock time
+    pub fuel_limit: Option<u64>,
+    /// skip the plugin with a warning when it fails to load, instead of failing the chain
+    #[serde(default)]
+    pub optional: bool,
+    /// store identical values once in the plugin store, useful when many keys cache the same
+    /// response
+    #[serde(default)]
+    pub dedup_store: bool,
+    /// evict entries from the plugin store when it has more entries, unbounded when unset
+    pub store_max_entries: Option<usize>,
+    #[serde(default)]
+    pub store_eviction: Eviction,
+    /// remove the expired store entries in background every interval, 0 disables it, the
+    /// expired entries are still removed when they are read
+    #[serde(default = "default_store_sweep_interval_secs")]
+    pub store_sweep_interval_secs: u64,
+    /// the most keys returned by a `map-keys` scan
+    #[serde(default = "default_store_scan_limit")]
+    pub store_scan_limit: usize,
+    /// use the store shared by the `shared` plugins of the server instead of an own one, so
+    /// the plugins can share state, the shared plugins must have the same store options
+    #[serde(default)]
+    pub shared: bool,
+    /// the most instances of this plugin, every instance holds its own wasmtime store with
+    /// the plugin linear memory, usually a few MiB, the deadpool default is 4 times the CPU
+    /// count
+    pub pool_max_size: Option<usize>,
+    /// fail the query when no instance is free in it, wait forever when unset
+    pub pool_timeout_ms: Option<u64>,
+    /// the named plugin chains which this plugin can call by name
+    #[serde(default)]
+    pub branches: HashMap<String, Vec<Plugin>>,
+    /// the precompiled FST maps which this plugin can read by name, a relative path is under
+    /// the plugin dir, the plugin can't open any other file
+    #[serde(default)]
+    pub static_maps: HashMap<String, String>,
+    /// the CA files which this plugin can verify the TLS servers with by name, a relative path
+    /// is under the plugin dir, the webpki roots are used when the plugin names no CA
+    #[serde(default)]
+    pub tls_cas: HashMap<String, String>,
     #[serde(flatten)]
     pub config: HashMap<String, serde_yaml::Value>,
 }
+
+/// route the matched queries to a named chain of the server
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Route {
+    /// match the query name and its subdomains, such as `.internal`, any name when unset
+    pub suffix: Option<String>,
+    /// match the query types, such as `A` or `PTR`, any type when empty
+    #[serde(default)]
+    pub query_types: Vec<String>,
+    pub chain: String,
+}
+
+fn default_store_sweep_interval_secs() -> u64 {
+    60
+}
+
+fn default_store_scan_limit() -> usize {
+    1000
+}
+
+impl Plugin {
+    /// bound the store of this plugin and the branch plugins which have no own bound
+    pub fn default_store_max_entries(&mut self, store_max_entries: usize) {
+        self.store_max_entries.get_or_insert(store_max_entries);
+
+        for plugin in self.branches.values_mut().flatten() {
+            plugin.default_store_max_entries(store_max_entries);
+        }
+    }
+}
@@ -1,46 +1,87 @@
+use std::collections::HashMap;
 use std::io;
-use std::sync::Arc;
-use std::time::{Duration, Instant};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use async_trait::async_trait;
 use bytes::Bytes;
-use dashmap::DashMap;
 use host::WasiCtx;
-use tap::TapFallible;
-use tracing::error;
+use tracing::{debug, error, info, trace, warn};
+use trust_dns_proto::rr::dnssec::{Algorithm, DigestType, PublicKey, PublicKeyEnum};
 use wasi_cap_std_sync::WasiCtxBuilder;
 
-pub use self::tcp::TcpHelper;
+#[cfg(test)]
+pub use self::static_map::tests::write_static_map;
+pub use self::static_map::StaticMap;
+pub use self::store::{Eviction, PluginStore, StoreOptions};
+pub use self::tcp::{TcpHelper, TlsClient};
 pub use self::udp::UdpHelper;
-use super::helper::Error;
 use super::helper::Host as HelperHost;
-use super::pool::PluginPool;
+use super::helper::{Error, LogLevel, Transport};
+use super::pool::{Downstream, PluginPool, RunError};
+use super::udp_helper::{Addr, Ip};
+use crate::metrics;
 
+mod static_map;
+mod store;
 mod tcp;
 mod udp;
 
 pub struct HostHelper {
     wasi_ctx: WasiCtx,
+    plugin_name: Arc<String>,
     raw_config: Arc<String>,
     udp_helper: UdpHelper,
     tcp_helper: TcpHelper,
-    next_plugin: Option<PluginPool>,
-    plugin_store_map: Arc<DashMap<Bytes, StoreValue>>,
+    downstream: Downstream,
+    plugin_store_map: Arc<PluginStore>,
+    /// the static maps configured for the plugin, keyed by name
+    static_maps: Arc<HashMap<String, StaticMap>>,
+    request_context: RequestContext,
+}
+
+/// per query state, shared by all plugins which handle the same query
+#[derive(Debug, Clone)]
+pub struct RequestContext {
+    /// the request packet sent by the client, before any plugin mutates it
+    pub original_request: Bytes,
+    pub transport: Transport,
+    pub client_addr: Option<SocketAddr>,
+    /// the run durations of the plugins, a duration includes the downstream plugins
+    pub plugin_durations: Arc<Mutex<Vec<(String, Duration)>>>,
+}
+
+impl Default for RequestContext {
+    fn default() -> Self {
+        Self {
+            original_request: Default::default(),
+            transport: Transport::Udp,
+            client_addr: None,
+            plugin_durations: Default::default(),
+        }
+    }
 }
 
 impl HostHelper {
     pub fn new(
+        plugin_name: Arc<String>,
         raw_config: Arc<String>,
-        next_plugin: Option<PluginPool>,
-        plugin_store_map: Arc<DashMap<Bytes, StoreValue>>,
+        downstream: Downstream,
+        plugin_store_map: Arc<PluginStore>,
+        static_maps: Arc<HashMap<String, StaticMap>>,
+        tls_client: Arc<TlsClient>,
     ) -> Self {
         Self {
             wasi_ctx: WasiCtxBuilder::new().inherit_network().build(),
+            plugin_name,
             raw_config,
             udp_helper: Default::default(),
-            tcp_helper: Default::default(),
-            next_plugin,
+            tcp_helper: TcpHelper::new(tls_client),
+            downstream,
             plugin_store_map,
+            static_maps,
+            request_context: Default::default(),
         }
     }
 
@@ -56,9 +97,14 @@ impl HostHelper {
         &mut self.tcp_helper
     }
 
+    pub fn set_request_context(&mut self, request_context: RequestContext) {
+        self.request_context = request_context;
+    }
+
     pub fn reset(&mut self) {
         self.udp_helper.reset();
         self.tcp_helper.reset();
+        self.request_context = Default::default();
     }
 }
 
@@ -69,25 +115,38 @@ impl HelperHost for HostHelper {
         Ok(self.raw_config.to_string())
     }
 
+    #[inline]
+    async fn plugin_name(&mut self) -> anyhow::Result<String> {
+        Ok(self.plugin_name.to_string())
+    }
+
     async fn call_next_plugin(
         &mut self,
         dns_packet: Vec<u8>,
     ) -> anyhow::Result<Option<Result<Vec<u8>, Error>>> {
-        let plugin_pool = match &self.next_plugin {
+        let plugin_pool = match &self.downstream.next_plugin {
             None => return Ok(None),
             Some(plugin_pool) => plugin_pool,
         };
 
-        let mut next_plugin = plugin_pool
-            .get_plugin()
+        run_plugin(plugin_pool, self.request_context.clone(), &dns_packet)
             .await
-            .tap_err(|err| error!(%err, "get next plugin failed"))?;
-
-        let (plugin, store) = &mut *next_plugin;
+            .map(Some)
+    }
 
-        let result = plugin.plugin().call_run(store, &dns_packet).await?;
+    async fn call_plugin(
+        &mut self,
+        branch: String,
+        dns_packet: Vec<u8>,
+    ) -> anyhow::Result<Option<Result<Vec<u8>, Error>>> {
+        let plugin_pool = match self.downstream.branches.get(&branch) {
+            None => return Ok(None),
+            Some(plugin_pool) => plugin_pool,
+        };
 
-        Ok(Some(result))
+        run_plugin(plugin_pool, self.request_context.clone(), &dns_packet)
+            .await
+            .map(Some)
     }
 
     async fn map_set(
@@ -96,46 +155,306 @@ impl HelperHost for HostHelper {
         value: Vec<u8>,
         timeout: Option<u64>,
     ) -> anyhow::Result<()> {
-        self.plugin_store_map.insert(
-            key.into(),
-            StoreValue {
-                data: value.into(),
-                timeout: timeout.map(|timeout| Instant::now() + Duration::from_secs(timeout)),
-            },
-        );
+        self.plugin_store_map
+            .set(key.into(), value.into(), expires_at(timeout));
 
         Ok(())
     }
 
+    async fn map_cas(
+        &mut self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Vec<u8>,
+        timeout: Option<u64>,
+    ) -> anyhow::Result<bool> {
+        Ok(self.plugin_store_map.compare_and_swap(
+            key.into(),
+            expected.as_deref(),
+            new.into(),
+            expires_at(timeout),
+        ))
+    }
+
+    async fn map_increment(
+        &mut self,
+        key: Vec<u8>,
+        delta: i64,
+        timeout: Option<u64>,
+    ) -> anyhow::Result<i64> {
+        Ok(self
+            .plugin_store_map
+            .increment(key.into(), delta, expires_at(timeout)))
+    }
+
     async fn map_get(&mut self, key: Vec<u8>) -> anyhow::Result<Option<Vec<u8>>> {
-        match self.plugin_store_map.get(key.as_slice()) {
-            None => Ok(None),
-            Some(value) => {
-                if let Some(timeout) = value.timeout {
-                    if Instant::now().checked_duration_since(timeout).is_some() {
-                        self.plugin_store_map.remove(key.as_slice());
-
-                        return Ok(None);
-                    }
-                }
-
-                Ok(Some(value.data.clone().into()))
+        Ok(self.plugin_store_map.get(&key).map(Into::into))
+    }
+
+    async fn map_keys(&mut self, prefix: Vec<u8>) -> anyhow::Result<Vec<Vec<u8>>> {
+        Ok(self
+            .plugin_store_map
+            .keys(&prefix)
+            .into_iter()
+            .map(Into::into)
+            .collect())
+    }
+
+    async fn map_remove(&mut self, key: Vec<u8>) -> anyhow::Result<()> {
+        self.plugin_store_map.remove(&key);
+
+        Ok(())
+    }
+
+    async fn store_stats(&mut self) -> anyhow::Result<(u64, u64)> {
+        Ok((
+            self.plugin_store_map.entries() as _,
+            self.plugin_store_map.bytes(),
+        ))
+    }
+
+    async fn static_map_get(
+        &mut self,
+        name: String,
+        key: Vec<u8>,
+    ) -> anyhow::Result<Result<Option<Vec<u8>>, Error>> {
+        let static_map = match self.static_maps.get(&name) {
+            None => {
+                error!(name, "static map is not configured");
+
+                return Ok(Err(Error {
+                    msg: format!("static map {name} is not configured"),
+                    code: libc::ENOENT as _,
+                }));
+            }
+
+            Some(static_map) => static_map,
+        };
+
+        match static_map.get(&key) {
+            Err(err) => {
+                error!(%err, name, "read static map failed");
+
+                Ok(Err(Error {
+                    msg: err.to_string(),
+                    code: io_err_to_errno(err),
+                }))
             }
+
+            Ok(value) => Ok(Ok(value.map(|value| value.to_vec()))),
         }
     }
 
-    async fn map_remove(&mut self, key: Vec<u8>) -> anyhow::Result<()> {
-        self.plugin_store_map.remove(key.as_slice());
+    #[inline]
+    async fn original_request(&mut self) -> anyhow::Result<Vec<u8>> {
+        Ok(self.request_context.original_request.to_vec())
+    }
+
+    #[inline]
+    async fn request_transport(&mut self) -> anyhow::Result<Transport> {
+        Ok(self.request_context.transport)
+    }
+
+    #[inline]
+    async fn client_addr(&mut self) -> anyhow::Result<Option<Addr>> {
+        Ok(self.request_context.client_addr.map(from_socket_addr))
+    }
+
+    #[inline]
+    async fn sleep(&mut self, ms: u64) -> anyhow::Result<()> {
+        tokio::time::sleep(Duration::from_millis(ms)).await;
+
+        Ok(())
+    }
+
+    #[inline]
+    async fn metric_incr(&mut self, name: String) -> anyhow::Result<()> {
+        metrics::record_plugin_event(&self.plugin_name, &name);
 
         Ok(())
     }
+
+    async fn log_event(
+        &mut self,
+        level: LogLevel,
+        message: String,
+        fields: Vec<(String, String)>,
+    ) -> anyhow::Result<()> {
+        let plugin = self.plugin_name.as_str();
+        let fields = fields
+            .iter()
+            .map(|(key, value)| format!("{key}={value}"))
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        // the tracing level must be static
+        match level {
+            LogLevel::Trace => trace!(plugin, fields, "{message}"),
+            LogLevel::Debug => debug!(plugin, fields, "{message}"),
+            LogLevel::Info => info!(plugin, fields, "{message}"),
+            LogLevel::Warn => warn!(plugin, fields, "{message}"),
+            LogLevel::Error => error!(plugin, fields, "{message}"),
+        }
+
+        Ok(())
+    }
+
+    async fn random_u64(&mut self) -> anyhow::Result<u64> {
+        Ok(rand::random())
+    }
+
+    async fn now_unix_secs(&mut self) -> anyhow::Result<u64> {
+        Ok(unix_now().as_secs())
+    }
+
+    async fn now_millis(&mut self) -> anyhow::Result<u64> {
+        Ok(unix_now().as_millis() as _)
+    }
+
+    async fn dnssec_verify(
+        &mut self,
+        algorithm: u8,
+        public_key: Vec<u8>,
+        data: Vec<u8>,
+        signature: Vec<u8>,
+    ) -> anyhow::Result<bool> {
+        let algorithm = Algorithm::from_u8(algorithm);
+        let result = PublicKeyEnum::from_public_bytes(&public_key, algorithm)
+            .and_then(|public_key| public_key.verify(algorithm, &data, &signature));
+
+        if let Err(err) = &result {
+            debug!(plugin = %self.plugin_name, %err, ?algorithm, "dnssec verify failed");
+        }
+
+        Ok(result.is_ok())
+    }
+
+    async fn dnssec_digest(
+        &mut self,
+        digest_type: u8,
+        data: Vec<u8>,
+    ) -> anyhow::Result<Option<Vec<u8>>> {
+        let digest = DigestType::from_u8(digest_type)
+            .and_then(|digest_type| digest_type.hash(&data))
+            .ok()
+            .map(|digest| digest.as_ref().to_vec());
+
+        Ok(digest)
+    }
+}
+
+/// run the downstream plugin, a timeout is returned to the caller plugin so it can handle it
+async fn run_plugin(
+    plugin_pool: &PluginPool,
+    request_context: RequestContext,
+    dns_packet: &[u8],
+) -> anyhow::Result<Result<Vec<u8>, Error>> {
+    match plugin_pool.run(request_context, dns_packet).await {
+        Err(err @ RunError::Timeout { .. }) => Ok(Err(Error {
+            code: libc::ETIMEDOUT as _,
+            msg: err.to_string(),
+        })),
+
+        Err(err) => {
+            error!(%err, "run downstream plugin failed");
+
+            Err(err.into())
+        }
+
+        Ok(result) => Ok(result),
+    }
 }
 
 fn io_err_to_errno(err: io::Error) -> u32 {
     err.raw_os_error().unwrap_or(1) as _
 }
 
-pub struct StoreValue {
-    data: Bytes,
-    timeout: Option<Instant>,
+fn to_socket_addr(addr: Addr) -> SocketAddr {
+    let ip = match addr.addr {
+        Ip::V4(ip) => IpAddr::V4(Ipv4Addr::from(u32::from_be(ip))),
+        Ip::V6((high, low)) => IpAddr::V6(Ipv6Addr::from(((high as u128) << 64) | low as u128)),
+    };
+
+    SocketAddr::new(ip, u16::from_be(addr.port))
+}
+
+fn from_socket_addr(addr: SocketAddr) -> Addr {
+    let ip = match addr.ip() {
+        IpAddr::V4(ip) => Ip::V4(u32::from(ip).to_be()),
+        IpAddr::V6(ip) => {
+            let ip = u128::from(ip);
+
+            Ip::V6(((ip >> 64) as u64, ip as u64))
+        }
+    };
+
+    Addr {
+        addr: ip,
+        port: addr.port().to_be(),
+    }
+}
+
+/// the host clock before the unix epoch is treated as the epoch
+/// the expiry of a store entry after the timeout in seconds, a timeout too long to represent
+/// means no expiry
+fn expires_at(timeout: Option<u64>) -> Option<Instant> {
+    timeout.and_then(|timeout| Instant::now().checked_add(Duration::from_secs(timeout)))
+}
+
+fn unix_now() -> Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn v6_socket_addr_round_trip() {
+        let addr = "[2001:db8::1:2]:5353".parse::<SocketAddr>().unwrap();
+
+        let wire_addr = from_socket_addr(addr);
+        match wire_addr.addr {
+            Ip::V6((high, low)) => {
+                assert_eq!(high, 0x2001_0db8_0000_0000);
+                assert_eq!(low, 0x0000_0000_0001_0002);
+            }
+
+            Ip::V4(_) => panic!("v6 addr is converted to v4"),
+        }
+        assert_eq!(wire_addr.port, 5353u16.to_be());
+
+        assert_eq!(to_socket_addr(wire_addr), addr);
+    }
+
+    #[test]
+    fn v4_socket_addr_round_trip() {
+        let addr = "192.0.2.1:53".parse::<SocketAddr>().unwrap();
+
+        let wire_addr = from_socket_addr(addr);
+        match wire_addr.addr {
+            Ip::V4(ip) => assert_eq!(ip, u32::from_ne_bytes([192, 0, 2, 1])),
+            Ip::V6(_) => panic!("v4 addr is converted to v6"),
+        }
+        assert_eq!(wire_addr.port, 53u16.to_be());
+
+        assert_eq!(to_socket_addr(wire_addr), addr);
+    }
+
+    #[test]
+    fn expire_after_timeout() {
+        let now = Instant::now();
+        let expiry = expires_at(Some(60)).unwrap();
+
+        assert!(expiry >= now + Duration::from_secs(60));
+        assert!(expiry <= Instant::now() + Duration::from_secs(60));
+        assert_eq!(expires_at(None), None);
+    }
+
+    #[test]
+    fn overflowed_timeout_never_expires() {
+        assert_eq!(expires_at(Some(u64::MAX)), None);
+    }
 }
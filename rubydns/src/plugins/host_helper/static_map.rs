@@ -0,0 +1,124 @@
+use std::fs::File;
+use std::io;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use fst::Map;
+use memmap2::Mmap;
+
+/// a precompiled FST mapping keys to the offsets of their values in the companion `.data`
+/// file, every value there is prefixed by its u32 big endian length
+///
+/// both files are memory mapped, so a huge static zone doesn't need to be loaded into the
+/// plugin memory
+pub struct StaticMap {
+    map: Map<Mmap>,
+    data: Mmap,
+}
+
+impl StaticMap {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let map_file = File::open(path)?;
+        // Safety: the map files are built offline and must not be modified when they are used
+        let map = unsafe { Mmap::map(&map_file)? };
+        let map = Map::new(map).map_err(|err| io::Error::new(ErrorKind::InvalidData, err))?;
+
+        let data_file = File::open(data_path(path))?;
+        // Safety: same as the map file
+        let data = unsafe { Mmap::map(&data_file)? };
+
+        Ok(Self { map, data })
+    }
+
+    pub fn get(&self, key: &[u8]) -> io::Result<Option<&[u8]>> {
+        let offset = match self.map.get(key) {
+            None => return Ok(None),
+            Some(offset) => offset as usize,
+        };
+
+        let len = self
+            .data
+            .get(offset..offset + 4)
+            .ok_or_else(|| io::Error::new(ErrorKind::UnexpectedEof, "value length out of range"))?;
+        let len = u32::from_be_bytes(len.try_into().expect("length is 4 bytes")) as usize;
+
+        let value = self
+            .data
+            .get(offset + 4..offset + 4 + len)
+            .ok_or_else(|| io::Error::new(ErrorKind::UnexpectedEof, "value out of range"))?;
+
+        Ok(Some(value))
+    }
+}
+
+fn data_path(path: &Path) -> PathBuf {
+    let mut data_path = path.as_os_str().to_owned();
+    data_path.push(".data");
+
+    data_path.into()
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::{env, fs, process};
+
+    use fst::MapBuilder;
+
+    use super::*;
+
+    /// build the FST and its data file of the entries at the path
+    pub fn write_static_map(path: &Path, entries: &[(&str, &str)]) {
+        let mut entries = entries.to_vec();
+        entries.sort_unstable();
+
+        let mut builder = MapBuilder::new(File::create(path).unwrap()).unwrap();
+        let mut data = vec![];
+        for (key, value) in entries {
+            builder.insert(key, data.len() as u64).unwrap();
+            data.extend_from_slice(&(value.len() as u32).to_be_bytes());
+            data.extend_from_slice(value.as_bytes());
+        }
+        builder.finish().unwrap();
+
+        fs::write(data_path(path), data).unwrap();
+    }
+
+    #[test]
+    fn get_from_static_map() {
+        let dir = env::temp_dir().join(format!("rubydns-{}-static-map", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("zone.fst");
+        write_static_map(
+            &path,
+            &[
+                ("www.example.com", "192.0.2.1 2001:db8::1"),
+                ("mail.example.com", "192.0.2.2"),
+            ],
+        );
+
+        let static_map = StaticMap::open(&path).unwrap();
+
+        assert_eq!(
+            static_map.get(b"www.example.com").unwrap(),
+            Some(b"192.0.2.1 2001:db8::1".as_slice())
+        );
+        assert_eq!(
+            static_map.get(b"mail.example.com").unwrap(),
+            Some(b"192.0.2.2".as_slice())
+        );
+        assert_eq!(static_map.get(b"ftp.example.com").unwrap(), None);
+    }
+
+    #[test]
+    fn open_without_data_file() {
+        let dir = env::temp_dir().join(format!("rubydns-{}-static-map-no-data", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("zone.fst");
+        write_static_map(&path, &[("www.example.com", "192.0.2.1")]);
+        fs::remove_file(data_path(&path)).unwrap();
+
+        let err = StaticMap::open(&path).err().unwrap();
+
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+    }
+}
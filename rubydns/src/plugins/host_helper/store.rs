@@ -0,0 +1,734 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use serde::Deserialize;
+use tokio::time;
+use tracing::debug;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct StoreOptions {
+    /// content address the values, so the keys storing the same value share one copy
+    pub dedup: bool,
+    /// evict entries when the store has more entries, unbounded when unset
+    pub max_entries: Option<usize>,
+    pub eviction: Eviction,
+    /// remove the expired entries in background every interval, disabled when unset
+    pub sweep_interval: Option<Duration>,
+    /// the most keys returned by a scan
+    pub scan_limit: usize,
+}
+
+/// which entries are evicted first when the store is full, the expired entries are always
+/// evicted before others
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Eviction {
+    /// the least recently used entries
+    #[default]
+    Lru,
+
+    /// the entries closest to expiry, the entries without timeout are evicted last
+    Ttl,
+
+    /// weigh the idle time against the remaining ttl, so an idle entry about to expire goes
+    /// first
+    Hybrid,
+}
+
+/// the key value store shared by all instances of a plugin
+///
+/// when dedup is enabled, values are content addressed by their hash, so the keys storing
+/// the same value share one copy, which is released when no key refers it
+pub struct PluginStore {
+    options: StoreOptions,
+    entries: DashMap<Bytes, StoreValue>,
+    blobs: DashMap<u64, Blob>,
+    /// the keys and values size, a shared value is counted once, the map overhead isn't
+    /// counted
+    bytes: AtomicU64,
+    /// the base of the access times
+    epoch: Instant,
+    evicting: AtomicBool,
+}
+
+struct StoreValue {
+    data: StoreData,
+    timeout: Option<Instant>,
+    /// milliseconds since the store epoch
+    last_access: AtomicU64,
+}
+
+impl StoreValue {
+    fn expired(&self) -> bool {
+        self.timeout
+            .map(|timeout| Instant::now().checked_duration_since(timeout).is_some())
+            .unwrap_or(false)
+    }
+}
+
+enum StoreData {
+    Owned(Bytes),
+    Shared(u64),
+}
+
+struct Blob {
+    data: Bytes,
+    refs: usize,
+}
+
+impl PluginStore {
+    pub fn new(options: StoreOptions) -> Self {
+        Self {
+            options,
+            entries: Default::default(),
+            blobs: Default::default(),
+            bytes: AtomicU64::new(0),
+            epoch: Instant::now(),
+            evicting: AtomicBool::new(false),
+        }
+    }
+
+    pub fn set(&self, key: Bytes, value: Bytes, timeout: Option<Instant>) {
+        let key_len = key.len();
+        let value = self.new_value(value, timeout);
+
+        match self.entries.insert(key, value) {
+            Some(old_value) => self.release(old_value.data),
+            None => {
+                self.add_bytes(key_len);
+                self.evict_if_full();
+            }
+        }
+    }
+
+    /// set the value only when the current value equals the expected one, an absent or
+    /// expired value equals None, return whether the value is set
+    pub fn compare_and_swap(
+        &self,
+        key: Bytes,
+        expected: Option<&[u8]>,
+        value: Bytes,
+        timeout: Option<Instant>,
+    ) -> bool {
+        // the entry locks the key until the swap is done
+        match self.entries.entry(key) {
+            Entry::Vacant(entry) => {
+                if expected.is_some() {
+                    return false;
+                }
+
+                self.add_bytes(entry.key().len());
+                entry.insert(self.new_value(value, timeout));
+            }
+
+            Entry::Occupied(mut entry) => {
+                let current = if entry.get().expired() {
+                    None
+                } else {
+                    self.load(&entry.get().data)
+                };
+                if current.as_deref() != expected {
+                    return false;
+                }
+
+                let old_value = entry.insert(self.new_value(value, timeout));
+                self.release(old_value.data);
+
+                return true;
+            }
+        }
+
+        self.evict_if_full();
+
+        true
+    }
+
+    /// add the delta to the i64 big endian counter atomically, an absent, expired or
+    /// malformed counter starts from 0, return the new value
+    ///
+    /// the timeout is only set when the counter starts, so a windowed counter isn't extended
+    /// by the later increments
+    pub fn increment(&self, key: Bytes, delta: i64, timeout: Option<Instant>) -> i64 {
+        // the entry locks the key until the increment is done
+        match self.entries.entry(key) {
+            Entry::Vacant(entry) => {
+                self.add_bytes(entry.key().len());
+                entry.insert(self.new_value(Bytes::copy_from_slice(&delta.to_be_bytes()), timeout));
+            }
+
+            Entry::Occupied(mut entry) => {
+                let current = if entry.get().expired() {
+                    None
+                } else {
+                    self.load(&entry.get().data)
+                        .and_then(|data| <[u8; 8]>::try_from(data.as_ref()).ok())
+                        .map(i64::from_be_bytes)
+                };
+
+                let (value, timeout) = match current {
+                    None => (delta, timeout),
+                    Some(current) => (current.wrapping_add(delta), entry.get().timeout),
+                };
+
+                let old_value = entry
+                    .insert(self.new_value(Bytes::copy_from_slice(&value.to_be_bytes()), timeout));
+                self.release(old_value.data);
+
+                return value;
+            }
+        }
+
+        self.evict_if_full();
+
+        delta
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<Bytes> {
+        let value = self.entries.get(key)?;
+        if value.expired() {
+            drop(value);
+
+            if let Some((key, value)) = self.entries.remove_if(key, |_, value| value.expired()) {
+                self.sub_bytes(key.len());
+                self.release(value.data);
+            }
+
+            return None;
+        }
+
+        value
+            .last_access
+            .store(self.now_millis(), Ordering::Relaxed);
+
+        self.load(&value.data)
+    }
+
+    /// remove the expired entries, which are never read again so they aren't removed by
+    /// [`get`](Self::get)
+    ///
+    /// the expired keys are collected first and removed one by one, so a shard is never
+    /// locked for the whole scan
+    pub fn sweep(&self) -> usize {
+        let expired = self
+            .entries
+            .iter()
+            .filter(|entry| entry.value().expired())
+            .map(|entry| entry.key().clone())
+            .collect::<Vec<_>>();
+
+        let mut removed = 0;
+        for key in expired {
+            if let Some((key, value)) = self.entries.remove_if(&key, |_, value| value.expired()) {
+                self.sub_bytes(key.len());
+                self.release(value.data);
+                removed += 1;
+            }
+        }
+
+        removed
+    }
+
+    /// sweep the store every interval, the task stops when the store is dropped
+    pub fn spawn_sweeper(self: &Arc<Self>, interval: Duration) {
+        let store = Arc::downgrade(self);
+
+        tokio::spawn(sweep_periodically(store, interval));
+    }
+
+    pub fn remove(&self, key: &[u8]) {
+        if let Some((key, value)) = self.entries.remove(key) {
+            self.sub_bytes(key.len());
+            self.release(value.data);
+        }
+    }
+
+    /// the unexpired keys starting with the prefix, at most the scan limit, in no particular
+    /// order
+    pub fn keys(&self, prefix: &[u8]) -> Vec<Bytes> {
+        self.entries
+            .iter()
+            .filter(|entry| entry.key().starts_with(prefix) && !entry.value().expired())
+            .map(|entry| entry.key().clone())
+            .take(self.options.scan_limit)
+            .collect()
+    }
+
+    pub fn clear(&self) {
+        self.entries.clear();
+        self.blobs.clear();
+        self.bytes.store(0, Ordering::Relaxed);
+    }
+
+    pub fn entries(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// the approximate memory usage, it is maintained by the inserts and removes, so it is
+    /// cheap to call
+    pub fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    fn add_bytes(&self, len: usize) {
+        self.bytes.fetch_add(len as _, Ordering::Relaxed);
+    }
+
+    /// saturate at 0, a concurrent clear may reset the counter before the removed entry is
+    /// counted
+    fn sub_bytes(&self, len: usize) {
+        let _ = self
+            .bytes
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bytes| {
+                Some(bytes.saturating_sub(len as _))
+            });
+    }
+
+    fn new_value(&self, value: Bytes, timeout: Option<Instant>) -> StoreValue {
+        let data = if self.options.dedup {
+            self.acquire(value)
+        } else {
+            self.add_bytes(value.len());
+
+            StoreData::Owned(value)
+        };
+
+        StoreValue {
+            data,
+            timeout,
+            last_access: AtomicU64::new(self.now_millis()),
+        }
+    }
+
+    fn load(&self, data: &StoreData) -> Option<Bytes> {
+        match data {
+            StoreData::Owned(data) => Some(data.clone()),
+            StoreData::Shared(hash) => self.blobs.get(hash).map(|blob| blob.data.clone()),
+        }
+    }
+
+    fn evict_if_full(&self) {
+        if let Some(max_entries) = self.options.max_entries {
+            if self.entries.len() > max_entries {
+                self.evict(max_entries);
+            }
+        }
+    }
+
+    fn now_millis(&self) -> u64 {
+        self.epoch.elapsed().as_millis() as _
+    }
+
+    /// evict the entries down to 15/16 of the max entries, so the scan is amortized over
+    /// the following inserts
+    fn evict(&self, max_entries: usize) {
+        // another insert is evicting
+        if self.evicting.swap(true, Ordering::AcqRel) {
+            return;
+        }
+
+        let now = Instant::now();
+        let now_millis = self.now_millis();
+        let mut candidates = self
+            .entries
+            .iter()
+            .map(|entry| {
+                let score = self.eviction_score(entry.value(), now, now_millis);
+
+                (entry.key().clone(), score)
+            })
+            .collect::<Vec<_>>();
+
+        let count = candidates
+            .len()
+            .saturating_sub(max_entries - max_entries / 16);
+        if count > 0 {
+            candidates.select_nth_unstable_by(count - 1, |a, b| b.1.cmp(&a.1));
+
+            for (key, _) in &candidates[..count] {
+                self.remove(key);
+            }
+        }
+
+        self.evicting.store(false, Ordering::Release);
+    }
+
+    /// the entry with the higher score is evicted first
+    fn eviction_score(&self, value: &StoreValue, now: Instant, now_millis: u64) -> i64 {
+        let remaining = match value.timeout {
+            None => None,
+            Some(timeout) => match timeout.checked_duration_since(now) {
+                None => return i64::MAX,
+                Some(remaining) => Some(remaining.as_millis() as i64),
+            },
+        };
+        let idle = now_millis.saturating_sub(value.last_access.load(Ordering::Relaxed)) as i64;
+
+        match self.options.eviction {
+            Eviction::Lru => idle,
+            Eviction::Ttl => remaining.map(|remaining| -remaining).unwrap_or(i64::MIN),
+            Eviction::Hybrid => idle - remaining.unwrap_or(i64::MAX / 2),
+        }
+    }
+
+    fn acquire(&self, value: Bytes) -> StoreData {
+        let mut hasher = DefaultHasher::new();
+        value.hash(&mut hasher);
+        let hash = hasher.finish();
+
+        match self.blobs.entry(hash) {
+            Entry::Vacant(entry) => {
+                self.add_bytes(value.len());
+                entry.insert(Blob {
+                    data: value,
+                    refs: 1,
+                });
+            }
+
+            Entry::Occupied(mut entry) => {
+                let blob = entry.get_mut();
+                // hash collision, keep the value out of the shared blobs
+                if blob.data != value {
+                    self.add_bytes(value.len());
+
+                    return StoreData::Owned(value);
+                }
+
+                blob.refs += 1;
+            }
+        }
+
+        StoreData::Shared(hash)
+    }
+
+    fn release(&self, data: StoreData) {
+        let hash = match data {
+            StoreData::Owned(data) => {
+                self.sub_bytes(data.len());
+
+                return;
+            }
+
+            StoreData::Shared(hash) => hash,
+        };
+
+        if let Entry::Occupied(mut entry) = self.blobs.entry(hash) {
+            let blob = entry.get_mut();
+            blob.refs -= 1;
+            if blob.refs == 0 {
+                let blob = entry.remove();
+                self.sub_bytes(blob.data.len());
+            }
+        }
+    }
+}
+
+async fn sweep_periodically(store: Weak<PluginStore>, interval: Duration) {
+    let mut interval = time::interval(interval);
+    // the first tick completes immediately
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+
+        let store = match store.upgrade() {
+            None => return,
+            Some(store) => store,
+        };
+
+        let removed = store.sweep();
+        if removed > 0 {
+            debug!(removed, "sweep expired store entries");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    fn options(eviction: Eviction, max_entries: Option<usize>) -> StoreOptions {
+        StoreOptions {
+            dedup: false,
+            max_entries,
+            eviction,
+            sweep_interval: None,
+            scan_limit: 100,
+        }
+    }
+
+    /// the store epoch is moved back, so the entries can be given distinct access times
+    fn store(eviction: Eviction, max_entries: usize) -> PluginStore {
+        let mut store = PluginStore::new(options(eviction, Some(max_entries)));
+        store.epoch = Instant::now() - Duration::from_secs(1000);
+
+        store
+    }
+
+    fn key(i: usize) -> Bytes {
+        Bytes::from(format!("key-{i}"))
+    }
+
+    fn set_idle(store: &PluginStore, key: &Bytes, idle: Duration) {
+        let last_access = store.now_millis() - idle.as_millis() as u64;
+
+        store
+            .entries
+            .get(key)
+            .unwrap()
+            .last_access
+            .store(last_access, Ordering::Relaxed);
+    }
+
+    #[test]
+    fn concurrent_compare_and_swap_has_one_winner() {
+        let store = Arc::new(PluginStore::new(options(Eviction::Lru, None)));
+
+        let handles = (0..32u8)
+            .map(|i| {
+                let store = store.clone();
+
+                thread::spawn(move || {
+                    store.compare_and_swap(Bytes::from("lock"), None, Bytes::from(vec![i]), None)
+                })
+            })
+            .collect::<Vec<_>>();
+        let winners = handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .filter(|won| *won)
+            .count();
+
+        assert_eq!(winners, 1);
+        assert_eq!(store.entries(), 1);
+    }
+
+    #[test]
+    fn compare_and_swap_checks_expected() {
+        let store = PluginStore::new(options(Eviction::Lru, None));
+        let cas = |expected: Option<&[u8]>, value: &'static str| {
+            store.compare_and_swap(Bytes::from("key"), expected, Bytes::from(value), None)
+        };
+
+        assert!(!cas(Some(b"a"), "b"));
+        assert!(cas(None, "a"));
+        assert!(!cas(None, "b"));
+        assert!(cas(Some(b"a"), "b"));
+        assert_eq!(store.get(b"key").as_deref(), Some(&b"b"[..]));
+
+        // an expired value is the same as an absent one
+        store.set(
+            Bytes::from("expired"),
+            Bytes::from("a"),
+            Some(Instant::now()),
+        );
+        assert!(store.compare_and_swap(Bytes::from("expired"), None, Bytes::from("b"), None));
+    }
+
+    #[test]
+    fn concurrent_increment_counts_all() {
+        let store = Arc::new(PluginStore::new(options(Eviction::Lru, None)));
+
+        let handles = (0..16)
+            .map(|_| {
+                let store = store.clone();
+
+                thread::spawn(move || {
+                    for _ in 0..1000 {
+                        store.increment(Bytes::from("counter"), 1, None);
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        let counter = store.get(b"counter").unwrap();
+        assert_eq!(
+            i64::from_be_bytes(counter.as_ref().try_into().unwrap()),
+            16000
+        );
+    }
+
+    #[test]
+    fn lru_evicts_idle_entries() {
+        let store = store(Eviction::Lru, 16);
+        for i in 0..16 {
+            store.set(key(i), Bytes::from("value"), None);
+            // the former keys are more idle
+            set_idle(&store, &key(i), Duration::from_secs(100 - i as u64));
+        }
+        assert_eq!(store.entries(), 16);
+
+        store.set(key(16), Bytes::from("value"), None);
+
+        // trimmed to 15/16 of the max entries
+        assert_eq!(store.entries(), 15);
+        assert!(store.get(&key(0)).is_none());
+        assert!(store.get(&key(1)).is_none());
+        assert!(store.get(&key(2)).is_some());
+        assert!(store.get(&key(16)).is_some());
+    }
+
+    #[test]
+    fn ttl_evicts_entries_closest_to_expiry() {
+        let store = store(Eviction::Ttl, 16);
+        let now = Instant::now();
+        store.set(key(0), Bytes::from("value"), None);
+        for i in 1..16 {
+            store.set(
+                key(i),
+                Bytes::from("value"),
+                Some(now + Duration::from_secs(i as u64 * 10)),
+            );
+        }
+
+        store.set(key(16), Bytes::from("value"), None);
+
+        assert_eq!(store.entries(), 15);
+        assert!(store.get(&key(1)).is_none());
+        assert!(store.get(&key(2)).is_none());
+        assert!(store.get(&key(0)).is_some());
+        assert!(store.get(&key(3)).is_some());
+        assert!(store.get(&key(16)).is_some());
+    }
+
+    #[test]
+    fn hybrid_evicts_idle_entries_about_to_expire() {
+        let store = store(Eviction::Hybrid, 16);
+        let now = Instant::now();
+        for i in 0..16 {
+            store.set(
+                key(i),
+                Bytes::from("value"),
+                Some(now + Duration::from_secs(100)),
+            );
+        }
+        // idle and about to expire
+        store.set(
+            key(0),
+            Bytes::from("value"),
+            Some(now + Duration::from_secs(10)),
+        );
+        set_idle(&store, &key(0), Duration::from_secs(50));
+        // about to expire
+        store.set(
+            key(1),
+            Bytes::from("value"),
+            Some(now + Duration::from_secs(5)),
+        );
+        // idle for long but never expires
+        store.set(key(2), Bytes::from("value"), None);
+        set_idle(&store, &key(2), Duration::from_secs(90));
+
+        store.set(
+            key(16),
+            Bytes::from("value"),
+            Some(now + Duration::from_secs(100)),
+        );
+
+        assert_eq!(store.entries(), 15);
+        assert!(store.get(&key(0)).is_none());
+        assert!(store.get(&key(1)).is_none());
+        assert!(store.get(&key(2)).is_some());
+        assert!(store.get(&key(16)).is_some());
+    }
+
+    #[test]
+    fn sweep_removes_expired_entries() {
+        let store = PluginStore::new(options(Eviction::Lru, None));
+        store.set(Bytes::from("live"), Bytes::from("value"), None);
+        store.set(
+            Bytes::from("expired"),
+            Bytes::from("value"),
+            Some(Instant::now()),
+        );
+
+        assert_eq!(store.sweep(), 1);
+        assert_eq!(store.entries(), 1);
+        assert_eq!(store.bytes(), "live".len() as u64 + "value".len() as u64);
+    }
+
+    #[tokio::test]
+    async fn sweeper_removes_expired_entries_in_background() {
+        let store = Arc::new(PluginStore::new(options(Eviction::Lru, None)));
+        store.set(Bytes::from("live"), Bytes::from("value"), None);
+        store.set(
+            Bytes::from("expired"),
+            Bytes::from("value"),
+            Some(Instant::now() + Duration::from_millis(10)),
+        );
+
+        store.spawn_sweeper(Duration::from_millis(20));
+        time::sleep(Duration::from_millis(200)).await;
+
+        // the expired entry is never read, only the sweeper removes it
+        assert_eq!(store.entries(), 1);
+    }
+
+    #[test]
+    fn stats_follow_inserts_and_removes() {
+        let store = PluginStore::new(options(Eviction::Lru, None));
+
+        store.set(Bytes::from("a"), Bytes::from("12345"), None);
+        store.set(Bytes::from("bb"), Bytes::from("123"), None);
+        assert_eq!((store.entries(), store.bytes()), (2, 11));
+
+        // an overwrite only replaces the value bytes
+        store.set(Bytes::from("a"), Bytes::from("1"), None);
+        assert_eq!((store.entries(), store.bytes()), (2, 7));
+
+        store.increment(Bytes::from("counter"), 1, None);
+        assert_eq!((store.entries(), store.bytes()), (3, 22));
+
+        store.remove(b"a");
+        assert_eq!((store.entries(), store.bytes()), (2, 20));
+
+        // removing an absent key changes nothing
+        store.remove(b"a");
+        assert_eq!((store.entries(), store.bytes()), (2, 20));
+
+        store.remove(b"bb");
+        store.remove(b"counter");
+        assert_eq!((store.entries(), store.bytes()), (0, 0));
+    }
+
+    #[test]
+    fn dedup_shares_identical_values() {
+        let store = PluginStore::new(StoreOptions {
+            dedup: true,
+            ..options(Eviction::Lru, None)
+        });
+        let value = Bytes::from("shared value");
+
+        store.set(Bytes::from("a"), value.clone(), None);
+        store.set(Bytes::from("b"), value.clone(), None);
+
+        assert_eq!(store.blobs.len(), 1);
+        assert_eq!(store.blobs.iter().next().unwrap().refs, 2);
+        assert_eq!(store.bytes(), 2 + value.len() as u64);
+        assert_eq!(store.get(b"a"), Some(value.clone()));
+        assert_eq!(store.get(b"b"), Some(value.clone()));
+
+        store.remove(b"a");
+        assert_eq!(store.blobs.iter().next().unwrap().refs, 1);
+        assert_eq!(store.get(b"b"), Some(value.clone()));
+
+        // overwriting the last reference releases the shared copy
+        store.set(Bytes::from("b"), Bytes::from("other"), None);
+        assert_eq!(store.blobs.len(), 1);
+        assert_eq!(store.get(b"b").as_deref(), Some(&b"other"[..]));
+
+        store.remove(b"b");
+        assert!(store.blobs.is_empty());
+        assert_eq!(store.bytes(), 0);
+    }
+}
@@ -1,33 +1,135 @@
 use std::collections::HashMap;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::net::SocketAddr;
 use std::os::fd::AsRawFd;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use async_trait::async_trait;
 use bytes::BytesMut;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use futures_util::FutureExt;
+use rustls::{ClientConfig, ServerName};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
+use tokio::time;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::TlsConnector;
 use tracing::error;
 
-use super::io_err_to_errno;
+use super::{from_socket_addr, io_err_to_errno, to_socket_addr};
 use crate::plugins::tcp_helper::{Addr, Host};
 
+/// a released TLS stream is reused for at most this long, the servers close the idle
+/// connections anyway
+const TLS_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+/// the most idle TLS streams kept for a server
+const MAX_IDLE_TLS_STREAMS: usize = 8;
+
+/// the server addr, the server name and the CA name of a TLS stream
+type TlsKey = (SocketAddr, String, Option<String>);
+/// the released streams of a server with their release time
+type IdleStreams = Vec<(Instant, TlsStream<TcpStream>)>;
+
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
 #[derive(Debug)]
 enum Tcp {
     Stream(TcpStream),
+    TlsStream(Box<TlsStream<TcpStream>>, TlsKey),
     Listener(TcpListener),
 }
 
-#[derive(Debug, Default)]
+/// the TLS client of a plugin, shared by its instances, the CAs are loaded by the host from
+/// the plugin config, and the streams released by an instance are reused by the next
+/// connects to the same server, which saves the handshakes
+#[derive(Debug)]
+pub struct TlsClient {
+    /// verify the server certs with the webpki roots
+    default_config: Arc<ClientConfig>,
+    /// verify the server certs with the CAs of the plugin, keyed by name
+    ca_configs: HashMap<String, Arc<ClientConfig>>,
+    idle_streams: Mutex<HashMap<TlsKey, IdleStreams>>,
+}
+
+impl TlsClient {
+    pub fn new(
+        default_config: Arc<ClientConfig>,
+        ca_configs: HashMap<String, Arc<ClientConfig>>,
+    ) -> Self {
+        Self {
+            default_config,
+            ca_configs,
+            idle_streams: Default::default(),
+        }
+    }
+
+    fn client_config(&self, ca: Option<&str>) -> Result<Arc<ClientConfig>, u32> {
+        match ca {
+            None => Ok(self.default_config.clone()),
+            Some(ca) => self.ca_configs.get(ca).cloned().ok_or_else(|| {
+                error!(ca, "tls CA is not configured for the plugin");
+
+                libc::EINVAL as u32
+            }),
+        }
+    }
+
+    /// take an idle stream which is still open, the expired ones are dropped
+    fn take_idle(&self, key: &TlsKey) -> Option<TlsStream<TcpStream>> {
+        let mut idle_streams = self.idle_streams.lock().unwrap();
+        let streams = idle_streams.get_mut(key)?;
+
+        let mut tls_stream = None;
+        while let Some((released_at, mut stream)) = streams.pop() {
+            if released_at.elapsed() < TLS_IDLE_TIMEOUT && is_open(&mut stream) {
+                tls_stream = Some(stream);
+
+                break;
+            }
+        }
+        if streams.is_empty() {
+            idle_streams.remove(key);
+        }
+
+        tls_stream
+    }
+
+    fn put_idle(&self, key: TlsKey, tls_stream: TlsStream<TcpStream>) {
+        let mut idle_streams = self.idle_streams.lock().unwrap();
+        let streams = idle_streams.entry(key).or_default();
+
+        streams.retain(|(released_at, _)| released_at.elapsed() < TLS_IDLE_TIMEOUT);
+        if streams.len() < MAX_IDLE_TLS_STREAMS {
+            streams.push((Instant::now(), tls_stream));
+        }
+    }
+}
+
+/// an idle stream is open when nothing is readable on it, a closed one reads EOF, and any
+/// data is unexpected
+fn is_open(tls_stream: &mut TlsStream<TcpStream>) -> bool {
+    let mut buf = [0; 1];
+
+    tls_stream.read(&mut buf).now_or_never().is_none()
+}
+
+#[derive(Debug)]
 pub struct TcpHelper {
     fd_map: HashMap<u32, Tcp>,
+    tls_client: Arc<TlsClient>,
 }
 
 impl TcpHelper {
+    pub fn new(tls_client: Arc<TlsClient>) -> Self {
+        Self {
+            fd_map: Default::default(),
+            tls_client,
+        }
+    }
+
     async fn inner_bind(&mut self, addr: Addr) -> Result<u32, u32> {
-        let addr = SocketAddr::new(
-            IpAddr::V4(Ipv4Addr::from(u32::from_be(addr.addr))),
-            u16::from_be(addr.port),
-        );
+        let addr = to_socket_addr(addr);
 
         let listener = TcpListener::bind(addr).await.map_err(|err| {
             error!(%addr, %err, "bind tcp socket failed");
@@ -44,7 +146,7 @@ impl TcpHelper {
     async fn inner_accept(&mut self, fd: u32) -> Result<(u32, Addr), u32> {
         let listener = match self.fd_map.get_mut(&fd) {
             None => return Err(libc::EBADF as _),
-            Some(Tcp::Stream(_)) => return Err(libc::EBADF as _),
+            Some(Tcp::Stream(_) | Tcp::TlsStream(..)) => return Err(libc::EBADF as _),
             Some(Tcp::Listener(listener)) => listener,
         };
 
@@ -57,22 +159,11 @@ impl TcpHelper {
         let fd = tcp_stream.as_raw_fd();
         self.fd_map.insert(fd as _, Tcp::Stream(tcp_stream));
 
-        let ip = get_ipv4_be(&addr)?;
-
-        Ok((
-            fd as _,
-            Addr {
-                addr: ip,
-                port: addr.port().to_be(),
-            },
-        ))
+        Ok((fd as _, from_socket_addr(addr)))
     }
 
     async fn inner_connect(&mut self, addr: Addr) -> Result<u32, u32> {
-        let addr = SocketAddr::new(
-            IpAddr::V4(Ipv4Addr::from(u32::from_be(addr.addr))),
-            u16::from_be(addr.port),
-        );
+        let addr = to_socket_addr(addr);
 
         let tcp_stream = TcpStream::connect(addr).await.map_err(|err| {
             error!(%addr, "tcp socket connect failed");
@@ -87,6 +178,100 @@ impl TcpHelper {
         Ok(fd as _)
     }
 
+    async fn inner_connect_timeout(&mut self, addr: Addr, timeout_ms: u64) -> Result<u32, u32> {
+        match time::timeout(Duration::from_millis(timeout_ms), self.inner_connect(addr)).await {
+            Err(_) => {
+                error!(timeout_ms, "tcp socket connect timeout");
+
+                Err(libc::ETIMEDOUT as _)
+            }
+
+            Ok(result) => result,
+        }
+    }
+
+    async fn inner_connect_tls_timeout(
+        &mut self,
+        addr: Addr,
+        server_name: String,
+        ca: Option<String>,
+        timeout_ms: Option<u64>,
+    ) -> Result<u32, u32> {
+        let timeout_ms = match timeout_ms {
+            None => return self.inner_connect_tls(addr, server_name, ca).await,
+            Some(timeout_ms) => timeout_ms,
+        };
+
+        match time::timeout(
+            Duration::from_millis(timeout_ms),
+            self.inner_connect_tls(addr, server_name, ca),
+        )
+        .await
+        {
+            Err(_) => {
+                error!(timeout_ms, "tls connect timeout");
+
+                Err(libc::ETIMEDOUT as _)
+            }
+
+            Ok(result) => result,
+        }
+    }
+
+    async fn inner_connect_tls(
+        &mut self,
+        addr: Addr,
+        server_name: String,
+        ca: Option<String>,
+    ) -> Result<u32, u32> {
+        let addr = to_socket_addr(addr);
+        let key = (addr, server_name, ca);
+
+        if let Some(tls_stream) = self.tls_client.take_idle(&key) {
+            let fd = tls_stream.get_ref().0.as_raw_fd();
+            self.fd_map
+                .insert(fd as _, Tcp::TlsStream(Box::new(tls_stream), key));
+
+            return Ok(fd as _);
+        }
+
+        let server_name = ServerName::try_from(key.1.as_str()).map_err(|err| {
+            error!(%err, server_name = key.1, "invalid tls server name");
+
+            libc::EINVAL as u32
+        })?;
+        let tls_config = self.tls_client.client_config(key.2.as_deref())?;
+
+        let tcp_stream = TcpStream::connect(addr).await.map_err(|err| {
+            error!(%addr, "tcp socket connect failed");
+
+            io_err_to_errno(err)
+        })?;
+
+        let fd = tcp_stream.as_raw_fd();
+
+        let tls_stream = TlsConnector::from(tls_config)
+            .connect(server_name, tcp_stream)
+            .await
+            .map_err(|err| {
+                error!(%addr, %err, "tls handshake failed");
+
+                io_err_to_errno(err)
+            })?;
+
+        self.fd_map
+            .insert(fd as _, Tcp::TlsStream(Box::new(tls_stream), key));
+
+        Ok(fd as _)
+    }
+
+    /// keep a TLS stream idle for the next connects, any other fd is closed
+    fn inner_release(&mut self, fd: u32) {
+        if let Some(Tcp::TlsStream(tls_stream, key)) = self.fd_map.remove(&fd) {
+            self.tls_client.put_idle(key, *tls_stream);
+        }
+    }
+
     async fn inner_write(&mut self, fd: u32, buf: Vec<u8>) -> Result<u64, u32> {
         let tcp_stream = self.get_tcp_stream(fd)?;
 
@@ -138,11 +323,29 @@ impl TcpHelper {
         Ok(buf.freeze().into())
     }
 
-    fn get_tcp_stream(&mut self, fd: u32) -> Result<&mut TcpStream, u32> {
+    async fn inner_read_timeout(
+        &mut self,
+        fd: u32,
+        buf_size: u64,
+        timeout_ms: u64,
+    ) -> Result<Option<Vec<u8>>, u32> {
+        match time::timeout(
+            Duration::from_millis(timeout_ms),
+            self.inner_read(fd, buf_size),
+        )
+        .await
+        {
+            Err(_) => Ok(None),
+            Ok(result) => result.map(Some),
+        }
+    }
+
+    fn get_tcp_stream(&mut self, fd: u32) -> Result<&mut dyn Stream, u32> {
         match self.fd_map.get_mut(&fd) {
             None => Err(libc::EBADF as _),
             Some(Tcp::Listener(_)) => Err(libc::EBADF as _),
             Some(Tcp::Stream(tcp_stream)) => Ok(tcp_stream),
+            Some(Tcp::TlsStream(tls_stream, _)) => Ok(tls_stream.as_mut()),
         }
     }
 
@@ -168,6 +371,28 @@ impl Host for TcpHelper {
         Ok(self.inner_connect(addr).await)
     }
 
+    #[inline]
+    async fn connect_timeout(
+        &mut self,
+        addr: Addr,
+        timeout_ms: u64,
+    ) -> wasmtime::Result<Result<u32, u32>> {
+        Ok(self.inner_connect_timeout(addr, timeout_ms).await)
+    }
+
+    #[inline]
+    async fn connect_tls(
+        &mut self,
+        addr: Addr,
+        server_name: String,
+        ca: Option<String>,
+        timeout_ms: Option<u64>,
+    ) -> wasmtime::Result<Result<u32, u32>> {
+        Ok(self
+            .inner_connect_tls_timeout(addr, server_name, ca, timeout_ms)
+            .await)
+    }
+
     #[inline]
     async fn write(&mut self, fd: u32, buf: Vec<u8>) -> wasmtime::Result<Result<u64, u32>> {
         Ok(self.inner_write(fd, buf).await)
@@ -183,17 +408,172 @@ impl Host for TcpHelper {
         Ok(self.inner_read(fd, buf_size).await)
     }
 
+    #[inline]
+    async fn read_timeout(
+        &mut self,
+        fd: u32,
+        buf_size: u64,
+        timeout_ms: u64,
+    ) -> wasmtime::Result<Result<Option<Vec<u8>>, u32>> {
+        Ok(self.inner_read_timeout(fd, buf_size, timeout_ms).await)
+    }
+
     #[inline]
     async fn close(&mut self, fd: u32) -> wasmtime::Result<()> {
         self.fd_map.remove(&fd);
 
         Ok(())
     }
+
+    #[inline]
+    async fn release(&mut self, fd: u32) -> wasmtime::Result<()> {
+        self.inner_release(fd);
+
+        Ok(())
+    }
 }
 
-fn get_ipv4_be(addr: &SocketAddr) -> Result<u32, u32> {
-    match addr.ip() {
-        IpAddr::V4(ip) => Ok(u32::from_be_bytes(ip.octets()).to_be()),
-        IpAddr::V6(_) => Err(libc::ENOTSUP as _),
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use tokio::io;
+    use tokio_rustls::TlsAcceptor;
+
+    use super::*;
+    use crate::config::Tls;
+    use crate::tls;
+
+    fn testdata(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("testdata")
+            .join(name)
+    }
+
+    /// a TLS echo server with the `dns.example.com` cert, it counts the accepted connections
+    async fn echo_server() -> (SocketAddr, Arc<AtomicUsize>) {
+        let server_config = tls::load_server_config(&Tls {
+            cert_path: testdata("cert.pem"),
+            key_path: testdata("key.pem"),
+            client_ca_path: None,
+        })
+        .await
+        .unwrap();
+        let tls_acceptor = TlsAcceptor::from(server_config);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accepted = Arc::new(AtomicUsize::new(0));
+
+        let counter = accepted.clone();
+        tokio::spawn(async move {
+            loop {
+                let (tcp_stream, _) = listener.accept().await.unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+
+                let tls_acceptor = tls_acceptor.clone();
+                tokio::spawn(async move {
+                    if let Ok(tls_stream) = tls_acceptor.accept(tcp_stream).await {
+                        let (mut reader, mut writer) = io::split(tls_stream);
+                        let _ = io::copy(&mut reader, &mut writer).await;
+                    }
+                });
+            }
+        });
+
+        (addr, accepted)
+    }
+
+    async fn tcp_helper() -> TcpHelper {
+        let ca_config = tls::load_client_config(Some(&testdata("cert.pem")))
+            .await
+            .unwrap();
+        let tls_client = TlsClient::new(
+            tls::load_client_config(None).await.unwrap(),
+            HashMap::from([("test".to_string(), ca_config)]),
+        );
+
+        TcpHelper::new(Arc::new(tls_client))
+    }
+
+    async fn connect(
+        tcp_helper: &mut TcpHelper,
+        addr: SocketAddr,
+        server_name: &str,
+        ca: Option<&str>,
+    ) -> Result<u32, u32> {
+        tcp_helper
+            .inner_connect_tls_timeout(
+                from_socket_addr(addr),
+                server_name.to_string(),
+                ca.map(ToString::to_string),
+                Some(5000),
+            )
+            .await
+    }
+
+    async fn echo(tcp_helper: &mut TcpHelper, fd: u32) -> Vec<u8> {
+        tcp_helper.inner_write(fd, b"ping".to_vec()).await.unwrap();
+        tcp_helper.inner_flush(fd).await.unwrap();
+
+        tcp_helper
+            .inner_read_timeout(fd, 4, 5000)
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn verify_server_cert_and_name_with_plugin_ca() {
+        let (addr, _) = echo_server().await;
+        let mut tcp_helper = tcp_helper().await;
+
+        let fd = connect(&mut tcp_helper, addr, "dns.example.com", Some("test"))
+            .await
+            .unwrap();
+        assert_eq!(echo(&mut tcp_helper, fd).await, b"ping");
+
+        // the cert is not for the name
+        assert!(
+            connect(&mut tcp_helper, addr, "other.example.com", Some("test"))
+                .await
+                .is_err()
+        );
+        // the self-signed cert is not trusted by the webpki roots
+        assert!(connect(&mut tcp_helper, addr, "dns.example.com", None)
+            .await
+            .is_err());
+        assert_eq!(
+            connect(&mut tcp_helper, addr, "dns.example.com", Some("missing")).await,
+            Err(libc::EINVAL as u32)
+        );
+    }
+
+    #[tokio::test]
+    async fn reuse_released_tls_stream() {
+        let (addr, accepted) = echo_server().await;
+        let mut tcp_helper = tcp_helper().await;
+
+        let fd = connect(&mut tcp_helper, addr, "dns.example.com", Some("test"))
+            .await
+            .unwrap();
+        echo(&mut tcp_helper, fd).await;
+        tcp_helper.inner_release(fd);
+
+        let fd = connect(&mut tcp_helper, addr, "dns.example.com", Some("test"))
+            .await
+            .unwrap();
+        assert_eq!(echo(&mut tcp_helper, fd).await, b"ping");
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+
+        // a closed stream is not reused
+        tcp_helper.fd_map.remove(&fd);
+
+        let fd = connect(&mut tcp_helper, addr, "dns.example.com", Some("test"))
+            .await
+            .unwrap();
+        assert_eq!(echo(&mut tcp_helper, fd).await, b"ping");
+        assert_eq!(accepted.load(Ordering::SeqCst), 2);
     }
 }
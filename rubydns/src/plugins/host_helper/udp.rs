@@ -1,13 +1,14 @@
 use std::collections::HashMap;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::os::fd::AsRawFd;
+use std::time::Duration;
 
 use async_trait::async_trait;
 use bytes::BytesMut;
 use tokio::net::UdpSocket;
+use tokio::time;
 use tracing::error;
 
-use super::io_err_to_errno;
+use super::{from_socket_addr, io_err_to_errno, to_socket_addr};
 use crate::plugins::udp_helper::{Addr, Host};
 
 #[derive(Debug, Default)]
@@ -17,10 +18,7 @@ pub struct UdpHelper {
 
 impl UdpHelper {
     async fn inner_bind(&mut self, addr: Addr) -> Result<u32, u32> {
-        let addr = SocketAddr::new(
-            IpAddr::V4(Ipv4Addr::from(u32::from_be(addr.addr))),
-            u16::from_be(addr.port),
-        );
+        let addr = to_socket_addr(addr);
 
         let udp_socket = UdpSocket::bind(addr).await.map_err(|err| {
             error!(%addr, %err, "bind udp socket failed");
@@ -39,10 +37,7 @@ impl UdpHelper {
             None => return Err(libc::EBADF as _),
             Some(udp_socket) => udp_socket,
         };
-        let addr = SocketAddr::new(
-            IpAddr::V4(Ipv4Addr::from(u32::from_be(addr.addr))),
-            u16::from_be(addr.port),
-        );
+        let addr = to_socket_addr(addr);
 
         udp_socket.connect(addr).await.map_err(|err| {
             error!(fd, %addr, "udp socket connect failed");
@@ -94,15 +89,29 @@ impl UdpHelper {
         Ok(buf.freeze().into())
     }
 
+    async fn inner_recv_timeout(
+        &mut self,
+        fd: u32,
+        buf_size: u64,
+        timeout_ms: u64,
+    ) -> Result<Option<Vec<u8>>, u32> {
+        match time::timeout(
+            Duration::from_millis(timeout_ms),
+            self.inner_recv(fd, buf_size),
+        )
+        .await
+        {
+            Err(_) => Ok(None),
+            Ok(result) => result.map(Some),
+        }
+    }
+
     async fn inner_send_to(&mut self, fd: u32, buf: Vec<u8>, addr: Addr) -> Result<u64, u32> {
         let udp_socket = match self.fd_map.get(&fd) {
             None => return Err(libc::EBADF as _),
             Some(udp_socket) => udp_socket,
         };
-        let addr = SocketAddr::new(
-            IpAddr::V4(Ipv4Addr::from(u32::from_be(addr.addr))),
-            u16::from_be(addr.port),
-        );
+        let addr = to_socket_addr(addr);
 
         udp_socket
             .send_to(&buf, addr)
@@ -138,19 +147,7 @@ impl UdpHelper {
             buf.set_len(n);
         }
 
-        let addr = match source.ip() {
-            IpAddr::V4(addr) => u32::from_be_bytes(addr.octets()),
-            // we don't support v6 yet
-            IpAddr::V6(_) => return Err(libc::ENOTSUP as _),
-        };
-
-        Ok((
-            buf.into(),
-            Addr {
-                addr,
-                port: source.port().to_be(),
-            },
-        ))
+        Ok((buf.into(), from_socket_addr(source)))
     }
 
     pub fn reset(&mut self) {
@@ -180,6 +177,16 @@ impl Host for UdpHelper {
         Ok(self.inner_recv(fd, buf_size).await)
     }
 
+    #[inline]
+    async fn recv_timeout(
+        &mut self,
+        fd: u32,
+        buf_size: u64,
+        timeout_ms: u64,
+    ) -> wasmtime::Result<Result<Option<Vec<u8>>, u32>> {
+        Ok(self.inner_recv_timeout(fd, buf_size, timeout_ms).await)
+    }
+
     #[inline]
     async fn send_to(
         &mut self,
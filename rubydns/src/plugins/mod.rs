@@ -1,22 +1,46 @@
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::iter;
+use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use bytes::Bytes;
-use futures_util::{stream, TryStreamExt};
+use futures_util::future::BoxFuture;
+use futures_util::{stream, FutureExt, TryStreamExt};
 use tap::TapFallible;
 use thiserror::Error;
 use tokio::fs;
-use tracing::{error, info, instrument};
+use tracing::{error, info, instrument, warn};
 use trust_dns_proto::error::ProtoError;
-use trust_dns_proto::op::{Message, MessageType, ResponseCode};
+use trust_dns_proto::op::{Message, MessageType, Query, ResponseCode};
+use trust_dns_proto::rr::rdata::opt::EdnsOption;
+use trust_dns_proto::rr::{Name, RecordType};
 use wasmtime::component::bindgen;
 use wasmtime::Engine;
 
 pub use self::config::Plugin as PluginConfig;
-use self::pool::PluginPool;
+pub use self::config::Route as RouteConfig;
+pub use self::helper::Transport;
+use self::host_helper::{PluginStore, RequestContext, StaticMap, StoreOptions, TlsClient};
+use self::pool::{Downstream, PluginPool, PoolOptions, RunError};
+use crate::metrics;
+use crate::tls;
 
 mod config;
 mod host_helper;
 mod pool;
+#[cfg(test)]
+pub mod test_plugin;
+
+/// the error code a plugin returns to drop the query without any response
+const DROP_ERROR_CODE: u32 = u32::MAX;
+
+/// the extended DNS error option code, RFC 8914
+pub const EDE_OPTION_CODE: u16 = 15;
+/// the extended DNS error info code "Other"
+const EDE_INFO_CODE_OTHER: u16 = 0;
 
 bindgen!({
     path: "../wit",
@@ -33,82 +57,258 @@ pub enum Error {
 
     #[error("get plugin from pool failed: {0}")]
     PluginPool(anyhow::Error),
+
+    #[error("plugin {plugin} run timeout after {timeout:?}")]
+    PluginTimeout { plugin: String, timeout: Duration },
+
+    #[error("plugin drops the query")]
+    Dropped,
+}
+
+impl From<RunError> for Error {
+    fn from(err: RunError) -> Self {
+        match err {
+            RunError::GetPlugin(err) => Self::PluginPool(err),
+            RunError::Run(err) => Self::PluginRun(err),
+            RunError::Timeout { plugin, timeout } => Self::PluginTimeout { plugin, timeout },
+        }
+    }
 }
 
 pub struct PluginChain {
+    /// the default chain, used by the queries matching no route
     plugin: PluginPool,
+    chains: HashMap<String, PluginPool>,
+    routes: Vec<Route>,
+    max_response_bytes: usize,
+    /// log the queries slower than it, with the plugin durations
+    slow_query_threshold: Option<Duration>,
 }
 
-impl PluginChain {
-    pub async fn new(plugin_dir: &Path, configs: Vec<PluginConfig>) -> anyhow::Result<Self> {
-        let mut engine_config = wasmtime::Config::new();
-        engine_config.wasm_component_model(true).async_support(true);
-        let engine = Engine::new(&engine_config)?;
-
-        let plugin = stream::iter(configs.into_iter().rev().map(Ok))
-            .try_fold(None, |next_plugin, plugin_config| {
-                let engine = engine.clone();
-
-                async move {
-                    let raw_config = serde_yaml::to_string(&plugin_config.config)?;
-                    let plugin_path = match plugin_config.plugin_path {
-                        None => plugin_dir.join(plugin_config.name.clone() + ".wasm"),
-                        Some(plugin_path) => PathBuf::from(plugin_path + ".wasm"),
-                    };
-
-                    let plugin_binary = fs::read(&plugin_path).await?;
-                    let plugin_pool =
-                        PluginPool::new(engine, plugin_binary.into(), raw_config, next_plugin)
-                            .await?;
-
-                    info!(plugin = %plugin_config.name, "create plugin pool done");
-
-                    Ok::<_, anyhow::Error>(Some(plugin_pool))
-                }
+/// the engine shared by all plugin chains, so they share one compiled code cache
+pub fn new_engine() -> anyhow::Result<Engine> {
+    let mut engine_config = wasmtime::Config::new();
+    engine_config
+        .wasm_component_model(true)
+        .async_support(true)
+        .consume_fuel(true);
+
+    Engine::new(&engine_config)
+}
+
+/// a compiled route config
+#[derive(Debug)]
+struct Route {
+    suffix: Option<Name>,
+    query_types: Vec<RecordType>,
+    chain: String,
+}
+
+impl Route {
+    fn new(route_config: RouteConfig) -> anyhow::Result<Self> {
+        let suffix = route_config
+            .suffix
+            .map(|suffix| {
+                let suffix = suffix.trim_matches('.');
+
+                Name::from_ascii(format!("{suffix}."))
+                    .map_err(|err| anyhow::anyhow!("invalid route suffix {suffix}: {err}"))
             })
+            .transpose()?;
+
+        let query_types = route_config
+            .query_types
+            .iter()
+            .map(|query_type| {
+                query_type
+                    .to_uppercase()
+                    .parse::<RecordType>()
+                    .map_err(|err| anyhow::anyhow!("invalid route query type {query_type}: {err}"))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        Ok(Self {
+            suffix,
+            query_types,
+            chain: route_config.chain,
+        })
+    }
+
+    fn matches(&self, query: &Query) -> bool {
+        if let Some(suffix) = &self.suffix {
+            if !suffix.zone_of(query.name()) {
+                return false;
+            }
+        }
+
+        self.query_types.is_empty() || self.query_types.contains(&query.query_type())
+    }
+}
+
+impl PluginChain {
+    pub async fn new(
+        engine: Engine,
+        plugin_dir: &Path,
+        configs: Vec<PluginConfig>,
+        chain_configs: HashMap<String, Vec<PluginConfig>>,
+        route_configs: Vec<RouteConfig>,
+        max_response_bytes: usize,
+        slow_query_threshold: Option<Duration>,
+    ) -> anyhow::Result<Self> {
+        let mut shared_plugins = vec![];
+        collect_shared_plugins(&configs, &mut shared_plugins);
+        for configs in chain_configs.values() {
+            collect_shared_plugins(configs, &mut shared_plugins);
+        }
+        let shared_store = new_shared_store(&shared_plugins)?;
+
+        let plugin = create_plugins(engine.clone(), plugin_dir, configs, shared_store.clone())
             .await?
-            .expect("no plugin set");
+            .ok_or_else(|| anyhow::anyhow!("no plugin is created"))?;
+
+        let mut chains = HashMap::with_capacity(chain_configs.len());
+        for (chain, configs) in chain_configs {
+            let plugin_pool =
+                create_plugins(engine.clone(), plugin_dir, configs, shared_store.clone())
+                    .await?
+                    .ok_or_else(|| anyhow::anyhow!("chain {chain} has no plugin"))?;
+
+            chains.insert(chain, plugin_pool);
+        }
+
+        let routes = route_configs
+            .into_iter()
+            .map(Route::new)
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        if let Some(route) = routes
+            .iter()
+            .find(|route| !chains.contains_key(&route.chain))
+        {
+            return Err(anyhow::anyhow!("route chain {} not exists", route.chain));
+        }
 
-        Ok(Self { plugin })
+        Ok(Self {
+            plugin,
+            chains,
+            routes,
+            max_response_bytes,
+            slow_query_threshold,
+        })
     }
 }
 
 impl PluginChain {
-    #[instrument(err, skip(self, dns_packet))]
+    /// clear the stores of the plugins with the name, return how many stores are cleared
+    pub fn clear_store(&self, plugin: &str) -> usize {
+        self.plugins()
+            .map(|plugin_pool| plugin_pool.clear_store(plugin))
+            .sum()
+    }
+
+    pub fn store_entries(&self) -> usize {
+        self.stores().iter().map(|store| store.entries()).sum()
+    }
+
+    pub fn store_bytes(&self) -> u64 {
+        self.stores().iter().map(|store| store.bytes()).sum()
+    }
+
+    /// the stores of all plugins, the shared store is counted once
+    fn stores(&self) -> Vec<&PluginStore> {
+        let mut stores = vec![];
+        for plugin in self.plugins() {
+            plugin.stores(&mut stores);
+        }
+
+        stores
+    }
+
+    fn plugins(&self) -> impl Iterator<Item = &PluginPool> {
+        iter::once(&self.plugin).chain(self.chains.values())
+    }
+
+    /// the chain of the first route matching the first query, or the default chain
+    fn route(&self, dns_message: &Message) -> (&str, &PluginPool) {
+        let chain = dns_message.query().and_then(|query| {
+            self.routes
+                .iter()
+                .find(|route| route.matches(query))
+                .map(|route| route.chain.as_str())
+        });
+
+        match chain.and_then(|chain| Some((chain, self.chains.get(chain)?))) {
+            None => ("default", &self.plugin),
+            Some(route) => route,
+        }
+    }
+
+    /// the query metadata is recorded by the span of the server handling the query
+    #[instrument(err, skip(self, dns_message, dns_packet))]
     pub async fn handle_dns(
         &self,
-        mut dns_message: Message,
+        transport: Transport,
+        client_addr: Option<SocketAddr>,
+        dns_message: Message,
         dns_packet: Bytes,
     ) -> Result<(Message, Bytes), Error> {
-        info!("start get plugin");
+        let start = Instant::now();
+        let request_context = RequestContext {
+            original_request: dns_packet.clone(),
+            transport,
+            client_addr,
+            plugin_durations: Default::default(),
+        };
+        let plugin_durations = request_context.plugin_durations.clone();
+
+        let result = self
+            .run_plugins(request_context, dns_message, dns_packet)
+            .await;
 
-        let mut obj = self.plugin.get_plugin().await.map_err(Error::PluginPool)?;
-        let (plugin, store) = &mut *obj;
+        let elapsed = start.elapsed();
+        metrics::record_plugin_chain_latency(elapsed);
+        if let Some(slow_query_threshold) = self.slow_query_threshold {
+            if elapsed >= slow_query_threshold {
+                warn!(
+                    ?elapsed,
+                    plugin_durations = ?plugin_durations.lock().unwrap(),
+                    "slow query"
+                );
+            }
+        }
 
-        info!("get plugin done, start call plugin");
+        result
+    }
+
+    async fn run_plugins(
+        &self,
+        request_context: RequestContext,
+        mut dns_message: Message,
+        dns_packet: Bytes,
+    ) -> Result<(Message, Bytes), Error> {
+        let (chain, plugin) = self.route(&dns_message);
+
+        info!(chain, "start call plugin");
 
         let result = plugin
-            .plugin()
-            .call_run(store, &dns_packet)
+            .run(request_context, &dns_packet)
             .await
             .map_err(|err| {
                 error!(%err, "plugin run failed");
 
-                Error::PluginRun(err)
+                Error::from(err)
             })?;
 
         let data = match result {
-            Err(err) => {
-                error!(?err, "plugin handle dns failed");
+            Err(err) if err.code == DROP_ERROR_CODE => {
+                info!(msg = %err.msg, "plugin drops the query");
 
-                dns_message.set_message_type(MessageType::Response);
-                dns_message.set_response_code(ResponseCode::ServFail);
+                return Err(Error::Dropped);
+            }
 
-                let response_packet = dns_message
-                    .to_vec()
-                    .tap_err(|err| error!(%err, ?dns_message, "encode error dns message failed"))?;
+            Err(err) => {
+                error!(?err, "plugin handle dns failed");
 
-                return Ok((dns_message, response_packet.into()));
+                return servfail_response(dns_message);
             }
 
             Ok(data) => data,
@@ -116,9 +316,781 @@ impl PluginChain {
 
         info!("call plugin done");
 
-        let response_message = Message::from_vec(&data)
+        if data.len() > self.max_response_bytes {
+            error!(
+                size = data.len(),
+                max_size = self.max_response_bytes,
+                "plugin response exceeds the size cap, replace it with SERVFAIL"
+            );
+
+            dns_message.set_message_type(MessageType::Response);
+            dns_message.set_response_code(ResponseCode::ServFail);
+            // the EDE option is carried by the OPT record, a request without EDNS gets the
+            // bare SERVFAIL
+            if let Some(edns) = dns_message.extensions_mut() {
+                let mut option_data = EDE_INFO_CODE_OTHER.to_be_bytes().to_vec();
+                option_data.extend_from_slice(b"response too large");
+
+                edns.options_mut().as_mut().clear();
+                edns.set_option(EdnsOption::Unknown(EDE_OPTION_CODE, option_data));
+            }
+
+            let response_packet = dns_message
+                .to_vec()
+                .tap_err(|err| error!(%err, ?dns_message, "encode error dns message failed"))?;
+
+            return Ok((dns_message, response_packet.into()));
+        }
+
+        let mut response_message = Message::from_vec(&data)
             .tap_err(|err| error!(%err, "decode response dns message failed"))?;
 
-        Ok((response_message, data.into()))
+        if response_message.message_type() != MessageType::Response {
+            error!(
+                ?response_message,
+                "plugin returns a non-response dns message, replace it with SERVFAIL"
+            );
+
+            return servfail_response(dns_message);
+        }
+
+        // a wrong ID means a buggy plugin or a spoofed upstream response, don't fix it up
+        if response_message.id() != dns_message.id() {
+            error!(
+                original_id = dns_message.id(),
+                response_id = response_message.id(),
+                "response id mismatch the original request, replace it with SERVFAIL"
+            );
+
+            return servfail_response(dns_message);
+        }
+
+        if same_queries(response_message.queries(), dns_message.queries()) {
+            return Ok((response_message, data.into()));
+        }
+
+        warn!(
+            original_queries = ?dns_message.queries(),
+            response_queries = ?response_message.queries(),
+            "response question mismatch the original request, restore it"
+        );
+
+        *response_message.queries_mut() = dns_message.take_queries();
+
+        let response_packet = response_message
+            .to_vec()
+            .tap_err(|err| error!(%err, "encode restored response dns message failed"))?;
+
+        Ok((response_message, response_packet.into()))
+    }
+}
+
+fn servfail_response(mut dns_message: Message) -> Result<(Message, Bytes), Error> {
+    dns_message.set_message_type(MessageType::Response);
+    dns_message.set_response_code(ResponseCode::ServFail);
+
+    let response_packet = dns_message
+        .to_vec()
+        .tap_err(|err| error!(%err, ?dns_message, "encode error dns message failed"))?;
+
+    Ok((dns_message, response_packet.into()))
+}
+
+/// create the plugins of a chain from the last one, return the first plugin
+///
+/// the optional plugins which fail to load are skipped, their upstream plugins call their
+/// downstream plugins directly
+fn create_plugins(
+    engine: Engine,
+    plugin_dir: &Path,
+    configs: Vec<PluginConfig>,
+    shared_store: Option<Arc<PluginStore>>,
+) -> BoxFuture<'_, anyhow::Result<Option<PluginPool>>> {
+    stream::iter(configs.into_iter().rev().map(Ok))
+        .try_fold(None, move |next_plugin, plugin_config| {
+            let engine = engine.clone();
+            let shared_store = shared_store.clone();
+
+            async move {
+                let name = plugin_config.name.clone();
+                let optional = plugin_config.optional;
+
+                match create_plugin(
+                    engine,
+                    plugin_dir,
+                    plugin_config,
+                    next_plugin.clone(),
+                    shared_store,
+                )
+                .await
+                {
+                    Err(err) if optional => {
+                        warn!(%err, plugin = %name, "create optional plugin pool failed, skip it");
+
+                        Ok(next_plugin)
+                    }
+
+                    Err(err) => Err(err),
+
+                    Ok(plugin_pool) => {
+                        info!(plugin = %name, "create plugin pool done");
+
+                        Ok(Some(plugin_pool))
+                    }
+                }
+            }
+        })
+        .boxed()
+}
+
+async fn create_plugin(
+    engine: Engine,
+    plugin_dir: &Path,
+    plugin_config: PluginConfig,
+    next_plugin: Option<PluginPool>,
+    shared_store: Option<Arc<PluginStore>>,
+) -> anyhow::Result<PluginPool> {
+    let plugin_store_options = store_options(&plugin_config);
+
+    let mut branches = HashMap::with_capacity(plugin_config.branches.len());
+    for (branch, configs) in plugin_config.branches {
+        let plugin_pool = create_plugins(engine.clone(), plugin_dir, configs, shared_store.clone())
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("branch {branch} has no plugin"))?;
+
+        branches.insert(branch, plugin_pool);
+    }
+
+    let raw_config = serde_yaml::to_string(&plugin_config.config)?;
+    let plugin_path = resolve_plugin_path(
+        plugin_dir,
+        &plugin_config.name,
+        plugin_config.plugin_path.as_deref(),
+    );
+
+    let plugin_binary = fs::read(&plugin_path).await?;
+    let static_maps = open_static_maps(plugin_dir, &plugin_config.static_maps)?;
+    let tls_client = load_tls_client(plugin_dir, &plugin_config.tls_cas).await?;
+    let plugin_store_map = match shared_store {
+        Some(shared_store) if plugin_config.shared => shared_store,
+        _ => new_store(plugin_store_options),
+    };
+
+    PluginPool::new(
+        plugin_config.name,
+        PoolOptions {
+            run_timeout: plugin_config.timeout_ms.map(Duration::from_millis),
+            max_size: plugin_config.pool_max_size,
+            wait_timeout: plugin_config.pool_timeout_ms.map(Duration::from_millis),
+            fuel_limit: plugin_config.fuel_limit,
+        },
+        plugin_store_map,
+        engine,
+        plugin_binary.into(),
+        raw_config,
+        static_maps,
+        tls_client,
+        Downstream {
+            next_plugin,
+            branches: Arc::new(branches),
+        },
+    )
+    .await
+}
+
+fn store_options(plugin_config: &PluginConfig) -> StoreOptions {
+    StoreOptions {
+        dedup: plugin_config.dedup_store,
+        max_entries: plugin_config.store_max_entries,
+        eviction: plugin_config.store_eviction,
+        sweep_interval: (plugin_config.store_sweep_interval_secs > 0)
+            .then(|| Duration::from_secs(plugin_config.store_sweep_interval_secs)),
+        scan_limit: plugin_config.store_scan_limit,
+    }
+}
+
+fn new_store(store_options: StoreOptions) -> Arc<PluginStore> {
+    let plugin_store = Arc::new(PluginStore::new(store_options));
+    if let Some(sweep_interval) = store_options.sweep_interval {
+        plugin_store.spawn_sweeper(sweep_interval);
+    }
+
+    plugin_store
+}
+
+/// collect the `shared` plugins of the chain and its branches
+fn collect_shared_plugins<'a>(
+    configs: &'a [PluginConfig],
+    shared_plugins: &mut Vec<&'a PluginConfig>,
+) {
+    for plugin_config in configs {
+        if plugin_config.shared {
+            shared_plugins.push(plugin_config);
+        }
+
+        for configs in plugin_config.branches.values() {
+            collect_shared_plugins(configs, shared_plugins);
+        }
+    }
+}
+
+/// the store shared by the `shared` plugins, [`None`] when no plugin is shared, the shared
+/// plugins must have the same store options, so none of them is silently ignored
+fn new_shared_store(shared_plugins: &[&PluginConfig]) -> anyhow::Result<Option<Arc<PluginStore>>> {
+    let shared_store_options = match shared_plugins.first() {
+        None => return Ok(None),
+        Some(plugin_config) => store_options(plugin_config),
+    };
+
+    if let Some(plugin_config) = shared_plugins
+        .iter()
+        .find(|plugin_config| store_options(plugin_config) != shared_store_options)
+    {
+        return Err(anyhow::anyhow!(
+            "shared plugin {} store options differ from plugin {}",
+            plugin_config.name,
+            shared_plugins[0].name
+        ));
+    }
+
+    Ok(Some(new_store(shared_store_options)))
+}
+
+/// an absolute plugin path is used as it is, a relative one is joined to the plugin dir, the
+/// `.wasm` extension is appended unless it is present
+fn resolve_plugin_path(plugin_dir: &Path, name: &str, plugin_path: Option<&str>) -> PathBuf {
+    let mut plugin_path = plugin_dir
+        .join(plugin_path.unwrap_or(name))
+        .into_os_string();
+    if Path::new(&plugin_path).extension() != Some(OsStr::new("wasm")) {
+        plugin_path.push(".wasm");
+    }
+
+    plugin_path.into()
+}
+
+/// open the static maps of a plugin by the host config, so a plugin only reads the maps the
+/// operator gives it, a relative path is under the plugin dir
+fn open_static_maps(
+    plugin_dir: &Path,
+    static_maps: &HashMap<String, String>,
+) -> anyhow::Result<HashMap<String, StaticMap>> {
+    static_maps
+        .iter()
+        .map(|(name, path)| {
+            let path = plugin_dir.join(path);
+            let static_map = StaticMap::open(&path).map_err(|err| {
+                anyhow::anyhow!("open static map {name} at {} failed: {err}", path.display())
+            })?;
+
+            Ok((name.clone(), static_map))
+        })
+        .collect()
+}
+
+/// load the TLS CAs of a plugin by the host config, so a plugin only trusts the CAs the
+/// operator gives it, a relative path is under the plugin dir
+async fn load_tls_client(
+    plugin_dir: &Path,
+    tls_cas: &HashMap<String, String>,
+) -> anyhow::Result<TlsClient> {
+    let mut ca_configs = HashMap::with_capacity(tls_cas.len());
+    for (name, path) in tls_cas {
+        let path = plugin_dir.join(path);
+        let client_config = tls::load_client_config(Some(&path)).await.map_err(|err| {
+            anyhow::anyhow!("load tls CA {name} at {} failed: {err}", path.display())
+        })?;
+
+        ca_configs.insert(name.clone(), client_config);
+    }
+
+    Ok(TlsClient::new(
+        tls::load_client_config(None).await?,
+        ca_configs,
+    ))
+}
+
+/// compare the queries case sensitively, so a 0x20 randomized name is treated as mutated
+fn same_queries(a: &[Query], b: &[Query]) -> bool {
+    a.len() == b.len()
+        && a.iter().zip(b).all(|(a, b)| {
+            a.name().eq_case(b.name())
+                && a.query_type() == b.query_type()
+                && a.query_class() == b.query_class()
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::str::FromStr;
+
+    use trust_dns_proto::op::Edns;
+    use trust_dns_proto::rr::rdata::opt::EdnsCode;
+    use trust_dns_proto::rr::DNSClass;
+
+    use super::*;
+
+    fn query(name: &str, query_type: RecordType) -> Query {
+        Query::query(Name::from_str(name).unwrap(), query_type)
+    }
+
+    fn parse_route(config: &str) -> Route {
+        Route::new(serde_yaml::from_str(config).unwrap()).unwrap()
+    }
+
+    /// a chain of the plugin `test` running the body
+    async fn plugin_chain(test: &str, run: &str) -> PluginChain {
+        plugin_chain_with(test, run, 65535, None).await
+    }
+
+    async fn plugin_chain_with(
+        test: &str,
+        run: &str,
+        max_response_bytes: usize,
+        slow_query_threshold: Option<Duration>,
+    ) -> PluginChain {
+        let plugin_dir = test_plugin::plugin_dir(test, &[("test", run)]);
+
+        PluginChain::new(
+            new_engine().unwrap(),
+            &plugin_dir,
+            vec![serde_yaml::from_str("name: test").unwrap()],
+            HashMap::new(),
+            vec![],
+            max_response_bytes,
+            slow_query_threshold,
+        )
+        .await
+        .unwrap()
+    }
+
+    fn request() -> (Message, Bytes) {
+        let mut message = Message::new();
+        message
+            .set_id(4096)
+            .set_recursion_desired(true)
+            .add_query(query("www.example.com.", RecordType::A));
+        let dns_packet = message.to_vec().unwrap();
+
+        (message, dns_packet.into())
+    }
+
+    async fn handle(plugin_chain: &PluginChain, request: &Message, dns_packet: Bytes) -> Message {
+        let (_, response_packet) = plugin_chain
+            .handle_dns(Transport::Udp, None, request.clone(), dns_packet)
+            .await
+            .unwrap();
+
+        Message::from_vec(&response_packet).unwrap()
+    }
+
+    /// the formatted logs written in the scope of the returned guard
+    #[derive(Clone, Default)]
+    struct LogBuffer(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl LogBuffer {
+        fn capture(&self) -> tracing::subscriber::DefaultGuard {
+            let log_buffer = self.clone();
+            let subscriber = tracing_subscriber::fmt()
+                .with_writer(move || log_buffer.clone())
+                .with_ansi(false)
+                .finish();
+
+            tracing::subscriber::set_default(subscriber)
+        }
+
+        fn logs(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    impl io::Write for LogBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn resolve_absolute_plugin_path() {
+        let plugin_dir = Path::new("/plugins");
+
+        assert_eq!(
+            resolve_plugin_path(plugin_dir, "cache", Some("/opt/rubydns/cache")),
+            PathBuf::from("/opt/rubydns/cache.wasm")
+        );
+        assert_eq!(
+            resolve_plugin_path(plugin_dir, "cache", Some("/opt/rubydns/cache.wasm")),
+            PathBuf::from("/opt/rubydns/cache.wasm")
+        );
+    }
+
+    #[test]
+    fn resolve_relative_plugin_path() {
+        let plugin_dir = Path::new("/plugins");
+
+        assert_eq!(
+            resolve_plugin_path(plugin_dir, "cache", None),
+            PathBuf::from("/plugins/cache.wasm")
+        );
+        assert_eq!(
+            resolve_plugin_path(plugin_dir, "cache", Some("v2/cache")),
+            PathBuf::from("/plugins/v2/cache.wasm")
+        );
+        assert_eq!(
+            resolve_plugin_path(plugin_dir, "cache", Some("v2/cache.wasm")),
+            PathBuf::from("/plugins/v2/cache.wasm")
+        );
+        // only the .wasm extension is kept as it is
+        assert_eq!(
+            resolve_plugin_path(plugin_dir, "cache", Some("cache.v2")),
+            PathBuf::from("/plugins/cache.v2.wasm")
+        );
+    }
+
+    #[test]
+    fn open_relative_static_map() {
+        let plugin_dir = test_plugin::plugin_dir("static-map", &[]);
+        std::fs::create_dir_all(plugin_dir.join("maps")).unwrap();
+        host_helper::write_static_map(
+            &plugin_dir.join("maps/zone.fst"),
+            &[("www.example.com", "192.0.2.1")],
+        );
+
+        let static_maps = open_static_maps(
+            &plugin_dir,
+            &HashMap::from([("zone".to_string(), "maps/zone.fst".to_string())]),
+        )
+        .unwrap();
+
+        assert_eq!(
+            static_maps["zone"].get(b"www.example.com").unwrap(),
+            Some(b"192.0.2.1".as_slice())
+        );
+    }
+
+    #[test]
+    fn fail_to_open_missing_static_map() {
+        let plugin_dir = test_plugin::plugin_dir("static-map-missing", &[]);
+
+        let err = open_static_maps(
+            &plugin_dir,
+            &HashMap::from([("zone".to_string(), "missing.fst".to_string())]),
+        )
+        .err()
+        .unwrap();
+
+        assert!(err.to_string().contains("open static map zone"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn create_missing_plugin_fails() {
+        let plugin_config: PluginConfig = serde_yaml::from_str("name: missing").unwrap();
+
+        let err = create_plugin(
+            new_engine().unwrap(),
+            Path::new("/nonexistent/plugins"),
+            plugin_config,
+            None,
+            None,
+        )
+        .await
+        .err()
+        .unwrap();
+
+        assert_eq!(
+            err.downcast_ref::<io::Error>().unwrap().kind(),
+            io::ErrorKind::NotFound
+        );
+    }
+
+    #[tokio::test]
+    async fn skip_missing_optional_plugin() {
+        let plugin_dir = test_plugin::plugin_dir("optional", &[("test", test_plugin::RESPOND)]);
+        let plugin_chain = PluginChain::new(
+            new_engine().unwrap(),
+            &plugin_dir,
+            vec![
+                serde_yaml::from_str("{name: missing, optional: true}").unwrap(),
+                serde_yaml::from_str("name: test").unwrap(),
+            ],
+            HashMap::new(),
+            vec![],
+            65535,
+            None,
+        )
+        .await
+        .unwrap();
+        let (request, dns_packet) = request();
+
+        let response = handle(&plugin_chain, &request, dns_packet).await;
+
+        assert_eq!(response.message_type(), MessageType::Response);
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        assert_eq!(response.id(), request.id());
+    }
+
+    /// a server whose plugin `test` stores its name, and whose `other.example` queries are
+    /// routed to the chain of the plugin `other`, which stores its name too
+    async fn storing_plugin_chain(test: &str, shared: bool) -> PluginChain {
+        let plugin_dir = test_plugin::plugin_dir(
+            test,
+            &[
+                ("test", test_plugin::STORE_PLUGIN_NAME),
+                ("other", test_plugin::STORE_PLUGIN_NAME),
+            ],
+        );
+        let plugin_config = |name: &str| {
+            serde_yaml::from_str(&format!("{{name: {name}, shared: {shared}}}")).unwrap()
+        };
+
+        PluginChain::new(
+            new_engine().unwrap(),
+            &plugin_dir,
+            vec![plugin_config("test")],
+            HashMap::from([("other".to_string(), vec![plugin_config("other")])]),
+            vec![serde_yaml::from_str("{suffix: other.example, chain: other}").unwrap()],
+            65535,
+            None,
+        )
+        .await
+        .unwrap()
+    }
+
+    async fn query_both_chains(plugin_chain: &PluginChain) {
+        for name in ["www.example.com.", "www.other.example."] {
+            let mut request = Message::new();
+            request.set_id(4096).add_query(query(name, RecordType::A));
+            let dns_packet = request.to_vec().unwrap();
+
+            handle(plugin_chain, &request, dns_packet.into()).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn shared_plugins_share_store() {
+        let plugin_chain = storing_plugin_chain("shared-store", true).await;
+        query_both_chains(&plugin_chain).await;
+        assert_eq!(plugin_chain.store_entries(), 2);
+
+        // clearing the store of one shared plugin clears the state of both
+        assert_eq!(plugin_chain.clear_store("test"), 1);
+        assert_eq!(plugin_chain.store_entries(), 0);
+    }
+
+    #[tokio::test]
+    async fn plugins_own_store_unless_shared() {
+        let plugin_chain = storing_plugin_chain("own-store", false).await;
+        query_both_chains(&plugin_chain).await;
+        assert_eq!(plugin_chain.store_entries(), 2);
+
+        assert_eq!(plugin_chain.clear_store("test"), 1);
+        assert_eq!(plugin_chain.store_entries(), 1);
+    }
+
+    #[tokio::test]
+    async fn shared_plugins_need_same_store_options() {
+        let configs: Vec<PluginConfig> = serde_yaml::from_str(
+            "
+- {name: a, shared: true, store_max_entries: 100}
+- {name: b, shared: true, branches: {c: [{name: c, shared: true, store_max_entries: 10}]}}
+- {name: d, store_max_entries: 10}
+",
+        )
+        .unwrap();
+        let mut shared_plugins = vec![];
+        collect_shared_plugins(&configs, &mut shared_plugins);
+
+        let names = shared_plugins
+            .iter()
+            .map(|plugin_config| plugin_config.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["a", "b", "c"]);
+
+        let err = new_shared_store(&shared_plugins).err().unwrap();
+        assert!(err.to_string().contains("shared plugin b"), "{err}");
+        assert!(new_shared_store(&shared_plugins[..1]).unwrap().is_some());
+        assert!(new_shared_store(&[]).unwrap().is_none());
+    }
+
+    #[test]
+    fn route_matches_suffix() {
+        let route = parse_route("{suffix: .internal, chain: internal}");
+
+        assert!(route.matches(&query("internal.", RecordType::A)));
+        assert!(route.matches(&query("www.internal.", RecordType::AAAA)));
+        assert!(route.matches(&query("WWW.Internal.", RecordType::A)));
+        assert!(!route.matches(&query("www.internal.example.", RecordType::A)));
+        assert!(!route.matches(&query("notinternal.", RecordType::A)));
+    }
+
+    #[test]
+    fn route_matches_query_types() {
+        let route = parse_route("{suffix: internal., query_types: [ptr, A], chain: internal}");
+
+        assert!(route.matches(&query("www.internal.", RecordType::A)));
+        assert!(route.matches(&query("1.0.internal.", RecordType::PTR)));
+        assert!(!route.matches(&query("www.internal.", RecordType::AAAA)));
+        assert!(!route.matches(&query("www.example.", RecordType::A)));
+
+        // any name when the suffix is unset
+        let route = parse_route("{query_types: [PTR], chain: reverse}");
+        assert!(route.matches(&query("www.example.", RecordType::PTR)));
+        assert!(!route.matches(&query("www.example.", RecordType::A)));
+    }
+
+    #[test]
+    fn invalid_route_query_type() {
+        let route_config = serde_yaml::from_str("{query_types: [NOPE], chain: c}").unwrap();
+
+        assert!(Route::new(route_config).is_err());
+    }
+
+    #[test]
+    fn same_queries_is_case_sensitive() {
+        let queries = [query("www.example.com.", RecordType::A)];
+
+        assert!(same_queries(
+            &queries,
+            &[query("www.example.com.", RecordType::A)]
+        ));
+        assert!(!same_queries(
+            &queries,
+            &[query("wWw.ExAmple.com.", RecordType::A)]
+        ));
+        assert!(!same_queries(
+            &queries,
+            &[query("www.example.com.", RecordType::AAAA)]
+        ));
+        assert!(!same_queries(&queries, &[]));
+
+        let mut chaos_query = query("www.example.com.", RecordType::A);
+        chaos_query.set_query_class(DNSClass::CH);
+        assert!(!same_queries(&queries, &[chaos_query]));
+    }
+
+    #[tokio::test]
+    async fn restore_original_question() {
+        let plugin_chain =
+            plugin_chain("restore-question", test_plugin::RESPOND_OTHER_QUESTION).await;
+        let (request, dns_packet) = request();
+
+        let response = handle(&plugin_chain, &request, dns_packet).await;
+
+        assert_eq!(response.message_type(), MessageType::Response);
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        assert_eq!(response.id(), request.id());
+        assert!(same_queries(response.queries(), request.queries()));
+    }
+
+    #[tokio::test]
+    async fn servfail_for_non_response() {
+        let plugin_chain = plugin_chain("non-response", test_plugin::ECHO).await;
+        let (request, dns_packet) = request();
+
+        let response = handle(&plugin_chain, &request, dns_packet).await;
+
+        assert_eq!(response.message_type(), MessageType::Response);
+        assert_eq!(response.response_code(), ResponseCode::ServFail);
+        assert_eq!(response.id(), request.id());
+    }
+
+    #[tokio::test]
+    async fn servfail_for_other_id() {
+        let plugin_chain = plugin_chain("other-id", test_plugin::RESPOND_OTHER_ID).await;
+        let (request, dns_packet) = request();
+
+        let response = handle(&plugin_chain, &request, dns_packet).await;
+
+        assert_eq!(response.message_type(), MessageType::Response);
+        assert_eq!(response.response_code(), ResponseCode::ServFail);
+        assert_eq!(response.id(), request.id());
+        assert!(same_queries(response.queries(), request.queries()));
+    }
+
+    #[tokio::test]
+    async fn servfail_with_ede_over_size_cap() {
+        let plugin_chain = plugin_chain_with("size-cap", test_plugin::RESPOND, 16, None).await;
+        let (mut request, _) = request();
+        request.set_edns(Edns::new());
+        let dns_packet = Bytes::from(request.to_vec().unwrap());
+
+        let response = handle(&plugin_chain, &request, dns_packet).await;
+
+        assert_eq!(response.message_type(), MessageType::Response);
+        assert_eq!(response.response_code(), ResponseCode::ServFail);
+        let mut option_data = EDE_INFO_CODE_OTHER.to_be_bytes().to_vec();
+        option_data.extend_from_slice(b"response too large");
+        assert_eq!(
+            response
+                .extensions()
+                .as_ref()
+                .unwrap()
+                .option(EdnsCode::Unknown(EDE_OPTION_CODE)),
+            Some(&EdnsOption::Unknown(EDE_OPTION_CODE, option_data))
+        );
+    }
+
+    #[tokio::test]
+    async fn servfail_without_ede_over_size_cap() {
+        let plugin_chain =
+            plugin_chain_with("size-cap-no-edns", test_plugin::RESPOND, 16, None).await;
+        let (request, dns_packet) = request();
+
+        let response = handle(&plugin_chain, &request, dns_packet).await;
+
+        assert_eq!(response.response_code(), ResponseCode::ServFail);
+        assert!(response.extensions().is_none());
+    }
+
+    #[tokio::test]
+    async fn log_query_at_slow_threshold() {
+        let plugin_chain = plugin_chain_with(
+            "slow-query",
+            test_plugin::RESPOND,
+            65535,
+            Some(Duration::ZERO),
+        )
+        .await;
+        let (request, dns_packet) = request();
+        let log_buffer = LogBuffer::default();
+
+        {
+            let _guard = log_buffer.capture();
+            handle(&plugin_chain, &request, dns_packet).await;
+        }
+
+        let logs = log_buffer.logs();
+        assert!(logs.contains("slow query"), "{logs}");
+        assert!(logs.contains("plugin_durations"), "{logs}");
+    }
+
+    #[tokio::test]
+    async fn skip_query_under_slow_threshold() {
+        let plugin_chain = plugin_chain_with(
+            "fast-query",
+            test_plugin::RESPOND,
+            65535,
+            Some(Duration::from_secs(3600)),
+        )
+        .await;
+        let (request, dns_packet) = request();
+        let log_buffer = LogBuffer::default();
+
+        {
+            let _guard = log_buffer.capture();
+            handle(&plugin_chain, &request, dns_packet).await;
+        }
+
+        let logs = log_buffer.logs();
+        assert!(!logs.contains("slow query"), "{logs}");
+        // the logs are captured
+        assert!(logs.contains("start call plugin"), "{logs}");
     }
 }
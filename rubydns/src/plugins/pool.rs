@@ -1,59 +1,228 @@
-use std::ops::DerefMut;
+use std::collections::HashMap;
+use std::ptr;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use async_trait::async_trait;
 use bytes::Bytes;
-use dashmap::DashMap;
 use deadpool::managed;
 use deadpool::managed::{Pool, RecycleResult};
+use deadpool::Runtime;
 use host::command;
 use tap::TapFallible;
 use thiserror::Error;
-use tracing::{error, info};
+use tokio::time;
+use tracing::{error, info, instrument};
 use wasmtime::component::{Component, Linker};
 use wasmtime::{Engine, Store};
 
 use super::helper;
-use super::host_helper::HostHelper;
+use super::host_helper::{HostHelper, RequestContext};
 use super::tcp_helper;
 use super::udp_helper;
 use super::Rubydns;
-use crate::plugins::host_helper::StoreValue;
+use crate::plugins::host_helper::{PluginStore, StaticMap, TlsClient};
+
+/// the fuel a plugin runs between the yields to the executor
+const YIELD_FUEL: u64 = 10000;
 
 #[derive(Clone)]
 pub struct PluginPool {
+    name: String,
+    version: String,
+    timeout: Option<Duration>,
     pool: Pool<Manager>,
 }
 
+/// the plugins which a plugin can call
+#[derive(Clone, Default)]
+pub struct Downstream {
+    pub next_plugin: Option<PluginPool>,
+    /// the named branches, which are called by name instead of following the chain
+    pub branches: Arc<HashMap<String, PluginPool>>,
+}
+
+impl Downstream {
+    fn plugins(&self) -> impl Iterator<Item = &PluginPool> {
+        self.next_plugin.iter().chain(self.branches.values())
+    }
+}
+
+#[derive(Debug, Default, Copy, Clone)]
+pub struct PoolOptions {
+    /// bound a single run of the plugin
+    pub run_timeout: Option<Duration>,
+    /// the deadpool default is used when unset
+    pub max_size: Option<usize>,
+    /// wait for a free instance forever when unset
+    pub wait_timeout: Option<Duration>,
+    /// the fuel of a single run, the run traps when it is used up, unbounded when unset
+    pub fuel_limit: Option<u64>,
+}
+
+#[derive(Debug, Error)]
+pub enum RunError {
+    #[error("get plugin from pool failed: {0}")]
+    GetPlugin(anyhow::Error),
+
+    #[error("plugin run error: {0}")]
+    Run(wasmtime::Error),
+
+    #[error("plugin {plugin} run timeout after {timeout:?}")]
+    Timeout { plugin: String, timeout: Duration },
+}
+
 impl PluginPool {
+    #[allow(clippy::too_many_arguments)]
     pub async fn new(
+        name: String,
+        pool_options: PoolOptions,
+        plugin_store_map: Arc<PluginStore>,
         engine: Engine,
         plugin_binary: Bytes,
         raw_config: String,
-        next_plugin: Option<PluginPool>,
+        static_maps: HashMap<String, StaticMap>,
+        tls_client: TlsClient,
+        downstream: Downstream,
     ) -> anyhow::Result<Self> {
-        let pool = Pool::builder(Manager {
+        // compile once, the instances created by the pool share the compiled component
+        let component = Component::new(&engine, &plugin_binary)
+            .tap_err(|err| error!(%err, plugin = %name, "compile plugin failed"))?;
+
+        let mut builder = Pool::builder(Manager {
+            plugin_name: Arc::new(name.clone()),
             engine,
-            plugin_binary,
+            component,
             raw_config: Arc::new(raw_config),
-            next_plugin,
-            plugin_store_map: Arc::new(Default::default()),
+            downstream,
+            plugin_store_map,
+            static_maps: Arc::new(static_maps),
+            tls_client: Arc::new(tls_client),
+            fuel_limit: pool_options.fuel_limit,
         })
-        .build()
-        .expect("build plugin pool failed");
+        .runtime(Runtime::Tokio1)
+        .wait_timeout(pool_options.wait_timeout);
+        if let Some(max_size) = pool_options.max_size {
+            builder = builder.max_size(max_size);
+        }
 
-        let plugin_pool = Self { pool };
+        let pool = builder.build().expect("build plugin pool failed");
+
+        let mut plugin_pool = Self {
+            name,
+            version: Default::default(),
+            timeout: pool_options.run_timeout,
+            pool,
+        };
         plugin_pool.validate_config().await?;
+        plugin_pool.version = plugin_pool.load_version().await?;
 
-        info!(raw_config = %plugin_pool.pool.manager().raw_config, "plugin config valid");
+        info!(
+            plugin = %plugin_pool.name,
+            version = %plugin_pool.version,
+            raw_config = %plugin_pool.pool.manager().raw_config,
+            "plugin config valid"
+        );
 
         Ok(plugin_pool)
     }
 
-    pub async fn get_plugin(
+    /// get a plugin from pool and run it, the run is bounded by the plugin timeout if set
+    #[instrument(skip_all, fields(plugin = %self.name, version = %self.version))]
+    pub async fn run(
         &self,
-    ) -> anyhow::Result<impl DerefMut<Target = (Rubydns, Store<HostHelper>)> + '_> {
-        Ok(self.pool.get().await?)
+        request_context: RequestContext,
+        dns_packet: &[u8],
+    ) -> Result<Result<Vec<u8>, helper::Error>, RunError> {
+        let start = Instant::now();
+        let plugin_durations = request_context.plugin_durations.clone();
+
+        let result = self.run_plugin(request_context, dns_packet).await;
+
+        plugin_durations
+            .lock()
+            .unwrap()
+            .push((self.name.clone(), start.elapsed()));
+
+        result
+    }
+
+    async fn run_plugin(
+        &self,
+        request_context: RequestContext,
+        dns_packet: &[u8],
+    ) -> Result<Result<Vec<u8>, helper::Error>, RunError> {
+        let mut object = self
+            .pool
+            .get()
+            .await
+            .map_err(|err| RunError::GetPlugin(err.into()))?;
+        let (plugin, store) = &mut *object;
+        store.data_mut().set_request_context(request_context);
+
+        let run = plugin.plugin().call_run(&mut *store, dns_packet);
+        let result = match self.timeout {
+            None => run.await,
+
+            Some(timeout) => match time::timeout(timeout, run).await {
+                Ok(result) => result,
+
+                Err(_) => {
+                    error!(plugin = %self.name, ?timeout, "plugin run timeout");
+
+                    // the instance is interrupted in the middle of a call, don't give it back to the pool
+                    drop(managed::Object::take(object));
+
+                    return Err(RunError::Timeout {
+                        plugin: self.name.clone(),
+                        timeout,
+                    });
+                }
+            },
+        };
+
+        result.map_err(|err| {
+            error!(%err, plugin = %self.name, "plugin run trapped, discard the instance");
+
+            // a trapped instance, such as one running out of fuel, can't be called again
+            drop(managed::Object::take(object));
+
+            RunError::Run(err)
+        })
+    }
+
+    /// clear the stores of the plugins with the name in this plugin and the downstream plugins,
+    /// return how many stores are cleared
+    pub fn clear_store(&self, plugin: &str) -> usize {
+        let manager = self.pool.manager();
+        let mut cleared = 0;
+        if self.name == plugin {
+            manager.plugin_store_map.clear();
+            cleared += 1;
+        }
+
+        cleared
+            + manager
+                .downstream
+                .plugins()
+                .map(|downstream| downstream.clear_store(plugin))
+                .sum::<usize>()
+    }
+
+    /// collect the stores of this plugin and the downstream plugins, a store shared by many
+    /// plugins is collected once
+    pub fn stores<'a>(&'a self, stores: &mut Vec<&'a PluginStore>) {
+        let manager = self.pool.manager();
+        if !stores
+            .iter()
+            .any(|store| ptr::eq(*store, &*manager.plugin_store_map))
+        {
+            stores.push(&manager.plugin_store_map);
+        }
+
+        for plugin in manager.downstream.plugins() {
+            plugin.stores(stores);
+        }
     }
 
     async fn validate_config(&self) -> anyhow::Result<()> {
@@ -73,12 +242,33 @@ impl PluginPool {
             Err(err) => {
                 error!(?err, raw_config = %self.pool.manager().raw_config, "plugin config invalid");
 
-                Err(anyhow::anyhow!("plugin config invalid: {err:?}"))
+                Err(anyhow::anyhow!(
+                    "plugin {} config invalid: {}",
+                    self.name,
+                    err.msg
+                ))
             }
 
             Ok(()) => Ok(()),
         }
     }
+
+    async fn load_version(&self) -> anyhow::Result<String> {
+        let mut object = self
+            .pool
+            .get()
+            .await
+            .tap_err(|err| error!(%err, "get plugin failed"))?;
+        let (plugin, store) = &mut *object;
+
+        let version = plugin
+            .plugin()
+            .call_version(store)
+            .await
+            .tap_err(|err| error!(%err, "call plugin version failed"))?;
+
+        Ok(version)
+    }
 }
 
 #[derive(Debug, Error)]
@@ -90,11 +280,38 @@ pub struct Error {
 }
 
 struct Manager {
+    plugin_name: Arc<String>,
     engine: Engine,
-    plugin_binary: Bytes,
+    component: Component,
     raw_config: Arc<String>,
-    next_plugin: Option<PluginPool>,
-    plugin_store_map: Arc<DashMap<Bytes, StoreValue>>,
+    downstream: Downstream,
+    plugin_store_map: Arc<PluginStore>,
+    static_maps: Arc<HashMap<String, StaticMap>>,
+    tls_client: Arc<TlsClient>,
+    fuel_limit: Option<u64>,
+}
+
+impl Manager {
+    /// give the store the fuel of a run, the plugin yields every [`YIELD_FUEL`] so the run
+    /// timeout can interrupt it, a limited run traps after it uses up the limit
+    fn refuel(&self, store: &mut Store<HostHelper>) -> Result<(), Error> {
+        match self.fuel_limit {
+            None => store.out_of_fuel_async_yield(u64::MAX, YIELD_FUEL),
+
+            Some(fuel_limit) => {
+                // the first slice and the injected ones add up to the limit
+                let injections = fuel_limit.saturating_sub(1) / YIELD_FUEL;
+                let first_slice = fuel_limit - injections * YIELD_FUEL;
+
+                let remaining = store.consume_fuel(0)?;
+                store.consume_fuel(remaining)?;
+                store.add_fuel(first_slice)?;
+                store.out_of_fuel_async_yield(injections, YIELD_FUEL);
+            }
+        }
+
+        Ok(())
+    }
 }
 
 #[async_trait]
@@ -107,13 +324,16 @@ impl managed::Manager for Manager {
         let mut store = Store::new(
             &self.engine,
             HostHelper::new(
+                self.plugin_name.clone(),
                 self.raw_config.clone(),
-                self.next_plugin.clone(),
+                self.downstream.clone(),
                 self.plugin_store_map.clone(),
+                self.static_maps.clone(),
+                self.tls_client.clone(),
             ),
         );
 
-        store.out_of_fuel_async_yield(u64::MAX, 10000);
+        self.refuel(&mut store)?;
 
         helper::add_to_linker(&mut linker, |state: &mut HostHelper| state)
             .tap_err(|err| error!(%err, "helper add to linker failed"))?;
@@ -124,8 +344,7 @@ impl managed::Manager for Manager {
         tcp_helper::add_to_linker(&mut linker, |state: &mut HostHelper| state.tcp_helper())
             .tap_err(|err| error!(%err, "tcp_helper add to linker failed"))?;
 
-        let component = Component::new(&self.engine, &self.plugin_binary)?;
-        let (plugin, _) = Rubydns::instantiate_async(&mut store, &component, &linker).await?;
+        let (plugin, _) = Rubydns::instantiate_async(&mut store, &self.component, &linker).await?;
 
         Ok((plugin, store))
     }
@@ -133,8 +352,158 @@ impl managed::Manager for Manager {
     async fn recycle(&self, obj: &mut Self::Type) -> RecycleResult<Self::Error> {
         let store = &mut obj.1;
         store.data_mut().reset();
-        store.out_of_fuel_async_yield(u64::MAX, 10000);
+        self.refuel(store)?;
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    extern crate test;
+
+    use test::Bencher;
+    use tokio::runtime::Runtime as TokioRuntime;
+
+    use super::*;
+    use crate::plugins::host_helper::{Eviction, StoreOptions};
+    use crate::plugins::{new_engine, test_plugin};
+    use crate::tls;
+
+    async fn plugin_pool(run: &str, pool_options: PoolOptions) -> PluginPool {
+        PluginPool::new(
+            "test".to_string(),
+            pool_options,
+            Arc::new(PluginStore::new(StoreOptions {
+                dedup: false,
+                max_entries: None,
+                eviction: Eviction::Lru,
+                sweep_interval: None,
+                scan_limit: 100,
+            })),
+            new_engine().unwrap(),
+            test_plugin::plugin(run).into(),
+            String::new(),
+            HashMap::new(),
+            TlsClient::new(tls::load_client_config(None).await.unwrap(), HashMap::new()),
+            Downstream::default(),
+        )
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn slow_plugin_timeout() {
+        let timeout = Duration::from_millis(100);
+        let plugin_pool = plugin_pool(
+            test_plugin::LOOP,
+            PoolOptions {
+                run_timeout: Some(timeout),
+                ..Default::default()
+            },
+        )
+        .await;
+        // the instance which loads the config and the version
+        assert_eq!(plugin_pool.pool.status().size, 1);
+
+        match plugin_pool.run(RequestContext::default(), &[0; 12]).await {
+            Err(RunError::Timeout {
+                plugin,
+                timeout: run_timeout,
+            }) => {
+                assert_eq!(plugin, "test");
+                assert_eq!(run_timeout, timeout);
+            }
+
+            _ => panic!("slow plugin doesn't time out"),
+        }
+
+        // the interrupted instance is discarded instead of given back to the pool
+        assert_eq!(plugin_pool.pool.status().size, 0);
+    }
+
+    #[tokio::test]
+    async fn fuel_exhausted_run_traps() {
+        let plugin_pool = plugin_pool(
+            test_plugin::LOOP,
+            PoolOptions {
+                fuel_limit: Some(100_000),
+                ..Default::default()
+            },
+        )
+        .await;
+
+        match plugin_pool.run(RequestContext::default(), &[0; 12]).await {
+            Err(RunError::Run(_)) => {}
+            _ => panic!("plugin doesn't run out of fuel"),
+        }
+
+        // the trapped instance is discarded
+        assert_eq!(plugin_pool.pool.status().size, 0);
+    }
+
+    #[tokio::test]
+    async fn fuel_limited_run_timeout() {
+        let timeout = Duration::from_millis(100);
+        let plugin_pool = plugin_pool(
+            test_plugin::LOOP,
+            PoolOptions {
+                run_timeout: Some(timeout),
+                fuel_limit: Some(u64::MAX / 2),
+                ..Default::default()
+            },
+        )
+        .await;
+
+        // the plugin yields while it burns the limit, so the timeout fires
+        match plugin_pool.run(RequestContext::default(), &[0; 12]).await {
+            Err(RunError::Timeout { .. }) => {}
+            _ => panic!("fuel limited plugin doesn't time out"),
+        }
+    }
+
+    #[tokio::test]
+    async fn read_plugin_name() {
+        let plugin_pool = plugin_pool(test_plugin::STORE_PLUGIN_NAME, PoolOptions::default()).await;
+
+        plugin_pool
+            .run(RequestContext::default(), &[0; 12])
+            .await
+            .unwrap();
+
+        // the plugin stores its configured name as the key
+        assert!(plugin_pool
+            .pool
+            .manager()
+            .plugin_store_map
+            .get(b"test")
+            .is_some());
+    }
+
+    /// every query gets a new instance, like a pool whose instances keep trapping, only the
+    /// instantiation is paid as the component is compiled once
+    #[bench]
+    fn instantiate_under_pool_churn(bencher: &mut Bencher) {
+        let runtime = TokioRuntime::new().unwrap();
+        let plugin_pool =
+            runtime.block_on(plugin_pool(test_plugin::RESPOND, PoolOptions::default()));
+
+        bencher.iter(|| {
+            runtime.block_on(async {
+                let object = plugin_pool.pool.get().await.unwrap();
+                drop(managed::Object::take(object));
+            })
+        });
+
+        assert_eq!(plugin_pool.pool.status().size, 0);
+    }
+
+    /// the compilation which every new instance paid before the component is compiled once
+    #[bench]
+    fn compile_component(bencher: &mut Bencher) {
+        let engine = new_engine().unwrap();
+        let plugin_binary = test_plugin::plugin(test_plugin::RESPOND);
+
+        bencher.iter(|| Component::new(&engine, &plugin_binary).unwrap());
+    }
+}
@@ -0,0 +1,145 @@
+//! the WAT components standing in for the real plugins in the host tests, the real plugins
+//! are built for wasm32-wasi by their own crates
+//!
+//! the `run` body gets the dns packet in `$ptr` and `$len` and returns the pointer of the
+//! result, `$ok` returns the packet as the response
+
+use std::path::{Path, PathBuf};
+use std::{env, fs, process};
+
+/// answer the query with the request itself, only the QR bit is set
+pub const RESPOND: &str =
+    "(call $set-qr (local.get $ptr)) (call $ok (local.get $ptr) (local.get $len))";
+
+/// return the query as it is, it is not a response
+pub const ECHO: &str = "(call $ok (local.get $ptr) (local.get $len))";
+
+/// answer with the first letter of the query name replaced by `x`
+pub const RESPOND_OTHER_QUESTION: &str = "
+    (call $set-qr (local.get $ptr))
+    (i32.store8 offset=13 (local.get $ptr) (i32.const 0x78))
+    (call $ok (local.get $ptr) (local.get $len))";
+
+/// answer with the low byte of the message ID flipped
+pub const RESPOND_OTHER_ID: &str = "
+    (call $set-qr (local.get $ptr))
+    (i32.store8 offset=1 (local.get $ptr) (i32.xor (i32.load8_u offset=1 (local.get $ptr)) (i32.const 0xff)))
+    (call $ok (local.get $ptr) (local.get $len))";
+
+/// store the question section in the plugin store without expiry, then answer the query
+pub const STORE_QUESTION: &str = "
+    (call $map-set
+      (i32.add (local.get $ptr) (i32.const 12)) (i32.sub (local.get $len) (i32.const 12))
+      (i32.const 0) (i32.const 0)
+      (i32.const 0) (i64.const 0))
+    (call $set-qr (local.get $ptr))
+    (call $ok (local.get $ptr) (local.get $len))";
+
+/// store the plugin name in the plugin store without expiry, then answer the query
+pub const STORE_PLUGIN_NAME: &str = "
+    (call $plugin-name (i32.const 64))
+    (call $map-set
+      (i32.load (i32.const 64)) (i32.load offset=4 (i32.const 64))
+      (i32.const 0) (i32.const 0)
+      (i32.const 0) (i64.const 0))
+    (call $set-qr (local.get $ptr))
+    (call $ok (local.get $ptr) (local.get $len))";
+
+/// never return, the run burns fuel until it is interrupted
+pub const LOOP: &str = "(loop $forever (br $forever)) (unreachable)";
+
+/// a plugin component whose `run` export executes the body
+pub fn plugin(run: &str) -> String {
+    format!(
+        r#"(component
+  (import "helper" (instance $helper
+    (export "map-set" (func (param "key" (list u8)) (param "value" (list u8)) (param "timeout" (option u64))))
+    (export "plugin-name" (func (result string)))
+  ))
+
+  (core module $libc
+    (memory (export "memory") 1)
+    (global $heap (mut i32) (i32.const 1024))
+    ;; a bump allocator, the arguments of a call are the only live allocations, so it
+    ;; wraps around instead of growing
+    (func (export "realloc") (param i32 i32 i32 i32) (result i32)
+      (local $ptr i32)
+      (if (i32.gt_u (i32.add (global.get $heap) (local.get 3)) (i32.const 65536))
+        (then (global.set $heap (i32.const 1024))))
+      (local.set $ptr (global.get $heap))
+      (global.set $heap (i32.add (local.get $ptr) (local.get 3)))
+      (local.get $ptr))
+  )
+  (core instance $libc (instantiate $libc))
+
+  (core func $map-set (canon lower (func $helper "map-set") (memory $libc "memory")))
+  (core func $plugin-name (canon lower (func $helper "plugin-name") (memory $libc "memory") (realloc (func $libc "realloc"))))
+
+  (core module $plugin
+    (import "libc" "memory" (memory 1))
+    (import "helper" "map-set" (func $map-set (param i32 i32 i32 i32 i32 i64)))
+    (import "helper" "plugin-name" (func $plugin-name (param i32)))
+
+    (func $set-qr (param $ptr i32)
+      (i32.store8 offset=2 (local.get $ptr)
+        (i32.or (i32.load8_u offset=2 (local.get $ptr)) (i32.const 0x80))))
+
+    (func $ok (param $ptr i32) (param $len i32) (result i32)
+      (i32.store8 (i32.const 0) (i32.const 0))
+      (i32.store offset=4 (i32.const 0) (local.get $ptr))
+      (i32.store offset=8 (i32.const 0) (local.get $len))
+      (i32.const 0))
+
+    (func (export "run") (param $ptr i32) (param $len i32) (result i32)
+      {run})
+
+    (func (export "valid-config") (result i32)
+      (i32.store8 (i32.const 16) (i32.const 0))
+      (i32.const 16))
+
+    (data (i32.const 48) "test")
+    (func (export "version") (result i32)
+      (i32.store (i32.const 32) (i32.const 48))
+      (i32.store offset=4 (i32.const 32) (i32.const 4))
+      (i32.const 32))
+  )
+  (core instance $plugin (instantiate $plugin
+    (with "libc" (instance $libc))
+    (with "helper" (instance
+      (export "map-set" (func $map-set))
+      (export "plugin-name" (func $plugin-name))))
+  ))
+
+  (type $error (record (field "code" u32) (field "msg" string)))
+  (func $run (param "dns-packet" (list u8)) (result (result (list u8) (error $error)))
+    (canon lift (core func $plugin "run") (memory $libc "memory") (realloc (func $libc "realloc"))))
+  (func $valid-config (result (result (error $error)))
+    (canon lift (core func $plugin "valid-config") (memory $libc "memory") (realloc (func $libc "realloc"))))
+  (func $version (result string)
+    (canon lift (core func $plugin "version") (memory $libc "memory") (realloc (func $libc "realloc"))))
+
+  (instance $exports
+    (export "run" (func $run))
+    (export "valid-config" (func $valid-config))
+    (export "version" (func $version)))
+  (export "plugin" (instance $exports))
+)"#
+    )
+}
+
+/// a plugin dir under the temp dir which is unique to the test, the plugins are written as
+/// `<name>.wasm`, the component text is accepted like the binary
+pub fn plugin_dir(test: &str, plugins: &[(&str, &str)]) -> PathBuf {
+    let plugin_dir = env::temp_dir().join(format!("rubydns-{}-{test}", process::id()));
+    fs::create_dir_all(&plugin_dir).unwrap();
+
+    for (name, run) in plugins {
+        write_plugin(&plugin_dir, name, run);
+    }
+
+    plugin_dir
+}
+
+pub fn write_plugin(plugin_dir: &Path, name: &str, run: &str) {
+    fs::write(plugin_dir.join(format!("{name}.wasm")), plugin(run)).unwrap();
+}
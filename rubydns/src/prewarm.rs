@@ -0,0 +1,172 @@
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use futures_util::future::join_all;
+use tracing::{info, instrument, warn};
+use trust_dns_proto::op::{Message, MessageType, Query};
+use trust_dns_proto::rr::{Name, RecordType};
+
+use crate::config;
+use crate::plugins::Transport;
+use crate::server::ServerState;
+
+/// query the configured names through the plugin chain, so the cache plugins are warmed
+/// before the clients ask
+pub struct Prewarm {
+    state: Arc<ServerState>,
+    queries: Vec<Query>,
+    interval: Option<Duration>,
+}
+
+impl Prewarm {
+    pub fn new(state: Arc<ServerState>, config: config::Prewarm) -> anyhow::Result<Self> {
+        let query_types = config
+            .query_types
+            .iter()
+            .map(|query_type| {
+                RecordType::from_str(query_type)
+                    .with_context(|| format!("invalid prewarm query type {query_type}"))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let mut queries = Vec::with_capacity(config.names.len() * query_types.len());
+        for name in &config.names {
+            let name =
+                Name::from_str(name).with_context(|| format!("invalid prewarm name {name}"))?;
+
+            queries.extend(
+                query_types
+                    .iter()
+                    .map(|query_type| Query::query(name.clone(), *query_type)),
+            );
+        }
+
+        Ok(Self {
+            state,
+            queries,
+            interval: config.interval_secs.map(Duration::from_secs),
+        })
+    }
+
+    /// query the names at once, and again every interval when it is set
+    pub async fn run(self) {
+        loop {
+            self.prewarm().await;
+
+            match self.interval {
+                None => return,
+                Some(interval) => tokio::time::sleep(interval).await,
+            }
+        }
+    }
+
+    #[instrument(skip(self))]
+    async fn prewarm(&self) {
+        // use the current chain, so a reloaded chain is warmed by the next round
+        let plugin_chain = match self.state.plugin_chain() {
+            None => {
+                warn!("plugin chain is not ready, skip prewarm");
+
+                return;
+            }
+
+            Some(plugin_chain) => plugin_chain,
+        };
+
+        let results = join_all(self.queries.iter().map(|query| {
+            let plugin_chain = plugin_chain.clone();
+
+            async move {
+                let mut dns_message = Message::new();
+                dns_message
+                    .set_id(rand::random())
+                    .set_message_type(MessageType::Query)
+                    .set_recursion_desired(true)
+                    .add_query(query.clone());
+
+                let dns_packet = dns_message.to_vec()?;
+
+                plugin_chain
+                    .handle_dns(Transport::Udp, None, dns_message, dns_packet.into())
+                    .await?;
+
+                Ok::<_, anyhow::Error>(())
+            }
+        }))
+        .await;
+
+        let mut failed = 0;
+        for (query, result) in self.queries.iter().zip(results) {
+            if let Err(err) = result {
+                warn!(%err, %query, "prewarm query failed");
+
+                failed += 1;
+            }
+        }
+
+        info!(queries = self.queries.len(), failed, "prewarm done");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::config::NotReady;
+    use crate::plugins::{new_engine, test_plugin, PluginChain};
+
+    fn prewarm_config(names: &[&str], query_types: &[&str]) -> config::Prewarm {
+        config::Prewarm {
+            names: names.iter().map(|name| name.to_string()).collect(),
+            query_types: query_types
+                .iter()
+                .map(|query_type| query_type.to_string())
+                .collect(),
+            interval_secs: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn resolve_and_cache_names_at_startup() {
+        let plugin_dir =
+            test_plugin::plugin_dir("prewarm", &[("test", test_plugin::STORE_QUESTION)]);
+        let plugin_chain = PluginChain::new(
+            new_engine().unwrap(),
+            &plugin_dir,
+            vec![serde_yaml::from_str("name: test").unwrap()],
+            HashMap::new(),
+            vec![],
+            65535,
+            None,
+        )
+        .await
+        .unwrap();
+        let state = Arc::new(ServerState::new(NotReady::default(), None, None));
+        state.set_plugin_chain(plugin_chain);
+
+        let names = ["www.example.com.", "mail.example.com.", "example.org."];
+        let query_types = ["A", "AAAA"];
+        Prewarm::new(state.clone(), prewarm_config(&names, &query_types))
+            .unwrap()
+            .run()
+            .await;
+
+        // the plugin stores every question it answers
+        assert_eq!(
+            state.plugin_chain().unwrap().store_entries(),
+            names.len() * query_types.len()
+        );
+    }
+
+    #[tokio::test]
+    async fn reject_invalid_query_type() {
+        let state = Arc::new(ServerState::new(NotReady::default(), None, None));
+
+        let result = Prewarm::new(state, prewarm_config(&["example.com."], &["NOPE"]));
+
+        assert!(result.is_err());
+    }
+}
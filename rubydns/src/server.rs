@@ -1,37 +1,49 @@
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 
 use bytes::Bytes;
 use tap::TapFallible;
-use tracing::{error, instrument};
-use trust_dns_proto::op::{Message, MessageType, ResponseCode};
+use tokio::sync::{Notify, OwnedSemaphorePermit, Semaphore};
+use tokio::time;
+use tracing::{error, field, instrument, warn, Span};
+use trust_dns_proto::op::{Edns, Message, MessageType, ResponseCode};
+use trust_dns_proto::rr::rdata::opt::EdnsOption;
+use trust_dns_proto::rr::RecordType;
 
-use crate::handle::udp;
-use crate::plugins::PluginChain;
+use crate::config::NotReady;
+use crate::handle::{Accept, ClientIdentify, Respond};
+use crate::metrics;
+use crate::plugins::{Error as PluginError, PluginChain, EDE_OPTION_CODE};
 
-pub struct Server<UdpHandler> {
-    inner: Arc<ServerInner<UdpHandler>>,
+/// the highest EDNS version the server understands
+const SUPPORTED_EDNS_VERSION: u8 = 0;
+
+/// the extended DNS error info code "Not Ready", RFC 8914
+const EDE_INFO_CODE_NOT_READY: u16 = 14;
+
+pub struct Server<Handler> {
+    inner: Arc<ServerInner<Handler>>,
 }
 
-impl<UdpHandler: udp::Accept + udp::Respond> Server<UdpHandler>
+impl<Handler> Server<Handler>
 where
-    UdpHandler: udp::Accept,
-    UdpHandler: udp::Respond<Identify = <UdpHandler as udp::Accept>::Identify>,
-    UdpHandler: Send + Sync + 'static,
+    Handler: Accept + Respond<Identify = <Handler as Accept>::Identify>,
+    Handler: Send + Sync + 'static,
 {
-    pub fn new(udp_handler: UdpHandler, plugin_chain: PluginChain) -> Self {
+    /// the servers listening on the same address with different transports share the
+    /// state, so they use the same plugin chain
+    pub fn new(handler: Handler, state: Arc<ServerState>) -> Self {
         Self {
-            inner: Arc::new(ServerInner {
-                udp_handler,
-                plugin_chain,
-            }),
+            inner: Arc::new(ServerInner { handler, state }),
         }
     }
 
     pub async fn serve(&mut self) {
         loop {
-            let (identify, dns_message, dns_packet) = match self.inner.udp_handler.accept().await {
+            let (identify, dns_message, dns_packet) = match self.inner.handler.accept().await {
                 Err(err) => {
-                    error!(%err, "accept udp request failed");
+                    error!(%err, "accept request failed");
 
                     continue;
                 }
@@ -45,52 +57,227 @@ where
 
     fn handle(
         &mut self,
-        identify: <UdpHandler as udp::Accept>::Identify,
+        identify: <Handler as Accept>::Identify,
         dns_message: Message,
         dns_packet: Bytes,
     ) {
+        let permit = match self.inner.state.acquire_inflight() {
+            Err(()) => {
+                let dropped = self.inner.state.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+                warn!(
+                    queries = ?dns_message.queries(),
+                    dropped,
+                    "too many in-flight queries, drop the query"
+                );
+
+                return;
+            }
+
+            Ok(permit) => permit,
+        };
+
         let inner = self.inner.clone();
 
         tokio::spawn(async move {
             let _ = inner.handle(identify, dns_message, dns_packet).await;
+
+            drop(permit);
         });
     }
 }
 
-pub struct ServerInner<UdpHandler> {
-    udp_handler: UdpHandler,
-    plugin_chain: PluginChain,
+/// the server state which can be inspected and changed when the server is serving
+pub struct ServerState {
+    /// unset until the plugin chain is created
+    plugin_chain: RwLock<Option<Arc<PluginChain>>>,
+    /// wake the queries queued for the plugin chain
+    ready: Notify,
+    not_ready: NotReady,
+    /// the upper bound of the plugin chain handling a query
+    query_timeout: Option<Duration>,
+    /// bound the queries handling at the same time
+    inflight: Option<Arc<Semaphore>>,
+    maintenance: AtomicBool,
+    queries: AtomicU64,
+    /// the queries dropped because of the in-flight bound
+    dropped: AtomicU64,
+}
+
+impl ServerState {
+    /// the state isn't ready until the plugin chain is set
+    pub fn new(
+        not_ready: NotReady,
+        query_timeout: Option<Duration>,
+        max_inflight: Option<usize>,
+    ) -> Self {
+        Self {
+            plugin_chain: RwLock::new(None),
+            ready: Notify::new(),
+            not_ready,
+            query_timeout,
+            inflight: max_inflight.map(|max_inflight| Arc::new(Semaphore::new(max_inflight))),
+            maintenance: AtomicBool::new(false),
+            queries: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    pub fn plugin_chain(&self) -> Option<Arc<PluginChain>> {
+        self.plugin_chain.read().unwrap().clone()
+    }
+
+    /// swap the plugin chain, the queries which are handling keep using the old one
+    pub fn set_plugin_chain(&self, plugin_chain: PluginChain) {
+        *self.plugin_chain.write().unwrap() = Some(Arc::new(plugin_chain));
+
+        self.ready.notify_waiters();
+    }
+
+    /// get the plugin chain, when it isn't ready, wait for it if the not ready policy
+    /// queues the queries
+    async fn ready_plugin_chain(&self) -> Option<Arc<PluginChain>> {
+        // register before checking, so a chain set between them still wakes it
+        let ready = self.ready.notified();
+        if let Some(plugin_chain) = self.plugin_chain() {
+            return Some(plugin_chain);
+        }
+
+        match self.not_ready {
+            NotReady::Servfail => None,
+            NotReady::Queue { timeout_ms } => {
+                time::timeout(Duration::from_millis(timeout_ms), ready)
+                    .await
+                    .ok()?;
+
+                self.plugin_chain()
+            }
+        }
+    }
+
+    pub fn maintenance(&self) -> bool {
+        self.maintenance.load(Ordering::Relaxed)
+    }
+
+    /// when in maintenance, the server refuses all queries without calling the plugin chain
+    pub fn set_maintenance(&self, maintenance: bool) {
+        self.maintenance.store(maintenance, Ordering::Relaxed);
+    }
+
+    pub fn queries(&self) -> u64 {
+        self.queries.load(Ordering::Relaxed)
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// the permit is held until the query is handled, it fails without waiting when the
+    /// in-flight queries reach the bound, so a flood can't queue unboundedly
+    fn acquire_inflight(&self) -> Result<Option<OwnedSemaphorePermit>, ()> {
+        match &self.inflight {
+            None => Ok(None),
+            Some(inflight) => inflight
+                .clone()
+                .try_acquire_owned()
+                .map(Some)
+                .map_err(|_| ()),
+        }
+    }
+}
+
+pub struct ServerInner<Handler> {
+    handler: Handler,
+    state: Arc<ServerState>,
 }
 
-impl<UdpHandler> ServerInner<UdpHandler>
+impl<Handler> ServerInner<Handler>
 where
-    UdpHandler: udp::Accept,
-    UdpHandler: udp::Respond<Identify = <UdpHandler as udp::Accept>::Identify>,
+    Handler: Accept + Respond<Identify = <Handler as Accept>::Identify>,
 {
-    #[instrument(err, skip(self, dns_message, dns_packet))]
+    /// the span carries the query metadata, the plugin chain span is under it, so the plugin
+    /// logs can be attributed to the query, the packet is kept out of the span
+    #[instrument(
+        err,
+        skip(self, dns_message, dns_packet),
+        fields(id = dns_message.id(), name, query_type, query_class)
+    )]
     async fn handle(
         &self,
-        identify: <UdpHandler as udp::Accept>::Identify,
+        identify: <Handler as Accept>::Identify,
         mut dns_message: Message,
         dns_packet: Bytes,
     ) -> anyhow::Result<()> {
-        let response = match self
-            .plugin_chain
-            .handle_dns(dns_message.clone(), dns_packet)
-            .await
+        if let Some(query) = dns_message.query() {
+            let span = Span::current();
+            span.record("name", field::display(query.name()));
+            span.record("query_type", field::display(query.query_type()));
+            span.record("query_class", field::display(query.query_class()));
+        }
+
+        self.state.queries.fetch_add(1, Ordering::Relaxed);
+        metrics::record_query();
+
+        let response = if self.state.maintenance() {
+            dns_message.set_message_type(MessageType::Response);
+            dns_message.set_response_code(ResponseCode::Refused);
+
+            dns_message.to_vec()?.into()
+        } else if opt_record_count(&dns_message) > 1 {
+            warn!("query has more than one OPT record");
+
+            format_error_response(&dns_message).to_vec()?.into()
+        } else if let Some(edns) = dns_message
+            .extensions()
+            .as_ref()
+            .filter(|edns| edns.version() > SUPPORTED_EDNS_VERSION)
         {
-            Err(err) => {
-                error!(%err, "plugins handle dns request failed");
+            warn!(version = edns.version(), "unsupported edns version");
+
+            bad_version_response(&dns_message).to_vec()?.into()
+        } else if dns_message.queries().is_empty() {
+            empty_question_response(&dns_message).to_vec()?.into()
+        } else if let Some(plugin_chain) = self.state.ready_plugin_chain().await {
+            let handle_dns = plugin_chain.handle_dns(
+                <Handler as Accept>::Identify::TRANSPORT,
+                Some(identify.client_addr()),
+                dns_message.clone(),
+                dns_packet,
+            );
+            let result = match self.state.query_timeout {
+                None => Ok(handle_dns.await),
+                Some(query_timeout) => time::timeout(query_timeout, handle_dns).await,
+            };
+
+            match result {
+                Ok(Err(PluginError::Dropped)) => return Ok(()),
+
+                Ok(Err(err)) => {
+                    error!(%err, "plugins handle dns request failed");
 
-                dns_message.set_message_type(MessageType::Response);
-                dns_message.set_response_code(ResponseCode::ServFail);
+                    servfail_response(dns_message).to_vec()?.into()
+                }
+
+                Err(_) => {
+                    error!(
+                        queries = ?dns_message.queries(),
+                        timeout = ?self.state.query_timeout,
+                        "plugins handle dns request timeout"
+                    );
+
+                    servfail_response(dns_message).to_vec()?.into()
+                }
 
-                dns_message.to_vec()?.into()
+                Ok(Ok((_, response))) => response,
             }
-            Ok((_, response)) => response,
+        } else {
+            warn!("plugin chain is not ready");
+
+            not_ready_response(&dns_message).to_vec()?.into()
         };
 
-        self.udp_handler
+        metrics::record_response(&response);
+
+        self.handler
             .respond(identify, response)
             .await
             .tap_err(|err| error!(%err, "respond dns failed"))?;
@@ -98,3 +285,292 @@ where
         Ok(())
     }
 }
+
+fn servfail_response(mut request_message: Message) -> Message {
+    request_message.set_message_type(MessageType::Response);
+    request_message.set_response_code(ResponseCode::ServFail);
+
+    request_message
+}
+
+/// the OPT records left in the additional section, the handle only keeps them there when
+/// the query has more than one
+fn opt_record_count(request_message: &Message) -> usize {
+    request_message
+        .additionals()
+        .iter()
+        .filter(|record| record.record_type() == RecordType::OPT)
+        .count()
+}
+
+/// RFC 6891 requires a FORMERR response to a query with more than one OPT record
+fn format_error_response(request_message: &Message) -> Message {
+    let mut response_message = Message::new();
+    response_message
+        .set_id(request_message.id())
+        .set_message_type(MessageType::Response)
+        .set_op_code(request_message.op_code())
+        .set_recursion_desired(request_message.recursion_desired())
+        .set_response_code(ResponseCode::FormErr)
+        .add_queries(request_message.queries().iter().cloned());
+
+    response_message
+}
+
+fn not_ready_response(request_message: &Message) -> Message {
+    let mut response_message = Message::new();
+    response_message
+        .set_id(request_message.id())
+        .set_message_type(MessageType::Response)
+        .set_op_code(request_message.op_code())
+        .set_recursion_desired(request_message.recursion_desired())
+        .set_response_code(ResponseCode::ServFail)
+        .add_queries(request_message.queries().iter().cloned());
+
+    if let Some(request_edns) = request_message.extensions() {
+        let mut edns = Edns::new();
+        edns.set_version(SUPPORTED_EDNS_VERSION)
+            .set_max_payload(request_edns.max_payload());
+        edns.options_mut().insert(EdnsOption::Unknown(
+            EDE_OPTION_CODE,
+            EDE_INFO_CODE_NOT_READY.to_be_bytes().to_vec(),
+        ));
+
+        response_message.set_edns(edns);
+    }
+
+    response_message
+}
+
+/// RFC 6891 requires a BADVERS response carrying the supported version to an unsupported
+/// EDNS version
+fn bad_version_response(request_message: &Message) -> Message {
+    let mut edns = Edns::new();
+    edns.set_version(SUPPORTED_EDNS_VERSION);
+    if let Some(request_edns) = request_message.extensions() {
+        edns.set_max_payload(request_edns.max_payload());
+    }
+
+    let mut response_message = Message::new();
+    response_message
+        .set_id(request_message.id())
+        .set_message_type(MessageType::Response)
+        .set_op_code(request_message.op_code())
+        .set_recursion_desired(request_message.recursion_desired())
+        .set_response_code(ResponseCode::BADVERS)
+        .add_queries(request_message.queries().iter().cloned())
+        .set_edns(edns);
+
+    response_message
+}
+
+/// a query without question is malformed, except the EDNS only probes such as the cookie
+/// or keepalive probes, which get a NOERROR response carrying the OPT record
+fn empty_question_response(request_message: &Message) -> Message {
+    let mut response_message = Message::new();
+    response_message
+        .set_id(request_message.id())
+        .set_message_type(MessageType::Response)
+        .set_op_code(request_message.op_code())
+        .set_recursion_desired(request_message.recursion_desired());
+
+    match request_message.extensions() {
+        None => {
+            warn!("query has no question");
+
+            response_message.set_response_code(ResponseCode::FormErr);
+        }
+
+        Some(request_edns) => {
+            let mut edns = Edns::new();
+            edns.set_version(SUPPORTED_EDNS_VERSION)
+                .set_max_payload(request_edns.max_payload());
+
+            response_message
+                .set_response_code(ResponseCode::NoError)
+                .set_edns(edns);
+        }
+    }
+
+    response_message
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::str::FromStr;
+
+    use trust_dns_proto::op::{OpCode, Query};
+    use trust_dns_proto::rr::rdata::opt::EdnsCode;
+    use trust_dns_proto::rr::rdata::OPT;
+    use trust_dns_proto::rr::{Name, RData, Record};
+
+    use super::*;
+    use crate::plugins::{new_engine, test_plugin};
+
+    fn request() -> Message {
+        let mut message = Message::new();
+        message
+            .set_id(1234)
+            .set_op_code(OpCode::Query)
+            .set_recursion_desired(true)
+            .add_query(Query::query(
+                Name::from_str("www.example.com.").unwrap(),
+                RecordType::A,
+            ));
+
+        message
+    }
+
+    #[test]
+    fn edns_version_1_gets_bad_version() {
+        let mut request_message = request();
+        let mut edns = Edns::new();
+        edns.set_version(1).set_max_payload(1232);
+        request_message.set_edns(edns);
+
+        // the BADVERS code is extended, it must survive the encoding into the OPT record
+        let response_message = bad_version_response(&request_message).to_vec().unwrap();
+        let response_message = Message::from_vec(&response_message).unwrap();
+
+        assert_eq!(response_message.id(), 1234);
+        assert_eq!(response_message.message_type(), MessageType::Response);
+        assert_eq!(response_message.response_code(), ResponseCode::BADVERS);
+        assert_eq!(response_message.queries(), request_message.queries());
+
+        let edns = response_message.extensions().as_ref().unwrap();
+        assert_eq!(edns.version(), SUPPORTED_EDNS_VERSION);
+        assert_eq!(edns.max_payload(), 1232);
+    }
+
+    #[test]
+    fn two_opt_records_get_format_error() {
+        let mut request_message = request();
+        for _ in 0..2 {
+            request_message.add_additional(Record::from_rdata(
+                Name::root(),
+                0,
+                RData::OPT(OPT::new(HashMap::new())),
+            ));
+        }
+
+        assert_eq!(opt_record_count(&request_message), 2);
+        assert_eq!(opt_record_count(&request()), 0);
+
+        let response_message = format_error_response(&request_message);
+
+        assert_eq!(response_message.id(), 1234);
+        assert_eq!(response_message.response_code(), ResponseCode::FormErr);
+        assert!(response_message.recursion_desired());
+        assert_eq!(response_message.queries(), request_message.queries());
+        assert!(response_message.additionals().is_empty());
+    }
+
+    #[test]
+    fn cookie_only_probe_gets_no_error() {
+        let mut request_message = Message::new();
+        let mut edns = Edns::new();
+        edns.set_max_payload(1232);
+        edns.options_mut()
+            .insert(EdnsOption::Unknown(10, vec![1, 2, 3, 4, 5, 6, 7, 8]));
+        request_message.set_id(1234).set_edns(edns);
+
+        let response_message = empty_question_response(&request_message);
+
+        assert_eq!(response_message.id(), 1234);
+        assert_eq!(response_message.response_code(), ResponseCode::NoError);
+        assert_eq!(
+            response_message.extensions().as_ref().unwrap().version(),
+            SUPPORTED_EDNS_VERSION
+        );
+    }
+
+    #[test]
+    fn empty_question_gets_format_error() {
+        let mut request_message = Message::new();
+        request_message.set_id(1234);
+
+        let response_message = empty_question_response(&request_message);
+
+        assert_eq!(response_message.id(), 1234);
+        assert_eq!(response_message.response_code(), ResponseCode::FormErr);
+        assert!(response_message.extensions().is_none());
+    }
+
+    async fn plugin_chain(test: &str) -> PluginChain {
+        let plugin_dir = test_plugin::plugin_dir(test, &[("test", test_plugin::RESPOND)]);
+
+        PluginChain::new(
+            new_engine().unwrap(),
+            &plugin_dir,
+            vec![serde_yaml::from_str("name: test").unwrap()],
+            HashMap::new(),
+            vec![],
+            65535,
+            None,
+        )
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn servfail_when_not_ready() {
+        let state = ServerState::new(NotReady::Servfail, None, None);
+
+        // it doesn't wait for the chain
+        let plugin_chain = time::timeout(Duration::from_secs(1), state.ready_plugin_chain())
+            .await
+            .unwrap();
+        assert!(plugin_chain.is_none());
+
+        let mut request_message = request();
+        request_message.set_edns(Edns::new());
+        let response_message = not_ready_response(&request_message).to_vec().unwrap();
+        let response_message = Message::from_vec(&response_message).unwrap();
+
+        assert_eq!(response_message.id(), 1234);
+        assert_eq!(response_message.response_code(), ResponseCode::ServFail);
+        assert_eq!(
+            response_message
+                .extensions()
+                .as_ref()
+                .unwrap()
+                .option(EdnsCode::Unknown(EDE_OPTION_CODE)),
+            Some(&EdnsOption::Unknown(
+                EDE_OPTION_CODE,
+                EDE_INFO_CODE_NOT_READY.to_be_bytes().to_vec()
+            ))
+        );
+    }
+
+    #[tokio::test]
+    async fn queue_until_ready() {
+        let state = Arc::new(ServerState::new(
+            NotReady::Queue { timeout_ms: 5000 },
+            None,
+            None,
+        ));
+        let plugin_chain = plugin_chain("queue-until-ready").await;
+
+        let ready_plugin_chain = tokio::spawn({
+            let state = state.clone();
+
+            async move { state.ready_plugin_chain().await }
+        });
+        time::sleep(Duration::from_millis(50)).await;
+        state.set_plugin_chain(plugin_chain);
+
+        let ready_plugin_chain = ready_plugin_chain.await.unwrap().unwrap();
+        assert!(Arc::ptr_eq(
+            &ready_plugin_chain,
+            &state.plugin_chain().unwrap()
+        ));
+    }
+
+    #[tokio::test]
+    async fn give_up_queue_after_timeout() {
+        let state = ServerState::new(NotReady::Queue { timeout_ms: 20 }, None, None);
+
+        assert!(state.ready_plugin_chain().await.is_none());
+    }
+}
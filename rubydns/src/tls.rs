@@ -0,0 +1,242 @@
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context};
+use rustls::server::AllowAnyAuthenticatedClient;
+use rustls::sign;
+use rustls::{
+    Certificate, ClientConfig, OwnedTrustAnchor, PrivateKey, RootCertStore, ServerConfig,
+    SignatureScheme,
+};
+use rustls_pemfile::Item;
+use tokio::fs;
+use tracing::{info, warn};
+use webpki::{EndEntityCert, SignatureAlgorithm};
+
+use crate::config::Tls;
+
+/// warn when the cert expires within the days
+const EXPIRY_WARNING_DAYS: i64 = 30;
+
+/// the message signed by the private key to check it matches the cert
+const KEY_MATCH_MESSAGE: &[u8] = b"rubydns tls key match check";
+
+/// the signature schemes tried to check the private key matches the cert
+const KEY_MATCH_SCHEMES: &[(SignatureScheme, &SignatureAlgorithm)] = &[
+    (
+        SignatureScheme::ECDSA_NISTP256_SHA256,
+        &webpki::ECDSA_P256_SHA256,
+    ),
+    (
+        SignatureScheme::ECDSA_NISTP384_SHA384,
+        &webpki::ECDSA_P384_SHA384,
+    ),
+    (SignatureScheme::ED25519, &webpki::ED25519),
+    (
+        SignatureScheme::RSA_PSS_SHA256,
+        &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
+    ),
+    (
+        SignatureScheme::RSA_PKCS1_SHA256,
+        &webpki::RSA_PKCS1_2048_8192_SHA256,
+    ),
+];
+
+/// load the cert chain and private key of the server, verify the client certs with the
+/// client CA when it is set
+pub async fn load_server_config(tls: &Tls) -> anyhow::Result<Arc<ServerConfig>> {
+    let certs = load_certs(&tls.cert_path).await?;
+    let key = load_private_key(&tls.key_path).await?;
+
+    check_key_match(&certs[0], &key).with_context(|| {
+        format!(
+            "cert {} and key {} mismatch",
+            tls.cert_path.display(),
+            tls.key_path.display()
+        )
+    })?;
+    check_expiry(&tls.cert_path, &certs[0])?;
+
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let builder = match &tls.client_ca_path {
+        None => builder.with_no_client_auth(),
+        Some(client_ca_path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(client_ca_path).await? {
+                roots.add(&cert).with_context(|| {
+                    format!("add client CA {} failed", client_ca_path.display())
+                })?;
+            }
+
+            builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots).boxed())
+        }
+    };
+
+    let server_config = builder.with_single_cert(certs, key)?;
+
+    info!(
+        cert_path = %tls.cert_path.display(),
+        mtls = tls.client_ca_path.is_some(),
+        "load tls config done"
+    );
+
+    Ok(Arc::new(server_config))
+}
+
+/// load the client config verifying the server certs with the CA, or the webpki roots when
+/// the CA is unset
+pub async fn load_client_config(ca_path: Option<&Path>) -> anyhow::Result<Arc<ClientConfig>> {
+    let mut roots = RootCertStore::empty();
+    match ca_path {
+        None => {
+            roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|anchor| {
+                OwnedTrustAnchor::from_subject_spki_name_constraints(
+                    anchor.subject,
+                    anchor.spki,
+                    anchor.name_constraints,
+                )
+            }));
+        }
+
+        Some(ca_path) => {
+            for cert in load_certs(ca_path).await? {
+                roots
+                    .add(&cert)
+                    .with_context(|| format!("add CA {} failed", ca_path.display()))?;
+            }
+        }
+    }
+
+    let client_config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+
+    Ok(Arc::new(client_config))
+}
+
+async fn load_certs(path: &Path) -> anyhow::Result<Vec<Certificate>> {
+    let data = fs::read(path)
+        .await
+        .with_context(|| format!("read cert {} failed", path.display()))?;
+
+    let certs = read_pem_items(&data)?
+        .into_iter()
+        .filter_map(|item| match item {
+            Item::X509Certificate(cert) => Some(Certificate(cert)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    if certs.is_empty() {
+        return Err(anyhow!("no cert in {}", path.display()));
+    }
+
+    Ok(certs)
+}
+
+async fn load_private_key(path: &Path) -> anyhow::Result<PrivateKey> {
+    let data = fs::read(path)
+        .await
+        .with_context(|| format!("read private key {} failed", path.display()))?;
+
+    read_pem_items(&data)?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| anyhow!("no private key in {}", path.display()))
+}
+
+fn read_pem_items(mut data: &[u8]) -> io::Result<Vec<Item>> {
+    let mut items = vec![];
+    while let Some(item) = rustls_pemfile::read_one(&mut data)? {
+        items.push(item);
+    }
+
+    Ok(items)
+}
+
+/// sign a message with the private key, and verify the signature with the public key of
+/// the cert
+fn check_key_match(cert: &Certificate, key: &PrivateKey) -> anyhow::Result<()> {
+    let signing_key = sign::any_supported_type(key)?;
+    let end_entity_cert = EndEntityCert::try_from(cert.0.as_slice())
+        .map_err(|err| anyhow!("parse cert failed: {err:?}"))?;
+
+    let matched = KEY_MATCH_SCHEMES.iter().any(|(scheme, algorithm)| {
+        signing_key
+            .choose_scheme(&[*scheme])
+            .and_then(|signer| signer.sign(KEY_MATCH_MESSAGE).ok())
+            .map(|signature| {
+                end_entity_cert
+                    .verify_signature(algorithm, KEY_MATCH_MESSAGE, &signature)
+                    .is_ok()
+            })
+            .unwrap_or(false)
+    });
+    if !matched {
+        return Err(anyhow!("private key doesn't match the cert public key"));
+    }
+
+    Ok(())
+}
+
+fn check_expiry(path: &Path, cert: &Certificate) -> anyhow::Result<()> {
+    let (_, cert) = x509_parser::parse_x509_certificate(&cert.0)
+        .map_err(|err| anyhow!("parse cert {} failed: {err}", path.display()))?;
+
+    match cert.validity().time_to_expiration() {
+        None => warn!(cert_path = %path.display(), "cert is expired or not valid yet"),
+
+        Some(expiration) if expiration.whole_days() < EXPIRY_WARNING_DAYS => {
+            warn!(
+                cert_path = %path.display(),
+                days = expiration.whole_days(),
+                "cert expires soon"
+            )
+        }
+
+        Some(_) => {}
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn testdata(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("testdata")
+            .join(name)
+    }
+
+    #[tokio::test]
+    async fn load_self_signed_pair() {
+        let tls = Tls {
+            cert_path: testdata("cert.pem"),
+            key_path: testdata("key.pem"),
+            client_ca_path: None,
+        };
+
+        load_server_config(&tls).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn reject_mismatched_pair() {
+        let tls = Tls {
+            cert_path: testdata("cert.pem"),
+            key_path: testdata("other-key.pem"),
+            client_ca_path: None,
+        };
+
+        let err = load_server_config(&tls).await.unwrap_err();
+
+        assert!(err.to_string().contains("mismatch"), "{err}");
+    }
+}